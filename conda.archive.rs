@@ -0,0 +1,429 @@
+// conda.archive.rs
+
+use std::borrow::Cow;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use zip::write::FileOptions;
+use zip::CompressionMethod;
+
+use crate::digest;
+use crate::settings::CompressionType;
+
+/// The newest `.conda` format version this module understands.
+pub const CONDA_FORMAT_VERSION: u64 = 2;
+
+/// The default bzip2 level for `.tar.bz2` packages.
+pub(crate) const DEFAULT_BZIP2_LEVEL: u32 = 9;
+
+/// The default zstd level for the tarballs inside `.conda` packages.
+pub(crate) const DEFAULT_ZSTD_LEVEL: i32 = 19;
+
+/// Represents the two conda package archive formats.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ArchiveType {
+    /// A bzip2-compressed tarball (`.tar.bz2`).
+    TarBz2,
+    /// A zip of zstd-compressed `info` and `pkg` tarballs (`.conda`).
+    Conda,
+}
+
+impl ArchiveType {
+    /// Returns the file extension of the format, including the leading dot.
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ArchiveType::TarBz2 => ".tar.bz2",
+            ArchiveType::Conda => ".conda",
+        }
+    }
+
+    /// Splits a package filename into its `name-version-build` stem and format.
+    pub fn split_filename(filename: &str) -> Option<(&str, ArchiveType)> {
+        if let Some(stem) = filename.strip_suffix(".tar.bz2") {
+            Some((stem, ArchiveType::TarBz2))
+        } else {
+            filename.strip_suffix(".conda").map(|stem| (stem, ArchiveType::Conda))
+        }
+    }
+
+    /// Returns the format of a package path, based on its filename.
+    pub fn from_path(path: &Path) -> Option<ArchiveType> {
+        let filename = path.file_name()?.to_str()?;
+        Self::split_filename(filename).map(|(_, archive_type)| archive_type)
+    }
+
+    /// Returns the format that uses a compression type, if conda has one.
+    pub fn from_compression(compression: &CompressionType) -> Option<ArchiveType> {
+        match compression {
+            CompressionType::Bzip2 => Some(ArchiveType::TarBz2),
+            CompressionType::Zstd => Some(ArchiveType::Conda),
+            CompressionType::Gzip | CompressionType::Lzma => None,
+        }
+    }
+}
+
+/// Represents the `metadata.json` member of a `.conda` package.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CondaMetadata {
+    pub conda_pkg_format_version: u64,
+}
+
+impl Default for CondaMetadata {
+    fn default() -> Self {
+        CondaMetadata { conda_pkg_format_version: CONDA_FORMAT_VERSION }
+    }
+}
+
+/// Represents possible errors that can occur when reading or writing package archives.
+#[derive(Error, Debug)]
+pub enum ArchiveError {
+    #[error("Failed to read {path}: {source}")]
+    IoError { path: PathBuf, source: io::Error },
+
+    #[error("Invalid .conda archive {path}: {source}")]
+    ZipError { path: PathBuf, source: zip::result::ZipError },
+
+    #[error("{0} is not a .conda or .tar.bz2 package")]
+    UnknownFormat(PathBuf),
+
+    #[error("{path} has no {member} member")]
+    MissingMember { path: PathBuf, member: String },
+
+    #[error("{path} uses .conda format version {version}, newer than supported")]
+    UnsupportedVersion { path: PathBuf, version: u64 },
+
+    #[error("Invalid metadata.json in {path}: {source}")]
+    InvalidMetadata { path: PathBuf, source: serde_json::Error },
+}
+
+/// Reads a single file from the `info/` section of a package, e.g.
+/// `info/index.json`. For `.conda` packages the payload is never decompressed.
+pub fn read_info_file(path: &Path, name: &str) -> Result<Option<Vec<u8>>, ArchiveError> {
+    let io_error = |source| ArchiveError::IoError { path: path.to_path_buf(), source };
+    let file = File::open(path).map_err(io_error)?;
+
+    match ArchiveType::from_path(path) {
+        Some(ArchiveType::TarBz2) => {
+            let decoder = bzip2::read::BzDecoder::new(BufReader::new(file));
+            find_tar_member(decoder, name).map_err(io_error)
+        }
+        Some(ArchiveType::Conda) => {
+            let mut zip = zip::ZipArchive::new(BufReader::new(file))
+                .map_err(|source| ArchiveError::ZipError { path: path.to_path_buf(), source })?;
+            let info_name = conda_member(&zip, "info", path)?;
+            let member = zip
+                .by_name(&info_name)
+                .map_err(|source| ArchiveError::ZipError { path: path.to_path_buf(), source })?;
+            let decoder = zstd::stream::read::Decoder::new(member).map_err(io_error)?;
+            find_tar_member(decoder, name).map_err(io_error)
+        }
+        None => Err(ArchiveError::UnknownFormat(path.to_path_buf())),
+    }
+}
+
+/// Scans a tar stream for a member and returns its contents.
+fn find_tar_member<R: Read>(reader: R, name: &str) -> io::Result<Option<Vec<u8>>> {
+    let mut archive = tar::Archive::new(reader);
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        if entry.path()?.as_ref() == Path::new(name) {
+            let mut contents = Vec::new();
+            entry.read_to_end(&mut contents)?;
+            return Ok(Some(contents));
+        }
+    }
+    Ok(None)
+}
+
+/// Represents the type of an entry in a package archive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EntryKind {
+    File,
+    Directory,
+    Symlink,
+    HardLink,
+    Other,
+}
+
+/// Represents a tar entry of a package, as stored in the archive.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ArchiveEntry {
+    pub path: PathBuf,
+    pub kind: EntryKind,
+    pub mode: u32,
+    pub mtime: u64,
+    pub size: u64,
+
+    /// The target of symlinks and hard links.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub link_target: Option<PathBuf>,
+
+    /// The sha256 of the contents of regular files.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+}
+
+/// Lists the entries of a package, `info/` section first for `.conda`
+/// packages and in archive order otherwise, hashing file contents.
+pub fn entries(path: &Path) -> Result<Vec<ArchiveEntry>, ArchiveError> {
+    let archive_type = ArchiveType::from_path(path).ok_or_else(|| ArchiveError::UnknownFormat(path.to_path_buf()))?;
+    entries_as(path, archive_type)
+}
+
+/// Lists the entries of a package whose filename may not carry its format,
+/// such as a partially written file.
+pub(crate) fn entries_as(path: &Path, archive_type: ArchiveType) -> Result<Vec<ArchiveEntry>, ArchiveError> {
+    let io_error = |source| ArchiveError::IoError { path: path.to_path_buf(), source };
+    let file = File::open(path).map_err(io_error)?;
+    let mut entries = Vec::new();
+
+    match archive_type {
+        ArchiveType::TarBz2 => read_entries(bzip2::read::BzDecoder::new(BufReader::new(file)), &mut entries).map_err(io_error)?,
+        ArchiveType::Conda => {
+            let mut zip = zip::ZipArchive::new(BufReader::new(file))
+                .map_err(|source| ArchiveError::ZipError { path: path.to_path_buf(), source })?;
+            check_metadata(&mut zip, path)?;
+            for section in ["info", "pkg"] {
+                let name = conda_member(&zip, section, path)?;
+                let member = zip
+                    .by_name(&name)
+                    .map_err(|source| ArchiveError::ZipError { path: path.to_path_buf(), source })?;
+                let decoder = zstd::stream::read::Decoder::new(member).map_err(io_error)?;
+                read_entries(decoder, &mut entries).map_err(io_error)?;
+            }
+        }
+    }
+    Ok(entries)
+}
+
+fn read_entries<R: Read>(reader: R, out: &mut Vec<ArchiveEntry>) -> io::Result<()> {
+    let mut archive = tar::Archive::new(reader);
+    for entry in archive.entries()? {
+        let entry = entry?;
+        let header = entry.header();
+        let entry_type = header.entry_type();
+        let kind = if entry_type.is_file() {
+            EntryKind::File
+        } else if entry_type.is_dir() {
+            EntryKind::Directory
+        } else if entry_type.is_symlink() {
+            EntryKind::Symlink
+        } else if entry_type.is_hard_link() {
+            EntryKind::HardLink
+        } else {
+            EntryKind::Other
+        };
+        let (mode, mtime) = (header.mode()?, header.mtime()?);
+        let path = entry.path()?.into_owned();
+        let link_target = entry.link_name()?.map(Cow::into_owned);
+        let size = entry.size();
+        let sha256 = if kind == EntryKind::File {
+            Some(digest::sha256_reader(entry)?)
+        } else {
+            None
+        };
+
+        out.push(ArchiveEntry { path, kind, mode, mtime, size, link_target, sha256 });
+    }
+    Ok(())
+}
+
+/// Which parts of a package to extract.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Section {
+    All,
+    InfoOnly,
+}
+
+/// Extracts a package file into a directory.
+pub fn extract(path: &Path, dest: &Path) -> Result<(), ArchiveError> {
+    let archive_type = ArchiveType::from_path(path).ok_or_else(|| ArchiveError::UnknownFormat(path.to_path_buf()))?;
+    let file = File::open(path).map_err(|source| ArchiveError::IoError { path: path.to_path_buf(), source })?;
+    extract_stream(BufReader::new(file), archive_type, dest)
+}
+
+/// Extracts a package from a stream, such as a download in progress, without
+/// buffering it in memory or on disk first.
+pub fn extract_stream<R: Read>(reader: R, archive_type: ArchiveType, dest: &Path) -> Result<(), ArchiveError> {
+    extract_sections(reader, archive_type, dest, Section::All)
+}
+
+/// Extracts only the `info/` section of a package. For `.conda` packages the
+/// payload is skipped without being decompressed.
+pub fn extract_info(path: &Path, dest: &Path) -> Result<(), ArchiveError> {
+    let io_error = |source| ArchiveError::IoError { path: path.to_path_buf(), source };
+    match ArchiveType::from_path(path) {
+        Some(ArchiveType::Conda) => {
+            let file = File::open(path).map_err(io_error)?;
+            let mut zip = zip::ZipArchive::new(BufReader::new(file))
+                .map_err(|source| ArchiveError::ZipError { path: path.to_path_buf(), source })?;
+            check_metadata(&mut zip, path)?;
+            let info_name = conda_member(&zip, "info", path)?;
+            let member = zip
+                .by_name(&info_name)
+                .map_err(|source| ArchiveError::ZipError { path: path.to_path_buf(), source })?;
+            let decoder = zstd::stream::read::Decoder::new(member).map_err(io_error)?;
+            unpack_tar(decoder, dest, Section::All)
+        }
+        Some(archive_type) => {
+            let file = File::open(path).map_err(io_error)?;
+            extract_sections(BufReader::new(file), archive_type, dest, Section::InfoOnly)
+        }
+        None => Err(ArchiveError::UnknownFormat(path.to_path_buf())),
+    }
+}
+
+fn extract_sections<R: Read>(mut reader: R, archive_type: ArchiveType, dest: &Path, section: Section) -> Result<(), ArchiveError> {
+    let io_error = |source| ArchiveError::IoError { path: dest.to_path_buf(), source };
+    fs::create_dir_all(dest).map_err(io_error)?;
+
+    match archive_type {
+        ArchiveType::TarBz2 => unpack_tar(bzip2::read::BzDecoder::new(reader), dest, section),
+        ArchiveType::Conda => {
+            // Read the zip sequentially so non-seekable streams work too.
+            loop {
+                let member = zip::read::read_zipfile_from_stream(&mut reader)
+                    .map_err(|source| ArchiveError::ZipError { path: dest.to_path_buf(), source })?;
+                let mut member = match member {
+                    Some(member) => member,
+                    None => return Ok(()),
+                };
+
+                let name = member.name().to_string();
+                if name == "metadata.json" {
+                    let mut contents = Vec::new();
+                    member.read_to_end(&mut contents).map_err(io_error)?;
+                    parse_metadata(&contents, dest)?;
+                } else if name.ends_with(".tar.zst") && (name.starts_with("info-") || (name.starts_with("pkg-") && section == Section::All)) {
+                    let decoder = zstd::stream::read::Decoder::new(member).map_err(io_error)?;
+                    unpack_tar(decoder, dest, Section::All)?;
+                }
+            }
+        }
+    }
+}
+
+/// Unpacks a tar stream, optionally keeping only `info/` entries.
+fn unpack_tar<R: Read>(reader: R, dest: &Path, section: Section) -> Result<(), ArchiveError> {
+    let io_error = |source| ArchiveError::IoError { path: dest.to_path_buf(), source };
+    let mut archive = tar::Archive::new(reader);
+    archive.set_overwrite(true);
+    archive.set_preserve_mtime(true);
+
+    if section == Section::All {
+        return archive.unpack(dest).map_err(io_error);
+    }
+    for entry in archive.entries().map_err(io_error)? {
+        let mut entry = entry.map_err(io_error)?;
+        if entry.path().map_err(io_error)?.starts_with("info") {
+            entry.unpack_in(dest).map_err(io_error)?;
+        }
+    }
+    Ok(())
+}
+
+/// Returns the name of the `info-*.tar.zst` or `pkg-*.tar.zst` member of a `.conda` zip.
+pub(crate) fn conda_member<R: Read + io::Seek>(zip: &zip::ZipArchive<R>, section: &str, path: &Path) -> Result<String, ArchiveError> {
+    let prefix = format!("{}-", section);
+    zip.file_names()
+        .find(|member| member.starts_with(&prefix) && member.ends_with(".tar.zst"))
+        .map(String::from)
+        .ok_or_else(|| ArchiveError::MissingMember {
+            path: path.to_path_buf(),
+            member: format!("{}*.tar.zst", prefix),
+        })
+}
+
+/// Rejects `.conda` files written in a newer format version.
+pub(crate) fn check_metadata<R: Read + io::Seek>(zip: &mut zip::ZipArchive<R>, path: &Path) -> Result<(), ArchiveError> {
+    let mut member = match zip.by_name("metadata.json") {
+        Ok(member) => member,
+        Err(zip::result::ZipError::FileNotFound) => return Ok(()),
+        Err(source) => return Err(ArchiveError::ZipError { path: path.to_path_buf(), source }),
+    };
+    let mut contents = Vec::new();
+    member
+        .read_to_end(&mut contents)
+        .map_err(|source| ArchiveError::IoError { path: path.to_path_buf(), source })?;
+    parse_metadata(&contents, path).map(|_| ())
+}
+
+fn parse_metadata(contents: &[u8], path: &Path) -> Result<CondaMetadata, ArchiveError> {
+    let metadata: CondaMetadata = serde_json::from_slice(contents)
+        .map_err(|source| ArchiveError::InvalidMetadata { path: path.to_path_buf(), source })?;
+    if metadata.conda_pkg_format_version > CONDA_FORMAT_VERSION {
+        return Err(ArchiveError::UnsupportedVersion {
+            path: path.to_path_buf(),
+            version: metadata.conda_pkg_format_version,
+        });
+    }
+    Ok(metadata)
+}
+
+/// Creates a package file from files relative to `base_dir`. The format
+/// follows the extension of `dest`; `info/` files go into the info section
+/// (first in a `.tar.bz2`, a separate tarball in a `.conda`). The level
+/// defaults to the format's usual maximum.
+pub fn create(base_dir: &Path, files: &[PathBuf], dest: &Path, compression_level: Option<i32>) -> Result<(), ArchiveError> {
+    let (stem, archive_type) = dest
+        .file_name()
+        .and_then(|name| name.to_str())
+        .and_then(ArchiveType::split_filename)
+        .ok_or_else(|| ArchiveError::UnknownFormat(dest.to_path_buf()))?;
+
+    let (mut info, mut pkg): (Vec<&PathBuf>, Vec<&PathBuf>) = files.iter().partition(|f| f.starts_with("info"));
+    info.sort();
+    pkg.sort();
+
+    let tmp_path = dest.with_file_name(format!("{}{}.partial", stem, archive_type.extension()));
+    let io_error = |source| ArchiveError::IoError { path: tmp_path.clone(), source };
+    let zip_error = |source| ArchiveError::ZipError { path: tmp_path.clone(), source };
+    let file = BufWriter::new(File::create(&tmp_path).map_err(io_error)?);
+
+    match archive_type {
+        ArchiveType::TarBz2 => {
+            let level = compression_level.map_or(DEFAULT_BZIP2_LEVEL, |level| level.clamp(1, 9) as u32);
+            let encoder = bzip2::write::BzEncoder::new(file, bzip2::Compression::new(level));
+            let all: Vec<&PathBuf> = info.into_iter().chain(pkg).collect();
+            let encoder = write_tar(base_dir, &all, encoder).map_err(io_error)?;
+            encoder.finish().and_then(|mut file| file.flush()).map_err(io_error)?;
+        }
+        ArchiveType::Conda => {
+            let level = compression_level.unwrap_or(DEFAULT_ZSTD_LEVEL);
+            let options = FileOptions::default().compression_method(CompressionMethod::Stored);
+            let mut zip = zip::ZipWriter::new(file);
+
+            zip.start_file("metadata.json", options).map_err(zip_error)?;
+            let metadata = serde_json::to_vec(&CondaMetadata::default())
+                .map_err(|source| ArchiveError::InvalidMetadata { path: tmp_path.clone(), source })?;
+            zip.write_all(&metadata).map_err(io_error)?;
+
+            // The info tarball comes first so streaming readers reach it
+            // before the payload.
+            for (section, members) in [("info", &info), ("pkg", &pkg)] {
+                zip.start_file(format!("{}-{}.tar.zst", section, stem), options).map_err(zip_error)?;
+                let encoder = zstd::stream::write::Encoder::new(&mut zip, level).map_err(io_error)?;
+                write_tar(base_dir, members, encoder)
+                    .and_then(|encoder| encoder.finish())
+                    .map_err(io_error)?;
+            }
+            zip.finish().map_err(zip_error)?.flush().map_err(io_error)?;
+        }
+    }
+
+    fs::rename(&tmp_path, dest).map_err(|source| ArchiveError::IoError { path: dest.to_path_buf(), source })
+}
+
+/// Writes files into a tar stream, storing symlinks as links.
+fn write_tar<W: Write>(base_dir: &Path, files: &[&PathBuf], writer: W) -> io::Result<W> {
+    let mut builder = tar::Builder::new(writer);
+    builder.follow_symlinks(false);
+    for file in files {
+        builder.append_path_with_name(base_dir.join(file), file)?;
+    }
+    builder.into_inner()
+}
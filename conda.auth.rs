@@ -0,0 +1,250 @@
+// conda.auth.rs
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use reqwest::blocking::RequestBuilder;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use url::Url;
+
+/// The placeholder that replaces secrets in displayed URLs.
+const REDACTED: &str = "********";
+
+/// Represents the credentials used for a channel host.
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Authentication {
+    /// A conda token, sent as a `/t/<token>/` URL path prefix.
+    CondaToken { token: String },
+
+    /// A token sent in an `Authorization: Bearer` header.
+    BearerToken { token: String },
+
+    /// HTTP basic credentials.
+    BasicHttp { username: String, password: String },
+}
+
+impl fmt::Debug for Authentication {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Authentication::CondaToken { .. } => f.write_str("CondaToken(********)"),
+            Authentication::BearerToken { .. } => f.write_str("BearerToken(********)"),
+            Authentication::BasicHttp { username, .. } => write!(f, "BasicHttp({}:********)", username),
+        }
+    }
+}
+
+/// Represents possible errors that can occur when working with the authentication store.
+#[derive(Error, Debug)]
+pub enum AuthError {
+    #[error("Failed to access authentication file {path}: {source}")]
+    IoError { path: PathBuf, source: std::io::Error },
+
+    #[error("Failed to parse authentication file: {0}")]
+    ParseError(#[from] serde_json::Error),
+
+    #[error("Authentication file {0} is readable by other users; run `chmod 600` on it")]
+    InsecurePermissions(PathBuf),
+}
+
+/// A file-based store of credentials keyed by host, optionally followed by a path prefix
+/// (e.g. `conda.example.com` or `conda.example.com/private-channel`).
+#[derive(Debug, Clone, Default)]
+pub struct AuthStore {
+    path: Option<PathBuf>,
+    entries: BTreeMap<String, Authentication>,
+}
+
+impl AuthStore {
+    /// Returns the default location of the authentication file.
+    pub fn default_path() -> Option<PathBuf> {
+        dirs::home_dir().map(|home| home.join(".conda").join("auth.json"))
+    }
+
+    /// Loads the store from a file, refusing files other users can read.
+    /// A missing file yields an empty store.
+    pub fn load(path: &Path) -> Result<Self, AuthError> {
+        let io_error = |source| AuthError::IoError { path: path.to_path_buf(), source };
+
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Ok(AuthStore { path: Some(path.to_path_buf()), entries: BTreeMap::new() });
+            }
+            Err(e) => return Err(io_error(e)),
+        };
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(path).map_err(io_error)?.permissions().mode();
+            if mode & 0o077 != 0 {
+                return Err(AuthError::InsecurePermissions(path.to_path_buf()));
+            }
+        }
+
+        Ok(AuthStore {
+            path: Some(path.to_path_buf()),
+            entries: serde_json::from_str(&contents)?,
+        })
+    }
+
+    /// Writes the store back to the file it was loaded from, readable only by the owner.
+    pub fn save(&self) -> Result<(), AuthError> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let io_error = |source| AuthError::IoError { path: path.clone(), source };
+
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).map_err(io_error)?;
+        }
+
+        let tmp_path = path.with_extension("tmp");
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        let mut file = options.open(&tmp_path).map_err(io_error)?;
+        file.write_all(serde_json::to_string_pretty(&self.entries)?.as_bytes())
+            .map_err(io_error)?;
+        fs::rename(&tmp_path, path).map_err(io_error)
+    }
+
+    /// Stores credentials for a host or host/path prefix.
+    pub fn insert(&mut self, key: impl Into<String>, auth: Authentication) {
+        self.entries.insert(key.into().trim_end_matches('/').to_string(), auth);
+    }
+
+    /// Removes the credentials stored under a key.
+    pub fn remove(&mut self, key: &str) -> Option<Authentication> {
+        self.entries.remove(key.trim_end_matches('/'))
+    }
+
+    /// Returns the keys of all stored credentials.
+    pub fn keys(&self) -> impl Iterator<Item = &str> {
+        self.entries.keys().map(String::as_str)
+    }
+
+    /// Returns the credentials for a URL, preferring the longest matching key.
+    pub fn get(&self, url: &Url) -> Option<&Authentication> {
+        let host = host_key(url)?;
+        let path = url.path().trim_end_matches('/');
+
+        self.entries
+            .iter()
+            .filter(|(key, _)| match key.strip_prefix(host.as_str()) {
+                Some("") => true,
+                Some(rest) => rest.starts_with('/') && (path == rest || path.starts_with(&format!("{}/", rest))),
+                None => false,
+            })
+            .max_by_key(|(key, _)| key.len())
+            .map(|(_, auth)| auth)
+    }
+
+    /// Applies the stored credentials for a URL to a request. Conda tokens are
+    /// injected into the URL, so the request must be built from the returned URL.
+    pub fn authenticate_url(&self, url: &Url) -> Url {
+        match self.get(url) {
+            Some(Authentication::CondaToken { token }) if !has_token(url) => {
+                let mut authenticated = url.clone();
+                authenticated.set_path(&format!("/t/{}{}", token, url.path()));
+                authenticated
+            }
+            _ => url.clone(),
+        }
+    }
+
+    /// Adds the authorization header for a URL to a request, if needed.
+    pub fn authenticate_request(&self, url: &Url, request: RequestBuilder) -> RequestBuilder {
+        match self.get(url) {
+            Some(Authentication::BearerToken { token }) => request.bearer_auth(token),
+            Some(Authentication::BasicHttp { username, password }) => request.basic_auth(username, Some(password)),
+            _ => request,
+        }
+    }
+
+    /// Removes any token or basic credentials from a channel URL and stores them,
+    /// returning the URL that is safe to persist in configuration files.
+    pub fn extract_credentials(&mut self, channel_url: &str) -> Result<String, url::ParseError> {
+        let mut url = Url::parse(channel_url)?;
+        let host = match host_key(&url) {
+            Some(host) => host,
+            None => return Ok(channel_url.to_string()),
+        };
+
+        if !url.username().is_empty() {
+            let auth = Authentication::BasicHttp {
+                username: url.username().to_string(),
+                password: url.password().unwrap_or_default().to_string(),
+            };
+            self.insert(host.clone(), auth);
+            let _ = url.set_username("");
+            let _ = url.set_password(None);
+        }
+
+        let segments: Vec<String> = url
+            .path_segments()
+            .map(|segments| segments.map(String::from).collect())
+            .unwrap_or_default();
+        if segments.len() >= 2 && segments[0] == "t" && !segments[1].is_empty() {
+            self.insert(host, Authentication::CondaToken { token: segments[1].clone() });
+            url.set_path(&format!("/{}", segments[2..].join("/")));
+        }
+
+        Ok(url.to_string())
+    }
+}
+
+/// Returns the `host[:port]` key of a URL.
+fn host_key(url: &Url) -> Option<String> {
+    let host = url.host_str()?;
+    Some(match url.port() {
+        Some(port) => format!("{}:{}", host, port),
+        None => host.to_string(),
+    })
+}
+
+fn has_token(url: &Url) -> bool {
+    url.path().starts_with("/t/")
+}
+
+/// Replaces conda tokens and basic-auth passwords in a URL with a placeholder,
+/// so it can be shown in logs, errors and settings displays.
+pub fn redact_url(url: &str) -> String {
+    let mut parsed = match Url::parse(url) {
+        Ok(parsed) => parsed,
+        Err(_) => return redact_token_path(url),
+    };
+
+    if parsed.password().is_some() {
+        let _ = parsed.set_password(Some(REDACTED));
+    }
+    if has_token(&parsed) {
+        let redacted = redact_token_path(parsed.path());
+        parsed.set_path(&redacted);
+    }
+    parsed.to_string()
+}
+
+/// Replaces the segment following a `/t/` path segment with a placeholder.
+fn redact_token_path(text: &str) -> String {
+    match text.find("/t/") {
+        Some(start) => {
+            let token_start = start + 3;
+            let token_end = text[token_start..]
+                .find('/')
+                .map_or(text.len(), |end| token_start + end);
+            format!("{}{}{}", &text[..token_start], REDACTED, &text[token_end..])
+        }
+        None => text.to_string(),
+    }
+}
@@ -0,0 +1,473 @@
+// conda.build.rs
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::Deserialize;
+use thiserror::Error;
+use walkdir::WalkDir;
+
+use crate::archive::{self, ArchiveError, ArchiveType};
+use crate::channel::Platform;
+use crate::digest;
+use crate::package::{AboutJson, FileMode, IndexJson, PathType, PathsEntry, PathsJson};
+use crate::settings::CompressionType;
+
+/// Represents possible errors that can occur when building packages.
+#[derive(Error, Debug)]
+pub enum BuildError {
+    #[error("Failed to access {path}: {source}")]
+    IoError { path: PathBuf, source: io::Error },
+
+    #[error("Invalid packager manifest {path}: {source}")]
+    ManifestError { path: PathBuf, source: toml::de::Error },
+
+    #[error("Failed to serialize package metadata: {0}")]
+    SerializeError(#[from] serde_json::Error),
+
+    #[error(transparent)]
+    ArchiveError(#[from] ArchiveError),
+
+    #[error("Conda packages cannot use {0:?} compression")]
+    UnsupportedCompression(CompressionType),
+
+    #[error("Staging directory {0} does not exist or is empty")]
+    EmptyStagingDir(PathBuf),
+}
+
+/// Represents the parts of `conda.packager.toml` that describe a package.
+#[derive(Debug, Clone, Deserialize)]
+pub struct PackagerManifest {
+    pub package: ManifestPackage,
+    #[serde(default)]
+    pub conda: ManifestConda,
+}
+
+/// Represents the `[package]` table of the manifest.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ManifestPackage {
+    pub name: String,
+    pub version: String,
+    #[serde(default)]
+    pub description: Option<String>,
+    #[serde(default)]
+    pub license: Option<String>,
+    #[serde(default)]
+    pub repository: Option<String>,
+    #[serde(default)]
+    pub documentation: Option<String>,
+    #[serde(default)]
+    pub metadata: ManifestMetadata,
+}
+
+/// Represents the `[package.metadata]` table of the manifest.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ManifestMetadata {
+    #[serde(default)]
+    pub long_description: Option<String>,
+    #[serde(default, rename = "conda-forge")]
+    pub conda_forge: CondaForgeMetadata,
+}
+
+/// Represents the `[package.metadata.conda-forge]` tables of the manifest.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct CondaForgeMetadata {
+    #[serde(default)]
+    pub build: ManifestBuild,
+    #[serde(default)]
+    pub package: ManifestLinks,
+    #[serde(default)]
+    pub test: ManifestTest,
+}
+
+/// Represents `[package.metadata.conda-forge.build]`.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ManifestBuild {
+    #[serde(default)]
+    pub number: u64,
+}
+
+/// Represents `[package.metadata.conda-forge.package]`.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ManifestLinks {
+    /// The license file, relative to the manifest.
+    #[serde(default)]
+    pub license_file: Option<PathBuf>,
+    #[serde(default)]
+    pub doc_url: Option<String>,
+    #[serde(default)]
+    pub dev_url: Option<String>,
+    #[serde(default)]
+    pub doc_source_url: Option<String>,
+}
+
+/// Represents `[package.metadata.conda-forge.test]`.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ManifestTest {
+    #[serde(default)]
+    pub commands: Vec<String>,
+
+    /// Extra packages the tests need, as name to version constraint.
+    #[serde(default)]
+    pub requires: BTreeMap<String, String>,
+}
+
+/// Represents the `[conda]` table of the manifest.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ManifestConda {
+    #[serde(default)]
+    pub env: ManifestEnv,
+}
+
+/// Represents `[conda.env]`, whose dependencies become the package's run requirements.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ManifestEnv {
+    #[serde(default)]
+    pub dependencies: BTreeMap<String, String>,
+}
+
+impl PackagerManifest {
+    /// Loads a manifest from a TOML file.
+    pub fn load(path: &Path) -> Result<Self, BuildError> {
+        let contents = fs::read_to_string(path).map_err(|source| BuildError::IoError { path: path.to_path_buf(), source })?;
+        toml::from_str(&contents).map_err(|source| BuildError::ManifestError { path: path.to_path_buf(), source })
+    }
+
+    /// Returns the run requirements as match specs.
+    pub fn depends(&self) -> Vec<String> {
+        specs(&self.conda.env.dependencies)
+    }
+}
+
+/// Builds a conda package from a staged install directory, laid out as the
+/// files should appear in an environment, and a packager manifest.
+#[derive(Debug, Clone)]
+pub struct PackageBuilder {
+    manifest: PackagerManifest,
+    manifest_dir: PathBuf,
+    staging_dir: PathBuf,
+    build_prefix: Option<PathBuf>,
+    platform: Platform,
+    build_string: Option<String>,
+    compression: CompressionType,
+    compression_level: Option<i32>,
+}
+
+impl PackageBuilder {
+    /// Creates a builder. Relative paths in the manifest resolve against `manifest_dir`.
+    pub fn new(manifest: PackagerManifest, manifest_dir: impl Into<PathBuf>, staging_dir: impl Into<PathBuf>) -> Self {
+        PackageBuilder {
+            manifest,
+            manifest_dir: manifest_dir.into(),
+            staging_dir: staging_dir.into(),
+            build_prefix: None,
+            platform: Platform::current(),
+            build_string: None,
+            compression: CompressionType::Zstd,
+            compression_level: None,
+        }
+    }
+
+    /// Creates a builder from a manifest file.
+    pub fn from_manifest_file(manifest_path: &Path, staging_dir: impl Into<PathBuf>) -> Result<Self, BuildError> {
+        let manifest = PackagerManifest::load(manifest_path)?;
+        let manifest_dir = manifest_path.parent().unwrap_or_else(|| Path::new(".")).to_path_buf();
+        Ok(Self::new(manifest, manifest_dir, staging_dir))
+    }
+
+    /// Sets the prefix the staged files were built for, such as the
+    /// `--prefix` given to `configure`, before being installed into the
+    /// staging directory (e.g. with `DESTDIR`). Files embedding it get it as
+    /// their prefix placeholder. Defaults to the staging directory itself.
+    ///
+    /// Binary files can only be relinked into prefixes no longer than their
+    /// placeholder, so binaries should be built for a long, padded prefix,
+    /// as conda-build does with `_h_env_placehold_placehold_...`.
+    pub fn with_build_prefix(mut self, prefix: impl Into<PathBuf>) -> Self {
+        self.build_prefix = Some(prefix.into());
+        self
+    }
+
+    /// Sets the target platform; `noarch` builds a `noarch: generic` package.
+    pub fn with_platform(mut self, platform: Platform) -> Self {
+        self.platform = platform;
+        self
+    }
+
+    /// Overrides the generated `h<hash>_<number>` build string.
+    pub fn with_build_string(mut self, build_string: impl Into<String>) -> Self {
+        self.build_string = Some(build_string.into());
+        self
+    }
+
+    /// Chooses the archive format: `Bzip2` for `.tar.bz2`, `Zstd` for `.conda`.
+    pub fn with_compression(mut self, compression: CompressionType, level: Option<i32>) -> Self {
+        self.compression = compression;
+        self.compression_level = level;
+        self
+    }
+
+    /// Returns the build string of the package.
+    pub fn build_string(&self) -> String {
+        self.build_string.clone().unwrap_or_else(|| {
+            let mut hashed = self.manifest.depends();
+            hashed.push(self.platform.as_str().to_string());
+            let hash = digest::sha256_hex(hashed.join("\n").as_bytes());
+            format!("h{}_{}", &hash[..7], self.manifest.package.metadata.conda_forge.build.number)
+        })
+    }
+
+    /// Writes `info/` into the staging directory, replacing any previous one,
+    /// and archives the package into `output_dir`, returning its path.
+    pub fn build(&self, output_dir: &Path) -> Result<PathBuf, BuildError> {
+        let archive_type = ArchiveType::from_compression(&self.compression)
+            .ok_or_else(|| BuildError::UnsupportedCompression(self.compression.clone()))?;
+        let io_error = |path: &Path| {
+            let path = path.to_path_buf();
+            move |source| BuildError::IoError { path, source }
+        };
+
+        let info_dir = self.staging_dir.join("info");
+        if info_dir.exists() {
+            fs::remove_dir_all(&info_dir).map_err(io_error(&info_dir))?;
+        }
+
+        let paths = self.collect_paths()?;
+        if paths.paths.is_empty() {
+            return Err(BuildError::EmptyStagingDir(self.staging_dir.clone()));
+        }
+        fs::create_dir_all(&info_dir).map_err(io_error(&info_dir))?;
+
+        let write_json = |name: &str, value: serde_json::Value| -> Result<(), BuildError> {
+            let path = info_dir.join(name);
+            fs::write(&path, serde_json::to_vec_pretty(&value)?).map_err(io_error(&path))
+        };
+        write_json("index.json", serde_json::to_value(self.index_json())?)?;
+        write_json("about.json", serde_json::to_value(self.about_json())?)?;
+        write_json("paths.json", serde_json::to_value(&paths)?)?;
+
+        let files: String = paths.paths.iter().map(|e| format!("{}\n", path_string(&e.path))).collect();
+        fs::write(info_dir.join("files"), files).map_err(io_error(&info_dir))?;
+
+        let has_prefix: String = paths
+            .paths
+            .iter()
+            .filter_map(|e| {
+                let placeholder = e.prefix_placeholder.as_ref()?;
+                let mode = if e.file_mode == Some(FileMode::Binary) { "binary" } else { "text" };
+                Some(format!("{} {} {}\n", placeholder, mode, path_string(&e.path)))
+            })
+            .collect();
+        if !has_prefix.is_empty() {
+            fs::write(info_dir.join("has_prefix"), has_prefix).map_err(io_error(&info_dir))?;
+        }
+
+        if let Some(license_file) = &self.manifest.package.metadata.conda_forge.package.license_file {
+            let source = self.manifest_dir.join(license_file);
+            let licenses_dir = info_dir.join("licenses");
+            fs::create_dir_all(&licenses_dir).map_err(io_error(&licenses_dir))?;
+            let name = license_file.file_name().unwrap_or(license_file.as_os_str());
+            fs::copy(&source, licenses_dir.join(name)).map_err(io_error(&source))?;
+        }
+
+        let test = &self.manifest.package.metadata.conda_forge.test;
+        if !test.commands.is_empty() || !test.requires.is_empty() {
+            let test_dir = info_dir.join("test");
+            fs::create_dir_all(&test_dir).map_err(io_error(&test_dir))?;
+            let (name, script) = if self.platform.is_windows() {
+                let script = test.commands.iter().map(|c| format!("{}\nIF %ERRORLEVEL% NEQ 0 exit /B 1\n", c));
+                ("run_test.bat", script.collect::<String>())
+            } else {
+                let script = test.commands.iter().map(|c| format!("{}\n", c));
+                ("run_test.sh", format!("set -ex\n\n{}", script.collect::<String>()))
+            };
+            fs::write(test_dir.join(name), script).map_err(io_error(&test_dir))?;
+            write_json("test/test_time_dependencies.json", serde_json::to_value(specs(&test.requires))?)?;
+        }
+
+        // Every file under info/ plus the payload, relative to the staging directory.
+        let mut files: Vec<PathBuf> = paths.paths.iter().map(|e| e.path.clone()).collect();
+        for entry in WalkDir::new(&info_dir) {
+            let entry = entry.map_err(|e| BuildError::IoError { path: info_dir.clone(), source: e.into() })?;
+            if entry.file_type().is_file() {
+                files.push(entry.path().strip_prefix(&self.staging_dir).expect("info is in the staging dir").to_path_buf());
+            }
+        }
+
+        fs::create_dir_all(output_dir).map_err(io_error(output_dir))?;
+        let package = &self.manifest.package;
+        let filename = format!("{}-{}-{}{}", package.name, package.version, self.build_string(), archive_type.extension());
+        let dest = output_dir.join(filename);
+        archive::create(&self.staging_dir, &files, &dest, self.compression_level)?;
+        Ok(dest)
+    }
+
+    /// Hashes every staged file and detects the ones embedding the build
+    /// prefix, which becomes their prefix placeholder.
+    fn collect_paths(&self) -> Result<PathsJson, BuildError> {
+        let placeholder = self.build_prefix.as_ref().unwrap_or(&self.staging_dir).display().to_string();
+        let mut paths = Vec::new();
+
+        let walker = WalkDir::new(&self.staging_dir).sort_by(|a, b| a.file_name().cmp(b.file_name()));
+        for entry in walker {
+            let entry = entry.map_err(|e| BuildError::IoError { path: self.staging_dir.clone(), source: e.into() })?;
+            if entry.file_type().is_dir() {
+                continue;
+            }
+            let path = entry.path();
+            let relative = path.strip_prefix(&self.staging_dir).expect("walked paths are in the staging dir").to_path_buf();
+
+            if entry.path_is_symlink() {
+                paths.push(PathsEntry {
+                    path: relative,
+                    path_type: PathType::SoftLink,
+                    sha256: None,
+                    size_in_bytes: None,
+                    file_mode: None,
+                    prefix_placeholder: None,
+                    no_link: false,
+                });
+                continue;
+            }
+
+            let contents = fs::read(path).map_err(|source| BuildError::IoError { path: path.to_path_buf(), source })?;
+            let has_prefix = contents.windows(placeholder.len()).any(|window| window == placeholder.as_bytes());
+            let file_mode = if contents.contains(&0) { FileMode::Binary } else { FileMode::Text };
+
+            paths.push(PathsEntry {
+                path: relative,
+                path_type: PathType::HardLink,
+                sha256: Some(digest::sha256_hex(&contents)),
+                size_in_bytes: Some(contents.len() as u64),
+                file_mode: if has_prefix { Some(file_mode) } else { None },
+                prefix_placeholder: if has_prefix { Some(placeholder.clone()) } else { None },
+                no_link: false,
+            });
+        }
+
+        Ok(PathsJson { paths, paths_version: 1 })
+    }
+
+    fn index_json(&self) -> IndexJson {
+        let package = &self.manifest.package;
+        let (platform, arch) = platform_arch(self.platform);
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis() as u64);
+
+        IndexJson {
+            name: package.name.clone(),
+            version: package.version.clone(),
+            build: self.build_string(),
+            build_number: package.metadata.conda_forge.build.number,
+            depends: self.manifest.depends(),
+            constrains: Vec::new(),
+            subdir: Some(self.platform.as_str().to_string()),
+            arch,
+            platform,
+            noarch: (self.platform == Platform::NoArch).then(|| "generic".to_string()),
+            license: package.license.clone(),
+            license_family: None,
+            timestamp: Some(timestamp),
+            track_features: None,
+            features: Vec::new(),
+        }
+    }
+
+    fn about_json(&self) -> AboutJson {
+        let package = &self.manifest.package;
+        let links = &package.metadata.conda_forge.package;
+        AboutJson {
+            summary: package.description.clone(),
+            description: package.metadata.long_description.as_ref().map(|d| d.trim().to_string()),
+            home: package.repository.clone(),
+            dev_url: links.dev_url.clone().or_else(|| package.repository.clone()),
+            doc_url: links.doc_url.clone().or_else(|| package.documentation.clone()),
+            license: package.license.clone(),
+            license_family: None,
+            channels: Vec::new(),
+            extra: links
+                .doc_source_url
+                .as_ref()
+                .map(|url| serde_json::json!({ "doc_source_url": url })),
+        }
+    }
+}
+
+/// Turns `name = "constraint"` pairs into match specs, treating `*` as any version.
+fn specs(requirements: &BTreeMap<String, String>) -> Vec<String> {
+    requirements
+        .iter()
+        .map(|(name, constraint)| match constraint.trim() {
+            "" | "*" => name.clone(),
+            constraint => format!("{} {}", name, constraint),
+        })
+        .collect()
+}
+
+/// Returns the `platform` and `arch` fields conda records for a subdir.
+fn platform_arch(platform: Platform) -> (Option<String>, Option<String>) {
+    let (os, arch) = match platform.as_str().split_once('-') {
+        Some(parts) => parts,
+        None => return (None, None),
+    };
+    let arch = match arch {
+        "64" => "x86_64",
+        "32" => "x86",
+        other => other,
+    };
+    (Some(os.to_string()), Some(arch.to_string()))
+}
+
+/// Returns a relative path as conda records it, with forward slashes.
+fn path_string(path: &Path) -> String {
+    path.to_string_lossy().replace('\\', "/")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn build_records_the_build_prefix_and_license() {
+        let root = tempfile::tempdir().unwrap();
+        let staging = root.path().join("staging");
+        let output = root.path().join("output");
+        fs::create_dir_all(staging.join("bin")).unwrap();
+        fs::write(staging.join("bin").join("tool"), "#!/bin/sh\nexec /opt/build-prefix/libexec/tool \"$@\"\n").unwrap();
+        fs::write(staging.join("README"), "no prefix here\n").unwrap();
+        fs::write(root.path().join("LICENSE"), "MIT\n").unwrap();
+
+        let manifest: PackagerManifest = toml::from_str(
+            r#"
+            [package]
+            name = "demo"
+            version = "1.0"
+            license = "MIT"
+
+            [package.metadata.conda-forge.package]
+            license_file = "LICENSE"
+            "#,
+        )
+        .unwrap();
+
+        let package = PackageBuilder::new(manifest, root.path(), &staging)
+            .with_platform(Platform::Linux64)
+            .with_build_string("h0_0")
+            .with_build_prefix("/opt/build-prefix")
+            .with_compression(CompressionType::Zstd, None)
+            .build(&output)
+            .unwrap();
+
+        assert_eq!(package, output.join("demo-1.0-h0_0.conda"));
+        assert!(package.is_file());
+        assert_eq!(
+            fs::read_to_string(staging.join("info").join("has_prefix")).unwrap(),
+            "/opt/build-prefix text bin/tool\n"
+        );
+        assert_eq!(fs::read_to_string(staging.join("info").join("licenses").join("LICENSE")).unwrap(), "MIT\n");
+    }
+}
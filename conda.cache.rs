@@ -0,0 +1,421 @@
+// conda.cache.rs
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use thiserror::Error;
+use walkdir::WalkDir;
+
+use crate::archive::ArchiveType;
+use crate::download::QUARANTINE_DIR;
+use crate::lock::{LockError, Locker};
+use crate::pkgs::{PackageCache, REPODATA_RECORD_FILE};
+use crate::prefix::{self, PrefixError};
+use crate::repodata::RepodataCache;
+
+/// The directory of each package cache that holds transaction logs.
+const LOGS_DIR: &str = ".logs";
+
+/// Represents possible errors that can occur when managing package caches.
+#[derive(Error, Debug)]
+pub enum CacheError {
+    #[error("Failed to access {path}: {source}")]
+    IoError { path: PathBuf, source: io::Error },
+
+    #[error(transparent)]
+    PrefixError(#[from] PrefixError),
+
+    #[error(transparent)]
+    LockError(#[from] LockError),
+}
+
+/// Represents what a clean removes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CleanTarget {
+    /// Package archives, which are only needed again to re-extract.
+    Tarballs,
+    /// Downloaded repodata.
+    IndexCache,
+    /// Extracted packages not linked into any known environment.
+    UnusedPackages,
+    /// Leftovers of interrupted downloads and extractions.
+    TempFiles,
+    /// Log files kept in the package caches.
+    Logs,
+}
+
+impl CleanTarget {
+    /// Every target, as `conda clean --all` removes.
+    pub const ALL: [CleanTarget; 5] = [
+        CleanTarget::Tarballs,
+        CleanTarget::IndexCache,
+        CleanTarget::UnusedPackages,
+        CleanTarget::TempFiles,
+        CleanTarget::Logs,
+    ];
+}
+
+/// Represents a package in a package cache, as an archive, an extracted
+/// directory, or both.
+#[derive(Debug, Clone)]
+pub struct CachedPackage {
+    /// The `name-version-build` of the package.
+    pub dist_name: String,
+    pub pkgs_dir: PathBuf,
+
+    /// Whether the package is in a read-only, typically shared, cache and so
+    /// is never removed.
+    pub read_only: bool,
+
+    /// The package archives, in either or both formats.
+    pub tarballs: Vec<PathBuf>,
+    pub tarball_size: u64,
+    pub extracted_dir: Option<PathBuf>,
+    pub extracted_size: u64,
+    pub last_used: SystemTime,
+
+    /// The environments the package is linked into.
+    pub linked_in: Vec<PathBuf>,
+}
+
+impl CachedPackage {
+    fn new(dist_name: &str, pkgs_dir: &Path, read_only: bool) -> Self {
+        CachedPackage {
+            dist_name: dist_name.to_string(),
+            pkgs_dir: pkgs_dir.to_path_buf(),
+            read_only,
+            tarballs: Vec::new(),
+            tarball_size: 0,
+            extracted_dir: None,
+            extracted_size: 0,
+            last_used: UNIX_EPOCH,
+            linked_in: Vec::new(),
+        }
+    }
+
+    /// Returns the disk space used by the package.
+    pub fn size(&self) -> u64 {
+        self.tarball_size + self.extracted_size
+    }
+
+    /// Returns whether any known environment uses the package.
+    pub fn is_linked(&self) -> bool {
+        !self.linked_in.is_empty()
+    }
+}
+
+/// Represents the disk usage of the package caches.
+#[derive(Debug, Clone, Default)]
+pub struct CacheUsage {
+    pub packages: Vec<CachedPackage>,
+    pub index_cache_bytes: u64,
+    pub temp_bytes: u64,
+    pub log_bytes: u64,
+}
+
+impl CacheUsage {
+    /// Returns the space used by package archives.
+    pub fn tarball_bytes(&self) -> u64 {
+        self.packages.iter().map(|p| p.tarball_size).sum()
+    }
+
+    /// Returns the space used by extracted packages.
+    pub fn extracted_bytes(&self) -> u64 {
+        self.packages.iter().map(|p| p.extracted_size).sum()
+    }
+
+    /// Returns the space counted against the cache size limit.
+    pub fn package_bytes(&self) -> u64 {
+        self.tarball_bytes() + self.extracted_bytes()
+    }
+
+    /// Returns the space used by everything the cache manager tracks.
+    pub fn total_bytes(&self) -> u64 {
+        self.package_bytes() + self.index_cache_bytes + self.temp_bytes + self.log_bytes
+    }
+}
+
+/// Represents what a clean or eviction removed.
+#[derive(Debug, Clone, Default)]
+pub struct CleanReport {
+    pub removed: Vec<PathBuf>,
+    pub freed_bytes: u64,
+}
+
+/// Manages the package caches: reports usage, cleans them selectively and
+/// evicts unused packages to respect a size limit.
+#[derive(Debug, Clone)]
+pub struct CacheManager {
+    pkgs_dirs: Vec<PathBuf>,
+    index_cache_dir: Option<PathBuf>,
+    prefixes: Vec<PathBuf>,
+    size_limit: Option<u64>,
+    locker: Option<Locker>,
+}
+
+impl CacheManager {
+    /// Creates a manager for the given package cache directories.
+    pub fn new(pkgs_dirs: Vec<PathBuf>) -> Self {
+        CacheManager {
+            pkgs_dirs,
+            index_cache_dir: None,
+            prefixes: Vec::new(),
+            size_limit: None,
+            locker: None,
+        }
+    }
+
+    /// Sets the directory of downloaded repodata.
+    pub fn with_index_cache(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.index_cache_dir = Some(dir.into());
+        self
+    }
+
+    /// Locks each repodata cache entry while removing it, so no process
+    /// reading or updating the entry sees it half removed. Package caches
+    /// are locked by the caller.
+    pub fn with_locker(mut self, locker: Locker) -> Self {
+        self.locker = Some(locker);
+        self
+    }
+
+    /// Sets the environments whose linked packages must be kept.
+    pub fn with_prefixes(mut self, prefixes: Vec<PathBuf>) -> Self {
+        self.prefixes = prefixes;
+        self
+    }
+
+    /// Sets the size, in bytes, that archives and extracted packages may use.
+    pub fn with_size_limit(mut self, limit: u64) -> Self {
+        self.size_limit = Some(limit);
+        self
+    }
+
+    /// Measures the package caches, finding which environments use each package.
+    pub fn usage(&self) -> Result<CacheUsage, CacheError> {
+        let linked = self.linked_packages()?;
+        let mut usage = CacheUsage::default();
+
+        for pkgs_dir in &self.pkgs_dirs {
+            let entries = match fs::read_dir(pkgs_dir) {
+                Ok(entries) => entries,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(source) => return Err(CacheError::IoError { path: pkgs_dir.clone(), source }),
+            };
+
+            let read_only = !PackageCache::new(pkgs_dir.clone()).is_writable();
+            let mut packages: BTreeMap<String, CachedPackage> = BTreeMap::new();
+            for entry in entries {
+                let path = entry.map_err(|source| CacheError::IoError { path: pkgs_dir.clone(), source })?.path();
+                let file_name = path.file_name().and_then(|name| name.to_str()).unwrap_or_default().to_string();
+
+                if is_temp_file(&file_name) {
+                    usage.temp_bytes += disk_usage(&path);
+                } else if file_name == LOGS_DIR || file_name.ends_with(".log") {
+                    usage.log_bytes += disk_usage(&path);
+                } else if let Some((stem, _)) = ArchiveType::split_filename(&file_name).filter(|_| path.is_file()) {
+                    let package = packages.entry(stem.to_string()).or_insert_with(|| CachedPackage::new(stem, pkgs_dir, read_only));
+                    package.tarball_size += disk_usage(&path);
+                    package.last_used = package.last_used.max(last_used(&path));
+                    package.tarballs.push(path);
+                } else if path.join("info").join("index.json").is_file() {
+                    let package = packages.entry(file_name.clone()).or_insert_with(|| CachedPackage::new(&file_name, pkgs_dir, read_only));
+                    package.extracted_size = disk_usage(&path);
+                    package.last_used = package.last_used.max(last_used(&path.join(REPODATA_RECORD_FILE)));
+                    package.extracted_dir = Some(path);
+                }
+            }
+
+            for (dist_name, mut package) in packages {
+                package.linked_in = linked.get(&dist_name).cloned().unwrap_or_default();
+                usage.packages.push(package);
+            }
+        }
+
+        if let Some(dir) = &self.index_cache_dir {
+            usage.index_cache_bytes = disk_usage(dir);
+        }
+        Ok(usage)
+    }
+
+    /// Removes the selected kinds of cache content. Extracted packages are
+    /// only removed when no known environment links them, and read-only
+    /// caches are left untouched.
+    pub fn clean(&self, targets: &[CleanTarget]) -> Result<CleanReport, CacheError> {
+        let usage = self.usage()?;
+        let packages = || usage.packages.iter().filter(|package| !package.read_only);
+        let mut report = CleanReport::default();
+
+        if targets.contains(&CleanTarget::Tarballs) {
+            for tarball in packages().flat_map(|package| &package.tarballs) {
+                remove(tarball, &mut report)?;
+            }
+        }
+        if targets.contains(&CleanTarget::UnusedPackages) {
+            for package in packages().filter(|package| !package.is_linked()) {
+                if let Some(dir) = &package.extracted_dir {
+                    remove(dir, &mut report)?;
+                }
+            }
+        }
+        if targets.contains(&CleanTarget::IndexCache) {
+            if let Some(dir) = &self.index_cache_dir {
+                self.clean_index_cache(dir, &mut report)?;
+            }
+        }
+
+        let clean_temp = targets.contains(&CleanTarget::TempFiles);
+        let clean_logs = targets.contains(&CleanTarget::Logs);
+        if clean_temp || clean_logs {
+            for pkgs_dir in &self.pkgs_dirs {
+                if !PackageCache::new(pkgs_dir.clone()).is_writable() {
+                    continue;
+                }
+                let entries = match fs::read_dir(pkgs_dir) {
+                    Ok(entries) => entries,
+                    Err(_) => continue,
+                };
+                for entry in entries.flatten() {
+                    let file_name = entry.file_name().to_string_lossy().into_owned();
+                    let is_log = file_name == LOGS_DIR || file_name.ends_with(".log");
+                    if (clean_temp && is_temp_file(&file_name)) || (clean_logs && is_log) {
+                        remove(&entry.path(), &mut report)?;
+                    }
+                }
+            }
+        }
+        Ok(report)
+    }
+
+    /// Removes the repodata cache entry by entry, each under its lock.
+    fn clean_index_cache(&self, dir: &Path, report: &mut CleanReport) -> Result<(), CacheError> {
+        let io_error = |source| CacheError::IoError { path: dir.to_path_buf(), source };
+        let mut cache = RepodataCache::new(dir);
+        if let Some(locker) = &self.locker {
+            cache = cache.with_locker(locker.clone());
+        }
+
+        for key in cache.keys().map_err(io_error)? {
+            let _lock = cache.lock_entry(&key)?;
+            for file in cache.entry_files(&key).map_err(io_error)? {
+                remove(&file, report)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Evicts least recently used content until archives and extracted
+    /// packages fit the size limit: archives whose package is extracted and
+    /// packages no known environment links. Linked packages are never evicted,
+    /// and read-only caches neither count toward the limit nor are evicted.
+    pub fn enforce_limit(&self) -> Result<CleanReport, CacheError> {
+        let mut report = CleanReport::default();
+        let limit = match self.size_limit {
+            Some(limit) => limit,
+            None => return Ok(report),
+        };
+        let usage = self.usage()?;
+        let packages = || usage.packages.iter().filter(|package| !package.read_only);
+        let mut used: u64 = packages().map(CachedPackage::size).sum();
+        if used <= limit {
+            return Ok(report);
+        }
+
+        // Each candidate is what can go for one package, oldest first.
+        let mut candidates: Vec<(SystemTime, Vec<&PathBuf>)> = packages()
+            .filter_map(|package| {
+                let paths: Vec<&PathBuf> = if package.is_linked() {
+                    package.tarballs.iter().filter(|_| package.extracted_dir.is_some()).collect()
+                } else {
+                    package.tarballs.iter().chain(&package.extracted_dir).collect()
+                };
+                if paths.is_empty() {
+                    None
+                } else {
+                    Some((package.last_used, paths))
+                }
+            })
+            .collect();
+        candidates.sort_by_key(|(last_used, _)| *last_used);
+
+        for (_, paths) in candidates {
+            if used <= limit {
+                break;
+            }
+            for path in paths {
+                let before = report.freed_bytes;
+                remove(path, &mut report)?;
+                used = used.saturating_sub(report.freed_bytes - before);
+            }
+        }
+        Ok(report)
+    }
+
+    /// Maps the `name-version-build` of every package linked into a known
+    /// environment to the environments linking it.
+    fn linked_packages(&self) -> Result<HashMap<String, Vec<PathBuf>>, CacheError> {
+        let mut linked: HashMap<String, Vec<PathBuf>> = HashMap::new();
+        for prefix in &self.prefixes {
+            if !prefix::conda_meta_dir(prefix).is_dir() {
+                continue;
+            }
+            for record in prefix::load_prefix_records(prefix)? {
+                let dist_name = record
+                    .extracted_package_dir
+                    .as_ref()
+                    .and_then(|dir| dir.file_name())
+                    .map(|name| name.to_string_lossy().into_owned())
+                    .unwrap_or_else(|| record.dist_name());
+                linked.entry(dist_name).or_default().push(prefix.clone());
+            }
+        }
+        Ok(linked)
+    }
+}
+
+/// Returns whether a package cache entry is left over from an interrupted
+/// download or extraction, or was quarantined after failing verification.
+fn is_temp_file(file_name: &str) -> bool {
+    file_name.starts_with(".extract-")
+        || file_name.ends_with(".partial")
+        || file_name.ends_with(".download")
+        || file_name.ends_with(".tmp")
+        || file_name == QUARANTINE_DIR
+}
+
+/// Returns when a file was last used, falling back to its modification time
+/// on filesystems that do not track access.
+fn last_used(path: &Path) -> SystemTime {
+    fs::metadata(path)
+        .and_then(|metadata| metadata.accessed().or_else(|_| metadata.modified()))
+        .unwrap_or(UNIX_EPOCH)
+}
+
+/// Returns the size of a file, or of everything below a directory. Symlinks
+/// count as themselves.
+fn disk_usage(path: &Path) -> u64 {
+    WalkDir::new(path)
+        .into_iter()
+        .filter_map(Result::ok)
+        .filter_map(|entry| entry.metadata().ok())
+        .filter(|metadata| !metadata.is_dir())
+        .map(|metadata| metadata.len())
+        .sum()
+}
+
+fn remove(path: &Path, report: &mut CleanReport) -> Result<(), CacheError> {
+    let size = disk_usage(path);
+    let result = if path.is_dir() { fs::remove_dir_all(path) } else { fs::remove_file(path) };
+    match result {
+        Ok(()) => {
+            report.removed.push(path.to_path_buf());
+            report.freed_bytes += size;
+            Ok(())
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(source) => Err(CacheError::IoError { path: path.to_path_buf(), source }),
+    }
+}
@@ -0,0 +1,551 @@
+// conda.channel.rs
+
+use std::collections::HashMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use url::Url;
+
+use crate::settings::CondaSettings;
+
+/// The channel alias used to expand short channel names.
+pub const DEFAULT_CHANNEL_ALIAS: &str = "https://conda.anaconda.org";
+
+/// The channels the `defaults` multichannel expands to.
+pub const DEFAULT_CHANNELS: &[&str] = &[
+    "https://repo.anaconda.com/pkgs/main",
+    "https://repo.anaconda.com/pkgs/r",
+];
+
+/// Represents a platform subdir of a channel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum Platform {
+    #[serde(rename = "noarch")]
+    NoArch,
+    #[serde(rename = "linux-32")]
+    Linux32,
+    #[serde(rename = "linux-64")]
+    Linux64,
+    #[serde(rename = "linux-aarch64")]
+    LinuxAarch64,
+    #[serde(rename = "linux-ppc64le")]
+    LinuxPpc64le,
+    #[serde(rename = "linux-s390x")]
+    LinuxS390x,
+    #[serde(rename = "osx-64")]
+    Osx64,
+    #[serde(rename = "osx-arm64")]
+    OsxArm64,
+    #[serde(rename = "win-32")]
+    Win32,
+    #[serde(rename = "win-64")]
+    Win64,
+    #[serde(rename = "win-arm64")]
+    WinArm64,
+}
+
+impl Platform {
+    /// All known platforms.
+    pub const ALL: &'static [Platform] = &[
+        Platform::NoArch,
+        Platform::Linux32,
+        Platform::Linux64,
+        Platform::LinuxAarch64,
+        Platform::LinuxPpc64le,
+        Platform::LinuxS390x,
+        Platform::Osx64,
+        Platform::OsxArm64,
+        Platform::Win32,
+        Platform::Win64,
+        Platform::WinArm64,
+    ];
+
+    /// Returns the subdir name of the platform.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Platform::NoArch => "noarch",
+            Platform::Linux32 => "linux-32",
+            Platform::Linux64 => "linux-64",
+            Platform::LinuxAarch64 => "linux-aarch64",
+            Platform::LinuxPpc64le => "linux-ppc64le",
+            Platform::LinuxS390x => "linux-s390x",
+            Platform::Osx64 => "osx-64",
+            Platform::OsxArm64 => "osx-arm64",
+            Platform::Win32 => "win-32",
+            Platform::Win64 => "win-64",
+            Platform::WinArm64 => "win-arm64",
+        }
+    }
+
+    /// Returns the platform this binary was compiled for.
+    pub fn current() -> Platform {
+        if cfg!(all(target_os = "linux", target_arch = "x86_64")) {
+            Platform::Linux64
+        } else if cfg!(all(target_os = "linux", target_arch = "aarch64")) {
+            Platform::LinuxAarch64
+        } else if cfg!(all(target_os = "linux", target_arch = "powerpc64")) {
+            Platform::LinuxPpc64le
+        } else if cfg!(all(target_os = "linux", target_arch = "s390x")) {
+            Platform::LinuxS390x
+        } else if cfg!(all(target_os = "linux", target_arch = "x86")) {
+            Platform::Linux32
+        } else if cfg!(all(target_os = "macos", target_arch = "aarch64")) {
+            Platform::OsxArm64
+        } else if cfg!(target_os = "macos") {
+            Platform::Osx64
+        } else if cfg!(all(target_os = "windows", target_arch = "aarch64")) {
+            Platform::WinArm64
+        } else if cfg!(all(target_os = "windows", target_arch = "x86")) {
+            Platform::Win32
+        } else if cfg!(target_os = "windows") {
+            Platform::Win64
+        } else {
+            Platform::NoArch
+        }
+    }
+
+    /// Returns whether this is a Windows platform.
+    pub fn is_windows(&self) -> bool {
+        matches!(self, Platform::Win32 | Platform::Win64 | Platform::WinArm64)
+    }
+}
+
+impl fmt::Display for Platform {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Platform {
+    type Err = ChannelError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Platform::ALL
+            .iter()
+            .find(|platform| platform.as_str() == s)
+            .copied()
+            .ok_or_else(|| ChannelError::UnknownPlatform(s.to_string()))
+    }
+}
+
+/// Represents possible errors that can occur when resolving channels.
+#[derive(Error, Debug)]
+pub enum ChannelError {
+    #[error("Unknown platform: {0}")]
+    UnknownPlatform(String),
+
+    #[error("Invalid channel URL {0}: {1}")]
+    InvalidUrl(String, url::ParseError),
+
+    #[error("Invalid channel path: {0}")]
+    InvalidPath(PathBuf),
+
+    #[error("Empty channel name")]
+    EmptyName,
+}
+
+/// Represents the settings that control how channel names are expanded.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ChannelConfig {
+    /// The URL prepended to short channel names such as `conda-forge`.
+    pub channel_alias: String,
+
+    /// The channels that make up the `defaults` multichannel.
+    pub default_channels: Vec<String>,
+
+    /// Channel names mapped to the base URL they are served under.
+    #[serde(default)]
+    pub custom_channels: HashMap<String, String>,
+
+    /// Multichannel names mapped to the channels they expand to.
+    #[serde(default)]
+    pub custom_multichannels: HashMap<String, Vec<String>>,
+
+    /// The directory relative channel paths are resolved against.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub root_dir: Option<PathBuf>,
+}
+
+impl Default for ChannelConfig {
+    fn default() -> Self {
+        ChannelConfig {
+            channel_alias: DEFAULT_CHANNEL_ALIAS.to_string(),
+            default_channels: DEFAULT_CHANNELS.iter().map(|c| c.to_string()).collect(),
+            custom_channels: HashMap::new(),
+            custom_multichannels: HashMap::new(),
+            root_dir: None,
+        }
+    }
+}
+
+impl ChannelConfig {
+    /// Creates a channel config using the default channels from the settings.
+    pub fn from_settings(settings: &CondaSettings) -> Self {
+        ChannelConfig {
+            default_channels: settings.default_channels.clone(),
+            ..ChannelConfig::default()
+        }
+    }
+
+    /// Resolves a channel string, expanding multichannels such as `defaults`.
+    pub fn resolve(&self, channel: &str) -> Result<Vec<Channel>, ChannelError> {
+        let channel = channel.trim();
+        if channel == "defaults" {
+            return self.default_channels.iter().map(|c| Channel::from_str(c, self)).collect();
+        }
+        if let Some(members) = self.custom_multichannels.get(channel) {
+            return members.iter().map(|c| Channel::from_str(c, self)).collect();
+        }
+        Ok(vec![Channel::from_str(channel, self)?])
+    }
+
+    /// Resolves a list of channel strings, dropping channels that resolve to
+    /// the same canonical name as an earlier one.
+    pub fn resolve_all<S: AsRef<str>>(&self, channels: &[S]) -> Result<Vec<Channel>, ChannelError> {
+        let mut resolved: Vec<Channel> = Vec::new();
+        for channel in channels {
+            for channel in self.resolve(channel.as_ref())? {
+                if !resolved.iter().any(|c| c.canonical_name() == channel.canonical_name()) {
+                    resolved.push(channel);
+                }
+            }
+        }
+        Ok(resolved)
+    }
+
+    fn alias_url(&self) -> Result<Url, ChannelError> {
+        parse_base_url(&self.channel_alias)
+    }
+}
+
+/// Represents a single resolved conda channel.
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct Channel {
+    /// The short name of the channel, if it has one (e.g. `conda-forge`).
+    pub name: Option<String>,
+
+    /// The URL of the channel, without token or platform subdir.
+    pub base_url: Url,
+
+    /// The conda token embedded in the channel URL, if any.
+    pub token: Option<String>,
+
+    /// The platform subdirs explicitly requested for the channel.
+    pub platforms: Option<Vec<Platform>>,
+}
+
+impl Channel {
+    /// Parses a channel name, URL or path using the given channel config.
+    pub fn from_str(channel: &str, config: &ChannelConfig) -> Result<Channel, ChannelError> {
+        let channel = channel.trim().trim_end_matches('/');
+        if channel.is_empty() {
+            return Err(ChannelError::EmptyName);
+        }
+
+        if has_scheme(channel) {
+            Self::from_url(channel, config)
+        } else if is_path(channel) {
+            Self::from_path(Path::new(channel), config)
+        } else {
+            Self::from_name(channel, config)
+        }
+    }
+
+    /// Creates a channel from a full URL, extracting any token and platform subdir.
+    fn from_url(channel: &str, config: &ChannelConfig) -> Result<Channel, ChannelError> {
+        let url = Url::parse(channel).map_err(|e| ChannelError::InvalidUrl(channel.to_string(), e))?;
+        let (url, token) = strip_token(url);
+        let (url, platform) = strip_platform(url);
+        let base_url = with_trailing_slash(url);
+
+        let name = if base_url.scheme() == "file" {
+            None
+        } else {
+            known_name(&base_url, config)?
+        };
+
+        Ok(Channel {
+            name,
+            base_url,
+            token,
+            platforms: platform.map(|p| vec![p]),
+        })
+    }
+
+    /// Creates a channel from a local directory path.
+    fn from_path(path: &Path, config: &ChannelConfig) -> Result<Channel, ChannelError> {
+        let expanded = match path.strip_prefix("~") {
+            Ok(rest) => dirs::home_dir()
+                .ok_or_else(|| ChannelError::InvalidPath(path.to_path_buf()))?
+                .join(rest),
+            Err(_) => path.to_path_buf(),
+        };
+        let absolute = if expanded.is_absolute() {
+            expanded
+        } else {
+            match &config.root_dir {
+                Some(root) => root.join(expanded),
+                None => std::env::current_dir()
+                    .map_err(|_| ChannelError::InvalidPath(path.to_path_buf()))?
+                    .join(expanded),
+            }
+        };
+
+        let url = Url::from_directory_path(&absolute).map_err(|_| ChannelError::InvalidPath(absolute.clone()))?;
+        let (base_url, platform) = strip_platform(url);
+
+        Ok(Channel {
+            name: None,
+            base_url: with_trailing_slash(base_url),
+            token: None,
+            platforms: platform.map(|p| vec![p]),
+        })
+    }
+
+    /// Creates a channel from a short name, optionally ending in a platform subdir.
+    fn from_name(channel: &str, config: &ChannelConfig) -> Result<Channel, ChannelError> {
+        let mut segments: Vec<&str> = channel.split('/').filter(|s| !s.is_empty()).collect();
+        let platform = match segments.last().map(|s| Platform::from_str(s)) {
+            Some(Ok(platform)) if segments.len() > 1 => {
+                segments.pop();
+                Some(platform)
+            }
+            _ => None,
+        };
+        let name = segments.join("/");
+        if name.is_empty() {
+            return Err(ChannelError::EmptyName);
+        }
+
+        // Custom channels match on the longest leading run of segments.
+        let custom = (1..=segments.len())
+            .rev()
+            .find_map(|n| config.custom_channels.get(&segments[..n].join("/")));
+
+        // Names such as `pkgs/main` refer to the matching `defaults` member.
+        if custom.is_none() {
+            for default in &config.default_channels {
+                let default_url = parse_base_url(default)?;
+                if default_url.path().trim_matches('/') == name {
+                    return Ok(Channel {
+                        name: Some(name),
+                        base_url: default_url,
+                        token: None,
+                        platforms: platform.map(|p| vec![p]),
+                    });
+                }
+            }
+        }
+
+        let (location, base) = match custom {
+            Some(location) => (location.as_str(), parse_base_url(location)?),
+            None => (config.channel_alias.as_str(), config.alias_url()?),
+        };
+        let base_url = base
+            .join(&format!("{}/", name))
+            .map_err(|e| ChannelError::InvalidUrl(location.to_string(), e))?;
+
+        Ok(Channel {
+            name: Some(name),
+            base_url,
+            token: None,
+            platforms: platform.map(|p| vec![p]),
+        })
+    }
+
+    /// Returns the name used to display and compare channels: the short name
+    /// for named channels and the token-free URL otherwise.
+    pub fn canonical_name(&self) -> String {
+        match &self.name {
+            Some(name) => name.clone(),
+            None => self.base_url.as_str().trim_end_matches('/').to_string(),
+        }
+    }
+
+    /// Returns whether the channel is served from the local filesystem.
+    pub fn is_local(&self) -> bool {
+        self.base_url.scheme() == "file"
+    }
+
+    /// Returns the platform subdirs to query: the explicitly requested ones,
+    /// or the given platform plus `noarch`.
+    pub fn platforms_or(&self, platform: Platform) -> Vec<Platform> {
+        match &self.platforms {
+            Some(platforms) => platforms.clone(),
+            None if platform == Platform::NoArch => vec![Platform::NoArch],
+            None => vec![platform, Platform::NoArch],
+        }
+    }
+
+    /// Returns the URL of a platform subdir, without the token.
+    pub fn platform_url(&self, platform: Platform) -> Url {
+        self.base_url
+            .join(&format!("{}/", platform.as_str()))
+            .expect("platform names are valid URL segments")
+    }
+
+    /// Returns the base URL with the token reinserted as a `/t/<token>/` prefix.
+    pub fn url_with_token(&self) -> Url {
+        match &self.token {
+            Some(token) => {
+                let mut url = self.base_url.clone();
+                url.set_path(&format!("/t/{}{}", token, self.base_url.path()));
+                url
+            }
+            None => self.base_url.clone(),
+        }
+    }
+}
+
+impl fmt::Debug for Channel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Channel")
+            .field("name", &self.name)
+            .field("base_url", &self.base_url.as_str())
+            .field("token", &self.token.as_ref().map(|_| "********"))
+            .field("platforms", &self.platforms)
+            .finish()
+    }
+}
+
+impl fmt::Display for Channel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.canonical_name())
+    }
+}
+
+/// Returns the short name of a URL that lives under the channel alias or a custom channel.
+fn known_name(url: &Url, config: &ChannelConfig) -> Result<Option<String>, ChannelError> {
+    // The most specific custom channel wins, whatever the map's order.
+    let mut best: Option<(usize, String)> = None;
+    for (name, location) in &config.custom_channels {
+        let location = parse_base_url(location)?;
+        let channel_url = location
+            .join(&format!("{}/", name))
+            .map_err(|e| ChannelError::InvalidUrl(location.to_string(), e))?;
+        if let Some(rest) = relative_path(&channel_url, url) {
+            let length = channel_url.path().len();
+            let longer = match &best {
+                Some((longest, _)) => length > *longest,
+                None => true,
+            };
+            if longer {
+                best = Some((length, join_name(name, &rest)));
+            }
+        }
+    }
+    if let Some((_, name)) = best {
+        return Ok(Some(name));
+    }
+
+    for default in &config.default_channels {
+        let default_url = parse_base_url(default)?;
+        if relative_path(&default_url, url).map_or(false, |rest| rest.is_empty()) {
+            return Ok(Some(default_url.path().trim_matches('/').to_string()));
+        }
+    }
+
+    let alias = config.alias_url()?;
+    Ok(relative_path(&alias, url).filter(|rest| !rest.is_empty()))
+}
+
+/// Returns the path of `url` below `base`, if `url` lives under `base`.
+fn relative_path(base: &Url, url: &Url) -> Option<String> {
+    if base.scheme() != url.scheme() || base.host_str() != url.host_str() || base.port() != url.port() {
+        return None;
+    }
+    let base_path = base.path().trim_end_matches('/');
+    let path = url.path().trim_end_matches('/');
+    if path == base_path {
+        return Some(String::new());
+    }
+    path.strip_prefix(&format!("{}/", base_path)).map(String::from)
+}
+
+fn join_name(name: &str, rest: &str) -> String {
+    if rest.is_empty() {
+        name.to_string()
+    } else {
+        format!("{}/{}", name, rest)
+    }
+}
+
+/// Removes a `/t/<token>/` path prefix from a URL.
+fn strip_token(mut url: Url) -> (Url, Option<String>) {
+    let segments: Vec<String> = match url.path_segments() {
+        Some(segments) => segments.map(String::from).collect(),
+        None => return (url, None),
+    };
+    if segments.len() >= 2 && segments[0] == "t" && !segments[1].is_empty() {
+        let token = segments[1].clone();
+        url.set_path(&format!("/{}", segments[2..].join("/")));
+        return (url, Some(token));
+    }
+    (url, None)
+}
+
+/// Removes a trailing platform subdir from a URL.
+fn strip_platform(mut url: Url) -> (Url, Option<Platform>) {
+    let segments: Vec<String> = match url.path_segments() {
+        Some(segments) => segments.filter(|s| !s.is_empty()).map(String::from).collect(),
+        None => return (url, None),
+    };
+    match segments.last().map(|s| Platform::from_str(s)) {
+        Some(Ok(platform)) => {
+            url.set_path(&format!("/{}", segments[..segments.len() - 1].join("/")));
+            (url, Some(platform))
+        }
+        _ => (url, None),
+    }
+}
+
+fn with_trailing_slash(mut url: Url) -> Url {
+    if !url.path().ends_with('/') {
+        let path = format!("{}/", url.path());
+        url.set_path(&path);
+    }
+    url
+}
+
+fn parse_base_url(location: &str) -> Result<Url, ChannelError> {
+    let url = if has_scheme(location) {
+        Url::parse(location).map_err(|e| ChannelError::InvalidUrl(location.to_string(), e))?
+    } else {
+        Url::from_directory_path(location).map_err(|_| ChannelError::InvalidPath(PathBuf::from(location)))?
+    };
+    Ok(with_trailing_slash(url))
+}
+
+fn has_scheme(channel: &str) -> bool {
+    channel.contains("://")
+}
+
+fn is_path(channel: &str) -> bool {
+    channel.starts_with('/')
+        || channel.starts_with("./")
+        || channel.starts_with("../")
+        || channel.starts_with('~')
+        || channel == "."
+        || channel.contains('\\')
+        || (channel.len() >= 2 && channel.as_bytes()[1] == b':')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn known_name_prefers_the_most_specific_custom_channel() {
+        let mut config = ChannelConfig::default();
+        config.custom_channels.insert("team".to_string(), "https://conda.example.com/".to_string());
+        config.custom_channels.insert("team/nightly".to_string(), "https://conda.example.com/".to_string());
+
+        let channel = Channel::from_str("https://conda.example.com/team/nightly/linux-64", &config).unwrap();
+        assert_eq!(channel.name.as_deref(), Some("team/nightly"));
+        let channel = Channel::from_str("https://conda.example.com/team/stable", &config).unwrap();
+        assert_eq!(channel.name.as_deref(), Some("team/stable"));
+    }
+}
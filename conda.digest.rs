@@ -0,0 +1,95 @@
+// conda.digest.rs
+
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+
+use md5::{Digest, Md5};
+
+/// Represents the digests conda records for a package file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileDigests {
+    pub md5: String,
+    pub sha256: String,
+    pub size: u64,
+}
+
+/// Computes the md5, sha256 and size of a file in a single pass.
+pub fn file_digests(path: &Path) -> io::Result<FileDigests> {
+    let mut file = File::open(path)?;
+    let mut md5 = Md5::new();
+    let mut sha256 = ring::digest::Context::new(&ring::digest::SHA256);
+    let mut size = 0u64;
+    let mut buffer = vec![0u8; 64 * 1024];
+
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        md5.update(&buffer[..read]);
+        sha256.update(&buffer[..read]);
+        size += read as u64;
+    }
+
+    Ok(FileDigests {
+        md5: data_encoding::HEXLOWER.encode(&md5.finalize()),
+        sha256: data_encoding::HEXLOWER.encode(sha256.finish().as_ref()),
+        size,
+    })
+}
+
+/// Returns the hex-encoded sha256 of everything a reader yields.
+pub fn sha256_reader<R: Read>(mut reader: R) -> io::Result<String> {
+    let mut sha256 = ring::digest::Context::new(&ring::digest::SHA256);
+    let mut buffer = vec![0u8; 64 * 1024];
+    loop {
+        let read = reader.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        sha256.update(&buffer[..read]);
+    }
+    Ok(data_encoding::HEXLOWER.encode(sha256.finish().as_ref()))
+}
+
+/// Returns the hex-encoded sha256 of some bytes.
+pub fn sha256_hex(bytes: &[u8]) -> String {
+    data_encoding::HEXLOWER.encode(ring::digest::digest(&ring::digest::SHA256, bytes).as_ref())
+}
+
+/// Represents a file whose size or hash differs from what its record publishes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DigestMismatch {
+    pub kind: &'static str,
+    pub expected: String,
+    pub actual: String,
+}
+
+impl fmt::Display for DigestMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {} does not match the expected {}", self.kind, self.actual, self.expected)
+    }
+}
+
+impl std::error::Error for DigestMismatch {}
+
+/// Checks digests against a published size and hashes. The sha256 is
+/// preferred; the md5 is only checked when no sha256 is published. With
+/// nothing published there is nothing to check, so success alone does not
+/// prove a file is the one the record describes.
+pub fn check_digests(digests: &FileDigests, size: Option<u64>, sha256: Option<&str>, md5: Option<&str>) -> Result<(), DigestMismatch> {
+    let mismatch = |kind, expected: String, actual: &str| DigestMismatch { kind, expected, actual: actual.to_string() };
+
+    if let Some(size) = size {
+        if size != digests.size {
+            return Err(mismatch("size", size.to_string(), &digests.size.to_string()));
+        }
+    }
+    match (sha256, md5) {
+        (Some(sha256), _) if !sha256.eq_ignore_ascii_case(&digests.sha256) => Err(mismatch("sha256", sha256.to_string(), &digests.sha256)),
+        (None, Some(md5)) if !md5.eq_ignore_ascii_case(&digests.md5) => Err(mismatch("md5", md5.to_string(), &digests.md5)),
+        _ => Ok(()),
+    }
+}
@@ -0,0 +1,526 @@
+// conda.download.rs
+
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use rayon::prelude::*;
+use rayon::{ThreadPool, ThreadPoolBuildError, ThreadPoolBuilder};
+use reqwest::header::{HeaderMap, HeaderValue, CONTENT_RANGE, RANGE};
+use reqwest::StatusCode;
+use thiserror::Error;
+use url::Url;
+
+use crate::auth::redact_url;
+use crate::digest::{self, DigestMismatch, FileDigests};
+use crate::network::{NetworkClient, NetworkError};
+use crate::repodata::PackageRecord;
+
+/// The directory, next to downloaded files, that files failing verification are moved into.
+pub const QUARANTINE_DIR: &str = ".quarantine";
+
+/// Represents possible errors that can occur when fetching package files.
+#[derive(Error, Debug)]
+pub enum DownloadError {
+    #[error("Failed to write {path}: {source}")]
+    IoError { path: PathBuf, source: io::Error },
+
+    #[error(transparent)]
+    NetworkError(#[from] NetworkError),
+
+    #[error("Server returned {status} for {url}")]
+    StatusError { url: String, status: StatusCode },
+
+    #[error("Invalid local URL: {0}")]
+    InvalidPath(String),
+
+    #[error("Failed to read the response for {url}: {source}")]
+    ReadError { url: String, source: io::Error },
+
+    #[error("Failed to start download threads: {0}")]
+    ThreadPoolError(#[from] ThreadPoolBuildError),
+
+    #[error("{url} failed verification: {mismatch}; the file was quarantined at {quarantined}")]
+    Corrupt {
+        url: String,
+        mismatch: DigestMismatch,
+        quarantined: PathBuf,
+    },
+}
+
+/// Represents a package file to download into `dest` and verify against its record.
+#[derive(Debug, Clone)]
+pub struct DownloadRequest {
+    pub url: Url,
+    pub dest: PathBuf,
+    pub record: PackageRecord,
+}
+
+/// Downloads package files concurrently on a bounded number of threads,
+/// optionally under a shared bandwidth cap, and extracts them on a separate
+/// pool as each download completes. Concurrent requests for the same URL
+/// share a single download.
+#[derive(Debug)]
+pub struct DownloadScheduler {
+    downloads: ThreadPool,
+    extractions: ThreadPool,
+    throttle: Option<Throttle>,
+    in_flight: Mutex<HashMap<String, Arc<InFlight>>>,
+}
+
+impl DownloadScheduler {
+    /// Creates a scheduler running up to `download_threads` downloads and
+    /// `extract_threads` extractions at once.
+    pub fn new(download_threads: usize, extract_threads: usize) -> Result<Self, DownloadError> {
+        let pool = |threads: usize, name: &'static str| {
+            ThreadPoolBuilder::new()
+                .num_threads(threads.max(1))
+                .thread_name(move |index| format!("{}-{}", name, index))
+                .build()
+        };
+        Ok(DownloadScheduler {
+            downloads: pool(download_threads, "download")?,
+            extractions: pool(extract_threads, "extract")?,
+            throttle: None,
+            in_flight: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Caps the combined download rate, in bytes per second.
+    pub fn with_bandwidth_limit(mut self, bytes_per_sec: u64) -> Self {
+        self.throttle = Some(Throttle::new(bytes_per_sec));
+        self
+    }
+
+    /// Fetches and verifies a single package file. A file already at `dest`
+    /// that matches the record is kept, and a request for a URL another
+    /// thread is already downloading waits for that download and reuses it.
+    pub fn fetch<'a>(
+        &self,
+        request: &DownloadRequest,
+        client: impl Fn() -> Result<&'a NetworkClient, NetworkError>,
+    ) -> Result<FileDigests, DownloadError> {
+        if let Some(digests) = verified_digests(&request.dest, &request.record) {
+            return Ok(digests);
+        }
+        if !has_digests(&request.record) && request.dest.is_file() {
+            log::warn!(
+                "Downloading {} again: its record publishes no size or hash to check the existing {} against",
+                redact_url(request.url.as_str()),
+                request.dest.display()
+            );
+        }
+
+        let key = request.url.as_str().to_string();
+        let leading = {
+            let mut in_flight = lock(&self.in_flight);
+            match in_flight.get(&key) {
+                Some(flight) => Err(flight.clone()),
+                None => {
+                    let flight = Arc::new(InFlight::new(&request.dest));
+                    in_flight.insert(key.clone(), flight.clone());
+                    Ok(flight)
+                }
+            }
+        };
+
+        match leading {
+            Ok(flight) => {
+                let _landing = Landing { in_flight: &self.in_flight, key, flight };
+                fetch_verified_throttled(&request.url, &request.dest, &request.record, client, self.throttle.as_ref())
+            }
+            Err(flight) => {
+                flight.wait();
+                // Reuse the other download, or download again if it failed
+                match verified_digests(&flight.dest, &request.record) {
+                    Some(digests) if flight.dest == request.dest => Ok(digests),
+                    Some(digests) => {
+                        copy_file(&flight.dest, &request.dest)?;
+                        Ok(digests)
+                    }
+                    None => fetch_verified_throttled(&request.url, &request.dest, &request.record, client, self.throttle.as_ref()),
+                }
+            }
+        }
+    }
+
+    /// Fetches every requested file concurrently and hands each to
+    /// `on_downloaded`, with its index, on the extraction threads as soon as it
+    /// is verified, so extraction overlaps the remaining downloads. Returns
+    /// the results in request order.
+    pub fn fetch_all<'a, C, F, R>(&self, requests: &[DownloadRequest], client: C, on_downloaded: F) -> Vec<Result<R, DownloadError>>
+    where
+        C: Fn() -> Result<&'a NetworkClient, NetworkError> + Sync,
+        F: Fn(usize, &DownloadRequest) -> R + Sync,
+        R: Send,
+    {
+        let results: Vec<Mutex<Option<Result<R, DownloadError>>>> = requests.iter().map(|_| Mutex::new(None)).collect();
+        let (client, on_downloaded, slots) = (&client, &on_downloaded, &results);
+
+        self.extractions.in_place_scope(|extractions| {
+            self.downloads.install(|| {
+                requests.par_iter().enumerate().for_each(|(index, request)| match self.fetch(request, client) {
+                    Ok(_) => extractions.spawn(move |_| {
+                        let result = on_downloaded(index, request);
+                        *lock(&slots[index]) = Some(Ok(result));
+                    }),
+                    Err(e) => *lock(&slots[index]) = Some(Err(e)),
+                });
+            });
+        });
+
+        results
+            .into_iter()
+            .map(|slot| {
+                slot.into_inner()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .expect("every request has a result")
+            })
+            .collect()
+    }
+}
+
+/// Represents a download other requests for the same URL wait for.
+#[derive(Debug)]
+struct InFlight {
+    dest: PathBuf,
+    done: Mutex<bool>,
+    finished: Condvar,
+}
+
+impl InFlight {
+    fn new(dest: &Path) -> Self {
+        InFlight {
+            dest: dest.to_path_buf(),
+            done: Mutex::new(false),
+            finished: Condvar::new(),
+        }
+    }
+
+    fn wait(&self) {
+        let mut done = lock(&self.done);
+        while !*done {
+            done = self.finished.wait(done).unwrap_or_else(|e| e.into_inner());
+        }
+    }
+}
+
+/// Marks a download as finished when dropped, even if it panicked, so that
+/// waiting requests never hang.
+struct Landing<'s> {
+    in_flight: &'s Mutex<HashMap<String, Arc<InFlight>>>,
+    key: String,
+    flight: Arc<InFlight>,
+}
+
+impl Drop for Landing<'_> {
+    fn drop(&mut self) {
+        lock(self.in_flight).remove(&self.key);
+        *lock(&self.flight.done) = true;
+        self.flight.finished.notify_all();
+    }
+}
+
+/// Limits the combined rate of all downloads sharing it.
+#[derive(Debug)]
+struct Throttle {
+    bytes_per_sec: u64,
+    window: Mutex<ThrottleWindow>,
+}
+
+#[derive(Debug)]
+struct ThrottleWindow {
+    started: Instant,
+    bytes: u64,
+}
+
+impl Throttle {
+    fn new(bytes_per_sec: u64) -> Self {
+        Throttle {
+            bytes_per_sec: bytes_per_sec.max(1),
+            window: Mutex::new(ThrottleWindow { started: Instant::now(), bytes: 0 }),
+        }
+    }
+
+    /// Accounts for `bytes` just received, sleeping as long as needed to keep
+    /// the rate since the window started under the cap.
+    fn consume(&self, bytes: u64) {
+        let delay = {
+            let mut window = lock(&self.window);
+            let allowed = Duration::from_secs_f64(window.bytes as f64 / self.bytes_per_sec as f64);
+            // Idle time does not build up credit for a later burst
+            if window.started.elapsed() > allowed + Duration::from_secs(1) {
+                window.started = Instant::now();
+                window.bytes = 0;
+            }
+            window.bytes += bytes;
+            let due = Duration::from_secs_f64(window.bytes as f64 / self.bytes_per_sec as f64);
+            due.checked_sub(window.started.elapsed())
+        };
+        if let Some(delay) = delay {
+            thread::sleep(delay);
+        }
+    }
+}
+
+/// Fetches a file into `dest`, copying `file://` URLs from disk. The client is
+/// only requested for remote URLs.
+pub fn fetch_file<'a>(url: &Url, dest: &Path, client: impl FnOnce() -> Result<&'a NetworkClient, NetworkError>) -> Result<(), DownloadError> {
+    fetch_file_throttled(url, dest, client, None, false)
+}
+
+/// Fetches a file into `dest`. With `resume`, a `.partial` file left by an
+/// interrupted download is continued with an HTTP Range request.
+fn fetch_file_throttled<'a>(
+    url: &Url,
+    dest: &Path,
+    client: impl FnOnce() -> Result<&'a NetworkClient, NetworkError>,
+    throttle: Option<&Throttle>,
+    resume: bool,
+) -> Result<(), DownloadError> {
+    if let Some(parent) = dest.parent() {
+        fs::create_dir_all(parent).map_err(|source| DownloadError::IoError { path: parent.to_path_buf(), source })?;
+    }
+
+    let tmp_path = dest.with_extension("partial");
+    if url.scheme() == "file" {
+        let source_path = url
+            .to_file_path()
+            .map_err(|_| DownloadError::InvalidPath(url.to_string()))?;
+        fs::copy(&source_path, &tmp_path).map_err(|source| DownloadError::IoError { path: source_path, source })?;
+    } else {
+        if !resume && tmp_path.exists() {
+            fs::remove_file(&tmp_path).map_err(|source| DownloadError::IoError { path: tmp_path.clone(), source })?;
+        }
+        download(client()?, url, &tmp_path, throttle)?;
+    }
+
+    fs::rename(&tmp_path, dest).map_err(|source| DownloadError::IoError { path: dest.to_path_buf(), source })
+}
+
+/// Fetches a package file listed in repodata, moving it to `dest` only once
+/// its size and hash match the record. A mismatching file is quarantined.
+pub fn fetch_verified<'a>(
+    url: &Url,
+    dest: &Path,
+    record: &PackageRecord,
+    client: impl Fn() -> Result<&'a NetworkClient, NetworkError>,
+) -> Result<FileDigests, DownloadError> {
+    fetch_verified_throttled(url, dest, record, client, None)
+}
+
+/// Fetches and verifies a package file, resuming an interrupted download.
+/// A resumed file that fails verification may have continued a partial file
+/// of different contents, so it is downloaded once more from scratch before
+/// being quarantined.
+fn fetch_verified_throttled<'a>(
+    url: &Url,
+    dest: &Path,
+    record: &PackageRecord,
+    client: impl Fn() -> Result<&'a NetworkClient, NetworkError>,
+    throttle: Option<&Throttle>,
+) -> Result<FileDigests, DownloadError> {
+    let file_name = dest.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+    let tmp_path = dest.with_file_name(format!("{}.download", file_name));
+    let io_error = |path: &Path| {
+        let path = path.to_path_buf();
+        move |source| DownloadError::IoError { path, source }
+    };
+
+    let check = |path: &Path| -> Result<_, DownloadError> {
+        let digests = digest::file_digests(path).map_err(io_error(path))?;
+        let checked = digest::check_digests(&digests, record.size, record.sha256.as_deref(), record.md5.as_deref());
+        Ok((digests, checked))
+    };
+
+    if !has_digests(record) {
+        log::warn!("Cannot verify {} from {}: its record publishes no size or hash", file_name, redact_url(url.as_str()));
+    }
+    let resumed = url.scheme() != "file" && fs::metadata(tmp_path.with_extension("partial")).map_or(false, |metadata| metadata.len() > 0);
+    fetch_file_throttled(url, &tmp_path, &client, throttle, true)?;
+    let mut verified = check(&tmp_path)?;
+    if let (true, (_, Err(mismatch))) = (resumed, &verified) {
+        log::warn!("Resumed download of {} from {} is corrupt, downloading it again: {}", file_name, redact_url(url.as_str()), mismatch);
+        fetch_file_throttled(url, &tmp_path, &client, throttle, false)?;
+        verified = check(&tmp_path)?;
+    }
+    let (digests, checked) = verified;
+    if let Err(mismatch) = checked {
+        let quarantined = quarantine(&tmp_path, &file_name).map_err(io_error(&tmp_path))?;
+        log::warn!("Quarantined {} from {}: {}", file_name, redact_url(url.as_str()), mismatch);
+        return Err(DownloadError::Corrupt { url: redact_url(url.as_str()), mismatch, quarantined });
+    }
+
+    fs::rename(&tmp_path, dest).map_err(io_error(dest))?;
+    Ok(digests)
+}
+
+/// Moves a file that failed verification into the quarantine directory next
+/// to it, under `file_name`, and returns its new path. Earlier quarantined
+/// copies are replaced.
+pub fn quarantine(path: &Path, file_name: &str) -> io::Result<PathBuf> {
+    let dir = path.parent().unwrap_or_else(|| Path::new(".")).join(QUARANTINE_DIR);
+    fs::create_dir_all(&dir)?;
+    let target = dir.join(file_name);
+    fs::rename(path, &target)?;
+    Ok(target)
+}
+
+/// Returns whether a record publishes a size or hash to check its package file against.
+fn has_digests(record: &PackageRecord) -> bool {
+    record.size.is_some() || record.sha256.is_some() || record.md5.is_some()
+}
+
+/// Returns the digests of the file at `path` if it exists and matches the
+/// record. Without a published size or hash nothing is known to match, as
+/// the file could hold anything.
+fn verified_digests(path: &Path, record: &PackageRecord) -> Option<FileDigests> {
+    if !has_digests(record) || !path.is_file() {
+        return None;
+    }
+    let digests = digest::file_digests(path).ok()?;
+    digest::check_digests(&digests, record.size, record.sha256.as_deref(), record.md5.as_deref()).ok()?;
+    Some(digests)
+}
+
+/// Copies a downloaded file to another destination, renaming it into place.
+fn copy_file(source: &Path, dest: &Path) -> Result<(), DownloadError> {
+    let io_error = |path: &Path| {
+        let path = path.to_path_buf();
+        move |source| DownloadError::IoError { path, source }
+    };
+    if let Some(parent) = dest.parent() {
+        fs::create_dir_all(parent).map_err(io_error(parent))?;
+    }
+    let tmp_path = dest.with_extension("partial");
+    fs::copy(source, &tmp_path).map_err(io_error(&tmp_path))?;
+    fs::rename(&tmp_path, dest).map_err(io_error(dest))
+}
+
+/// Downloads a remote URL into `dest`, continuing from the end of an existing
+/// `dest` when the server honours the Range request.
+fn download(client: &NetworkClient, url: &Url, dest: &Path, throttle: Option<&Throttle>) -> Result<(), DownloadError> {
+    let io_error = |source| DownloadError::IoError { path: dest.to_path_buf(), source };
+    let offset = fs::metadata(dest).map_or(0, |metadata| metadata.len());
+
+    let mut headers = HeaderMap::new();
+    if offset > 0 {
+        let range = HeaderValue::from_str(&format!("bytes={}-", offset)).expect("a valid Range header");
+        headers.insert(RANGE, range);
+    }
+    let mut response = client.get_with_headers(url.as_str(), headers)?;
+
+    let status = response.status();
+    let file = if offset > 0 && status == StatusCode::PARTIAL_CONTENT {
+        if content_range_start(&response) != Some(offset) {
+            // Not the continuation asked for; start over
+            fs::remove_file(dest).map_err(io_error)?;
+            return download(client, url, dest, throttle);
+        }
+        log::debug!("Resuming {} from byte {}", redact_url(url.as_str()), offset);
+        OpenOptions::new().append(true).open(dest)
+    } else if offset > 0 && status == StatusCode::RANGE_NOT_SATISFIABLE {
+        // The partial file is no prefix of the current file; start over
+        fs::remove_file(dest).map_err(io_error)?;
+        return download(client, url, dest, throttle);
+    } else if status.is_success() {
+        File::create(dest)
+    } else {
+        return Err(DownloadError::StatusError { url: redact_url(url.as_str()), status });
+    };
+    let mut file = file.map_err(io_error)?;
+
+    let mut buffer = vec![0; 64 * 1024];
+    loop {
+        let read = match response.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(read) => read,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(source) => return Err(DownloadError::ReadError { url: redact_url(url.as_str()), source }),
+        };
+        file.write_all(&buffer[..read]).map_err(io_error)?;
+        if let Some(throttle) = throttle {
+            throttle.consume(read as u64);
+        }
+    }
+    file.flush().map_err(io_error)
+}
+
+/// Returns the first byte of a partial response, from `Content-Range: bytes <start>-<end>/<size>`.
+fn content_range_start(response: &reqwest::blocking::Response) -> Option<u64> {
+    let value = response.headers().get(CONTENT_RANGE)?.to_str().ok()?;
+    value.strip_prefix("bytes ")?.split('-').next()?.trim().parse().ok()
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::digest::sha256_hex;
+    use crate::network::test_server::TestServer;
+
+    const FILE_NAME: &str = "demo-1.0-0.tar.bz2";
+
+    #[test]
+    fn corrupt_download_is_quarantined() {
+        let server = TestServer::start();
+        server.serve(&format!("/{}", FILE_NAME), "wrong bytes", None);
+        let client = server.client();
+        let dir = tempfile::tempdir().unwrap();
+        let dest = dir.path().join("pkgs").join(FILE_NAME);
+        let url = Url::parse(&server.url(&format!("/{}", FILE_NAME))).unwrap();
+        let expected = sha256_hex(b"right bytes");
+        let record = PackageRecord { sha256: Some(expected.clone()), ..PackageRecord::default() };
+
+        let error = fetch_verified(&url, &dest, &record, || Ok(&client)).unwrap_err();
+
+        let quarantined = dir.path().join("pkgs").join(QUARANTINE_DIR).join(FILE_NAME);
+        match &error {
+            DownloadError::Corrupt { quarantined: path, .. } => assert_eq!(path, &quarantined),
+            other => panic!("expected a corrupt download, got {:?}", other),
+        }
+        assert_eq!(
+            error.to_string(),
+            format!(
+                "{} failed verification: sha256 {} does not match the expected {}; the file was quarantined at {}",
+                url,
+                sha256_hex(b"wrong bytes"),
+                expected,
+                quarantined.display()
+            )
+        );
+        assert_eq!(fs::read(&quarantined).unwrap(), b"wrong bytes");
+        assert!(!dest.exists());
+    }
+
+    #[test]
+    fn existing_file_is_reused_only_when_its_record_can_check_it() {
+        let server = TestServer::start();
+        server.serve(&format!("/{}", FILE_NAME), "fresh", None);
+        let client = server.client();
+        let dir = tempfile::tempdir().unwrap();
+        let scheduler = DownloadScheduler::new(1, 1).unwrap();
+        let mut request = DownloadRequest {
+            url: Url::parse(&server.url(&format!("/{}", FILE_NAME))).unwrap(),
+            dest: dir.path().join(FILE_NAME),
+            record: PackageRecord { sha256: Some(sha256_hex(b"stale")), ..PackageRecord::default() },
+        };
+
+        fs::write(&request.dest, "stale").unwrap();
+        scheduler.fetch(&request, || Ok(&client)).unwrap();
+        assert_eq!(fs::read(&request.dest).unwrap(), b"stale");
+        assert!(server.take_requests().is_empty());
+
+        request.record = PackageRecord::default();
+        scheduler.fetch(&request, || Ok(&client)).unwrap();
+        assert_eq!(fs::read(&request.dest).unwrap(), b"fresh");
+        assert_eq!(server.take_requests().len(), 1);
+    }
+}
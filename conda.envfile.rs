@@ -0,0 +1,350 @@
+// conda.envfile.rs
+
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::channel::{Channel, ChannelConfig};
+use crate::matchspec::{MatchSpec, MatchSpecError};
+use crate::prefix::{self, PrefixError, PrefixRecord};
+
+/// Represents possible errors that can occur when reading or writing an
+/// environment file.
+#[derive(Error, Debug)]
+pub enum EnvFileError {
+    #[error("Failed to access {path}: {source}")]
+    IoError { path: PathBuf, source: io::Error },
+
+    #[error("Invalid environment file: {0}")]
+    ParseError(#[source] serde_yaml::Error),
+
+    #[error("Failed to serialize environment file: {0}")]
+    SerializeError(#[source] serde_yaml::Error),
+
+    #[error("Invalid spec: {0}")]
+    MatchSpecError(#[from] MatchSpecError),
+
+    #[error("Invalid environment state {path}: {source}")]
+    StateError { path: PathBuf, source: serde_json::Error },
+
+    #[error(transparent)]
+    PrefixError(#[from] PrefixError),
+}
+
+/// Represents which specs an export writes for each package.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ExportMode {
+    /// Every installed package as `name=version=build`.
+    #[default]
+    ExactBuilds,
+    /// Every installed package as `name=version`.
+    NoBuilds,
+    /// Only the specs the user requested, as recorded in the prefix history.
+    FromHistory,
+}
+
+/// Represents a conda `environment.yml`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct EnvironmentFile {
+    pub name: Option<String>,
+    pub channels: Vec<String>,
+
+    /// The conda dependencies.
+    pub dependencies: Vec<MatchSpec>,
+
+    /// The requirements of the nested `pip:` section, passed to pip as is.
+    pub pip: Vec<String>,
+
+    /// The environment variables set on activation.
+    pub variables: BTreeMap<String, String>,
+    pub prefix: Option<PathBuf>,
+}
+
+/// Represents the on-disk layout of an `environment.yml`, whose dependency
+/// list mixes spec strings with a `pip:` mapping.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct RawEnvironmentFile {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    channels: Vec<String>,
+
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    dependencies: Vec<RawDependency>,
+
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    variables: BTreeMap<String, serde_yaml::Value>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    prefix: Option<PathBuf>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+enum RawDependency {
+    Spec(String),
+    Pip { pip: Vec<String> },
+}
+
+impl EnvironmentFile {
+    /// Reads an environment file.
+    pub fn from_path(path: &Path) -> Result<Self, EnvFileError> {
+        let contents = fs::read_to_string(path).map_err(|source| EnvFileError::IoError { path: path.to_path_buf(), source })?;
+        Self::from_yaml_str(&contents)
+    }
+
+    /// Parses the contents of an environment file.
+    pub fn from_yaml_str(contents: &str) -> Result<Self, EnvFileError> {
+        let raw: RawEnvironmentFile = serde_yaml::from_str(contents).map_err(EnvFileError::ParseError)?;
+
+        let mut file = EnvironmentFile {
+            name: raw.name,
+            channels: raw.channels,
+            prefix: raw.prefix,
+            ..Default::default()
+        };
+        for dependency in raw.dependencies {
+            match dependency {
+                RawDependency::Spec(spec) => file.dependencies.push(spec.parse()?),
+                RawDependency::Pip { pip } => file.pip.extend(pip),
+            }
+        }
+        file.variables = raw
+            .variables
+            .into_iter()
+            .map(|(key, value)| (key, yaml_scalar(value)))
+            .collect();
+        Ok(file)
+    }
+
+    /// Renders the environment file as YAML, in conda's key order.
+    pub fn to_yaml_string(&self) -> Result<String, EnvFileError> {
+        let mut dependencies: Vec<RawDependency> = self
+            .dependencies
+            .iter()
+            .map(|spec| RawDependency::Spec(spec.to_string()))
+            .collect();
+        if !self.pip.is_empty() {
+            dependencies.push(RawDependency::Pip { pip: self.pip.clone() });
+        }
+
+        let raw = RawEnvironmentFile {
+            name: self.name.clone(),
+            channels: self.channels.clone(),
+            dependencies,
+            variables: self
+                .variables
+                .iter()
+                .map(|(key, value)| (key.clone(), serde_yaml::Value::String(value.clone())))
+                .collect(),
+            prefix: self.prefix.clone(),
+        };
+        serde_yaml::to_string(&raw).map_err(EnvFileError::SerializeError)
+    }
+
+    /// Writes the environment file.
+    pub fn write(&self, path: &Path) -> Result<(), EnvFileError> {
+        let contents = self.to_yaml_string()?;
+        fs::write(path, contents).map_err(|source| EnvFileError::IoError { path: path.to_path_buf(), source })
+    }
+
+    /// Describes an installed environment. Channels are listed in the order
+    /// packages are first installed from them, by canonical name. Pip
+    /// packages are listed unless exporting from history, which conda only
+    /// records for its own packages.
+    pub fn from_prefix(prefix: &Path, name: Option<&str>, mode: ExportMode, channel_config: &ChannelConfig) -> Result<Self, EnvFileError> {
+        let mut records = prefix::load_prefix_records(prefix)?;
+        records.sort_by(|a, b| a.record.name.cmp(&b.record.name));
+
+        let mut channels = Vec::new();
+        for record in &records {
+            if let Some(channel) = &record.channel {
+                let name = Channel::from_str(channel, channel_config)
+                    .map(|channel| channel.canonical_name())
+                    .unwrap_or_else(|_| channel.clone());
+                if !channels.contains(&name) {
+                    channels.push(name);
+                }
+            }
+        }
+
+        let (dependencies, pip) = match mode {
+            ExportMode::ExactBuilds | ExportMode::NoBuilds => {
+                let dependencies = records
+                    .iter()
+                    .map(|record| {
+                        let spec = match mode {
+                            ExportMode::ExactBuilds => format!("{}={}={}", record.record.name, record.record.version, record.record.build),
+                            _ => format!("{}={}", record.record.name, record.record.version),
+                        };
+                        spec.parse()
+                    })
+                    .collect::<Result<Vec<MatchSpec>, _>>()?;
+                (dependencies, pip_requirements(prefix, &records))
+            }
+            ExportMode::FromHistory => (requested_specs(prefix, &records)?, Vec::new()),
+        };
+
+        Ok(EnvironmentFile {
+            name: name.map(String::from),
+            channels,
+            dependencies,
+            pip,
+            variables: environment_variables(prefix)?,
+            prefix: Some(prefix.to_path_buf()),
+        })
+    }
+}
+
+/// Returns the specs the user explicitly requested for a prefix, replaying the
+/// `# update specs:` and `# remove specs:` lines of `conda-meta/history`, then
+/// adding the requested specs of packages linked without conda.
+pub fn requested_specs(prefix: &Path, records: &[PrefixRecord]) -> Result<Vec<MatchSpec>, EnvFileError> {
+    let history_path = prefix::conda_meta_dir(prefix).join("history");
+    let mut specs: Vec<MatchSpec> = Vec::new();
+    let history = match fs::read_to_string(&history_path) {
+        Ok(history) => history,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(source) => return Err(EnvFileError::IoError { path: history_path, source }),
+    };
+    for line in history.lines() {
+        if let Some(list) = line.strip_prefix("# update specs:") {
+            for spec in python_list(list) {
+                let spec: MatchSpec = spec.parse()?;
+                specs.retain(|existing| existing.name != spec.name);
+                specs.push(spec);
+            }
+        } else if let Some(list) = line.strip_prefix("# remove specs:") {
+            for spec in python_list(list) {
+                let name = spec.parse::<MatchSpec>()?.name;
+                specs.retain(|existing| existing.name != name);
+            }
+        }
+    }
+
+    for spec in records.iter().filter_map(|record| record.requested_spec.as_deref()) {
+        let spec: MatchSpec = spec.parse()?;
+        if !specs.iter().any(|existing| existing.name == spec.name) {
+            specs.push(spec);
+        }
+    }
+    Ok(specs)
+}
+
+/// Returns the `name==version` requirements of packages pip installed into a
+/// prefix: `.dist-info` directories that no conda package installed.
+pub fn pip_requirements(prefix: &Path, records: &[PrefixRecord]) -> Vec<String> {
+    let conda_files: HashSet<&str> = records
+        .iter()
+        .flat_map(|record| record.files.iter().map(String::as_str))
+        .collect();
+
+    let mut requirements = Vec::new();
+    for site_packages in site_packages_dirs(prefix) {
+        let entries = match fs::read_dir(&site_packages) {
+            Ok(entries) => entries,
+            Err(_) => continue,
+        };
+        for entry in entries.flatten() {
+            let dist_info = entry.path();
+            if dist_info.extension().and_then(|ext| ext.to_str()) != Some("dist-info") {
+                continue;
+            }
+            let metadata = dist_info.join("METADATA");
+            let relative = metadata
+                .strip_prefix(prefix)
+                .map(|path| path.to_string_lossy().replace('\\', "/"))
+                .unwrap_or_default();
+            let installer = fs::read_to_string(dist_info.join("INSTALLER")).unwrap_or_default();
+            if conda_files.contains(relative.as_str()) || installer.trim() == "conda" {
+                continue;
+            }
+            if let Some(requirement) = read_requirement(&metadata) {
+                requirements.push(requirement);
+            }
+        }
+    }
+    requirements.sort_by_key(|requirement| requirement.to_lowercase());
+    requirements.dedup();
+    requirements
+}
+
+/// Returns the environment variables conda sets on activating a prefix, from
+/// `conda-meta/state`.
+pub fn environment_variables(prefix: &Path) -> Result<BTreeMap<String, String>, EnvFileError> {
+    #[derive(Deserialize)]
+    struct State {
+        #[serde(default)]
+        env_vars: BTreeMap<String, String>,
+    }
+
+    let path = prefix::conda_meta_dir(prefix).join("state");
+    let contents = match fs::read_to_string(&path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(BTreeMap::new()),
+        Err(source) => return Err(EnvFileError::IoError { path, source }),
+    };
+    let state: State = serde_json::from_str(&contents).map_err(|source| EnvFileError::StateError { path, source })?;
+    Ok(state.env_vars)
+}
+
+/// Returns the `site-packages` directories of a prefix, for both the Unix
+/// `lib/pythonX.Y` layout and the Windows `Lib` layout.
+fn site_packages_dirs(prefix: &Path) -> Vec<PathBuf> {
+    let mut dirs = vec![prefix.join("Lib").join("site-packages")];
+    if let Ok(entries) = fs::read_dir(prefix.join("lib")) {
+        for entry in entries.flatten() {
+            if entry.file_name().to_string_lossy().starts_with("python") {
+                dirs.push(entry.path().join("site-packages"));
+            }
+        }
+    }
+    dirs
+}
+
+/// Reads `name==version` from the `Name:` and `Version:` headers of a
+/// `.dist-info/METADATA` file.
+fn read_requirement(metadata: &Path) -> Option<String> {
+    let contents = fs::read_to_string(metadata).ok()?;
+    let mut name = None;
+    let mut version = None;
+    // The headers end at the first blank line, where the description starts.
+    for line in contents.lines().take_while(|line| !line.is_empty()) {
+        if let Some(value) = line.strip_prefix("Name:") {
+            name = Some(value.trim());
+        } else if let Some(value) = line.strip_prefix("Version:") {
+            version = Some(value.trim());
+        }
+    }
+    Some(format!("{}=={}", name?, version?))
+}
+
+/// Parses the quoted items of a Python list literal such as
+/// `['numpy', "python[version='>=3.9']"]`, as written by conda's history.
+fn python_list(list: &str) -> Vec<String> {
+    let mut items = Vec::new();
+    let mut chars = list.chars();
+    while let Some(c) = chars.next() {
+        if c == '\'' || c == '"' {
+            let item: String = chars.by_ref().take_while(|&next| next != c).collect();
+            items.push(item);
+        }
+    }
+    items
+}
+
+/// Returns a YAML scalar as written, so `DEBUG: 1` gives `"1"`.
+fn yaml_scalar(value: serde_yaml::Value) -> String {
+    match value {
+        serde_yaml::Value::String(value) => value,
+        serde_yaml::Value::Null => String::new(),
+        serde_yaml::Value::Bool(value) => value.to_string(),
+        serde_yaml::Value::Number(value) => value.to_string(),
+        other => serde_yaml::to_string(&other).map(|yaml| yaml.trim().to_string()).unwrap_or_default(),
+    }
+}
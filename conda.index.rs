@@ -0,0 +1,337 @@
+// conda.index.rs
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::UNIX_EPOCH;
+
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::archive::{self, ArchiveError, ArchiveType};
+use crate::channel::Platform;
+use crate::digest;
+use crate::package::IndexJson;
+use crate::patch::{PatchError, PatchInstructions, PatchReport, PATCH_INSTRUCTIONS_FILE};
+use crate::repodata::{write_atomic, ChannelInfo, PackageRecord, RepoData};
+use crate::signing::{self, SigningError, SigningKey};
+use crate::version::VersionOrder;
+
+/// The zstd level used for `repodata.json.zst`.
+const REPODATA_ZSTD_LEVEL: i32 = 16;
+
+/// Represents possible errors that can occur when indexing a channel.
+#[derive(Error, Debug)]
+pub enum IndexError {
+    #[error("Failed to access {path}: {source}")]
+    IoError { path: PathBuf, source: io::Error },
+
+    #[error("Failed to serialize repodata: {0}")]
+    SerializeError(#[from] serde_json::Error),
+
+    #[error("Channel directory {0} does not exist")]
+    MissingChannel(PathBuf),
+
+    #[error(transparent)]
+    PatchError(#[from] PatchError),
+
+    #[error(transparent)]
+    SigningError(#[from] SigningError),
+}
+
+/// Represents the cached index of a single package file.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct CacheEntry {
+    mtime: u64,
+    size: u64,
+    record: PackageRecord,
+}
+
+/// Represents what indexing changed in one subdir.
+#[derive(Debug, Clone, Default)]
+pub struct SubdirReport {
+    pub subdir: String,
+    pub added: Vec<String>,
+    pub updated: Vec<String>,
+    pub removed: Vec<String>,
+    pub unchanged: usize,
+
+    /// Package files that could not be indexed, with the reason.
+    pub failed: Vec<(String, String)>,
+
+    /// What the subdir's `patch_instructions.json` changed, if it has one.
+    pub patches: Option<PatchReport>,
+}
+
+/// Represents the result of indexing a channel.
+#[derive(Debug, Clone, Default)]
+pub struct IndexReport {
+    pub subdirs: Vec<SubdirReport>,
+}
+
+impl IndexReport {
+    /// Returns whether any subdir changed.
+    pub fn has_changes(&self) -> bool {
+        self.subdirs
+            .iter()
+            .any(|s| !s.added.is_empty() || !s.updated.is_empty() || !s.removed.is_empty())
+    }
+}
+
+/// Builds `repodata.json`, `repodata.json.zst` and `current_repodata.json`
+/// for the subdirs of a local channel directory.
+#[derive(Debug, Clone)]
+pub struct ChannelIndexer {
+    channel_dir: PathBuf,
+    subdirs: Option<Vec<Platform>>,
+    signing_key: Option<Arc<SigningKey>>,
+}
+
+impl ChannelIndexer {
+    /// Creates an indexer for a channel directory.
+    pub fn new(channel_dir: impl Into<PathBuf>) -> Self {
+        ChannelIndexer {
+            channel_dir: channel_dir.into(),
+            subdirs: None,
+            signing_key: None,
+        }
+    }
+
+    /// Restricts indexing to the given subdirs instead of every platform subdir present.
+    pub fn with_subdirs(mut self, subdirs: Vec<Platform>) -> Self {
+        self.subdirs = Some(subdirs);
+        self
+    }
+
+    /// Signs the repodata records and writes a detached signature for every
+    /// package file that lacks one by this key.
+    pub fn with_signing_key(mut self, key: Arc<SigningKey>) -> Self {
+        self.signing_key = Some(key);
+        self
+    }
+
+    /// Returns the channel directory.
+    pub fn channel_dir(&self) -> &Path {
+        &self.channel_dir
+    }
+
+    /// Indexes every selected subdir. `noarch` is always created, since conda
+    /// clients require it.
+    pub fn index(&self) -> Result<IndexReport, IndexError> {
+        if !self.channel_dir.is_dir() {
+            return Err(IndexError::MissingChannel(self.channel_dir.clone()));
+        }
+
+        let subdirs = match &self.subdirs {
+            Some(subdirs) => subdirs.clone(),
+            None => Platform::ALL
+                .iter()
+                .copied()
+                .filter(|p| *p == Platform::NoArch || self.channel_dir.join(p.as_str()).is_dir())
+                .collect(),
+        };
+
+        let mut report = IndexReport::default();
+        for subdir in subdirs {
+            report.subdirs.push(self.index_subdir(subdir)?);
+        }
+        Ok(report)
+    }
+
+    /// Indexes one subdir, reusing cached records for files whose mtime and size
+    /// are unchanged, and applying the subdir's `patch_instructions.json`
+    /// before signing.
+    pub fn index_subdir(&self, platform: Platform) -> Result<SubdirReport, IndexError> {
+        let subdir = platform.as_str();
+        let dir = self.channel_dir.join(subdir);
+        let io_error = |path: &Path| {
+            let path = path.to_path_buf();
+            move |source| IndexError::IoError { path, source }
+        };
+        fs::create_dir_all(&dir).map_err(io_error(&dir))?;
+
+        let cache_path = dir.join(".cache").join("index_cache.json");
+        let mut cache: BTreeMap<String, CacheEntry> = fs::read_to_string(&cache_path)
+            .ok()
+            .and_then(|contents| serde_json::from_str(&contents).ok())
+            .unwrap_or_default();
+
+        let mut report = SubdirReport { subdir: subdir.to_string(), ..SubdirReport::default() };
+        let mut present = HashSet::new();
+        let mut changed = Vec::new();
+
+        for entry in fs::read_dir(&dir).map_err(io_error(&dir))? {
+            let entry = entry.map_err(io_error(&dir))?;
+            let filename = match entry.file_name().into_string() {
+                Ok(filename) if ArchiveType::split_filename(&filename).is_some() => filename,
+                _ => continue,
+            };
+            let metadata = entry.metadata().map_err(io_error(&entry.path()))?;
+            let mtime = metadata
+                .modified()
+                .ok()
+                .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                .map_or(0, |d| d.as_secs());
+            let size = metadata.len();
+
+            match cache.get(&filename) {
+                Some(cached) if cached.mtime == mtime && cached.size == size => report.unchanged += 1,
+                _ => changed.push((filename.clone(), mtime, size)),
+            }
+            present.insert(filename);
+        }
+
+        report.removed = cache.keys().filter(|f| !present.contains(f)).cloned().collect();
+        for filename in &report.removed {
+            cache.remove(filename);
+            let _ = fs::remove_file(signing::signature_path(&dir.join(filename)));
+        }
+
+        let indexed: Vec<(String, u64, u64, Result<PackageRecord, String>)> = changed
+            .into_par_iter()
+            .map(|(filename, mtime, size)| {
+                let record = index_package(&dir.join(&filename), subdir).map_err(|e| e.to_string());
+                (filename, mtime, size, record)
+            })
+            .collect();
+
+        for (filename, mtime, size, record) in indexed {
+            match record {
+                Ok(record) => {
+                    if cache.insert(filename.clone(), CacheEntry { mtime, size, record }).is_some() {
+                        report.updated.push(filename);
+                    } else {
+                        report.added.push(filename);
+                    }
+                }
+                Err(reason) => {
+                    log::warn!("Skipping {}/{}: {}", subdir, filename, reason);
+                    cache.remove(&filename);
+                    report.failed.push((filename, reason));
+                }
+            }
+        }
+
+        let mut repodata = RepoData {
+            info: Some(ChannelInfo { subdir: subdir.to_string(), base_url: None }),
+            repodata_version: Some(1),
+            ..RepoData::default()
+        };
+        for (filename, entry) in &cache {
+            match ArchiveType::split_filename(filename) {
+                Some((_, ArchiveType::Conda)) => repodata.conda_packages.insert(filename.clone(), entry.record.clone()),
+                _ => repodata.packages.insert(filename.clone(), entry.record.clone()),
+            };
+        }
+
+        // Keep the unpatched records alongside, so patches can be regenerated.
+        let path = dir.join("repodata_from_packages.json");
+        let unpatched = serde_json::to_vec_pretty(&serde_json::to_value(&repodata)?)?;
+        write_atomic(&path, &unpatched).map_err(io_error(&path))?;
+
+        if let Some(instructions) = PatchInstructions::load(&dir.join(PATCH_INSTRUCTIONS_FILE))? {
+            report.patches = Some(instructions.apply(&mut repodata)?);
+        }
+        if let Some(key) = &self.signing_key {
+            key.sign_repodata(&mut repodata)?;
+            let fresh: HashSet<&String> = report.added.iter().chain(&report.updated).collect();
+            let unsigned: Vec<&String> = cache
+                .keys()
+                .filter(|filename| fresh.contains(filename) || !signing::is_signed_by(&dir.join(filename), key.key_id()))
+                .collect();
+            unsigned
+                .into_par_iter()
+                .try_for_each(|filename| key.sign_artifact(&dir.join(filename)).map(|_| ()))?;
+        }
+        write_repodata(&dir, &repodata)?;
+
+        fs::create_dir_all(cache_path.parent().expect("cache path has a parent")).map_err(io_error(&cache_path))?;
+        write_atomic(&cache_path, serde_json::to_string(&cache)?.as_bytes()).map_err(io_error(&cache_path))?;
+
+        Ok(report)
+    }
+}
+
+/// Builds the repodata record of a single package file.
+pub fn index_package(path: &Path, subdir: &str) -> Result<PackageRecord, ArchiveError> {
+    let contents = archive::read_info_file(path, "info/index.json")?.ok_or_else(|| ArchiveError::MissingMember {
+        path: path.to_path_buf(),
+        member: "info/index.json".to_string(),
+    })?;
+    let index: IndexJson = serde_json::from_slice(&contents).map_err(|e| ArchiveError::IoError {
+        path: path.to_path_buf(),
+        source: io::Error::new(io::ErrorKind::InvalidData, e),
+    })?;
+    let digests = digest::file_digests(path).map_err(|source| ArchiveError::IoError { path: path.to_path_buf(), source })?;
+    Ok(index.to_record(subdir, &digests))
+}
+
+/// Writes `repodata.json`, `repodata.json.zst` and `current_repodata.json` into a subdir.
+pub fn write_repodata(dir: &Path, repodata: &RepoData) -> Result<(), IndexError> {
+    let io_error = |path: PathBuf| move |source| IndexError::IoError { path, source };
+
+    // Round-tripping through a Value sorts the keys, keeping output stable.
+    let full = serde_json::to_vec_pretty(&serde_json::to_value(repodata)?)?;
+    let path = dir.join("repodata.json");
+    write_atomic(&path, &full).map_err(io_error(path))?;
+
+    let path = dir.join("repodata.json.zst");
+    let compressed = zstd::encode_all(&full[..], REPODATA_ZSTD_LEVEL).map_err(io_error(path.clone()))?;
+    write_atomic(&path, &compressed).map_err(io_error(path))?;
+
+    let current = serde_json::to_vec_pretty(&serde_json::to_value(current_repodata(repodata))?)?;
+    let path = dir.join("current_repodata.json");
+    write_atomic(&path, &current).map_err(io_error(path))?;
+
+    Ok(())
+}
+
+/// Keeps only the records of the newest version of each package name.
+pub fn current_repodata(repodata: &RepoData) -> RepoData {
+    let mut latest: HashMap<&str, VersionOrder> = HashMap::new();
+    for (_, record) in repodata.records() {
+        if let Ok(version) = record.version.parse::<VersionOrder>() {
+            let newest = latest.entry(record.name.as_str()).or_insert_with(|| version.clone());
+            if version > *newest {
+                *newest = version;
+            }
+        }
+    }
+
+    let is_latest = |record: &PackageRecord| {
+        match (latest.get(record.name.as_str()), record.version.parse::<VersionOrder>()) {
+            (Some(newest), Ok(version)) => version == *newest,
+            _ => false,
+        }
+    };
+
+    RepoData {
+        info: repodata.info.clone(),
+        packages: repodata
+            .packages
+            .iter()
+            .filter(|(_, record)| is_latest(record))
+            .map(|(filename, record)| (filename.clone(), record.clone()))
+            .collect(),
+        conda_packages: repodata
+            .conda_packages
+            .iter()
+            .filter(|(_, record)| is_latest(record))
+            .map(|(filename, record)| (filename.clone(), record.clone()))
+            .collect(),
+        removed: Vec::new(),
+        repodata_version: repodata.repodata_version,
+        signatures: repodata
+            .signatures
+            .iter()
+            .filter(|(filename, _)| {
+                let record = repodata.packages.get(*filename).or_else(|| repodata.conda_packages.get(*filename));
+                record.is_some_and(is_latest)
+            })
+            .map(|(filename, signatures)| (filename.clone(), signatures.clone()))
+            .collect(),
+    }
+}
@@ -0,0 +1,176 @@
+// conda.inspect.rs
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::Serialize;
+use thiserror::Error;
+
+use crate::archive::{self, ArchiveError};
+use crate::package::{AboutJson, FileMode, IndexJson, PathType, RunExportsJson};
+use crate::pkgs::{self, PkgsError};
+
+/// Represents possible errors that can occur when inspecting a package.
+#[derive(Error, Debug)]
+pub enum InspectError {
+    #[error("Failed to read {path}: {source}")]
+    IoError { path: PathBuf, source: io::Error },
+
+    #[error(transparent)]
+    ArchiveError(#[from] ArchiveError),
+
+    #[error(transparent)]
+    PkgsError(#[from] PkgsError),
+}
+
+/// Represents the scripts conda runs around linking a package.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum LinkAction {
+    PreLink,
+    PostLink,
+    PreUnlink,
+}
+
+impl LinkAction {
+    const ALL: [LinkAction; 3] = [LinkAction::PreLink, LinkAction::PostLink, LinkAction::PreUnlink];
+
+    /// Returns the action as it appears in script names, e.g. `post-link`.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LinkAction::PreLink => "pre-link",
+            LinkAction::PostLink => "post-link",
+            LinkAction::PreUnlink => "pre-unlink",
+        }
+    }
+}
+
+impl fmt::Display for LinkAction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Represents a link script shipped in a package, such as `bin/.foo-post-link.sh`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct LinkScript {
+    pub action: LinkAction,
+    pub path: PathBuf,
+    pub contents: String,
+}
+
+/// Represents a file of a package.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct InspectedFile {
+    pub path: PathBuf,
+    pub path_type: PathType,
+    pub size: Option<u64>,
+
+    /// Whether the file contains the build prefix, which is replaced at link time.
+    pub has_prefix_placeholder: bool,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub file_mode: Option<FileMode>,
+
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub no_link: bool,
+}
+
+/// Represents what a package contains, for review before it is approved.
+#[derive(Debug, Clone, Serialize)]
+pub struct PackageInspection {
+    /// The archive or extracted directory that was inspected.
+    pub source: PathBuf,
+    pub index: IndexJson,
+    pub files: Vec<InspectedFile>,
+    pub about: Option<AboutJson>,
+    pub run_exports: Option<RunExportsJson>,
+    pub link_scripts: Vec<LinkScript>,
+}
+
+impl PackageInspection {
+    /// Returns the total size of the files whose size is known.
+    pub fn total_size(&self) -> u64 {
+        self.files.iter().filter_map(|file| file.size).sum()
+    }
+
+    /// Returns the files that get the prefix replaced at link time.
+    pub fn prefix_files(&self) -> impl Iterator<Item = &InspectedFile> {
+        self.files.iter().filter(|file| file.has_prefix_placeholder)
+    }
+}
+
+/// Inspects a `.conda` or `.tar.bz2` file by extracting it into a temporary directory.
+pub fn inspect_archive(path: &Path) -> Result<PackageInspection, InspectError> {
+    let tmp_dir = tempfile::Builder::new()
+        .prefix(".inspect-")
+        .tempdir()
+        .map_err(|source| InspectError::IoError { path: path.to_path_buf(), source })?;
+    archive::extract(path, tmp_dir.path())?;
+
+    let mut inspection = inspect_dir(tmp_dir.path())?;
+    inspection.source = path.to_path_buf();
+    Ok(inspection)
+}
+
+/// Inspects an extracted package directory, such as one in a package cache.
+pub fn inspect_dir(dir: &Path) -> Result<PackageInspection, InspectError> {
+    let info = dir.join("info");
+    let index: IndexJson = pkgs::read_json(&info.join("index.json"))?;
+    let has_prefix = pkgs::load_has_prefix(&info)?;
+    let paths = pkgs::load_paths(&info, &has_prefix)?;
+
+    let files = paths
+        .paths
+        .into_iter()
+        .map(|entry| {
+            // Packages predating paths.json do not record sizes.
+            let size = entry.size_in_bytes.or_else(|| match entry.path_type {
+                PathType::SoftLink | PathType::Directory => None,
+                _ => fs::symlink_metadata(dir.join(&entry.path)).ok().map(|metadata| metadata.len()),
+            });
+            InspectedFile {
+                has_prefix_placeholder: entry.prefix_placeholder.is_some(),
+                path: entry.path,
+                path_type: entry.path_type,
+                size,
+                file_mode: entry.file_mode,
+                no_link: entry.no_link,
+            }
+        })
+        .collect();
+
+    let link_scripts = find_link_scripts(dir, &index.name)?;
+    Ok(PackageInspection {
+        source: dir.to_path_buf(),
+        index,
+        files,
+        about: pkgs::read_optional_json(&info.join("about.json"))?,
+        run_exports: pkgs::read_optional_json(&info.join("run_exports.json"))?,
+        link_scripts,
+    })
+}
+
+/// Finds the scripts conda would run for a package: `bin/.<name>-<action>.sh`
+/// on Unix and `Scripts/.<name>-<action>.bat` on Windows.
+fn find_link_scripts(dir: &Path, name: &str) -> Result<Vec<LinkScript>, InspectError> {
+    let mut scripts = Vec::new();
+    for action in LinkAction::ALL {
+        for (scripts_dir, extension) in [("bin", "sh"), ("Scripts", "bat")] {
+            let path = Path::new(scripts_dir).join(format!(".{}-{}.{}", name, action, extension));
+            let full_path = dir.join(&path);
+            if !full_path.is_file() {
+                continue;
+            }
+            let contents = fs::read(&full_path).map_err(|source| InspectError::IoError { path: full_path.clone(), source })?;
+            scripts.push(LinkScript {
+                action,
+                path,
+                contents: String::from_utf8_lossy(&contents).into_owned(),
+            });
+        }
+    }
+    Ok(scripts)
+}
@@ -0,0 +1,309 @@
+// conda.jlap.rs
+
+use std::collections::HashMap;
+
+use blake2::digest::consts::U32;
+use blake2::digest::{Digest, KeyInit, Mac};
+use reqwest::header::{HeaderMap, HeaderValue, RANGE};
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::network::{NetworkClient, NetworkError};
+
+type Blake2b256 = blake2::Blake2b<U32>;
+type Blake2bMac256 = blake2::Blake2bMac<U32>;
+
+/// Represents the position reached in a `repodata.jlap` file, so the next
+/// fetch only needs to request the bytes appended since then.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JlapState {
+    /// Byte offset of the metadata line of the last consumed JLAP file.
+    pub position: u64,
+
+    /// Hex-encoded running checksum of every line before `position`.
+    pub iv: String,
+}
+
+/// Represents a single JSON patch line in a JLAP file.
+#[derive(Debug, Clone, Deserialize)]
+pub struct JlapPatch {
+    pub from: String,
+    pub to: String,
+    pub patch: json_patch::Patch,
+}
+
+/// Represents the metadata line that precedes the trailing checksum.
+#[derive(Debug, Clone, Deserialize)]
+struct JlapMetadata {
+    latest: String,
+}
+
+/// Represents the verified contents of a (possibly partial) JLAP download.
+#[derive(Debug, Clone)]
+pub struct FetchedJlap {
+    pub patches: Vec<JlapPatch>,
+    pub latest: String,
+    pub state: JlapState,
+}
+
+/// Represents possible errors that can occur when processing JLAP files.
+#[derive(Error, Debug)]
+pub enum JlapError {
+    #[error(transparent)]
+    NetworkError(#[from] NetworkError),
+
+    #[error("Server returned {status} for {url}")]
+    StatusError { url: String, status: StatusCode },
+
+    #[error("JLAP file is truncated or empty")]
+    Truncated,
+
+    #[error("Invalid checksum line: {0}")]
+    InvalidChecksum(String),
+
+    #[error("JLAP checksum mismatch: expected {expected}, computed {computed}")]
+    ChecksumMismatch { expected: String, computed: String },
+
+    #[error("Failed to parse JLAP line: {0}")]
+    ParseError(#[from] serde_json::Error),
+
+    #[error("No patch chain leads from {from} to {latest}")]
+    NoPatchPath { from: String, latest: String },
+
+    #[error("Failed to apply patch: {0}")]
+    PatchError(#[from] json_patch::PatchError),
+}
+
+/// Returns the hex-encoded, unkeyed blake2b-256 hash of some bytes.
+pub fn blake2_hex(bytes: &[u8]) -> String {
+    data_encoding::HEXLOWER.encode(&Blake2b256::digest(bytes))
+}
+
+/// Computes the next running checksum from the previous one and a line.
+fn chain(key: &[u8; 32], line: &[u8]) -> [u8; 32] {
+    let mut mac = <Blake2bMac256 as KeyInit>::new_from_slice(key).expect("32-byte key is valid for blake2b");
+    mac.update(line);
+    mac.finalize().into_bytes().into()
+}
+
+fn decode_hash(hex: &str) -> Result<[u8; 32], JlapError> {
+    let bytes = data_encoding::HEXLOWER_PERMISSIVE
+        .decode(hex.trim().as_bytes())
+        .map_err(|_| JlapError::InvalidChecksum(hex.to_string()))?;
+    bytes
+        .try_into()
+        .map_err(|_| JlapError::InvalidChecksum(hex.to_string()))
+}
+
+/// Fetches a `repodata.jlap` file, requesting only the new bytes when a
+/// previous state is known. Returns `None` if the server has no JLAP file.
+pub fn fetch(client: &NetworkClient, url: &str, state: Option<&JlapState>) -> Result<Option<FetchedJlap>, JlapError> {
+    if let Some(state) = state {
+        match fetch_range(client, url, state) {
+            Ok(fetched) => return Ok(fetched),
+            Err(e) => log::debug!("Partial JLAP fetch for {} failed, refetching whole file: {}", url, e),
+        }
+    }
+
+    let response = client.get(url)?;
+
+    match response.status() {
+        StatusCode::NOT_FOUND => Ok(None),
+        status if status.is_success() => {
+            let bytes = NetworkClient::bytes(url, response)?;
+            parse(&bytes, 0, None).map(Some)
+        }
+        status => Err(JlapError::StatusError { url: url.to_string(), status }),
+    }
+}
+
+/// Requests the bytes of a JLAP file starting at the stored position.
+fn fetch_range(client: &NetworkClient, url: &str, state: &JlapState) -> Result<Option<FetchedJlap>, JlapError> {
+    let mut headers = HeaderMap::new();
+    let range = format!("bytes={}-", state.position);
+    headers.insert(RANGE, HeaderValue::from_str(&range).expect("range header is ASCII"));
+    let response = client.get_with_headers(url, headers)?;
+
+    let status = response.status();
+    if status == StatusCode::NOT_FOUND {
+        return Ok(None);
+    }
+    if !status.is_success() {
+        return Err(JlapError::StatusError { url: url.to_string(), status });
+    }
+
+    let bytes = NetworkClient::bytes(url, response)?;
+
+    // Servers that ignore the Range header send the whole file.
+    if status == StatusCode::PARTIAL_CONTENT {
+        parse(&bytes, state.position, Some(decode_hash(&state.iv)?)).map(Some)
+    } else {
+        parse(&bytes, 0, None).map(Some)
+    }
+}
+
+/// Parses and verifies JLAP bytes that start at `offset` in the remote file.
+///
+/// When `iv` is `None` the bytes must be the whole file, whose first line is
+/// the initial checksum.
+pub fn parse(bytes: &[u8], offset: u64, iv: Option<[u8; 32]>) -> Result<FetchedJlap, JlapError> {
+    let mut lines = Vec::new();
+    let mut start = 0;
+    for (i, byte) in bytes.iter().enumerate() {
+        if *byte == b'\n' {
+            lines.push((start, &bytes[start..i]));
+            start = i + 1;
+        }
+    }
+    if start < bytes.len() {
+        lines.push((start, &bytes[start..]));
+    }
+
+    let mut lines = lines.into_iter();
+    let mut running = match iv {
+        Some(iv) => iv,
+        None => {
+            let (_, first) = lines.next().ok_or(JlapError::Truncated)?;
+            decode_hash(&String::from_utf8_lossy(first))?
+        }
+    };
+
+    let lines: Vec<(usize, &[u8])> = lines.collect();
+    if lines.len() < 2 {
+        return Err(JlapError::Truncated);
+    }
+    let (checksum_line, body) = lines.split_last().expect("at least two lines");
+    let (metadata_line, patch_lines) = body.split_last().expect("at least one line");
+
+    let mut patches = Vec::with_capacity(patch_lines.len());
+    for (_, line) in patch_lines {
+        running = chain(&running, line);
+        patches.push(serde_json::from_slice::<JlapPatch>(line)?);
+    }
+
+    let state = JlapState {
+        position: offset + metadata_line.0 as u64,
+        iv: data_encoding::HEXLOWER.encode(&running),
+    };
+
+    running = chain(&running, metadata_line.1);
+    let expected = String::from_utf8_lossy(checksum_line.1).trim().to_lowercase();
+    let computed = data_encoding::HEXLOWER.encode(&running);
+    if expected != computed {
+        return Err(JlapError::ChecksumMismatch { expected, computed });
+    }
+
+    let metadata: JlapMetadata = serde_json::from_slice(metadata_line.1)?;
+
+    Ok(FetchedJlap { patches, latest: metadata.latest, state })
+}
+
+impl FetchedJlap {
+    /// Applies the patches leading from `current_hash` to the latest hash,
+    /// returning the hash of the patched document.
+    pub fn apply(&self, document: &mut serde_json::Value, current_hash: &str) -> Result<String, JlapError> {
+        let by_origin: HashMap<&str, &JlapPatch> =
+            self.patches.iter().map(|p| (p.from.as_str(), p)).collect();
+
+        let no_path = || JlapError::NoPatchPath {
+            from: current_hash.to_string(),
+            latest: self.latest.clone(),
+        };
+
+        // A valid chain visits each patch at most once.
+        let mut hash = current_hash.to_string();
+        for _ in 0..=self.patches.len() {
+            if hash == self.latest {
+                return Ok(hash);
+            }
+            let patch = by_origin.get(hash.as_str()).ok_or_else(no_path)?;
+            json_patch::patch(document, &patch.patch)?;
+            hash = patch.to.clone();
+        }
+
+        Err(no_path())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::network::test_server::TestServer;
+
+    /// Builds a JLAP file: the initial checksum, the patch lines, the
+    /// metadata line and the running checksum of all of them.
+    pub(crate) fn jlap_file(iv: [u8; 32], patches: &[serde_json::Value], latest: &str) -> Vec<u8> {
+        let mut lines = vec![data_encoding::HEXLOWER.encode(&iv)];
+        lines.extend(patches.iter().map(|patch| patch.to_string()));
+        lines.push(json!({ "latest": latest }).to_string());
+
+        let running = lines[1..].iter().fold(iv, |running, line| chain(&running, line.as_bytes()));
+        lines.push(data_encoding::HEXLOWER.encode(&running));
+        lines.join("\n").into_bytes()
+    }
+
+    pub(crate) fn patch(from: &str, to: &str, package: &str) -> serde_json::Value {
+        json!({
+            "from": from,
+            "to": to,
+            "patch": [{ "op": "add", "path": format!("/packages/{}", package), "value": { "name": package, "version": "1.0", "build": "0" } }],
+        })
+    }
+
+    #[test]
+    fn parse_verifies_the_checksum_chain() {
+        let file = jlap_file([7; 32], &[patch("a", "b", "x"), patch("b", "c", "y")], "c");
+        let fetched = parse(&file, 0, None).unwrap();
+        assert_eq!(fetched.latest, "c");
+        assert_eq!(fetched.patches.len(), 2);
+
+        // Changing any patch line breaks every checksum after it.
+        let tampered = String::from_utf8(file).unwrap().replacen("\"x\"", "\"z\"", 1);
+        assert!(matches!(parse(tampered.as_bytes(), 0, None), Err(JlapError::ChecksumMismatch { .. })));
+    }
+
+    #[test]
+    fn fetch_resumes_from_the_cached_position() {
+        let server = TestServer::start();
+        let client = server.client();
+        let url = server.url("/repodata.jlap");
+
+        server.serve("/repodata.jlap", jlap_file([1; 32], &[patch("a", "b", "x")], "b"), None);
+        let first = fetch(&client, &url, None).unwrap().unwrap();
+
+        // The server rewrites the metadata and checksum after appending a patch.
+        let appended = jlap_file([1; 32], &[patch("a", "b", "x"), patch("b", "c", "y")], "c");
+        server.serve("/repodata.jlap", appended, None);
+        server.take_requests();
+
+        let second = fetch(&client, &url, Some(&first.state)).unwrap().unwrap();
+        let requests = server.take_requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].headers.get("range"), Some(&format!("bytes={}-", first.state.position)));
+        assert_eq!(second.latest, "c");
+        assert_eq!(second.patches.iter().map(|p| p.to.as_str()).collect::<Vec<_>>(), ["c"]);
+        assert!(second.state.position > first.state.position);
+    }
+
+    #[test]
+    fn fetch_refetches_the_whole_file_on_checksum_mismatch() {
+        let server = TestServer::start();
+        let client = server.client();
+        let url = server.url("/repodata.jlap");
+        server.serve("/repodata.jlap", jlap_file([1; 32], &[patch("a", "b", "x"), patch("b", "c", "y")], "c"), None);
+
+        // A running checksum that does not match the server's file.
+        let state = JlapState { position: 65, iv: data_encoding::HEXLOWER.encode(&[9; 32]) };
+        let fetched = fetch(&client, &url, Some(&state)).unwrap().unwrap();
+
+        let requests = server.take_requests();
+        assert_eq!(requests.len(), 2);
+        assert!(requests[0].headers.contains_key("range"));
+        assert!(!requests[1].headers.contains_key("range"));
+        assert_eq!(fetched.patches.len(), 2);
+        assert_eq!(fetched.latest, "c");
+    }
+}
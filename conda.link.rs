@@ -0,0 +1,630 @@
+// conda.link.rs
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use thiserror::Error;
+
+use crate::channel::Platform;
+use crate::digest;
+use crate::noarch::{self, EntryPoint, NoarchError, PythonInfo};
+use crate::package::{FileMode, PathType, PathsEntry};
+use crate::pkgs::ExtractedPackage;
+use crate::prefix::{self, Link, LinkType, PrefixError, PrefixPaths, PrefixPathsEntry, PrefixRecord};
+
+/// Represents possible errors that can occur when linking packages into a prefix.
+#[derive(Error, Debug)]
+pub enum LinkError {
+    #[error("Failed to link {path}: {source}")]
+    IoError { path: PathBuf, source: io::Error },
+
+    #[error("{path} already exists{}", owner.as_ref().map(|o| format!(" and belongs to {}", o)).unwrap_or_default())]
+    Clobber { path: PathBuf, owner: Option<String> },
+
+    #[error("Prefix {prefix} is longer than the placeholder in binary file {path}")]
+    PrefixTooLong { path: PathBuf, prefix: String },
+
+    #[error(transparent)]
+    PrefixError(#[from] PrefixError),
+
+    #[error(transparent)]
+    NoarchError(#[from] NoarchError),
+}
+
+/// Represents how packages are linked into a prefix.
+#[derive(Debug, Clone, Default)]
+pub struct LinkOptions {
+    /// Fall back to symlinks instead of copies when hardlinks are impossible.
+    pub allow_softlinks: bool,
+
+    /// Copy every file, e.g. when the package cache may be deleted.
+    pub always_copy: bool,
+
+    /// Replace files owned by other packages, or by no package, instead of failing.
+    pub allow_clobber: bool,
+}
+
+/// Links extracted packages into a prefix, tracking which package owns which file.
+#[derive(Debug)]
+pub struct Linker {
+    prefix: PathBuf,
+    options: LinkOptions,
+
+    /// Installed file paths and the `name-version-build` of their package.
+    owners: HashMap<PathBuf, (String, String)>,
+
+    /// The installed Python, which `noarch: python` packages are linked for.
+    python: Option<PythonInfo>,
+}
+
+impl Linker {
+    /// Creates a linker for a prefix, reading the records already installed in it.
+    pub fn new(prefix: impl Into<PathBuf>, options: LinkOptions) -> Result<Self, LinkError> {
+        let prefix = prefix.into();
+        let records = if prefix::conda_meta_dir(&prefix).is_dir() {
+            prefix::load_prefix_records(&prefix)?
+        } else {
+            Vec::new()
+        };
+
+        let mut owners = HashMap::new();
+        let mut python = None;
+        for record in &records {
+            for file in &record.files {
+                owners.insert(PathBuf::from(file), (record.record.name.clone(), record.dist_name()));
+            }
+            if record.record.name == "python" {
+                python = Some(PythonInfo::from_version(&record.record.version, Platform::current().is_windows())?);
+            }
+        }
+        Ok(Linker { prefix, options, owners, python })
+    }
+
+    /// Returns the prefix being linked into.
+    pub fn prefix(&self) -> &Path {
+        &self.prefix
+    }
+
+    /// Checks that a package can be linked: that none of its files would
+    /// clobber another package's file, and that the prefix fits the
+    /// placeholder of each of its binary files.
+    pub fn check(&self, package: &ExtractedPackage) -> Result<(), LinkError> {
+        self.plan(package).map(|_| ())
+    }
+
+    /// Links a package into the prefix and writes its `conda-meta` record.
+    /// The checks of [`Linker::check`] run before anything is written, and
+    /// if placing the files fails, the files placed so far are removed and
+    /// the files they replaced are put back.
+    /// `noarch: python` packages are remapped into the installed Python's
+    /// layout, get their entry point scripts and have their sources compiled.
+    pub fn link(&mut self, package: &ExtractedPackage, requested_spec: Option<&str>) -> Result<PrefixRecord, LinkError> {
+        let plan = self.plan(package)?;
+        let mut placement = Placement::default();
+        let record = match self.place(package, &plan, requested_spec, &mut placement) {
+            Ok(record) => {
+                placement.commit();
+                record
+            }
+            Err(e) => {
+                placement.roll_back(&self.prefix);
+                return Err(e);
+            }
+        };
+
+        let name = &record.record.name;
+        for file in &record.files {
+            self.owners.insert(PathBuf::from(file), (name.clone(), record.dist_name()));
+        }
+        if name == "python" {
+            self.python = Some(PythonInfo::from_version(&record.record.version, Platform::current().is_windows())?);
+        }
+        Ok(record)
+    }
+
+    /// Resolves how a package is linked and runs the checks that must pass
+    /// before any of its files are placed.
+    fn plan(&self, package: &ExtractedPackage) -> Result<LinkPlan, LinkError> {
+        let name = &package.repodata_record.record.name;
+        let python = if package.is_noarch_python() {
+            Some(self.python.clone().ok_or_else(|| NoarchError::MissingPython(name.clone()))?)
+        } else {
+            None
+        };
+        let target_path = |path: &Path| python.as_ref().map_or_else(|| path.to_path_buf(), |python| python.target_path(path));
+
+        let entry_points: Vec<EntryPoint> = match (&python, &package.link_json) {
+            (Some(_), Some(link_json)) => link_json
+                .noarch
+                .entry_points
+                .iter()
+                .map(|entry_point| entry_point.parse())
+                .collect::<Result<_, _>>()?,
+            _ => Vec::new(),
+        };
+
+        let mut targets: Vec<PathBuf> = package
+            .paths
+            .paths
+            .iter()
+            .filter(|e| e.path_type != PathType::Directory)
+            .map(|e| target_path(&e.path))
+            .collect();
+        if let Some(python) = &python {
+            targets.extend(entry_points.iter().map(|ep| python.entry_point_path(&ep.command)));
+            targets.extend(entry_points.iter().filter_map(|ep| python.entry_point_launcher_path(&ep.command)));
+        }
+
+        for relative in &targets {
+            let target = self.prefix.join(relative);
+            if fs::symlink_metadata(&target).is_err() {
+                continue;
+            }
+            // Files of another build of the same package are simply replaced.
+            let owner = self.owners.get(relative);
+            if owner.map_or(false, |(owner_name, _)| owner_name == name) {
+                continue;
+            }
+            if !self.options.allow_clobber {
+                return Err(LinkError::Clobber {
+                    path: relative.clone(),
+                    owner: owner.map(|(_, dist)| dist.clone()),
+                });
+            }
+            log::warn!("Clobbering {} while linking {}", target.display(), name);
+        }
+
+        let prefix = prefix_string(&self.prefix);
+        for entry in &package.paths.paths {
+            if let (Some(placeholder), Some(FileMode::Binary)) = (&entry.prefix_placeholder, entry.file_mode) {
+                if prefix.len() > placeholder.len() {
+                    return Err(LinkError::PrefixTooLong { path: entry.path.clone(), prefix });
+                }
+            }
+        }
+
+        Ok(LinkPlan { python, entry_points })
+    }
+
+    /// Places a package's files and writes its record, noting every file
+    /// placed or replaced in `placement`.
+    fn place(&self, package: &ExtractedPackage, plan: &LinkPlan, requested_spec: Option<&str>, placement: &mut Placement) -> Result<PrefixRecord, LinkError> {
+        let python = &plan.python;
+        let target_path = |path: &Path| python.as_ref().map_or_else(|| path.to_path_buf(), |python| python.target_path(path));
+
+        let mut paths = Vec::new();
+        let mut link_types = Vec::new();
+        for entry in &package.paths.paths {
+            let relative = target_path(&entry.path);
+            let (link_type, sha256_in_prefix) = self.link_entry(&package.dir, entry, &relative, placement)?;
+            link_types.extend(link_type);
+            paths.push(PrefixPathsEntry {
+                path: relative,
+                path_type: entry.path_type,
+                sha256: entry.sha256.clone(),
+                sha256_in_prefix,
+                size_in_bytes: entry.size_in_bytes,
+                file_mode: entry.file_mode,
+                prefix_placeholder: entry.prefix_placeholder.clone(),
+                no_link: entry.no_link,
+            });
+        }
+
+        if let Some(python) = python {
+            for entry_point in &plan.entry_points {
+                let script = self.prefix.join(python.entry_point_path(&entry_point.command));
+                placement.set_aside(&script)?;
+                let script = noarch::write_entry_point(&self.prefix, python, entry_point)?;
+                let path_type = if Platform::current().is_windows() {
+                    PathType::WindowsPythonEntryPointScript
+                } else {
+                    PathType::UnixPythonEntryPoint
+                };
+                paths.push(generated_entry(script, path_type));
+
+                if let Some(launcher) = python.entry_point_launcher_path(&entry_point.command) {
+                    placement.set_aside(&self.prefix.join(launcher))?;
+                    if let Some(launcher) = noarch::write_entry_point_launcher(&self.prefix, python, entry_point)? {
+                        paths.push(generated_entry(launcher, PathType::WindowsPythonEntryPointExe));
+                    }
+                }
+            }
+
+            let site_packages = python.site_packages();
+            let sources: Vec<PathBuf> = paths
+                .iter()
+                .filter(|e| e.path.starts_with(&site_packages) && e.path.extension().map_or(false, |ext| ext == "py"))
+                .map(|e| e.path.clone())
+                .collect();
+            // Compiled files are written by Python itself, so note them up front.
+            placement.created.extend(sources.iter().map(|source| self.prefix.join(python.pyc_path(source))));
+            for pyc in noarch::compile_pyc(&self.prefix, python, &sources)? {
+                paths.push(generated_entry(pyc, PathType::PycFile));
+            }
+        }
+
+        // The package-level link type is the weakest one any file needed.
+        let link_type = if link_types.contains(&LinkType::Copy) {
+            LinkType::Copy
+        } else if link_types.contains(&LinkType::SoftLink) {
+            LinkType::SoftLink
+        } else {
+            LinkType::HardLink
+        };
+
+        let repodata_record = &package.repodata_record;
+        let record = PrefixRecord {
+            record: repodata_record.record.clone(),
+            channel: Some(repodata_record.channel.clone()),
+            file_name: Some(repodata_record.file_name.clone()),
+            url: Some(repodata_record.url.clone()),
+            files: paths
+                .iter()
+                .filter(|e| e.path_type != PathType::Directory)
+                .map(|e| path_string(&e.path))
+                .collect(),
+            requested_spec: requested_spec.map(String::from),
+            paths_data: Some(PrefixPaths { paths_version: 1, paths }),
+            link: Some(Link { source: package.dir.clone(), link_type }),
+            extracted_package_dir: Some(package.dir.clone()),
+            package_tarball_full_path: package.dir.parent().map(|dir| dir.join(&repodata_record.file_name)),
+        };
+        prefix::write_prefix_record(&self.prefix, &record)?;
+        Ok(record)
+    }
+
+    /// Places one file, returning how it was placed and, if its contents
+    /// changed, their new hash.
+    fn link_entry(&self, package_dir: &Path, entry: &PathsEntry, relative: &Path, placement: &mut Placement) -> Result<(Option<LinkType>, Option<String>), LinkError> {
+        let source = package_dir.join(&entry.path);
+        let target = self.prefix.join(relative);
+        let io_error = |path: &Path| {
+            let path = path.to_path_buf();
+            move |source| LinkError::IoError { path, source }
+        };
+
+        if entry.path_type == PathType::Directory {
+            if !target.is_dir() {
+                fs::create_dir_all(&target).map_err(io_error(&target))?;
+                placement.dirs.push(target);
+            }
+            return Ok((None, None));
+        }
+
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent).map_err(io_error(parent))?;
+        }
+        placement.set_aside(&target)?;
+
+        if entry.path_type == PathType::SoftLink {
+            copy_symlink(&source, &target).map_err(io_error(&target))?;
+            return Ok((Some(LinkType::SoftLink), None));
+        }
+
+        if let Some(placeholder) = &entry.prefix_placeholder {
+            let mode = entry.file_mode.unwrap_or(FileMode::Text);
+            let contents = fs::read(&source).map_err(io_error(&source))?;
+            let replaced = replace_prefix(&contents, placeholder, &prefix_string(&self.prefix), mode)
+                .ok_or_else(|| LinkError::PrefixTooLong {
+                    path: entry.path.clone(),
+                    prefix: self.prefix.display().to_string(),
+                })?;
+            fs::write(&target, &replaced).map_err(io_error(&target))?;
+            let permissions = fs::metadata(&source).map_err(io_error(&source))?.permissions();
+            fs::set_permissions(&target, permissions).map_err(io_error(&target))?;
+
+            let sha256 = (replaced != contents).then(|| digest::sha256_hex(&replaced));
+            return Ok((Some(LinkType::Copy), sha256));
+        }
+
+        if entry.no_link || self.options.always_copy {
+            fs::copy(&source, &target).map_err(io_error(&target))?;
+            return Ok((Some(LinkType::Copy), None));
+        }
+
+        match fs::hard_link(&source, &target) {
+            Ok(()) => Ok((Some(LinkType::HardLink), None)),
+            Err(e) => {
+                log::debug!("Cannot hardlink {}: {}", source.display(), e);
+                if self.options.allow_softlinks && symlink(&source, &target).is_ok() {
+                    return Ok((Some(LinkType::SoftLink), None));
+                }
+                fs::copy(&source, &target).map_err(io_error(&target))?;
+                Ok((Some(LinkType::Copy), None))
+            }
+        }
+    }
+}
+
+/// The parts of linking a package that are resolved before placing its files.
+struct LinkPlan {
+    python: Option<PythonInfo>,
+    entry_points: Vec<EntryPoint>,
+}
+
+/// Tracks the files placed while linking one package, so that a failed link
+/// can be undone.
+#[derive(Default)]
+struct Placement {
+    /// Files the package placed, or is about to place.
+    created: Vec<PathBuf>,
+
+    /// Directory entries the package created.
+    dirs: Vec<PathBuf>,
+
+    /// Replaced files, renamed aside, and where they were moved to.
+    replaced: Vec<(PathBuf, PathBuf)>,
+}
+
+impl Placement {
+    /// Prepares placing a file at `target`: an existing file is renamed
+    /// aside so it can be put back, and the target is noted as placed.
+    fn set_aside(&mut self, target: &Path) -> Result<(), LinkError> {
+        if fs::symlink_metadata(target).is_ok() {
+            let mut backup = target.as_os_str().to_owned();
+            backup.push(".conda_replaced");
+            let backup = PathBuf::from(backup);
+            fs::rename(target, &backup).map_err(|source| LinkError::IoError { path: target.to_path_buf(), source })?;
+            self.replaced.push((target.to_path_buf(), backup));
+        }
+        self.created.push(target.to_path_buf());
+        Ok(())
+    }
+
+    /// Drops the replaced files once the package is linked.
+    fn commit(self) {
+        for (_, backup) in self.replaced {
+            if let Err(e) = fs::remove_file(&backup) {
+                log::warn!("Failed to remove replaced file {}: {}", backup.display(), e);
+            }
+        }
+    }
+
+    /// Removes the placed files and the directories they leave empty, and
+    /// puts the replaced files back.
+    fn roll_back(self, prefix: &Path) {
+        for path in &self.created {
+            match fs::remove_file(path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                    log::warn!("Failed to remove {} after a failed link: {}", path.display(), e)
+                }
+                _ => {}
+            }
+        }
+        for (target, backup) in self.replaced.iter().rev() {
+            if let Err(e) = fs::rename(backup, target) {
+                log::warn!("Failed to restore {} after a failed link: {}", target.display(), e);
+            }
+        }
+        let dirs = self.created.iter().chain(&self.dirs).flat_map(|path| path.ancestors());
+        if let Err(e) = remove_empty_dirs(prefix, dirs) {
+            log::warn!("Failed to clean up after a failed link: {}", e);
+        }
+    }
+}
+
+/// Returns the record of a file the linker generated rather than took from the package.
+fn generated_entry(path: PathBuf, path_type: PathType) -> PrefixPathsEntry {
+    PrefixPathsEntry {
+        path,
+        path_type,
+        sha256: None,
+        sha256_in_prefix: None,
+        size_in_bytes: None,
+        file_mode: None,
+        prefix_placeholder: None,
+        no_link: false,
+    }
+}
+
+/// Removes an installed package's files, the directories they leave empty,
+/// and its `conda-meta` record.
+pub fn unlink(prefix: &Path, record: &PrefixRecord) -> Result<(), LinkError> {
+    let files: Vec<PathBuf> = match &record.paths_data {
+        Some(paths_data) => paths_data
+            .paths
+            .iter()
+            .filter(|e| e.path_type != PathType::Directory)
+            .map(|e| e.path.clone())
+            .collect(),
+        None => record.files.iter().map(PathBuf::from).collect(),
+    };
+
+    let mut paths = Vec::new();
+    for file in &files {
+        let path = prefix.join(file);
+        match fs::remove_file(&path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(LinkError::IoError { path, source: e }),
+            _ => {}
+        }
+        paths.push(path);
+    }
+    remove_empty_dirs(prefix, paths.iter().flat_map(|path| path.ancestors().skip(1)))?;
+
+    prefix::remove_prefix_record(prefix, record)?;
+    Ok(())
+}
+
+/// Removes the given directories inside the prefix that are empty.
+fn remove_empty_dirs<'a>(prefix: &Path, dirs: impl Iterator<Item = &'a Path>) -> Result<(), LinkError> {
+    let mut dirs: Vec<&Path> = dirs.filter(|dir| dir.starts_with(prefix) && *dir != prefix).collect();
+
+    // Deepest directories first, so emptied parents can go too.
+    dirs.sort_by(|a, b| b.components().count().cmp(&a.components().count()).then(a.cmp(b)));
+    dirs.dedup();
+    for dir in dirs {
+        if fs::read_dir(dir).map_or(false, |mut entries| entries.next().is_none()) {
+            fs::remove_dir(dir).map_err(|source| LinkError::IoError { path: dir.to_path_buf(), source })?;
+        }
+    }
+    Ok(())
+}
+
+/// Replaces a prefix placeholder in file contents. Text files get a plain
+/// substitution. In binary files each placeholder sits in a NUL-terminated
+/// C string, so the string is rewritten in place and padded with NULs to
+/// keep every offset intact; `None` means the prefix does not fit.
+pub fn replace_prefix(contents: &[u8], placeholder: &str, prefix: &str, mode: FileMode) -> Option<Vec<u8>> {
+    let placeholder = placeholder.as_bytes();
+    let prefix = prefix.as_bytes();
+
+    match mode {
+        FileMode::Text => {
+            let mut out = Vec::with_capacity(contents.len());
+            let mut rest = contents;
+            while let Some(index) = find(rest, placeholder) {
+                out.extend_from_slice(&rest[..index]);
+                out.extend_from_slice(prefix);
+                rest = &rest[index + placeholder.len()..];
+            }
+            out.extend_from_slice(rest);
+            Some(out)
+        }
+        FileMode::Binary => {
+            if prefix.len() > placeholder.len() {
+                return find(contents, placeholder).map_or(Some(contents.to_vec()), |_| None);
+            }
+
+            let mut out = contents.to_vec();
+            let mut start = 0;
+            while let Some(offset) = find(&out[start..], placeholder) {
+                let begin = start + offset;
+                let end = out[begin..].iter().position(|b| *b == 0).map_or(out.len(), |i| begin + i);
+
+                // Replace every occurrence within this C string, then pad.
+                let original = out[begin..end].to_vec();
+                let mut replaced = Vec::with_capacity(original.len());
+                let mut rest = &original[..];
+                while let Some(index) = find(rest, placeholder) {
+                    replaced.extend_from_slice(&rest[..index]);
+                    replaced.extend_from_slice(prefix);
+                    rest = &rest[index + placeholder.len()..];
+                }
+                replaced.extend_from_slice(rest);
+                replaced.resize(original.len(), 0);
+
+                out[begin..end].copy_from_slice(&replaced);
+                start = end;
+            }
+            Some(out)
+        }
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    if needle.is_empty() || needle.len() > haystack.len() {
+        return None;
+    }
+    haystack.windows(needle.len()).position(|window| window == needle)
+}
+
+/// Returns the prefix as it is written into files, with forward slashes on Windows.
+fn prefix_string(prefix: &Path) -> String {
+    let prefix = prefix.display().to_string();
+    if cfg!(windows) {
+        prefix.replace('\\', "/")
+    } else {
+        prefix
+    }
+}
+
+/// Returns a relative path as conda records it, with forward slashes.
+fn path_string(path: &Path) -> String {
+    path.to_string_lossy().replace('\\', "/")
+}
+
+/// Recreates a symlink from the package at the target, pointing to the same place.
+fn copy_symlink(source: &Path, target: &Path) -> io::Result<()> {
+    let link = fs::read_link(source)?;
+    symlink(&link, target).or_else(|_| fs::copy(source, target).map(|_| ()))
+}
+
+#[cfg(unix)]
+fn symlink(original: &Path, link: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(original, link)
+}
+
+#[cfg(windows)]
+fn symlink(original: &Path, link: &Path) -> io::Result<()> {
+    std::os::windows::fs::symlink_file(original, link)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::package::{IndexJson, PathsJson};
+    use crate::repodata::RepodataRecord;
+
+    const PLACEHOLDER: &str = "/opt/anaconda1anaconda2anaconda3";
+
+    #[test]
+    fn replace_prefix_rewrites_a_c_string_in_place() {
+        let placeholder = PLACEHOLDER.as_bytes();
+        let contents = [b"\x7fELF\0".as_ref(), placeholder, b"/lib:", placeholder, b"/bin\0tail"].concat();
+
+        let replaced = replace_prefix(&contents, PLACEHOLDER, "/p", FileMode::Binary).unwrap();
+
+        let padding = vec![0u8; 2 * (placeholder.len() - "/p".len())];
+        assert_eq!(replaced, [b"\x7fELF\0".as_ref(), b"/p/lib:/p/bin", padding.as_slice(), b"\0tail"].concat());
+        assert_eq!(replaced.len(), contents.len());
+    }
+
+    #[test]
+    fn replace_prefix_refuses_a_prefix_longer_than_the_placeholder() {
+        let contents = [PLACEHOLDER.as_bytes(), b"/bin\0"].concat();
+        let prefix = format!("{}/too/long", PLACEHOLDER);
+
+        assert_eq!(replace_prefix(&contents, PLACEHOLDER, &prefix, FileMode::Binary), None);
+        // Text files have no fixed length to keep
+        assert!(replace_prefix(&contents, PLACEHOLDER, &prefix, FileMode::Text).is_some());
+    }
+
+    #[test]
+    fn failed_link_restores_replaced_files_and_removes_created_dirs() {
+        let dir = tempfile::tempdir().unwrap();
+        let package_dir = dir.path().join("pkgs").join("demo-1.0-0");
+        fs::create_dir_all(package_dir.join("bin")).unwrap();
+        fs::write(package_dir.join("bin").join("tool"), "new tool").unwrap();
+        let prefix = dir.path().join("env");
+        fs::create_dir_all(prefix.join("bin")).unwrap();
+        fs::write(prefix.join("bin").join("tool"), "old tool").unwrap();
+
+        let entry = |path: &str, path_type: PathType| PathsEntry {
+            path: PathBuf::from(path),
+            path_type,
+            sha256: None,
+            size_in_bytes: None,
+            file_mode: None,
+            prefix_placeholder: None,
+            no_link: false,
+        };
+        let package = ExtractedPackage {
+            dir: package_dir,
+            repodata_record: RepodataRecord::default(),
+            index: IndexJson::default(),
+            paths: PathsJson {
+                paths: vec![
+                    entry("share/demo", PathType::Directory),
+                    entry("bin/tool", PathType::HardLink),
+                    // Missing from the package, so placing it fails
+                    entry("lib/missing.so", PathType::HardLink),
+                ],
+                paths_version: 1,
+            },
+            about: None,
+            run_exports: None,
+            has_prefix: Vec::new(),
+            link_json: None,
+        };
+        let options = LinkOptions { allow_clobber: true, ..LinkOptions::default() };
+        let mut linker = Linker::new(prefix.clone(), options).unwrap();
+
+        assert!(matches!(linker.link(&package, None), Err(LinkError::IoError { .. })));
+        assert_eq!(fs::read_to_string(prefix.join("bin").join("tool")).unwrap(), "old tool");
+        assert!(!prefix.join("bin").join("tool.conda_replaced").exists());
+        assert!(!prefix.join("share").exists());
+        assert!(!prefix.join("lib").exists());
+    }
+}
@@ -0,0 +1,439 @@
+// conda.lock.rs
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+/// The lock file guarding a prefix or a package cache directory.
+pub const LOCK_FILE: &str = ".conda_lock";
+
+/// The default time to wait for a lock held by another process.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// How long a lock file may lack a readable owner before it is treated as
+/// left by a process that died while creating it.
+const OWNERLESS_GRACE: Duration = Duration::from_secs(30);
+
+/// The locks held by this process, keyed by their path in a canonical
+/// directory, with the number of guards on each. Locks guard against other
+/// processes, so the threads of this process share them.
+static HELD: Lazy<Mutex<HashMap<PathBuf, usize>>> = Lazy::new(Default::default);
+
+/// Represents possible errors that can occur when taking a lock.
+#[derive(Error, Debug)]
+pub enum LockError {
+    #[error("Failed to access lock {path}: {source}")]
+    IoError { path: PathBuf, source: io::Error },
+
+    #[error("Timed out after {}s waiting for {path}, held by {owner}", waited.as_secs())]
+    Timeout { path: PathBuf, owner: String, waited: Duration },
+}
+
+/// Represents the process holding a lock, as recorded in the lock file.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LockOwner {
+    pub pid: u32,
+    pub host: String,
+
+    /// When the lock was taken, in seconds since the Unix epoch.
+    pub acquired: u64,
+}
+
+impl LockOwner {
+    fn current() -> Self {
+        LockOwner {
+            pid: process::id(),
+            host: hostname(),
+            acquired: SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |time| time.as_secs()),
+        }
+    }
+
+    /// Returns whether the owner is known to be gone: a process of this host
+    /// that no longer runs. Owners on other hosts are never considered stale.
+    ///
+    /// An owner naming this process is reported stale too, as left by an
+    /// earlier process with the same PID; callers must first rule out that
+    /// this process holds the lock, as [`Locker::lock`] does through `HELD`.
+    pub fn is_stale(&self) -> bool {
+        if self.host != hostname() {
+            return false;
+        }
+        self.pid == process::id() || !is_running(self.pid)
+    }
+}
+
+impl fmt::Display for LockOwner {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "process {} on {}", self.pid, self.host)
+    }
+}
+
+/// Represents progress in taking a lock.
+#[derive(Debug, Clone)]
+pub enum LockEvent {
+    /// Another process holds the lock, so acquisition waits for it.
+    Waiting { path: PathBuf, owner: Option<LockOwner> },
+    /// A lock left by a process that no longer runs was removed.
+    BrokeStale { path: PathBuf, owner: Option<LockOwner> },
+    /// The lock was taken.
+    Acquired { path: PathBuf, waited: Duration },
+}
+
+impl fmt::Display for LockEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let owner = |owner: &Option<LockOwner>| owner.as_ref().map_or("an unknown process".to_string(), LockOwner::to_string);
+        match self {
+            LockEvent::Waiting { path, owner: held_by } => write!(f, "Waiting for lock {} held by {}", path.display(), owner(held_by)),
+            LockEvent::BrokeStale { path, owner: held_by } => write!(f, "Removed stale lock {} left by {}", path.display(), owner(held_by)),
+            LockEvent::Acquired { path, waited } => write!(f, "Acquired lock {} after {:.1}s", path.display(), waited.as_secs_f64()),
+        }
+    }
+}
+
+/// Reports lock events through the log, waits and stale locks being worth a
+/// user's attention.
+pub fn log_event(event: &LockEvent) {
+    match event {
+        LockEvent::Waiting { .. } => log::info!("{}", event),
+        LockEvent::BrokeStale { .. } => log::warn!("{}", event),
+        LockEvent::Acquired { .. } => log::debug!("{}", event),
+    }
+}
+
+/// A callback receiving lock events.
+pub type LockProgress = Arc<dyn Fn(&LockEvent) + Send + Sync>;
+
+/// Takes advisory locks on prefixes, package caches and repodata cache
+/// entries, so concurrent processes never modify them at the same time.
+#[derive(Clone)]
+pub struct Locker {
+    timeout: Duration,
+    poll_interval: Duration,
+    progress: Option<LockProgress>,
+}
+
+impl fmt::Debug for Locker {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Locker")
+            .field("timeout", &self.timeout)
+            .field("poll_interval", &self.poll_interval)
+            .finish()
+    }
+}
+
+impl Default for Locker {
+    fn default() -> Self {
+        Locker::new(DEFAULT_TIMEOUT)
+    }
+}
+
+impl Locker {
+    /// Creates a locker that waits up to `timeout` for locks held elsewhere.
+    /// A zero timeout fails at once.
+    pub fn new(timeout: Duration) -> Self {
+        Locker {
+            timeout,
+            poll_interval: Duration::from_millis(250),
+            progress: None,
+        }
+    }
+
+    /// Sets how often a held lock is checked again.
+    pub fn with_poll_interval(mut self, interval: Duration) -> Self {
+        self.poll_interval = interval;
+        self
+    }
+
+    /// Sets the callback receiving lock events.
+    pub fn with_progress(mut self, progress: impl Fn(&LockEvent) + Send + Sync + 'static) -> Self {
+        self.progress = Some(Arc::new(progress));
+        self
+    }
+
+    /// Locks an environment prefix.
+    pub fn lock_prefix(&self, prefix: &Path) -> Result<FileLock, LockError> {
+        self.lock(&prefix.join(LOCK_FILE))
+    }
+
+    /// Locks a package cache directory.
+    pub fn lock_pkgs_dir(&self, pkgs_dir: &Path) -> Result<FileLock, LockError> {
+        self.lock(&pkgs_dir.join(LOCK_FILE))
+    }
+
+    /// Takes the lock of the given lock file, creating its directory if
+    /// needed. Taking a lock this process already holds succeeds at once,
+    /// however the path to it is spelled.
+    pub fn lock(&self, path: &Path) -> Result<FileLock, LockError> {
+        let io_error = |path: &Path| {
+            let path = path.to_path_buf();
+            move |source| LockError::IoError { path, source }
+        };
+        let file_name = path
+            .file_name()
+            .ok_or_else(|| io_error(path)(io::Error::new(io::ErrorKind::InvalidInput, "not a lock file path")))?;
+        let parent = path.parent().filter(|parent| !parent.as_os_str().is_empty()).unwrap_or_else(|| Path::new("."));
+        fs::create_dir_all(parent).map_err(io_error(parent))?;
+        let path = &fs::canonicalize(parent).map_err(io_error(parent))?.join(file_name);
+
+        let start = Instant::now();
+        let mut waiting = false;
+        loop {
+            let owner = {
+                let mut held = HELD.lock().unwrap_or_else(|e| e.into_inner());
+                if let Some(count) = held.get_mut(path) {
+                    *count += 1;
+                    return Ok(FileLock { path: path.to_path_buf() });
+                }
+                match create_lock_file(path) {
+                    Ok(()) => {
+                        held.insert(path.to_path_buf(), 1);
+                        None
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::AlreadyExists => match read_owner(path) {
+                        Ok(owner) => Some(owner),
+                        // Released meanwhile
+                        Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                        Err(source) => return Err(LockError::IoError { path: path.to_path_buf(), source }),
+                    },
+                    Err(source) => return Err(LockError::IoError { path: path.to_path_buf(), source }),
+                }
+            };
+
+            let owner = match owner {
+                Some(owner) => owner,
+                None => {
+                    self.report(LockEvent::Acquired { path: path.to_path_buf(), waited: start.elapsed() });
+                    return Ok(FileLock { path: path.to_path_buf() });
+                }
+            };
+
+            // Decided under `HELD`, so a lock a thread of this process took
+            // meanwhile is never mistaken for one left by a dead process.
+            let (held, stale) = {
+                let guard = HELD.lock().unwrap_or_else(|e| e.into_inner());
+                let held = guard.contains_key(path);
+                let stale = !held
+                    && match &owner {
+                        Some(owner) => owner.is_stale(),
+                        None => ownerless_for(path) > OWNERLESS_GRACE,
+                    };
+                if stale {
+                    break_stale_lock(path, owner.as_ref()).map_err(io_error(path))?;
+                }
+                (held, stale)
+            };
+            if stale {
+                self.report(LockEvent::BrokeStale { path: path.to_path_buf(), owner: owner.clone() });
+            }
+            if held || stale {
+                continue;
+            }
+
+            let waited = start.elapsed();
+            if waited >= self.timeout {
+                return Err(LockError::Timeout {
+                    path: path.to_path_buf(),
+                    owner: owner.map_or("an unknown process".to_string(), |owner| owner.to_string()),
+                    waited,
+                });
+            }
+            if !waiting {
+                self.report(LockEvent::Waiting { path: path.to_path_buf(), owner });
+                waiting = true;
+            }
+            thread::sleep(self.poll_interval.min(self.timeout - waited));
+        }
+    }
+
+    fn report(&self, event: LockEvent) {
+        if let Some(progress) = &self.progress {
+            progress(&event);
+        }
+    }
+}
+
+/// Holds a lock until dropped.
+#[derive(Debug)]
+pub struct FileLock {
+    path: PathBuf,
+}
+
+impl FileLock {
+    /// Returns the lock file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for FileLock {
+    fn drop(&mut self) {
+        let mut held = HELD.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(count) = held.get_mut(&self.path) {
+            *count -= 1;
+            if *count == 0 {
+                held.remove(&self.path);
+                if let Err(e) = fs::remove_file(&self.path) {
+                    log::warn!("Failed to release lock {}: {}", self.path.display(), e);
+                }
+            }
+        }
+    }
+}
+
+/// Creates a lock file recording this process as its owner, failing with
+/// `AlreadyExists` if another process holds it.
+fn create_lock_file(path: &Path) -> io::Result<()> {
+    let mut file = OpenOptions::new().write(true).create_new(true).open(path)?;
+    let written = serde_json::to_vec(&LockOwner::current())
+        .map_err(io::Error::from)
+        .and_then(|owner| file.write_all(&owner))
+        .and_then(|_| file.sync_all());
+    if written.is_err() {
+        let _ = fs::remove_file(path);
+    }
+    written
+}
+
+/// Reads the owner of a lock file. `None` means the file exists but holds no
+/// owner yet, or a corrupt one.
+fn read_owner(path: &Path) -> io::Result<Option<LockOwner>> {
+    let contents = fs::read(path)?;
+    Ok(serde_json::from_slice(&contents).ok())
+}
+
+/// Returns how long an ownerless lock file has existed.
+fn ownerless_for(path: &Path) -> Duration {
+    fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+        .and_then(|modified| modified.elapsed().ok())
+        .unwrap_or_default()
+}
+
+/// Removes a stale lock. The lock is first renamed aside, so that if another
+/// process replaced it after it was found stale, the live lock is put back.
+fn break_stale_lock(path: &Path, stale: Option<&LockOwner>) -> io::Result<()> {
+    let mut claimed = path.as_os_str().to_owned();
+    claimed.push(format!(".stale-{}", process::id()));
+    let claimed = PathBuf::from(claimed);
+
+    match fs::rename(path, &claimed) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    }
+    if read_owner(&claimed).ok().flatten().as_ref() != stale {
+        let _ = fs::hard_link(&claimed, path);
+    }
+    fs::remove_file(&claimed)
+}
+
+#[cfg(unix)]
+fn hostname() -> String {
+    let mut buffer = [0u8; 256];
+    nix::unistd::gethostname(&mut buffer)
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+#[cfg(not(unix))]
+fn hostname() -> String {
+    std::env::var("COMPUTERNAME").unwrap_or_default()
+}
+
+#[cfg(unix)]
+fn is_running(pid: u32) -> bool {
+    use nix::errno::Errno;
+    use nix::sys::signal::kill;
+    use nix::unistd::Pid;
+
+    // Signal 0 only checks that the process exists; EPERM means it does.
+    !matches!(kill(Pid::from_raw(pid as i32), None), Err(Errno::ESRCH))
+}
+
+#[cfg(not(unix))]
+fn is_running(_pid: u32) -> bool {
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_owner(path: &Path, pid: u32, host: &str) {
+        let owner = LockOwner { pid, host: host.to_string(), acquired: 0 };
+        fs::write(path, serde_json::to_vec(&owner).unwrap()).unwrap();
+    }
+
+    fn quick_locker() -> Locker {
+        Locker::new(Duration::from_millis(200)).with_poll_interval(Duration::from_millis(10))
+    }
+
+    #[test]
+    fn lock_is_reentrant_under_any_spelling() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("sub")).unwrap();
+        let locker = quick_locker();
+
+        let first = locker.lock_pkgs_dir(dir.path()).unwrap();
+        let second = locker.lock(&dir.path().join("sub").join("..").join(LOCK_FILE)).unwrap();
+        assert_eq!(first.path(), second.path());
+
+        drop(first);
+        assert!(second.path().exists(), "the lock is held until its last guard is dropped");
+        let path = second.path().to_path_buf();
+        drop(second);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn lock_times_out_while_another_process_holds_it() {
+        let dir = tempfile::tempdir().unwrap();
+        // PID 1 always runs.
+        write_owner(&dir.path().join(LOCK_FILE), 1, &hostname());
+
+        match quick_locker().lock_pkgs_dir(dir.path()) {
+            Err(LockError::Timeout { owner, .. }) => assert!(owner.starts_with("process 1 on"), "{}", owner),
+            other => panic!("expected a timeout, got {:?}", other),
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn lock_breaks_a_lock_left_by_a_dead_process() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut child = std::process::Command::new("true").spawn().unwrap();
+        let dead = child.id();
+        child.wait().unwrap();
+        write_owner(&dir.path().join(LOCK_FILE), dead, &hostname());
+
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let recorded = Arc::clone(&events);
+        let locker = quick_locker().with_progress(move |event: &LockEvent| recorded.lock().unwrap().push(event.clone()));
+        let lock = locker.lock_pkgs_dir(dir.path()).unwrap();
+
+        assert_eq!(read_owner(lock.path()).unwrap().map(|owner| owner.pid), Some(process::id()));
+        let events = events.lock().unwrap();
+        assert!(events.iter().any(|event| matches!(event, LockEvent::BrokeStale { owner: Some(owner), .. } if owner.pid == dead)));
+    }
+
+    #[test]
+    fn lock_never_breaks_a_lock_owned_by_another_host() {
+        let dir = tempfile::tempdir().unwrap();
+        // No process has this PID, but on another host that proves nothing.
+        write_owner(&dir.path().join(LOCK_FILE), u32::MAX - 1, "elsewhere.invalid");
+
+        assert!(matches!(quick_locker().lock_pkgs_dir(dir.path()), Err(LockError::Timeout { .. })));
+        assert!(dir.path().join(LOCK_FILE).exists());
+    }
+}
@@ -0,0 +1,405 @@
+// conda.matchspec.rs
+
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use thiserror::Error;
+
+use crate::repodata::PackageRecord;
+use crate::version::{VersionError, VersionOrder};
+
+/// Represents possible errors that can occur when parsing match specs.
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum MatchSpecError {
+    #[error("Empty match spec")]
+    Empty,
+
+    #[error("Invalid match spec {0}: {1}")]
+    Invalid(String, String),
+
+    #[error("Invalid version in match spec: {0}")]
+    VersionError(#[from] VersionError),
+}
+
+/// Represents a version constraint such as `>=1.2,<2|3.*`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VersionSpec {
+    /// Matches any version.
+    Any,
+    /// A single comparison.
+    Constraint(Operator, VersionOrder),
+    /// All of the specs must match.
+    All(Vec<VersionSpec>),
+    /// Any of the specs must match.
+    AnyOf(Vec<VersionSpec>),
+}
+
+/// Represents a version comparison operator.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operator {
+    Equal,
+    NotEqual,
+    Greater,
+    GreaterEqual,
+    Less,
+    LessEqual,
+    /// `1.2.*` or `=1.2`: the version starts with the given components.
+    StartsWith,
+    /// `!=1.2.*`: the version does not start with the given components.
+    NotStartsWith,
+    /// `~=1.2.3`: at least the version, with the same leading components.
+    Compatible,
+}
+
+impl VersionSpec {
+    /// Returns whether a version satisfies the spec.
+    pub fn matches(&self, version: &VersionOrder) -> bool {
+        match self {
+            VersionSpec::Any => true,
+            VersionSpec::Constraint(op, bound) => match op {
+                Operator::Equal => version == bound,
+                Operator::NotEqual => version != bound,
+                Operator::Greater => version > bound,
+                Operator::GreaterEqual => version >= bound,
+                Operator::Less => version < bound,
+                Operator::LessEqual => version <= bound,
+                Operator::StartsWith => version.starts_with(bound),
+                Operator::NotStartsWith => !version.starts_with(bound),
+                Operator::Compatible => bound.is_compatible_with(version),
+            },
+            VersionSpec::All(specs) => specs.iter().all(|spec| spec.matches(version)),
+            VersionSpec::AnyOf(specs) => specs.iter().any(|spec| spec.matches(version)),
+        }
+    }
+}
+
+impl FromStr for VersionSpec {
+    type Err = MatchSpecError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if s.is_empty() || s == "*" {
+            return Ok(VersionSpec::Any);
+        }
+
+        if s.contains('|') {
+            return Ok(VersionSpec::AnyOf(s.split('|').map(str::parse).collect::<Result<_, _>>()?));
+        }
+        if s.contains(',') {
+            return Ok(VersionSpec::All(s.split(',').map(str::parse).collect::<Result<_, _>>()?));
+        }
+
+        const OPERATORS: &[(&str, Operator)] = &[
+            ("==", Operator::Equal),
+            ("!=", Operator::NotEqual),
+            (">=", Operator::GreaterEqual),
+            ("<=", Operator::LessEqual),
+            ("~=", Operator::Compatible),
+            (">", Operator::Greater),
+            ("<", Operator::Less),
+            ("=", Operator::StartsWith),
+        ];
+
+        let (op, version) = OPERATORS
+            .iter()
+            .find_map(|(prefix, op)| s.strip_prefix(prefix).map(|rest| (*op, rest.trim())))
+            .unwrap_or((Operator::Equal, s));
+
+        // A trailing `.*` or `*` turns an equality into a prefix match.
+        let (op, version) = match version.strip_suffix(".*").or_else(|| version.strip_suffix('*')) {
+            Some(prefix) => match op {
+                Operator::Equal | Operator::StartsWith => (Operator::StartsWith, prefix),
+                Operator::NotEqual => (Operator::NotStartsWith, prefix),
+                Operator::GreaterEqual | Operator::Greater => (op, prefix),
+                _ => (op, version),
+            },
+            None => (op, version),
+        };
+
+        if version.is_empty() {
+            return Ok(VersionSpec::Any);
+        }
+        Ok(VersionSpec::Constraint(op, version.parse()?))
+    }
+}
+
+/// Represents a conda match spec such as `conda-forge::numpy >=1.20 py39*`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MatchSpec {
+    pub name: String,
+    pub version: VersionSpec,
+
+    /// A build string, possibly containing `*` wildcards.
+    pub build: Option<String>,
+    pub build_number: Option<u64>,
+    pub channel: Option<String>,
+    pub subdir: Option<String>,
+
+    /// The spec as originally written.
+    source: String,
+}
+
+impl MatchSpec {
+    /// Creates a spec that matches any version of a package.
+    pub fn name_only(name: &str) -> Self {
+        MatchSpec {
+            name: name.to_string(),
+            version: VersionSpec::Any,
+            build: None,
+            build_number: None,
+            channel: None,
+            subdir: None,
+            source: name.to_string(),
+        }
+    }
+
+    /// Returns whether a record satisfies the spec. Channel constraints are
+    /// not checked here, since records do not carry their channel.
+    pub fn matches(&self, record: &PackageRecord) -> bool {
+        if record.name != self.name {
+            return false;
+        }
+        if let Some(subdir) = &self.subdir {
+            if &record.subdir != subdir {
+                return false;
+            }
+        }
+        if let Some(build_number) = self.build_number {
+            if record.build_number != build_number {
+                return false;
+            }
+        }
+        if let Some(build) = &self.build {
+            if !glob_matches(build, &record.build) {
+                return false;
+            }
+        }
+        match record.version.parse::<VersionOrder>() {
+            Ok(version) => self.version.matches(&version),
+            Err(_) => matches!(self.version, VersionSpec::Any),
+        }
+    }
+
+    /// Returns the spec as originally written.
+    pub fn as_str(&self) -> &str {
+        &self.source
+    }
+}
+
+impl FromStr for MatchSpec {
+    type Err = MatchSpecError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let source = s.trim().to_string();
+        let invalid = |reason: &str| MatchSpecError::Invalid(source.clone(), reason.to_string());
+        if source.is_empty() {
+            return Err(MatchSpecError::Empty);
+        }
+
+        // Strip a trailing `# comment` and split off `[key=value,...]` brackets.
+        let mut spec = source.split('#').next().unwrap_or_default().trim().to_string();
+        let mut brackets = Vec::new();
+        if let Some(start) = spec.find('[') {
+            let end = spec.rfind(']').filter(|&end| end > start).ok_or_else(|| invalid("unterminated bracket"))?;
+            for pair in split_bracket_pairs(&spec[start + 1..end]).ok_or_else(|| invalid("unterminated quote"))? {
+                if let Some((key, value)) = pair.split_once('=') {
+                    let value = value.trim().trim_matches(|c| c == '\'' || c == '"');
+                    brackets.push((key.trim().to_string(), value.to_string()));
+                }
+            }
+            spec = format!("{}{}", &spec[..start], &spec[end + 1..]);
+        }
+
+        // `channel/subdir::name ...`
+        let (mut channel, mut subdir, rest) = match spec.rsplit_once("::") {
+            Some((prefix, rest)) => {
+                let (channel, subdir) = split_channel_subdir(prefix);
+                (Some(channel), subdir, rest.trim().to_string())
+            }
+            None => (None, None, spec.trim().to_string()),
+        };
+
+        let name_end = rest
+            .find(|c: char| c.is_whitespace() || "=<>!~".contains(c))
+            .unwrap_or(rest.len());
+        let name = rest[..name_end].to_string();
+        if name.is_empty() {
+            return Err(invalid("missing package name"));
+        }
+        let remainder = rest[name_end..].trim();
+
+        let (mut version, mut build) = if remainder.is_empty() {
+            (VersionSpec::Any, None)
+        } else if remainder.starts_with('=') && !remainder.starts_with("==") {
+            // `name=1.2` means 1.2.*, while `name=1.2=build` pins the exact version.
+            let mut parts = remainder[1..].splitn(2, '=');
+            let version = parts.next().unwrap_or_default().trim();
+            match parts.next() {
+                Some(build) => (format!("=={}", version).parse()?, Some(build.trim().to_string())),
+                None if version.ends_with('*') => (version.parse()?, None),
+                None => (format!("={}", version).parse()?, None),
+            }
+        } else {
+            let mut parts = remainder.split_whitespace();
+            let version: String = parts.next().unwrap_or_default().to_string();
+            let build = parts.next().map(String::from);
+            if parts.next().is_some() {
+                return Err(invalid("too many space-separated fields"));
+            }
+            (version.parse()?, build)
+        };
+
+        let mut build_number = None;
+        for (key, value) in brackets {
+            match key.as_str() {
+                "version" => version = value.parse()?,
+                "build" => build = Some(value),
+                "build_number" => {
+                    build_number = Some(value.parse().map_err(|_| invalid("build_number must be an integer"))?)
+                }
+                "channel" => {
+                    let (bracket_channel, bracket_subdir) = split_channel_subdir(&value);
+                    channel = Some(bracket_channel);
+                    subdir = bracket_subdir.or(subdir);
+                }
+                "subdir" => subdir = Some(value),
+                _ => {}
+            }
+        }
+
+        Ok(MatchSpec {
+            name,
+            version,
+            build,
+            build_number,
+            channel,
+            subdir,
+            source,
+        })
+    }
+}
+
+impl fmt::Display for MatchSpec {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+impl Serialize for MatchSpec {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.source)
+    }
+}
+
+impl<'de> Deserialize<'de> for MatchSpec {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let source = String::deserialize(deserializer)?;
+        source.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// Splits the contents of `[key=value,...]` on the commas outside quotes, so
+/// quoted values such as `version='>=1.20,<2'` stay whole. Returns `None` if a
+/// quote is left open.
+fn split_bracket_pairs(contents: &str) -> Option<Vec<&str>> {
+    let mut pairs = Vec::new();
+    let mut quote = None;
+    let mut pair_start = 0;
+    for (index, c) in contents.char_indices() {
+        match (quote, c) {
+            (None, '\'' | '"') => quote = Some(c),
+            (Some(open), _) if c == open => quote = None,
+            (None, ',') => {
+                pairs.push(&contents[pair_start..index]);
+                pair_start = index + 1;
+            }
+            _ => {}
+        }
+    }
+    if quote.is_some() {
+        return None;
+    }
+    pairs.push(&contents[pair_start..]);
+    Some(pairs)
+}
+
+/// Splits `conda-forge/linux-64` into its channel and subdir parts.
+fn split_channel_subdir(value: &str) -> (String, Option<String>) {
+    match value.rsplit_once('/') {
+        Some((channel, subdir)) if subdir.parse::<crate::channel::Platform>().is_ok() => {
+            (channel.to_string(), Some(subdir.to_string()))
+        }
+        _ => (value.to_string(), None),
+    }
+}
+
+/// Matches text against a pattern where `*` matches any run of characters.
+pub fn glob_matches(pattern: &str, text: &str) -> bool {
+    let parts: Vec<&str> = pattern.split('*').collect();
+    if parts.len() == 1 {
+        return pattern == text;
+    }
+
+    let (first, last) = (parts[0], parts[parts.len() - 1]);
+    if !text.starts_with(first) || !text[first.len()..].ends_with(last) {
+        return false;
+    }
+
+    let mut remaining = &text[first.len()..text.len() - last.len()];
+    for part in &parts[1..parts.len() - 1] {
+        match remaining.find(part) {
+            Some(index) => remaining = &remaining[index + part.len()..],
+            None => return false,
+        }
+    }
+    true
+}
+
+/// Returns the package name a dependency string refers to.
+pub fn spec_name(spec: &str) -> &str {
+    let spec = spec.trim();
+    let spec = spec.rsplit_once("::").map_or(spec, |(_, rest)| rest.trim());
+    let end = spec
+        .find(|c: char| c.is_whitespace() || "=<>!~[".contains(c))
+        .unwrap_or(spec.len());
+    &spec[..end]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn version(s: &str) -> VersionOrder {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn quoted_bracket_version_keeps_every_constraint() {
+        let spec: MatchSpec = "numpy[version='>=1.20,<2', build=py*]".parse().unwrap();
+        assert_eq!(spec.name, "numpy");
+        assert_eq!(spec.build.as_deref(), Some("py*"));
+        assert!(spec.version.matches(&version("1.20")));
+        assert!(spec.version.matches(&version("1.26.4")));
+        assert!(!spec.version.matches(&version("1.19")));
+        assert!(!spec.version.matches(&version("2.0")));
+
+        let spec: MatchSpec = r#"numpy[version=">=1.20,<2"]"#.parse().unwrap();
+        assert!(!spec.version.matches(&version("2.1")));
+    }
+
+    #[test]
+    fn malformed_brackets_are_rejected() {
+        for spec in ["numpy]version=1.0[", "numpy[version=1.0", "numpy]["] {
+            assert!(
+                matches!(spec.parse::<MatchSpec>(), Err(MatchSpecError::Invalid(_, reason)) if reason == "unterminated bracket"),
+                "{} should be an unterminated bracket",
+                spec
+            );
+        }
+        assert!(matches!(
+            "numpy[version='>=1.20,<2]".parse::<MatchSpec>(),
+            Err(MatchSpecError::Invalid(_, reason)) if reason == "unterminated quote"
+        ));
+    }
+}
@@ -0,0 +1,378 @@
+// conda.mirror.rs
+
+use std::collections::{BTreeMap, HashSet, VecDeque};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use rayon::prelude::*;
+use thiserror::Error;
+
+use crate::archive::ArchiveType;
+use crate::channel::Platform;
+use crate::download;
+use crate::index::{self, IndexError};
+use crate::matchspec::MatchSpec;
+use crate::network::{NetworkClient, NetworkError};
+use crate::repodata::{self, ChannelInfo, PackageRecord, RepoData, RepodataError, SubdirRepodata};
+
+/// Represents possible errors that can occur when mirroring a channel.
+#[derive(Error, Debug)]
+pub enum MirrorError {
+    #[error("Failed to access {path}: {source}")]
+    IoError { path: PathBuf, source: io::Error },
+
+    #[error("Failed to read mirrored repodata: {0}")]
+    RepodataError(#[from] RepodataError),
+
+    #[error("Failed to write mirrored repodata: {0}")]
+    IndexError(#[from] IndexError),
+}
+
+/// Represents a package file selected for mirroring.
+#[derive(Debug, Clone)]
+pub struct MirroredPackage<'a> {
+    pub source: &'a SubdirRepodata,
+    pub filename: &'a str,
+    pub record: &'a PackageRecord,
+}
+
+/// Represents the package files needed to satisfy a set of specs.
+#[derive(Debug, Clone, Default)]
+pub struct Resolution<'a> {
+    pub packages: Vec<MirroredPackage<'a>>,
+
+    /// Specs, including dependencies, that no source record satisfies.
+    pub unresolved: Vec<String>,
+}
+
+/// Represents what a mirror sync changed.
+#[derive(Debug, Clone, Default)]
+pub struct MirrorReport {
+    /// Downloaded files, as `<subdir>/<filename>`.
+    pub downloaded: Vec<String>,
+
+    /// Files already present with the expected hash.
+    pub unchanged: usize,
+
+    /// Files deleted because no spec selects them any more.
+    pub removed: Vec<String>,
+
+    /// Files that could not be downloaded or verified, with the reason.
+    pub failed: Vec<(String, String)>,
+
+    pub unresolved: Vec<String>,
+}
+
+/// Mirrors the packages matching a set of specs, and everything they depend
+/// on, from source channels into a local channel directory.
+#[derive(Debug, Clone)]
+pub struct Mirror {
+    target_dir: PathBuf,
+    specs: Vec<MatchSpec>,
+    prune: bool,
+}
+
+impl Mirror {
+    /// Creates a mirror of the given specs in a channel directory.
+    pub fn new(target_dir: impl Into<PathBuf>, specs: Vec<MatchSpec>) -> Self {
+        Mirror {
+            target_dir: target_dir.into(),
+            specs,
+            prune: false,
+        }
+    }
+
+    /// Deletes previously mirrored files that the specs no longer select.
+    pub fn with_prune(mut self, prune: bool) -> Self {
+        self.prune = prune;
+        self
+    }
+
+    /// Returns the target channel directory.
+    pub fn target_dir(&self) -> &Path {
+        &self.target_dir
+    }
+
+    /// Selects every source record matching the specs, then every record
+    /// matching their dependencies, until the closure is complete.
+    pub fn resolve<'a>(&self, sources: &'a [SubdirRepodata]) -> Resolution<'a> {
+        let mut resolution = Resolution::default();
+        let mut selected = HashSet::new();
+        let mut seen: HashSet<String> = HashSet::new();
+        let mut queue: VecDeque<MatchSpec> = self.specs.iter().cloned().collect();
+
+        while let Some(spec) = queue.pop_front() {
+            if !seen.insert(spec.to_string()) {
+                continue;
+            }
+
+            let mut found = false;
+            for source in sources.iter().filter(|source| channel_matches(&spec, source)) {
+                for (filename, record) in source.repodata.records_named(&spec.name) {
+                    if !spec.matches(record) {
+                        continue;
+                    }
+                    found = true;
+                    if !selected.insert((source.platform, filename.as_str())) {
+                        continue;
+                    }
+
+                    for dependency in &record.depends {
+                        match dependency.parse::<MatchSpec>() {
+                            Ok(dependency) if dependency.name.starts_with("__") => {}
+                            Ok(dependency) => queue.push_back(dependency),
+                            Err(e) => log::warn!("Ignoring dependency {:?} of {}: {}", dependency, filename, e),
+                        }
+                    }
+                    resolution.packages.push(MirroredPackage { source, filename, record });
+                }
+            }
+
+            if !found {
+                resolution.unresolved.push(spec.to_string());
+            }
+        }
+
+        resolution.unresolved.sort();
+        resolution
+    }
+
+    /// Downloads the resolved packages that are missing or changed, verifying
+    /// their hashes, and writes repodata for every mirrored subdir. The client
+    /// is only requested for remote sources.
+    pub fn sync<'a, F>(&self, sources: &[SubdirRepodata], client: F) -> Result<MirrorReport, MirrorError>
+    where
+        F: Fn() -> Result<&'a NetworkClient, NetworkError> + Sync,
+    {
+        let resolution = self.resolve(sources);
+        let mut report = MirrorReport { unresolved: resolution.unresolved, ..MirrorReport::default() };
+
+        let mut by_platform: BTreeMap<Platform, Vec<&MirroredPackage>> = BTreeMap::new();
+        by_platform.entry(Platform::NoArch).or_default();
+        for package in &resolution.packages {
+            by_platform.entry(package.source.platform).or_default().push(package);
+        }
+
+        for (platform, packages) in by_platform {
+            self.sync_subdir(platform, &packages, &client, &mut report)?;
+        }
+        Ok(report)
+    }
+
+    fn sync_subdir<'a, F>(
+        &self,
+        platform: Platform,
+        packages: &[&MirroredPackage],
+        client: &F,
+        report: &mut MirrorReport,
+    ) -> Result<(), MirrorError>
+    where
+        F: Fn() -> Result<&'a NetworkClient, NetworkError> + Sync,
+    {
+        let subdir = platform.as_str();
+        let dir = self.target_dir.join(subdir);
+        fs::create_dir_all(&dir).map_err(|source| MirrorError::IoError { path: dir.clone(), source })?;
+        let existing = repodata::load_local(&dir)?;
+
+        let mut wanted: BTreeMap<&str, &PackageRecord> = BTreeMap::new();
+        let mut signatures = BTreeMap::new();
+        let mut to_download = Vec::new();
+        for package in packages {
+            // The first channel providing a filename wins.
+            if wanted.contains_key(package.filename) {
+                continue;
+            }
+            wanted.insert(package.filename, package.record);
+            if let Some(signed) = package.source.repodata.signatures.get(package.filename) {
+                signatures.insert(package.filename.to_string(), signed.clone());
+            }
+
+            let unchanged = existing
+                .records()
+                .any(|(filename, record)| filename == package.filename && same_artifact(record, package.record))
+                && dir.join(package.filename).is_file();
+            if unchanged {
+                report.unchanged += 1;
+            } else {
+                to_download.push(*package);
+            }
+        }
+
+        let results: Vec<(String, Result<(), String>)> = to_download
+            .par_iter()
+            .map(|package| (package.filename.to_string(), fetch_verified(package, &dir, client)))
+            .collect();
+
+        let mut failed = HashSet::new();
+        for (filename, result) in results {
+            match result {
+                Ok(()) => report.downloaded.push(format!("{}/{}", subdir, filename)),
+                Err(reason) => {
+                    log::warn!("Failed to mirror {}/{}: {}", subdir, filename, reason);
+                    report.failed.push((format!("{}/{}", subdir, filename), reason));
+                    failed.insert(filename);
+                }
+            }
+        }
+
+        let mut mirrored = RepoData {
+            info: Some(ChannelInfo { subdir: subdir.to_string(), base_url: None }),
+            repodata_version: Some(1),
+            ..RepoData::default()
+        };
+
+        for (filename, record) in existing.records() {
+            if wanted.contains_key(filename.as_str()) || !dir.join(filename).is_file() {
+                continue;
+            }
+            if self.prune {
+                let path = dir.join(filename);
+                fs::remove_file(&path).map_err(|source| MirrorError::IoError { path, source })?;
+                report.removed.push(format!("{}/{}", subdir, filename));
+            } else {
+                insert_record(&mut mirrored, filename, record);
+                if let Some(signed) = existing.signatures.get(filename) {
+                    mirrored.signatures.insert(filename.clone(), signed.clone());
+                }
+            }
+        }
+        for (filename, record) in wanted {
+            if !failed.contains(filename) {
+                insert_record(&mut mirrored, filename, record);
+                if let Some(signed) = signatures.remove(filename) {
+                    mirrored.signatures.insert(filename.to_string(), signed);
+                }
+            }
+        }
+
+        index::write_repodata(&dir, &mirrored)?;
+        Ok(())
+    }
+}
+
+/// Downloads a package next to its final path and moves it into place only
+/// once its size and hash match the source record.
+fn fetch_verified<'a, F>(package: &MirroredPackage, dir: &Path, client: &F) -> Result<(), String>
+where
+    F: Fn() -> Result<&'a NetworkClient, NetworkError>,
+{
+    let url = package
+        .source
+        .channel
+        .platform_url(package.source.platform)
+        .join(package.filename)
+        .map_err(|e| e.to_string())?;
+    download::fetch_verified(&url, &dir.join(package.filename), package.record, client)
+        .map(|_| ())
+        .map_err(|e| e.to_string())
+}
+
+/// Returns whether two records describe the same package file.
+fn same_artifact(a: &PackageRecord, b: &PackageRecord) -> bool {
+    match (&a.sha256, &b.sha256, &a.md5, &b.md5) {
+        (Some(a), Some(b), _, _) => a.eq_ignore_ascii_case(b),
+        (_, _, Some(a), Some(b)) => a.eq_ignore_ascii_case(b),
+        _ => false,
+    }
+}
+
+fn insert_record(repodata: &mut RepoData, filename: &str, record: &PackageRecord) {
+    match ArchiveType::split_filename(filename) {
+        Some((_, ArchiveType::Conda)) => repodata.conda_packages.insert(filename.to_string(), record.clone()),
+        _ => repodata.packages.insert(filename.to_string(), record.clone()),
+    };
+}
+
+fn channel_matches(spec: &MatchSpec, source: &SubdirRepodata) -> bool {
+    let channel_ok = spec.channel.as_ref().map_or(true, |channel| {
+        source.channel.canonical_name() == *channel || source.channel.name.as_ref() == Some(channel)
+    });
+    let subdir_ok = spec.subdir.as_ref().map_or(true, |subdir| source.platform.as_str() == subdir);
+    channel_ok && subdir_ok
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::channel::{Channel, ChannelConfig};
+    use crate::digest;
+
+    fn record(name: &str, version: &str, depends: &[&str]) -> PackageRecord {
+        PackageRecord {
+            name: name.to_string(),
+            version: version.to_string(),
+            build: "0".to_string(),
+            subdir: "linux-64".to_string(),
+            depends: depends.iter().map(|d| d.to_string()).collect(),
+            ..Default::default()
+        }
+    }
+
+    fn source(channel_dir: &Path, records: Vec<PackageRecord>) -> SubdirRepodata {
+        let mut repodata = RepoData::default();
+        for record in records {
+            let filename = format!("{}-{}-{}.conda", record.name, record.version, record.build);
+            repodata.conda_packages.insert(filename, record);
+        }
+        SubdirRepodata {
+            channel: Channel::from_str(channel_dir.to_str().unwrap(), &ChannelConfig::default()).unwrap(),
+            platform: Platform::Linux64,
+            repodata,
+        }
+    }
+
+    fn no_network<'a>() -> Result<&'a NetworkClient, NetworkError> {
+        panic!("file:// channels need no network client")
+    }
+
+    #[test]
+    fn resolve_selects_the_transitive_closure() {
+        let root = tempfile::tempdir().unwrap();
+        let sources = [source(
+            root.path(),
+            vec![
+                record("app", "1.0", &["lib >=1", "__glibc >=2.17"]),
+                record("lib", "1.0", &["zlib", "missing"]),
+                record("lib", "0.9", &[]),
+                record("zlib", "1.2", &[]),
+                record("unrelated", "1.0", &[]),
+            ],
+        )];
+
+        let mirror = Mirror::new(root.path().join("mirror"), vec!["app".parse().unwrap()]);
+        let resolution = mirror.resolve(&sources);
+
+        let mut selected: Vec<&str> = resolution.packages.iter().map(|package| package.filename).collect();
+        selected.sort_unstable();
+        assert_eq!(selected, ["app-1.0-0.conda", "lib-1.0-0.conda", "zlib-1.2-0.conda"]);
+        assert_eq!(resolution.unresolved, ["missing"]);
+    }
+
+    #[test]
+    fn sync_skips_artifacts_already_mirrored() {
+        let root = tempfile::tempdir().unwrap();
+        let channel_dir = root.path().join("channel");
+        fs::create_dir_all(channel_dir.join("linux-64")).unwrap();
+
+        let contents = b"not really a package";
+        fs::write(channel_dir.join("linux-64").join("app-1.0-0.conda"), contents).unwrap();
+        let app = PackageRecord {
+            sha256: Some(digest::sha256_hex(contents)),
+            size: Some(contents.len() as u64),
+            ..record("app", "1.0", &[])
+        };
+        let sources = [source(&channel_dir, vec![app])];
+        let mirror = Mirror::new(root.path().join("mirror"), vec!["app".parse().unwrap()]);
+
+        let first = mirror.sync(&sources, no_network).unwrap();
+        assert_eq!(first.downloaded, ["linux-64/app-1.0-0.conda"]);
+        assert_eq!(first.unchanged, 0);
+        assert!(root.path().join("mirror").join("linux-64").join("app-1.0-0.conda").is_file());
+
+        let second = mirror.sync(&sources, no_network).unwrap();
+        assert!(second.downloaded.is_empty());
+        assert_eq!(second.unchanged, 1);
+        assert!(second.failed.is_empty());
+    }
+}
@@ -0,0 +1,542 @@
+// conda.network.rs
+
+use std::fs;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::thread;
+use std::time::Duration;
+
+use reqwest::blocking::{Client, Response};
+use reqwest::header::{HeaderMap, RETRY_AFTER};
+use reqwest::{Certificate, Identity, Proxy, StatusCode};
+use thiserror::Error;
+use url::Url;
+
+use crate::auth::{redact_url, AuthStore};
+use crate::settings::{CondaSettings, ProxySettings};
+
+/// The delay before the first retry; later retries back off exponentially.
+const RETRY_BASE_DELAY: Duration = Duration::from_millis(500);
+
+/// The longest delay between two attempts.
+const RETRY_MAX_DELAY: Duration = Duration::from_secs(30);
+
+/// Represents possible errors that can occur when making network requests.
+/// URLs are always redacted, so these are safe to log.
+#[derive(Error, Debug)]
+pub enum NetworkError {
+    #[error("Invalid URL {0}: {1}")]
+    InvalidUrl(String, url::ParseError),
+
+    #[error("HTTP request for {url} failed: {source}")]
+    RequestError { url: String, source: reqwest::Error },
+
+    #[error("Failed to read {path}: {source}")]
+    CertificateReadError { path: PathBuf, source: std::io::Error },
+
+    #[error("Invalid certificate or key in {path}: {source}")]
+    CertificateError { path: PathBuf, source: reqwest::Error },
+
+    #[error("Invalid proxy URL {url}: {source}")]
+    ProxyError { url: String, source: reqwest::Error },
+
+    #[error("Failed to build HTTP client: {0}")]
+    BuildError(reqwest::Error),
+}
+
+impl NetworkError {
+    /// Wraps a reqwest error, removing the (possibly authenticated) URL it carries.
+    pub fn request(url: &str, source: reqwest::Error) -> Self {
+        NetworkError::RequestError {
+            url: redact_url(url),
+            source: source.without_url(),
+        }
+    }
+}
+
+/// The HTTP client used by every network code path. It applies the stored
+/// channel credentials to each request and retries transient failures.
+#[derive(Debug, Clone)]
+pub struct NetworkClient {
+    client: Client,
+    auth: AuthStore,
+    max_retries: u32,
+}
+
+impl NetworkClient {
+    /// Creates a network client from a reqwest client and an authentication store,
+    /// without retries.
+    pub fn new(client: Client, auth: AuthStore) -> Self {
+        NetworkClient { client, auth, max_retries: 0 }
+    }
+
+    /// Creates a network client that applies the timeouts, retries, TLS and
+    /// proxy options of the settings.
+    pub fn from_settings(settings: &CondaSettings, auth: AuthStore) -> Result<Self, NetworkError> {
+        let mut builder = Client::builder()
+            .timeout(Duration::from_secs(settings.network_timeout))
+            .connect_timeout(Duration::from_secs(settings.connect_timeout));
+
+        if !settings.ssl_verify {
+            log::warn!("SSL verification is disabled; HTTPS certificates will not be checked");
+            builder = builder.danger_accept_invalid_certs(true);
+        }
+
+        if let Some(bundle) = &settings.ssl_ca_bundle {
+            for certificate in load_ca_bundle(bundle)? {
+                builder = builder.add_root_certificate(certificate);
+            }
+        }
+
+        if let Some(cert_path) = &settings.client_cert {
+            builder = builder.identity(load_identity(cert_path, settings.client_cert_key.as_ref())?);
+        }
+
+        if let Some(proxy_settings) = &settings.proxy_settings {
+            builder = builder.no_proxy().proxy(build_proxy(proxy_settings)?);
+        }
+
+        Ok(NetworkClient {
+            client: builder.build().map_err(NetworkError::BuildError)?,
+            auth,
+            max_retries: settings.max_retries,
+        })
+    }
+
+    /// Returns the authentication store used by the client.
+    pub fn auth(&self) -> &AuthStore {
+        &self.auth
+    }
+
+    /// Sends a GET request to a URL.
+    pub fn get(&self, url: &str) -> Result<Response, NetworkError> {
+        self.get_with_headers(url, HeaderMap::new())
+    }
+
+    /// Sends a GET request with extra headers to a URL, retrying connection
+    /// errors, timeouts and retryable server responses with exponential
+    /// backoff and jitter.
+    pub fn get_with_headers(&self, url: &str, headers: HeaderMap) -> Result<Response, NetworkError> {
+        let parsed = Url::parse(url).map_err(|e| NetworkError::InvalidUrl(redact_url(url), e))?;
+        let authenticated = self.auth.authenticate_url(&parsed);
+
+        let mut attempt = 0;
+        loop {
+            let request = self.client.get(authenticated.clone()).headers(headers.clone());
+            let result = self.auth.authenticate_request(&parsed, request).send();
+
+            let delay = match &result {
+                Ok(response) if is_retryable_status(response.status()) => {
+                    retry_after(response).unwrap_or_else(|| backoff_delay(attempt))
+                }
+                Err(e) if e.is_timeout() || e.is_connect() => backoff_delay(attempt),
+                _ => return result.map_err(|source| NetworkError::request(url, source)),
+            };
+
+            if attempt >= self.max_retries {
+                return result.map_err(|source| NetworkError::request(url, source));
+            }
+
+            attempt += 1;
+            log::debug!(
+                "Retrying {} in {:?} (attempt {} of {})",
+                redact_url(url),
+                delay,
+                attempt,
+                self.max_retries
+            );
+            thread::sleep(delay);
+        }
+    }
+
+    /// Reads the whole body of a response to a request for `url`.
+    pub fn bytes(url: &str, response: Response) -> Result<Vec<u8>, NetworkError> {
+        response
+            .bytes()
+            .map(|bytes| bytes.to_vec())
+            .map_err(|source| NetworkError::request(url, source))
+    }
+}
+
+fn is_retryable_status(status: StatusCode) -> bool {
+    matches!(
+        status,
+        StatusCode::TOO_MANY_REQUESTS
+            | StatusCode::INTERNAL_SERVER_ERROR
+            | StatusCode::BAD_GATEWAY
+            | StatusCode::SERVICE_UNAVAILABLE
+            | StatusCode::GATEWAY_TIMEOUT
+    )
+}
+
+/// Returns the delay requested by a `Retry-After: <seconds>` header.
+fn retry_after(response: &Response) -> Option<Duration> {
+    let seconds: u64 = response.headers().get(RETRY_AFTER)?.to_str().ok()?.trim().parse().ok()?;
+    Some(Duration::from_secs(seconds).min(RETRY_MAX_DELAY))
+}
+
+/// Returns the exponential backoff delay for an attempt, plus up to one base delay of jitter.
+fn backoff_delay(attempt: u32) -> Duration {
+    let exponential = RETRY_BASE_DELAY
+        .checked_mul(1 << attempt.min(16))
+        .unwrap_or(RETRY_MAX_DELAY)
+        .min(RETRY_MAX_DELAY);
+
+    let mut random = [0u8; 4];
+    let jitter_ms = match ring::rand::SecureRandom::fill(&ring::rand::SystemRandom::new(), &mut random) {
+        Ok(()) => u64::from(u32::from_le_bytes(random)) % RETRY_BASE_DELAY.as_millis() as u64,
+        Err(_) => 0,
+    };
+
+    exponential + Duration::from_millis(jitter_ms)
+}
+
+/// Loads every certificate of a PEM bundle.
+fn load_ca_bundle(path: &PathBuf) -> Result<Vec<Certificate>, NetworkError> {
+    let contents = fs::read_to_string(path)
+        .map_err(|source| NetworkError::CertificateReadError { path: path.clone(), source })?;
+
+    const END_MARKER: &str = "-----END CERTIFICATE-----";
+    contents
+        .split_inclusive(END_MARKER)
+        .filter(|block| block.contains("-----BEGIN CERTIFICATE-----"))
+        .map(|block| {
+            Certificate::from_pem(block.trim().as_bytes())
+                .map_err(|source| NetworkError::CertificateError { path: path.clone(), source })
+        })
+        .collect()
+}
+
+/// Loads a PEM client certificate, appending its key when stored separately.
+fn load_identity(cert_path: &PathBuf, key_path: Option<&PathBuf>) -> Result<Identity, NetworkError> {
+    let mut pem = fs::read(cert_path)
+        .map_err(|source| NetworkError::CertificateReadError { path: cert_path.clone(), source })?;
+    if let Some(key_path) = key_path {
+        pem.push(b'\n');
+        pem.extend(
+            fs::read(key_path)
+                .map_err(|source| NetworkError::CertificateReadError { path: key_path.clone(), source })?,
+        );
+    }
+    Identity::from_pem(&pem).map_err(|source| NetworkError::CertificateError { path: cert_path.clone(), source })
+}
+
+/// Builds a proxy that routes by URL scheme and skips hosts matching `no_proxy`.
+fn build_proxy(settings: &ProxySettings) -> Result<Proxy, NetworkError> {
+    let parse = |proxy: &Option<String>| -> Result<Option<Url>, NetworkError> {
+        proxy
+            .as_deref()
+            .map(|url| Url::parse(url).map_err(|e| NetworkError::InvalidUrl(redact_url(url), e)))
+            .transpose()
+    };
+    let http_proxy = parse(&settings.http_proxy)?;
+    let https_proxy = parse(&settings.https_proxy)?;
+    let no_proxy = NoProxy::new(settings.no_proxy.as_deref().unwrap_or_default());
+
+    Ok(Proxy::custom(move |url| {
+        if no_proxy.matches(url) {
+            return None;
+        }
+        match url.scheme() {
+            "https" => https_proxy.clone().or_else(|| http_proxy.clone()),
+            "http" => http_proxy.clone(),
+            _ => None,
+        }
+    }))
+}
+
+/// Represents the `no_proxy` list: exact hosts, `*.`/`.` domain suffixes,
+/// `host:port` entries, IP addresses, CIDR ranges and `*` for everything.
+#[derive(Debug, Clone, Default)]
+pub struct NoProxy {
+    entries: Vec<NoProxyEntry>,
+}
+
+#[derive(Debug, Clone)]
+enum NoProxyEntry {
+    All,
+    Host { host: String, port: Option<u16> },
+    DomainSuffix(String),
+    Cidr { network: IpAddr, prefix: u8 },
+}
+
+impl NoProxy {
+    /// Parses the entries of a `no_proxy` list.
+    pub fn new<S: AsRef<str>>(entries: &[S]) -> Self {
+        let entries = entries
+            .iter()
+            .flat_map(|entry| entry.as_ref().split(','))
+            .map(|entry| entry.trim().to_lowercase())
+            .filter(|entry| !entry.is_empty())
+            .map(|entry| parse_no_proxy_entry(&entry))
+            .collect();
+        NoProxy { entries }
+    }
+
+    /// Returns whether requests to the URL should bypass the proxy.
+    pub fn matches(&self, url: &Url) -> bool {
+        let host = match url.host_str() {
+            Some(host) => host.trim_start_matches('[').trim_end_matches(']').to_lowercase(),
+            None => return false,
+        };
+        let port = url.port_or_known_default();
+        let ip: Option<IpAddr> = host.parse().ok();
+
+        self.entries.iter().any(|entry| match entry {
+            NoProxyEntry::All => true,
+            NoProxyEntry::Host { host: entry_host, port: entry_port } => {
+                (entry_host == &host || host.ends_with(&format!(".{}", entry_host)))
+                    && entry_port.iter().all(|&p| Some(p) == port)
+            }
+            NoProxyEntry::DomainSuffix(suffix) => host.ends_with(suffix.as_str()) || host == suffix[1..],
+            NoProxyEntry::Cidr { network, prefix } => ip.is_some_and(|ip| cidr_contains(network, *prefix, &ip)),
+        })
+    }
+}
+
+fn parse_no_proxy_entry(entry: &str) -> NoProxyEntry {
+    if entry == "*" {
+        return NoProxyEntry::All;
+    }
+    if let Some(suffix) = entry.strip_prefix("*.") {
+        return NoProxyEntry::DomainSuffix(format!(".{}", suffix));
+    }
+    if entry.starts_with('.') {
+        return NoProxyEntry::DomainSuffix(entry.to_string());
+    }
+    if let Some((network, prefix)) = entry.split_once('/') {
+        if let (Ok(network), Ok(prefix)) = (network.parse::<IpAddr>(), prefix.parse::<u8>()) {
+            return NoProxyEntry::Cidr { network, prefix };
+        }
+    }
+    if let Ok(ip) = entry.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>() {
+        let prefix = if ip.is_ipv4() { 32 } else { 128 };
+        return NoProxyEntry::Cidr { network: ip, prefix };
+    }
+    match entry.rsplit_once(':') {
+        Some((host, port)) if port.parse::<u16>().is_ok() => NoProxyEntry::Host {
+            host: host.to_string(),
+            port: port.parse().ok(),
+        },
+        _ => NoProxyEntry::Host { host: entry.to_string(), port: None },
+    }
+}
+
+/// Returns whether an address lies within a network of the given prefix length.
+fn cidr_contains(network: &IpAddr, prefix: u8, ip: &IpAddr) -> bool {
+    match (network, ip) {
+        (IpAddr::V4(network), IpAddr::V4(ip)) => {
+            let prefix = prefix.min(32);
+            let mask = if prefix == 0 { 0 } else { u32::MAX << (32 - prefix) };
+            u32::from(*network) & mask == u32::from(*ip) & mask
+        }
+        (IpAddr::V6(network), IpAddr::V6(ip)) => {
+            let prefix = prefix.min(128);
+            let mask = if prefix == 0 { 0 } else { u128::MAX << (128 - prefix) };
+            u128::from(*network) & mask == u128::from(*ip) & mask
+        }
+        _ => false,
+    }
+}
+
+/// A minimal HTTP server on localhost, serving fixed files with ETag and
+/// Range support, for tests of code that fetches over the network.
+#[cfg(test)]
+pub(crate) mod test_server {
+    use std::collections::HashMap;
+    use std::io::{self, BufRead, BufReader, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    use reqwest::blocking::Client;
+
+    use super::NetworkClient;
+    use crate::auth::AuthStore;
+
+    /// A request the server received, with lowercase header names.
+    #[derive(Debug, Clone)]
+    pub(crate) struct Request {
+        pub path: String,
+        pub headers: HashMap<String, String>,
+    }
+
+    struct File {
+        body: Vec<u8>,
+        etag: Option<String>,
+    }
+
+    #[derive(Default)]
+    struct State {
+        files: HashMap<String, File>,
+        requests: Vec<Request>,
+    }
+
+    pub(crate) struct TestServer {
+        base_url: String,
+        state: Arc<Mutex<State>>,
+    }
+
+    impl TestServer {
+        /// Starts a server on a free port. Its thread lives as long as the test process.
+        pub fn start() -> Self {
+            let listener = TcpListener::bind("127.0.0.1:0").expect("bind a local port");
+            let base_url = format!("http://{}", listener.local_addr().expect("local address"));
+            let state = Arc::new(Mutex::new(State::default()));
+            let shared = Arc::clone(&state);
+            thread::spawn(move || {
+                for stream in listener.incoming().flatten() {
+                    if let Err(e) = handle(stream, &shared) {
+                        log::warn!("test server: {}", e);
+                    }
+                }
+            });
+            TestServer { base_url, state }
+        }
+
+        /// Returns the URL of a path on the server.
+        pub fn url(&self, path: &str) -> String {
+            format!("{}{}", self.base_url, path)
+        }
+
+        /// Serves `body` at `path`, replacing whatever was served there.
+        pub fn serve(&self, path: &str, body: impl Into<Vec<u8>>, etag: Option<&str>) {
+            let file = File { body: body.into(), etag: etag.map(String::from) };
+            self.state.lock().unwrap().files.insert(path.to_string(), file);
+        }
+
+        /// Returns and forgets the requests received so far.
+        pub fn take_requests(&self) -> Vec<Request> {
+            std::mem::take(&mut self.state.lock().unwrap().requests)
+        }
+
+        /// Returns a client without retries or authentication.
+        pub fn client(&self) -> NetworkClient {
+            NetworkClient::new(Client::new(), AuthStore::default())
+        }
+    }
+
+    fn handle(stream: TcpStream, state: &Mutex<State>) -> io::Result<()> {
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut request_line = String::new();
+        reader.read_line(&mut request_line)?;
+        let path = request_line.split_whitespace().nth(1).unwrap_or("/").to_string();
+
+        let mut headers = HashMap::new();
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line)? == 0 || line.trim().is_empty() {
+                break;
+            }
+            if let Some((name, value)) = line.split_once(':') {
+                headers.insert(name.trim().to_lowercase(), value.trim().to_string());
+            }
+        }
+
+        let (status, mut extra, body) = {
+            let mut state = state.lock().unwrap();
+            state.requests.push(Request { path: path.clone(), headers: headers.clone() });
+            respond(state.files.get(&path), &headers)
+        };
+
+        let mut stream = stream;
+        extra.push(format!("Content-Length: {}", body.len()));
+        extra.push("Connection: close".to_string());
+        write!(stream, "HTTP/1.1 {}\r\n", status)?;
+        for header in extra {
+            write!(stream, "{}\r\n", header)?;
+        }
+        write!(stream, "\r\n")?;
+        stream.write_all(&body)?;
+        stream.flush()
+    }
+
+    fn respond(file: Option<&File>, headers: &HashMap<String, String>) -> (&'static str, Vec<String>, Vec<u8>) {
+        let file = match file {
+            Some(file) => file,
+            None => return ("404 Not Found", Vec::new(), Vec::new()),
+        };
+        let mut extra: Vec<String> = file.etag.iter().map(|etag| format!("ETag: {}", etag)).collect();
+
+        if file.etag.is_some() && headers.get("if-none-match") == file.etag.as_ref() {
+            return ("304 Not Modified", extra, Vec::new());
+        }
+
+        let start = headers
+            .get("range")
+            .and_then(|range| range.strip_prefix("bytes="))
+            .and_then(|range| range.strip_suffix('-'))
+            .and_then(|start| start.parse::<usize>().ok());
+        match start {
+            Some(start) if start >= file.body.len() => {
+                extra.push(format!("Content-Range: bytes */{}", file.body.len()));
+                ("416 Range Not Satisfiable", extra, Vec::new())
+            }
+            Some(start) => {
+                extra.push(format!("Content-Range: bytes {}-{}/{}", start, file.body.len() - 1, file.body.len()));
+                ("206 Partial Content", extra, file.body[start..].to_vec())
+            }
+            None => ("200 OK", extra, file.body.clone()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn no_proxy_matches_hosts_domains_and_networks() {
+        let cases = [
+            (".example.com", "https://example.com/", true),
+            (".example.com", "https://repo.example.com/", true),
+            (".example.com", "https://notexample.com/", false),
+            ("*.example.com", "https://repo.example.com/", true),
+            ("*", "https://anything.test/", true),
+            ("10.0.0.0/8", "http://10.1.2.3/", true),
+            ("10.0.0.0/8", "http://11.0.0.1/", false),
+            ("fd00::/8", "http://[fd12::1]/", true),
+            ("fd00::/8", "http://[fe80::1]/", false),
+            ("internal.test:8080", "http://internal.test:8080/", true),
+            ("internal.test:8080", "http://internal.test/", false),
+            ("internal.test", "http://internal.test:8080/", true),
+        ];
+        for &(entry, url, expected) in &cases {
+            let no_proxy = NoProxy::new(&[entry]);
+            assert_eq!(no_proxy.matches(&Url::parse(url).unwrap()), expected, "{} against {}", entry, url);
+        }
+    }
+
+    #[test]
+    fn parse_no_proxy_entry_recognizes_each_form() {
+        let cases = [
+            ("*", "All"),
+            ("*.example.com", r#"DomainSuffix(".example.com")"#),
+            (".example.com", r#"DomainSuffix(".example.com")"#),
+            ("10.0.0.0/8", "Cidr { network: 10.0.0.0, prefix: 8 }"),
+            ("fd00::/8", "Cidr { network: fd00::, prefix: 8 }"),
+            ("[::1]", "Cidr { network: ::1, prefix: 128 }"),
+            ("internal.test:8080", r#"Host { host: "internal.test", port: Some(8080) }"#),
+            ("internal.test", r#"Host { host: "internal.test", port: None }"#),
+        ];
+        for &(entry, expected) in &cases {
+            assert_eq!(format!("{:?}", parse_no_proxy_entry(entry)), expected, "{}", entry);
+        }
+    }
+
+    #[test]
+    fn cidr_contains_masks_by_prefix() {
+        let ip = |ip: &str| ip.parse::<IpAddr>().unwrap();
+        let cases = [
+            ("192.168.0.0", 16, "192.168.255.1", true),
+            ("192.168.0.0", 24, "192.168.1.1", false),
+            ("0.0.0.0", 0, "203.0.113.7", true),
+            ("2001:db8::", 32, "2001:db8:ffff::1", true),
+            ("2001:db8::", 48, "2001:db8:1::1", false),
+            ("10.0.0.0", 8, "::ffff:10.0.0.1", false),
+        ];
+        for &(network, prefix, address, expected) in &cases {
+            assert_eq!(cidr_contains(&ip(network), prefix, &ip(address)), expected, "{} in {}/{}", address, network, prefix);
+        }
+    }
+}
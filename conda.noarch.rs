@@ -0,0 +1,290 @@
+// conda.noarch.rs
+
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Component, Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::str::FromStr;
+
+use thiserror::Error;
+
+/// Represents possible errors that can occur when installing `noarch: python` packages.
+#[derive(Error, Debug)]
+pub enum NoarchError {
+    #[error("{0} is a noarch: python package, but the environment has no python installed")]
+    MissingPython(String),
+
+    #[error("Cannot determine the Python version from {0}")]
+    InvalidPythonVersion(String),
+
+    #[error("Invalid entry point {0}")]
+    InvalidEntryPoint(String),
+
+    #[error("Failed to write {path}: {source}")]
+    IoError { path: PathBuf, source: io::Error },
+
+    #[error("Failed to compile bytecode with {python}: {source}")]
+    CompileError { python: PathBuf, source: io::Error },
+}
+
+/// Represents the Python of an environment, which decides where the files of
+/// `noarch: python` packages go.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PythonInfo {
+    pub major: u64,
+    pub minor: u64,
+    windows: bool,
+}
+
+impl PythonInfo {
+    /// Creates the info from a python record version such as `3.11.4`.
+    pub fn from_version(version: &str, windows: bool) -> Result<Self, NoarchError> {
+        let mut parts = version.split('.').map(|part| {
+            let digits: String = part.chars().take_while(char::is_ascii_digit).collect();
+            digits.parse::<u64>().ok()
+        });
+        match (parts.next().flatten(), parts.next().flatten()) {
+            (Some(major), Some(minor)) => Ok(PythonInfo { major, minor, windows }),
+            _ => Err(NoarchError::InvalidPythonVersion(version.to_string())),
+        }
+    }
+
+    /// Returns the `site-packages` directory relative to the prefix.
+    pub fn site_packages(&self) -> PathBuf {
+        if self.windows {
+            PathBuf::from("Lib").join("site-packages")
+        } else {
+            PathBuf::from("lib").join(format!("python{}.{}", self.major, self.minor)).join("site-packages")
+        }
+    }
+
+    /// Returns the scripts directory relative to the prefix.
+    pub fn bin_dir(&self) -> PathBuf {
+        PathBuf::from(if self.windows { "Scripts" } else { "bin" })
+    }
+
+    /// Returns the interpreter relative to the prefix.
+    pub fn interpreter(&self) -> PathBuf {
+        if self.windows {
+            PathBuf::from("python.exe")
+        } else {
+            PathBuf::from("bin").join(format!("python{}.{}", self.major, self.minor))
+        }
+    }
+
+    /// Returns where the script of an entry point goes, relative to the prefix.
+    pub fn entry_point_path(&self, command: &str) -> PathBuf {
+        if self.windows {
+            self.bin_dir().join(format!("{}-script.py", command))
+        } else {
+            self.bin_dir().join(command)
+        }
+    }
+
+    /// Returns where the launcher of an entry point goes, relative to the
+    /// prefix. Only Windows needs one, to run the `-script.py` file.
+    pub fn entry_point_launcher_path(&self, command: &str) -> Option<PathBuf> {
+        self.windows.then(|| self.bin_dir().join(format!("{}.exe", command)))
+    }
+
+    /// Maps a path inside a `noarch: python` package to where it is installed:
+    /// `site-packages/` into the environment's site-packages and
+    /// `python-scripts/` into its scripts directory.
+    pub fn target_path(&self, path: &Path) -> PathBuf {
+        if let Ok(rest) = path.strip_prefix("site-packages") {
+            self.site_packages().join(rest)
+        } else if let Ok(rest) = path.strip_prefix("python-scripts") {
+            self.bin_dir().join(rest)
+        } else {
+            path.to_path_buf()
+        }
+    }
+
+    /// Returns where Python writes the bytecode of a source file.
+    pub fn pyc_path(&self, py_path: &Path) -> PathBuf {
+        if self.major < 3 {
+            return py_path.with_extension("pyc");
+        }
+        let stem = py_path.file_stem().and_then(|s| s.to_str()).unwrap_or_default();
+        let file_name = format!("{}.cpython-{}{}.pyc", stem, self.major, self.minor);
+        match py_path.parent() {
+            Some(parent) => parent.join("__pycache__").join(file_name),
+            None => PathBuf::from("__pycache__").join(file_name),
+        }
+    }
+}
+
+/// Represents a console script declared as `name = module:function`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EntryPoint {
+    pub command: String,
+    pub module: String,
+    pub function: String,
+}
+
+impl FromStr for EntryPoint {
+    type Err = NoarchError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || NoarchError::InvalidEntryPoint(s.to_string());
+        let (command, target) = s.split_once('=').ok_or_else(invalid)?;
+        let (module, function) = target.split_once(':').ok_or_else(invalid)?;
+        let (command, module, function) = (command.trim(), module.trim(), function.trim());
+
+        // Only a plain file name is allowed, so the script stays in the scripts directory.
+        let mut components = Path::new(command).components();
+        if !matches!((components.next(), components.next()), (Some(Component::Normal(_)), None)) || module.is_empty() || function.is_empty() {
+            return Err(invalid());
+        }
+        Ok(EntryPoint {
+            command: command.to_string(),
+            module: module.to_string(),
+            function: function.to_string(),
+        })
+    }
+}
+
+impl EntryPoint {
+    /// Returns the Python source of the script, in the form conda generates.
+    pub fn script(&self) -> String {
+        let import_name = self.function.split('.').next().unwrap_or(&self.function);
+        format!(
+            "# -*- coding: utf-8 -*-\n\
+             import re\n\
+             import sys\n\
+             \n\
+             from {} import {}\n\
+             \n\
+             if __name__ == '__main__':\n    \
+                 sys.argv[0] = re.sub(r'(-script\\.pyw?|\\.exe)?$', '', sys.argv[0])\n    \
+                 sys.exit({}())\n",
+            self.module, import_name, self.function
+        )
+    }
+}
+
+/// Writes the script of an entry point, returning its path relative to the
+/// prefix. On Unix this is an executable with a shebang for the prefix's
+/// Python; on Windows it is `<name>-script.py`, which needs the `<name>.exe`
+/// launcher written by [`write_entry_point_launcher`] to be run by name.
+pub fn write_entry_point(prefix: &Path, python: &PythonInfo, entry_point: &EntryPoint) -> Result<PathBuf, NoarchError> {
+    let relative = python.entry_point_path(&entry_point.command);
+    let path = prefix.join(&relative);
+    let io_error = |source| NoarchError::IoError { path: path.clone(), source };
+
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).map_err(io_error)?;
+    }
+    let contents = if python.windows {
+        entry_point.script()
+    } else {
+        format!("#!{}\n{}", shebang_interpreter(prefix, python), entry_point.script())
+    };
+    fs::write(&path, contents).map_err(io_error)?;
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).map_err(io_error)?;
+    }
+    Ok(relative)
+}
+
+/// Copies the launcher that runs an entry point's `<name>-script.py` to
+/// `Scripts\<name>.exe`, returning its path relative to the prefix. The
+/// launcher, `cli-64.exe` or its equivalent for the architecture, is taken
+/// from the setuptools or conda installed in the prefix. Returns `None` on
+/// Unix, and with a warning when the prefix has no launcher, in which case
+/// the script can still be run with `python Scripts\<name>-script.py`.
+pub fn write_entry_point_launcher(prefix: &Path, python: &PythonInfo, entry_point: &EntryPoint) -> Result<Option<PathBuf>, NoarchError> {
+    let relative = match python.entry_point_launcher_path(&entry_point.command) {
+        Some(relative) => relative,
+        None => return Ok(None),
+    };
+    let launcher = match find_launcher(prefix, python) {
+        Some(launcher) => launcher,
+        None => {
+            log::warn!(
+                "No {} in {} to launch the {} entry point; run it with python {}",
+                LAUNCHER,
+                prefix.display(),
+                entry_point.command,
+                python.entry_point_path(&entry_point.command).display()
+            );
+            return Ok(None);
+        }
+    };
+
+    let path = prefix.join(&relative);
+    fs::copy(&launcher, &path).map_err(|source| NoarchError::IoError { path, source })?;
+    Ok(Some(relative))
+}
+
+/// The launcher that runs `<name>-script.py` as `<name>.exe`.
+#[cfg(target_arch = "aarch64")]
+const LAUNCHER: &str = "cli-arm64.exe";
+#[cfg(target_arch = "x86")]
+const LAUNCHER: &str = "cli-32.exe";
+#[cfg(not(any(target_arch = "aarch64", target_arch = "x86")))]
+const LAUNCHER: &str = "cli-64.exe";
+
+/// Finds the entry point launcher shipped by setuptools or conda in the prefix.
+fn find_launcher(prefix: &Path, python: &PythonInfo) -> Option<PathBuf> {
+    let site_packages = prefix.join(python.site_packages());
+    [site_packages.join("setuptools"), site_packages.join("conda").join("shell")]
+        .iter()
+        .map(|dir| dir.join(LAUNCHER))
+        .find(|launcher| launcher.is_file())
+}
+
+/// Returns the interpreter for a shebang line. Kernels truncate long shebangs,
+/// so deep prefixes go through `/usr/bin/env` instead.
+fn shebang_interpreter(prefix: &Path, python: &PythonInfo) -> String {
+    let interpreter = prefix.join(python.interpreter()).display().to_string();
+    if interpreter.len() > 125 || interpreter.contains(' ') {
+        format!("/usr/bin/env python{}.{}", python.major, python.minor)
+    } else {
+        interpreter
+    }
+}
+
+/// Compiles the given source files, relative to the prefix, with the
+/// prefix's Python, and returns the relative paths of the bytecode files
+/// that were written. Files Python fails to compile are skipped.
+pub fn compile_pyc(prefix: &Path, python: &PythonInfo, py_files: &[PathBuf]) -> Result<Vec<PathBuf>, NoarchError> {
+    if py_files.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let interpreter = prefix.join(python.interpreter());
+    let compile_error = |source| NoarchError::CompileError { python: interpreter.clone(), source };
+    let mut child = Command::new(&interpreter)
+        .args(["-Wi", "-m", "compileall", "-q", "-l", "-i", "-"])
+        .current_dir(prefix)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(compile_error)?;
+
+    {
+        let stdin = child.stdin.as_mut().expect("stdin is piped");
+        for file in py_files {
+            writeln!(stdin, "{}", file.display()).map_err(compile_error)?;
+        }
+    }
+    let output = child.wait_with_output().map_err(compile_error)?;
+    if !output.status.success() {
+        log::warn!(
+            "Some files could not be compiled by {}: {}",
+            interpreter.display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+
+    Ok(py_files
+        .iter()
+        .map(|file| python.pyc_path(file))
+        .filter(|pyc| prefix.join(pyc).is_file())
+        .collect())
+}
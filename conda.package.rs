@@ -0,0 +1,271 @@
+// conda.package.rs
+
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+
+use crate::digest::FileDigests;
+use crate::repodata::PackageRecord;
+
+/// Represents the `info/index.json` file of a package.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct IndexJson {
+    pub name: String,
+    pub version: String,
+    pub build: String,
+    #[serde(default)]
+    pub build_number: u64,
+    #[serde(default)]
+    pub depends: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub constrains: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub subdir: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub arch: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub platform: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub noarch: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub license: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub license_family: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub track_features: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub features: Vec<String>,
+}
+
+impl IndexJson {
+    /// Builds the repodata record of a package file from its index and digests.
+    pub fn to_record(&self, subdir: &str, digests: &FileDigests) -> PackageRecord {
+        PackageRecord {
+            name: self.name.clone(),
+            version: self.version.clone(),
+            build: self.build.clone(),
+            build_number: self.build_number,
+            depends: self.depends.clone(),
+            constrains: self.constrains.clone(),
+            subdir: self.subdir.clone().unwrap_or_else(|| subdir.to_string()),
+            noarch: self.noarch.clone(),
+            md5: Some(digests.md5.clone()),
+            sha256: Some(digests.sha256.clone()),
+            size: Some(digests.size),
+            timestamp: self.timestamp,
+            license: self.license.clone(),
+            license_family: self.license_family.clone(),
+            track_features: self.track_features.clone(),
+            features: self.features.clone(),
+            revoked: false,
+        }
+    }
+}
+
+/// The placeholder conda-build embeds when `info/has_prefix` gives none.
+pub const DEFAULT_PREFIX_PLACEHOLDER: &str = "/opt/anaconda1anaconda2anaconda3";
+
+/// Represents how a file is placed into a prefix.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PathType {
+    #[serde(rename = "hardlink")]
+    HardLink,
+    #[serde(rename = "softlink")]
+    SoftLink,
+    Directory,
+    /// Bytecode compiled at install time for a `noarch: python` package.
+    PycFile,
+    /// A `bin/` script generated from a `noarch: python` entry point.
+    UnixPythonEntryPoint,
+    /// A `Scripts\<name>-script.py` generated from a `noarch: python` entry point.
+    WindowsPythonEntryPointScript,
+    /// The `Scripts\<name>.exe` launcher running a `<name>-script.py`.
+    WindowsPythonEntryPointExe,
+}
+
+/// Represents how the prefix placeholder in a file is replaced.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FileMode {
+    Text,
+    Binary,
+}
+
+/// Represents one file entry of `info/paths.json`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PathsEntry {
+    #[serde(rename = "_path")]
+    pub path: PathBuf,
+    pub path_type: PathType,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub size_in_bytes: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file_mode: Option<FileMode>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prefix_placeholder: Option<String>,
+
+    /// Whether the file must be copied rather than linked.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub no_link: bool,
+}
+
+/// Represents the `info/paths.json` file of a package.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PathsJson {
+    pub paths: Vec<PathsEntry>,
+    #[serde(default = "default_paths_version")]
+    pub paths_version: u64,
+}
+
+fn default_paths_version() -> u64 {
+    1
+}
+
+/// Represents the `info/about.json` file of a package.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct AboutJson {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub summary: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub home: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dev_url: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub doc_url: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub license: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub license_family: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub channels: Vec<String>,
+
+    /// Free-form recipe metadata, such as maintainers.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub extra: Option<serde_json::Value>,
+}
+
+/// Represents the `info/run_exports.json` file of a package.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RunExportsJson {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub weak: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub strong: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub noarch: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub weak_constrains: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub strong_constrains: Vec<String>,
+}
+
+/// Represents one line of `info/has_prefix`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HasPrefixEntry {
+    pub placeholder: String,
+    pub file_mode: FileMode,
+    pub path: PathBuf,
+}
+
+/// Parses `info/has_prefix`, whose lines are either `<path>` or
+/// `<placeholder> <text|binary> <path>`, optionally quoted.
+pub fn parse_has_prefix(contents: &str) -> Vec<HasPrefixEntry> {
+    contents
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .filter_map(|line| {
+            let fields = split_quoted(line);
+            match fields.as_slice() {
+                [path] => Some(HasPrefixEntry {
+                    placeholder: DEFAULT_PREFIX_PLACEHOLDER.to_string(),
+                    file_mode: FileMode::Text,
+                    path: PathBuf::from(path),
+                }),
+                [placeholder, mode, path] => Some(HasPrefixEntry {
+                    placeholder: placeholder.clone(),
+                    file_mode: if mode == "binary" { FileMode::Binary } else { FileMode::Text },
+                    path: PathBuf::from(path),
+                }),
+                _ => {
+                    log::warn!("Ignoring malformed has_prefix line: {}", line);
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+/// Splits a line on whitespace, keeping double-quoted fields together.
+fn split_quoted(line: &str) -> Vec<String> {
+    let mut fields = Vec::new();
+    let mut current = String::new();
+    let mut quoted = false;
+    for c in line.trim().chars() {
+        match c {
+            '"' => quoted = !quoted,
+            c if c.is_whitespace() && !quoted => {
+                if !current.is_empty() {
+                    fields.push(std::mem::take(&mut current));
+                }
+            }
+            c => current.push(c),
+        }
+    }
+    if !current.is_empty() {
+        fields.push(current);
+    }
+    fields
+}
+
+impl PathsJson {
+    /// Builds the paths of an old package that has no `info/paths.json` from
+    /// its `info/files` and `info/has_prefix`.
+    pub fn from_legacy(files: &str, has_prefix: &[HasPrefixEntry], no_link: &[PathBuf]) -> Self {
+        let paths = files
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .map(|line| {
+                let path = PathBuf::from(line);
+                let prefix = has_prefix.iter().find(|entry| entry.path == path);
+                PathsEntry {
+                    path_type: PathType::HardLink,
+                    sha256: None,
+                    size_in_bytes: None,
+                    file_mode: prefix.map(|entry| entry.file_mode),
+                    prefix_placeholder: prefix.map(|entry| entry.placeholder.clone()),
+                    no_link: no_link.contains(&path),
+                    path,
+                }
+            })
+            .collect();
+        PathsJson { paths, paths_version: 1 }
+    }
+}
+
+/// Represents the `info/link.json` file of a `noarch` package.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LinkJson {
+    #[serde(default)]
+    pub noarch: NoarchLinks,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub package_metadata_version: Option<u64>,
+}
+
+/// Represents the `noarch` section of `info/link.json`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NoarchLinks {
+    #[serde(default, rename = "type")]
+    pub noarch_type: String,
+
+    /// Console scripts as `name = module:function`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub entry_points: Vec<String>,
+}
@@ -0,0 +1,266 @@
+// conda.patch.rs
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use thiserror::Error;
+
+use crate::channel::{Channel, Platform};
+use crate::repodata::{PackageRecord, RepoData};
+
+/// The dependency conda adds to revoked packages so they can never be installed.
+pub const REVOKED_DEPENDENCY: &str = "package_has_been_revoked";
+
+/// The name of the patch file in a channel subdir.
+pub const PATCH_INSTRUCTIONS_FILE: &str = "patch_instructions.json";
+
+/// Represents possible errors that can occur when applying repodata patches.
+#[derive(Error, Debug)]
+pub enum PatchError {
+    #[error("Failed to read {path}: {source}")]
+    IoError { path: PathBuf, source: io::Error },
+
+    #[error("Invalid patch instructions {path}: {source}")]
+    ParseError { path: PathBuf, source: serde_json::Error },
+
+    #[error("Patch for {filename} produces an invalid record: {source}")]
+    InvalidPatch { filename: String, source: serde_json::Error },
+}
+
+/// Represents a `patch_instructions.json` file, as produced by channel patch
+/// generators.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct PatchInstructions {
+    #[serde(default = "default_patch_version")]
+    pub patch_instructions_version: u64,
+
+    /// Field overrides for `.tar.bz2` records, keyed by filename. A `null`
+    /// value removes the field. Unless overridden in `packages.conda`, they
+    /// also apply to the `.conda` file of the same package.
+    #[serde(default)]
+    pub packages: BTreeMap<String, Map<String, Value>>,
+
+    /// Field overrides for `.conda` records, keyed by filename.
+    #[serde(default, rename = "packages.conda")]
+    pub conda_packages: BTreeMap<String, Map<String, Value>>,
+
+    /// Filenames to drop from the repodata.
+    #[serde(default)]
+    pub remove: Vec<String>,
+
+    /// Filenames to keep listed but make uninstallable.
+    #[serde(default)]
+    pub revoke: Vec<String>,
+}
+
+fn default_patch_version() -> u64 {
+    1
+}
+
+/// Represents what a patch did to one record.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PatchAction {
+    /// The named fields were changed.
+    Modified(Vec<String>),
+    Removed,
+    Revoked,
+}
+
+/// Represents a change made to one record.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PatchChange {
+    pub filename: String,
+    pub action: PatchAction,
+}
+
+impl fmt::Display for PatchChange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.action {
+            PatchAction::Modified(fields) => write!(f, "{}: patched {}", self.filename, fields.join(", ")),
+            PatchAction::Removed => write!(f, "{}: removed", self.filename),
+            PatchAction::Revoked => write!(f, "{}: revoked", self.filename),
+        }
+    }
+}
+
+/// Represents the result of applying patch instructions to repodata.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct PatchReport {
+    pub changes: Vec<PatchChange>,
+
+    /// Filenames named by the instructions that the repodata does not contain.
+    pub missing: Vec<String>,
+}
+
+impl PatchReport {
+    /// Returns whether the patch changed anything.
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+}
+
+impl PatchInstructions {
+    /// Loads patch instructions, returning `None` if the file does not exist.
+    pub fn load(path: &Path) -> Result<Option<Self>, PatchError> {
+        let contents = match fs::read(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(source) => return Err(PatchError::IoError { path: path.to_path_buf(), source }),
+        };
+        serde_json::from_slice(&contents)
+            .map(Some)
+            .map_err(|source| PatchError::ParseError { path: path.to_path_buf(), source })
+    }
+
+    /// Applies the instructions to repodata in place: field overrides first,
+    /// then revocations, then removals.
+    pub fn apply(&self, repodata: &mut RepoData) -> Result<PatchReport, PatchError> {
+        let mut report = PatchReport::default();
+
+        for (filename, patch) in &self.packages {
+            let mut found = false;
+            if let Some(record) = repodata.packages.get_mut(filename) {
+                found = true;
+                record_change(&mut report, filename, patch_record(filename, record, patch)?);
+            }
+            if let Some(conda_filename) = conda_twin(filename) {
+                if !self.conda_packages.contains_key(&conda_filename) {
+                    if let Some(record) = repodata.conda_packages.get_mut(&conda_filename) {
+                        found = true;
+                        record_change(&mut report, &conda_filename, patch_record(&conda_filename, record, patch)?);
+                    }
+                }
+            }
+            if !found {
+                report.missing.push(filename.clone());
+            }
+        }
+
+        for (filename, patch) in &self.conda_packages {
+            match repodata.conda_packages.get_mut(filename) {
+                Some(record) => record_change(&mut report, filename, patch_record(filename, record, patch)?),
+                None => report.missing.push(filename.clone()),
+            }
+        }
+
+        for filename in &self.revoke {
+            let mut found = false;
+            for filename in with_twin(filename) {
+                if let Some(record) = find_record(repodata, &filename) {
+                    found = true;
+                    if revoke(record) {
+                        report.changes.push(PatchChange { filename, action: PatchAction::Revoked });
+                    }
+                }
+            }
+            if !found {
+                report.missing.push(filename.clone());
+            }
+        }
+
+        for filename in &self.remove {
+            let mut found = false;
+            for filename in with_twin(filename) {
+                let removed = repodata
+                    .packages
+                    .remove(&filename)
+                    .or_else(|| repodata.conda_packages.remove(&filename));
+                if removed.is_some() {
+                    found = true;
+                    if !repodata.removed.contains(&filename) {
+                        repodata.removed.push(filename.clone());
+                    }
+                    report.changes.push(PatchChange { filename, action: PatchAction::Removed });
+                }
+            }
+            if !found && !repodata.removed.contains(filename) {
+                report.missing.push(filename.clone());
+            }
+        }
+
+        repodata.removed.sort();
+        report.missing.sort();
+        report.missing.dedup();
+        Ok(report)
+    }
+}
+
+/// Returns where locally maintained hotfixes for a channel subdir live under
+/// a patch directory: `<dir>/<channel>/<subdir>/patch_instructions.json`.
+pub fn hotfix_path(dir: &Path, channel: &Channel, platform: Platform) -> PathBuf {
+    let channel_dir: String = channel
+        .canonical_name()
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || "-_.".contains(c) { c } else { '_' })
+        .collect();
+    dir.join(channel_dir).join(platform.as_str()).join(PATCH_INSTRUCTIONS_FILE)
+}
+
+/// Overrides record fields with the patch values, returning the changed fields.
+fn patch_record(filename: &str, record: &mut PackageRecord, patch: &Map<String, Value>) -> Result<Vec<String>, PatchError> {
+    let invalid = |source| PatchError::InvalidPatch { filename: filename.to_string(), source };
+    let mut value = serde_json::to_value(&*record).map_err(invalid)?;
+    let fields = value.as_object_mut().expect("records serialize to objects");
+
+    let mut changed = Vec::new();
+    for (key, new_value) in patch {
+        let differs = match new_value {
+            Value::Null => fields.remove(key).is_some(),
+            _ => fields.insert(key.clone(), new_value.clone()).as_ref() != Some(new_value),
+        };
+        if differs {
+            changed.push(key.clone());
+        }
+    }
+
+    if !changed.is_empty() {
+        *record = serde_json::from_value(value).map_err(invalid)?;
+    }
+    Ok(changed)
+}
+
+fn record_change(report: &mut PatchReport, filename: &str, fields: Vec<String>) {
+    if !fields.is_empty() {
+        report.changes.push(PatchChange {
+            filename: filename.to_string(),
+            action: PatchAction::Modified(fields),
+        });
+    }
+}
+
+/// Marks a record revoked, returning whether it was not already.
+fn revoke(record: &mut PackageRecord) -> bool {
+    if record.revoked {
+        return false;
+    }
+    record.revoked = true;
+    if !record.depends.iter().any(|d| d == REVOKED_DEPENDENCY) {
+        record.depends.push(REVOKED_DEPENDENCY.to_string());
+    }
+    true
+}
+
+fn find_record<'a>(repodata: &'a mut RepoData, filename: &str) -> Option<&'a mut PackageRecord> {
+    if repodata.packages.contains_key(filename) {
+        repodata.packages.get_mut(filename)
+    } else {
+        repodata.conda_packages.get_mut(filename)
+    }
+}
+
+/// Returns the `.conda` filename of a `.tar.bz2` package.
+fn conda_twin(filename: &str) -> Option<String> {
+    filename.strip_suffix(".tar.bz2").map(|stem| format!("{}.conda", stem))
+}
+
+fn with_twin(filename: &str) -> Vec<String> {
+    let mut filenames = vec![filename.to_string()];
+    filenames.extend(conda_twin(filename));
+    filenames
+}
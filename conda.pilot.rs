@@ -4,14 +4,43 @@
 use std::collections::HashMap;
 use std::error::Error;
 use std::fs::{self, File};
-use std::io::{self, Read, Write};
+use std::io::{self, Read};
 use std::path::{Path, PathBuf};
 use std::process::Command;
+use std::sync::Arc;
+use std::time::Duration;
 
+use once_cell::sync::OnceCell;
+use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
-use reqwest;
 use semver::Version;
 use toml;
+use url::Url;
+
+use crate::archive::ArchiveType;
+use crate::auth::{AuthStore, Authentication};
+use crate::build::PackageBuilder;
+use crate::cache::{CacheManager, CacheUsage, CleanReport, CleanTarget};
+use crate::channel::{Channel, ChannelConfig, Platform};
+use crate::download::{DownloadError, DownloadRequest, DownloadScheduler};
+use crate::envfile::{EnvironmentFile, ExportMode};
+use crate::index::{ChannelIndexer, IndexReport};
+use crate::inspect::{self, PackageInspection};
+use crate::link::{self, LinkOptions, Linker};
+use crate::lock::{self, FileLock, LockEvent, LockProgress, Locker};
+use crate::matchspec::MatchSpec;
+use crate::mirror::{Mirror, MirrorReport};
+use crate::network::{NetworkClient, NetworkError};
+use crate::patch::{self, PatchError, PatchInstructions};
+use crate::pkgs::{CacheVerification, ExtractedPackage, PackageCache, PackageCaches};
+use crate::prefix::{self, PrefixRecord};
+use crate::query::{self, WhoNeeds};
+use crate::settings::CondaSettings;
+use crate::repodata::{self, PackageRecord, RepoData, RepodataCache, RepodataRecord, SubdirRepodata};
+use crate::shards::{self, ShardedSubdir};
+use crate::signing::{SigningError, SigningKey, TrustStore};
+use crate::transmute::{TransmuteReport, Transmuter};
+use crate::updates::{self, SubdirFailure, UpdateReport};
 
 /// Represents a Conda package
 #[derive(Debug, Serialize, Deserialize)]
@@ -34,6 +63,12 @@ struct CondaEnvironment {
 pub struct CondaPackageManager {
     environments: HashMap<String, CondaEnvironment>,
     config: CondaConfig,
+    settings: CondaSettings,
+    auth: AuthStore,
+    trust: TrustStore,
+    client: OnceCell<NetworkClient>,
+    scheduler: OnceCell<DownloadScheduler>,
+    lock_progress: Option<LockProgress>,
 }
 
 /// Configuration for the Conda package manager
@@ -42,6 +77,17 @@ struct CondaConfig {
     default_channel: String,
     custom_channels: Vec<String>,
     cache_dir: PathBuf,
+    #[serde(default)]
+    channel_alias: Option<String>,
+    #[serde(default)]
+    default_channels: Option<Vec<String>>,
+    #[serde(default)]
+    channel_locations: HashMap<String, String>,
+    #[serde(default)]
+    custom_multichannels: HashMap<String, Vec<String>>,
+    /// Directory of local repodata hotfixes, laid out as `<channel>/<subdir>/patch_instructions.json`
+    #[serde(default)]
+    repodata_patch_dir: Option<PathBuf>,
 }
 
 impl CondaPackageManager {
@@ -49,10 +95,25 @@ impl CondaPackageManager {
     pub fn new() -> Result<Self, Box<dyn Error>> {
         let config = Self::load_config()?;
         let environments = Self::discover_environments(&config)?;
+        let settings = Self::load_settings()?;
+        let auth = match AuthStore::default_path() {
+            Some(path) => AuthStore::load(&path)?,
+            None => AuthStore::default(),
+        };
+        let trust = match TrustStore::default_path() {
+            Some(path) => TrustStore::load(&path)?,
+            None => TrustStore::default(),
+        };
 
         Ok(CondaPackageManager {
             environments,
             config,
+            settings,
+            auth,
+            trust,
+            client: OnceCell::new(),
+            scheduler: OnceCell::new(),
+            lock_progress: None,
         })
     }
 
@@ -70,6 +131,44 @@ impl CondaPackageManager {
         Ok(config)
     }
 
+    /// Get the path of the settings file
+    fn settings_path() -> Result<PathBuf, Box<dyn Error>> {
+        Ok(dirs::home_dir()
+            .ok_or("Unable to determine home directory")?
+            .join(".conda")
+            .join("settings.toml"))
+    }
+
+    /// Get the conda.toml to read settings sections from: `$CONDA_TOML`, then
+    /// conda.toml in the working directory, then `~/.conda/conda.toml`
+    fn conda_toml_path() -> Option<PathBuf> {
+        if let Some(path) = std::env::var_os("CONDA_TOML") {
+            return Some(PathBuf::from(path));
+        }
+        let local = PathBuf::from("conda.toml");
+        if local.is_file() {
+            return Some(local);
+        }
+        dirs::home_dir()
+            .map(|home| home.join(".conda").join("conda.toml"))
+            .filter(|path| path.is_file())
+    }
+
+    /// Load the settings: defaults, overridden by the `[conda.*]` sections of
+    /// conda.toml, overridden in turn by the non-default values of the settings file
+    fn load_settings() -> Result<CondaSettings, Box<dyn Error>> {
+        let mut settings = CondaSettings::new();
+        if let Some(path) = Self::conda_toml_path() {
+            settings.apply_conda_toml(&path)?;
+        }
+        let settings_path = Self::settings_path()?;
+        if settings_path.is_file() {
+            settings.merge(&CondaSettings::load(&settings_path)?);
+        }
+        settings.validate().map_err(|errors| errors.join("; "))?;
+        Ok(settings)
+    }
+
     /// Discover existing Conda environments
     fn discover_environments(config: &CondaConfig) -> Result<HashMap<String, CondaEnvironment>, Box<dyn Error>> {
         let mut environments = HashMap::new();
@@ -150,6 +249,7 @@ impl CondaPackageManager {
 
     /// Install a package in a specific environment
     pub fn install_package(&mut self, env_name: &str, package_name: &str, version: Option<&str>) -> Result<(), Box<dyn Error>> {
+        let _lock = self.lock_environment(env_name)?;
         let env = self.environments.get_mut(env_name).ok_or("Environment not found")?;
 
         let package_spec = match version {
@@ -175,6 +275,7 @@ impl CondaPackageManager {
 
     /// Remove a package from a specific environment
     pub fn remove_package(&mut self, env_name: &str, package_name: &str) -> Result<(), Box<dyn Error>> {
+        let _lock = self.lock_environment(env_name)?;
         let env = self.environments.get_mut(env_name).ok_or("Environment not found")?;
 
         let output = Command::new("conda")
@@ -201,6 +302,7 @@ impl CondaPackageManager {
 
     /// Update all packages in a specific environment
     pub fn update_all_packages(&mut self, env_name: &str) -> Result<(), Box<dyn Error>> {
+        let _lock = self.lock_environment(env_name)?;
         let output = Command::new("conda")
             .args(&["update", "--all", "-n", env_name, "-y"])
             .output()?;
@@ -219,75 +321,764 @@ impl CondaPackageManager {
     }
 
     /// Search for a package in the configured channels
-    pub fn search_package(&self, query: &str) -> Result<Vec<Package>, Box<dyn Error>> {
-        let url = format!("{}/search?q={}", self.config.default_channel, query);
-        let response = reqwest::blocking::get(&url)?;
-        let search_results: Vec<Package> = response.json()?;
+    pub fn search_package(&self, query: &str) -> Result<Vec<PackageRecord>, Box<dyn Error>> {
+        let mut search_results = Vec::new();
+        for subdir in self.fetch_channel_repodata()? {
+            search_results.extend(
+                subdir
+                    .repodata
+                    .records()
+                    .filter(|(_, record)| record.name.contains(query))
+                    .map(|(_, record)| record.clone()),
+            );
+        }
+        search_results.sort_by(|a, b| a.name.cmp(&b.name).then(a.build_number.cmp(&b.build_number)));
         Ok(search_results)
     }
 
-    /// Export environment to a YAML file
-    pub fn export_environment(&self, env_name: &str, output_path: &Path) -> Result<(), Box<dyn Error>> {
+    /// Export an environment to a standard `environment.yml`, with exact
+    /// builds, versions only, or only the specs the user requested
+    pub fn export_environment(&self, env_name: &str, output_path: &Path, mode: ExportMode) -> Result<(), Box<dyn Error>> {
         let env = self.environments.get(env_name).ok_or("Environment not found")?;
-        let yaml_content = serde_yaml::to_string(&env.packages)?;
-        
-        let mut file = File::create(output_path)?;
-        file.write_all(yaml_content.as_bytes())?;
+        let file = EnvironmentFile::from_prefix(&env.path, Some(&env.name), mode, &self.channel_config())?;
+        file.write(output_path)?;
         Ok(())
     }
 
-    /// Import environment from a YAML file
+    /// Create an environment from a standard `environment.yml`, including its
+    /// pip requirements and variables. The file is validated before conda runs.
     pub fn import_environment(&mut self, env_name: &str, input_path: &Path) -> Result<(), Box<dyn Error>> {
-        let yaml_content = fs::read_to_string(input_path)?;
-        let packages: HashMap<String, Package> = serde_yaml::from_str(&yaml_content)?;
+        let file = EnvironmentFile::from_path(input_path)?;
+        if file.dependencies.is_empty() && file.pip.is_empty() {
+            return Err(format!("{} lists no dependencies", input_path.display()).into());
+        }
 
-        self.create_environment(env_name, "3.8")?; // Default to Python 3.8, can be adjusted
+        let output = Command::new("conda")
+            .args(&["env", "create", "-n", env_name, "-f"])
+            .arg(input_path)
+            .output()?;
 
-        for (name, package) in packages {
-            self.install_package(env_name, &name, Some(&package.version.to_string()))?;
+        if !output.status.success() {
+            return Err(Box::new(io::Error::new(
+                io::ErrorKind::Other,
+                String::from_utf8_lossy(&output.stderr),
+            )));
         }
 
+        let env_path = dirs::home_dir()
+            .ok_or("Unable to determine home directory")?
+            .join("anaconda3")
+            .join("envs")
+            .join(env_name);
+
+        let packages = Self::load_packages(&env_path)?;
+        let environment = CondaEnvironment {
+            name: env_name.to_string(),
+            packages,
+            path: env_path,
+        };
+
+        self.environments.insert(env_name.to_string(), environment);
         Ok(())
     }
 
     /// Get information about a specific package
     pub fn get_package_info(&self, package_name: &str) -> Result<Package, Box<dyn Error>> {
         let url = format!("{}/get/{}", self.config.default_channel, package_name);
-        let response = reqwest::blocking::get(&url)?;
+        let response = self.client()?.get(&url)?;
         let package_info: Package = response.json()?;
         Ok(package_info)
     }
 
-    /// Check for package updates in a specific environment
-    pub fn check_updates(&self, env_name: &str) -> Result<Vec<(String, Version, Version)>, Box<dyn Error>> {
+    /// Check for package updates in a specific environment, fetching each
+    /// channel subdir once and comparing all installed packages against it.
+    /// A subdir that fails to fetch leaves its packages unchecked rather than
+    /// failing the whole report
+    pub fn check_updates(&self, env_name: &str) -> Result<UpdateReport, Box<dyn Error>> {
         let env = self.environments.get(env_name).ok_or("Environment not found")?;
-        let mut updates = Vec::new();
-
-        for (name, package) in &env.packages {
-            let latest_info = self.get_package_info(name)?;
-            if latest_info.version > package.version {
-                updates.push((name.clone(), package.version.clone(), latest_info.version));
+        let installed = prefix::load_prefix_records(&env.path)?;
+        let pins = prefix::load_pins(&env.path)?;
+
+        let cache = self.repodata_cache();
+        let fetched: Vec<_> = self
+            .channel_subdirs()?
+            .into_par_iter()
+            .map(|(channel, platform)| {
+                let repodata = self.fetch_subdir(&cache, &channel, platform);
+                (channel, platform, repodata)
+            })
+            .collect();
+        let mut subdirs = Vec::new();
+        let mut failed = Vec::new();
+        for (channel, platform, repodata) in fetched {
+            match repodata {
+                Ok(repodata) => subdirs.push(SubdirRepodata { channel, platform, repodata }),
+                Err(e) => {
+                    let failure = SubdirFailure {
+                        channel: channel.canonical_name(),
+                        subdir: platform.as_str().to_string(),
+                        error: e.to_string(),
+                    };
+                    log::warn!("Not checking updates from {}", failure);
+                    failed.push(failure);
+                }
             }
         }
 
-        Ok(updates)
+        Ok(updates::check_updates(&installed, &subdirs, &failed, &pins, &self.channel_config()))
     }
 
     /// Clean up unused packages and caches
     pub fn clean(&self) -> Result<(), Box<dyn Error>> {
-        let output = Command::new("conda")
-            .args(&["clean", "--all", "-y"])
-            .output()?;
+        let report = self.clean_cache(&CleanTarget::ALL)?;
+        log::info!("Removed {} entries, freeing {} bytes", report.removed.len(), report.freed_bytes);
+        Ok(())
+    }
 
-        if !output.status.success() {
-            return Err(Box::new(io::Error::new(
-                io::ErrorKind::Other,
-                String::from_utf8_lossy(&output.stderr),
-            )));
+    /// Get every prefix packages may be linked into: the root prefix, the discovered
+    /// environments and the environments registered in `~/.conda/environments.txt`
+    fn known_prefixes(&self) -> Vec<PathBuf> {
+        let mut prefixes: Vec<PathBuf> = self.environments.values().map(|env| env.path.clone()).collect();
+        if let Some(home) = dirs::home_dir() {
+            prefixes.push(home.join("anaconda3"));
+            if let Ok(contents) = fs::read_to_string(home.join(".conda").join("environments.txt")) {
+                prefixes.extend(contents.lines().map(str::trim).filter(|line| !line.is_empty()).map(PathBuf::from));
+            }
         }
+        prefixes.sort();
+        prefixes.dedup();
+        prefixes
+    }
+
+    /// Get a manager for the package caches, aware of every known environment
+    /// and the configured size limit
+    pub fn cache_manager(&self) -> CacheManager {
+        CacheManager::new(self.settings.pkgs_dirs.clone())
+            .with_index_cache(self.config.cache_dir.join("repodata"))
+            .with_prefixes(self.known_prefixes())
+            .with_size_limit(self.settings.package_cache_size_limit)
+            .with_locker(self.locker())
+    }
+
+    /// Report the disk usage of the package caches
+    pub fn cache_usage(&self) -> Result<CacheUsage, Box<dyn Error>> {
+        Ok(self.cache_manager().usage()?)
+    }
+
+    /// Remove the selected kinds of cache content, keeping packages linked into any known environment
+    pub fn clean_cache(&self, targets: &[CleanTarget]) -> Result<CleanReport, Box<dyn Error>> {
+        let _locks = self.lock_pkgs_dirs()?;
+        Ok(self.cache_manager().clean(targets)?)
+    }
+
+    /// Evict least recently used, unlinked cache content until the caches fit the size limit
+    pub fn enforce_cache_limit(&self) -> Result<CleanReport, Box<dyn Error>> {
+        let _locks = self.lock_pkgs_dirs()?;
+        Ok(self.cache_manager().enforce_limit()?)
+    }
+}
 
+// Implementation of cross-process locking
+
+impl CondaPackageManager {
+    /// Report lock waits and acquisitions to the given callback instead of the log
+    pub fn set_lock_progress(&mut self, progress: impl Fn(&LockEvent) + Send + Sync + 'static) {
+        self.lock_progress = Some(Arc::new(progress));
+    }
+
+    /// Get a locker waiting up to the configured lock timeout
+    pub fn locker(&self) -> Locker {
+        let locker = Locker::new(Duration::from_secs(self.settings.lock_timeout));
+        match self.lock_progress.clone() {
+            Some(progress) => locker.with_progress(move |event: &LockEvent| progress(event)),
+            None => locker.with_progress(lock::log_event),
+        }
+    }
+
+    /// Lock an environment against changes by other processes
+    fn lock_environment(&self, env_name: &str) -> Result<FileLock, Box<dyn Error>> {
+        let env = self.environments.get(env_name).ok_or("Environment not found")?;
+        Ok(self.locker().lock_prefix(&env.path)?)
+    }
+
+    /// Lock every writable package cache; read-only caches are never modified
+    fn lock_pkgs_dirs(&self) -> Result<Vec<FileLock>, Box<dyn Error>> {
+        let locker = self.locker();
+        let mut locks = Vec::new();
+        for cache in self.pkgs_caches().iter().filter(|cache| cache.is_writable()) {
+            locks.push(locker.lock_pkgs_dir(cache.dir())?);
+        }
+        Ok(locks)
+    }
+}
+
+// Implementation of repodata handling
+
+impl CondaPackageManager {
+    /// Get the cache holding downloaded repodata
+    pub fn repodata_cache(&self) -> RepodataCache {
+        RepodataCache::new(self.config.cache_dir.join("repodata")).with_locker(self.locker())
+    }
+
+    /// Get the HTTP client, creating it on first use so local-only setups never build one
+    fn client(&self) -> Result<&NetworkClient, NetworkError> {
+        self.client.get_or_try_init(|| {
+            // Tokens still embedded in configured channel URLs apply in memory only
+            let mut auth = self.auth.clone();
+            for channel in self.channels().unwrap_or_default() {
+                if let (Some(token), Some(host)) = (&channel.token, channel.base_url.host_str()) {
+                    if auth.get(&channel.base_url).is_none() {
+                        auth.insert(host, Authentication::CondaToken { token: token.clone() });
+                    }
+                }
+            }
+            NetworkClient::from_settings(&self.settings, auth)
+        })
+    }
+
+    /// Fetch the repodata for a channel subdir, applying incremental updates when available
+    pub fn fetch_repodata(&self, subdir_url: &str) -> Result<RepoData, Box<dyn Error>> {
+        let url = Url::parse(subdir_url)?;
+        let repodata = repodata::fetch_subdir(&self.repodata_cache(), &url, || self.client())?;
+        Ok(repodata)
+    }
+
+    /// Fetch the repodata of every configured channel for the current platform and noarch
+    pub fn fetch_channel_repodata(&self) -> Result<Vec<SubdirRepodata>, Box<dyn Error>> {
+        self.fetch_subdirs(self.channel_subdirs()?)
+    }
+
+    /// Get every subdir of the configured channels
+    fn channel_subdirs(&self) -> Result<Vec<(Channel, Platform)>, Box<dyn Error>> {
+        Ok(self
+            .channels()?
+            .into_iter()
+            .flat_map(|channel| {
+                channel
+                    .platforms_or(Platform::current())
+                    .into_iter()
+                    .map(move |platform| (channel.clone(), platform))
+            })
+            .collect())
+    }
+
+    /// Fetch the repodata of the given channel subdirs concurrently, dropping
+    /// records without a trusted signature when signatures are verified
+    fn fetch_subdirs(&self, subdirs: Vec<(Channel, Platform)>) -> Result<Vec<SubdirRepodata>, Box<dyn Error>> {
+        let cache = self.repodata_cache();
+        subdirs
+            .into_par_iter()
+            .map(|(channel, platform)| -> Result<SubdirRepodata, Box<dyn Error + Send + Sync>> {
+                let repodata = self.fetch_subdir(&cache, &channel, platform)?;
+                Ok(SubdirRepodata { channel, platform, repodata })
+            })
+            .collect::<Result<_, _>>()
+            .map_err(|e| e as Box<dyn Error>)
+    }
+
+    /// Fetch the repodata of a single channel subdir and post-process it
+    fn fetch_subdir(&self, cache: &RepodataCache, channel: &Channel, platform: Platform) -> Result<RepoData, Box<dyn Error + Send + Sync>> {
+        let url = channel.platform_url(platform);
+        let mut repodata = repodata::fetch_subdir(cache, &url, || self.client())?;
+        self.postprocess_repodata(channel, platform, &mut repodata)?;
+        Ok(repodata)
+    }
+
+    /// Drop the records of fetched repodata without a trusted signature, when
+    /// signatures are verified, and apply the local hotfixes for its subdir
+    fn postprocess_repodata(&self, channel: &Channel, platform: Platform, repodata: &mut RepoData) -> Result<(), Box<dyn Error + Send + Sync>> {
+        if self.settings.verify_signatures {
+            for rejected in self.trust.verify_repodata(&channel.canonical_name(), repodata)? {
+                log::warn!("{}/{}: {}", channel, platform, rejected);
+            }
+        }
+        if let Some(patch_dir) = &self.config.repodata_patch_dir {
+            self.apply_hotfixes(patch_dir, channel, platform, repodata)?;
+        }
+        Ok(())
+    }
+
+    /// Apply the locally maintained patch instructions for a channel subdir, if any
+    fn apply_hotfixes(&self, patch_dir: &Path, channel: &Channel, platform: Platform, repodata: &mut RepoData) -> Result<(), PatchError> {
+        let path = patch::hotfix_path(patch_dir, channel, platform);
+        if let Some(instructions) = PatchInstructions::load(&path)? {
+            let report = instructions.apply(repodata)?;
+            for change in &report.changes {
+                log::info!("{}/{}: {}", channel, platform, change);
+            }
+            for filename in &report.missing {
+                log::debug!("{}/{}: patch target {} not found", channel, platform, filename);
+            }
+        }
         Ok(())
     }
+
+    /// Find the installed packages of an environment that depend on packages matching a spec
+    pub fn whoneeds_installed(&self, env_name: &str, spec: &str, max_depth: Option<usize>) -> Result<WhoNeeds, Box<dyn Error>> {
+        let env = self.environments.get(env_name).ok_or("Environment not found")?;
+        let spec: MatchSpec = spec.parse()?;
+        let installed = prefix::load_prefix_records(&env.path)?;
+        Ok(query::whoneeds(installed.iter().map(|r| &r.record), &spec, max_depth))
+    }
+
+    /// Find the records in the configured channels that depend on packages matching a spec
+    pub fn whoneeds_in_channels(&self, spec: &str, max_depth: Option<usize>) -> Result<WhoNeeds, Box<dyn Error>> {
+        let spec: MatchSpec = spec.parse()?;
+        let subdirs = self.fetch_channel_repodata()?;
+        let records = subdirs.iter().flat_map(|subdir| subdir.repodata.records().map(|(_, record)| record));
+        Ok(query::whoneeds(records, &spec, max_depth))
+    }
+
+    /// Mirror the packages matching the given specs, and their dependencies,
+    /// from the given channels and subdirs into a local channel directory
+    pub fn mirror(
+        &self,
+        channels: &[String],
+        subdirs: &[Platform],
+        specs: Vec<MatchSpec>,
+        target_dir: &Path,
+        prune: bool,
+    ) -> Result<MirrorReport, Box<dyn Error>> {
+        let mut platforms = subdirs.to_vec();
+        if !platforms.contains(&Platform::NoArch) {
+            platforms.push(Platform::NoArch);
+        }
+
+        let sources = self
+            .channel_config()
+            .resolve_all(channels)?
+            .into_iter()
+            .flat_map(|channel| platforms.iter().map(move |platform| (channel.clone(), *platform)))
+            .collect();
+        let sources = self.fetch_subdirs(sources)?;
+
+        let mirror = Mirror::new(target_dir, specs).with_prune(prune);
+        Ok(mirror.sync(&sources, || self.client())?)
+    }
+
+    /// Mirror the packages of an environment file, falling back to the
+    /// configured channels when the file lists none
+    pub fn mirror_environment_file(
+        &self,
+        env_file: &Path,
+        subdirs: &[Platform],
+        target_dir: &Path,
+        prune: bool,
+    ) -> Result<MirrorReport, Box<dyn Error>> {
+        let file = EnvironmentFile::from_path(env_file)?;
+        let (mut channels, specs) = (file.channels, file.dependencies);
+        if channels.is_empty() {
+            channels.push(self.config.default_channel.clone());
+            channels.extend(self.config.custom_channels.iter().cloned());
+        }
+        self.mirror(&channels, subdirs, specs, target_dir, prune)
+    }
+
+    /// Fetch a package file from a channel subdir into a directory, returning
+    /// its path. The file is checked against the size and hashes of its
+    /// repodata record, and quarantined if they do not match.
+    pub fn fetch_package(&self, channel: &Channel, platform: Platform, filename: &str, record: &PackageRecord, dest_dir: &Path) -> Result<PathBuf, Box<dyn Error>> {
+        let request = DownloadRequest {
+            url: channel.platform_url(platform).join(filename)?,
+            dest: dest_dir.join(filename),
+            record: record.clone(),
+        };
+        self.download_scheduler()?.fetch(&request, || self.client())?;
+        Ok(request.dest)
+    }
+
+    /// Get the download scheduler, sized by the configured download and extract threads
+    fn download_scheduler(&self) -> Result<&DownloadScheduler, DownloadError> {
+        self.scheduler.get_or_try_init(|| {
+            let scheduler = DownloadScheduler::new(self.settings.download_threads, self.settings.extract_threads)?;
+            Ok(match self.settings.download_bandwidth_limit {
+                Some(limit) => scheduler.with_bandwidth_limit(limit),
+                None => scheduler,
+            })
+        })
+    }
+
+    /// Recheck every cached package archive against its recorded size and
+    /// hashes, quarantining those that no longer match
+    pub fn verify_cache(&self) -> Result<CacheVerification, Box<dyn Error>> {
+        let _locks = self.lock_pkgs_dirs()?;
+        let mut report = CacheVerification::default();
+        for cache in self.pkgs_caches().iter() {
+            report.extend(cache.verify()?);
+        }
+        Ok(report)
+    }
+
+    /// Get all package caches in the configured order, read-only shared
+    /// caches included
+    pub fn pkgs_caches(&self) -> PackageCaches {
+        PackageCaches::new(&self.settings.pkgs_dirs)
+    }
+
+    /// Get the first writable package cache, where packages are downloaded and extracted
+    pub fn pkgs_cache(&self) -> Result<PackageCache, Box<dyn Error>> {
+        Ok(self.pkgs_caches().writable()?.clone())
+    }
+
+    /// Download and extract a package into the package cache, reusing an
+    /// earlier extraction or archive of the same package file from any cache.
+    /// Only the first writable cache is written to, and it is chosen before
+    /// anything is downloaded.
+    pub fn fetch_and_extract(&self, channel: &Channel, platform: Platform, filename: &str, record: &PackageRecord) -> Result<ExtractedPackage, Box<dyn Error>> {
+        let caches = self.pkgs_caches();
+        let record = if self.settings.verify_signatures {
+            self.signed_record(channel, platform, filename, record)?
+        } else {
+            record.clone()
+        };
+        let repodata_record = RepodataRecord {
+            record,
+            file_name: filename.to_string(),
+            url: channel.platform_url(platform).join(filename)?.to_string(),
+            channel: channel.base_url.as_str().trim_end_matches('/').to_string(),
+        };
+        if let Some(extracted) = caches.get(&repodata_record) {
+            return Ok(extracted);
+        }
+
+        let cache = caches.writable()?;
+        let _lock = self.locker().lock_pkgs_dir(cache.dir())?;
+        if let Some(extracted) = cache.get(&repodata_record) {
+            // Extracted by another process while waiting for the lock
+            return Ok(extracted);
+        }
+        if let Some(archive_path) = caches.find_archive(&repodata_record) {
+            return Ok(cache.extract(&archive_path, &repodata_record)?);
+        }
+
+        // Make room before downloading, so the new package is never the one evicted
+        if let Err(e) = self.enforce_cache_limit() {
+            log::warn!("Failed to enforce the package cache size limit: {}", e);
+        }
+
+        let archive_path = self.fetch_package(channel, platform, filename, &repodata_record.record, cache.dir())?;
+        Ok(cache.extract(&archive_path, &repodata_record)?)
+    }
+
+    /// Download and extract many packages into the package cache. Downloads run
+    /// concurrently and each package is extracted as soon as its download
+    /// completes; packages already in any cache are reused as in `fetch_and_extract`.
+    pub fn fetch_and_extract_all(&self, packages: &[(&Channel, Platform, &str, &PackageRecord)]) -> Result<Vec<ExtractedPackage>, Box<dyn Error>> {
+        let caches = self.pkgs_caches();
+        let mut records = Vec::with_capacity(packages.len());
+        for &(channel, platform, filename, record) in packages {
+            let record = if self.settings.verify_signatures {
+                self.signed_record(channel, platform, filename, record)?
+            } else {
+                record.clone()
+            };
+            records.push(RepodataRecord {
+                record,
+                file_name: filename.to_string(),
+                url: channel.platform_url(platform).join(filename)?.to_string(),
+                channel: channel.base_url.as_str().trim_end_matches('/').to_string(),
+            });
+        }
+
+        let mut extracted: Vec<Option<ExtractedPackage>> = records.iter().map(|record| caches.get(record)).collect();
+        if extracted.iter().all(Option::is_some) {
+            return Ok(extracted.into_iter().flatten().collect());
+        }
+
+        let cache = caches.writable()?;
+        let _lock = self.locker().lock_pkgs_dir(cache.dir())?;
+        if let Err(e) = self.enforce_cache_limit() {
+            log::warn!("Failed to enforce the package cache size limit: {}", e);
+        }
+
+        // Each package file is fetched and extracted once, however often it is requested
+        let mut requests = Vec::new();
+        let mut request_records = Vec::new();
+        let mut requested: HashMap<&str, usize> = HashMap::new();
+        let mut request_of = vec![None; records.len()];
+        for (index, record) in records.iter().enumerate() {
+            if extracted[index].is_some() {
+                continue;
+            }
+            if let Some(found) = cache.get(record) {
+                extracted[index] = Some(found);
+                continue;
+            }
+            // Archives in any cache, read-only ones included, are extracted where they are
+            if let Some(archive_path) = caches.find_archive(record) {
+                extracted[index] = Some(cache.extract(&archive_path, record)?);
+                continue;
+            }
+            let request = match requested.get(record.url.as_str()) {
+                Some(&request) => request,
+                None => {
+                    requests.push(DownloadRequest {
+                        url: Url::parse(&record.url)?,
+                        dest: cache.archive_path(&record.file_name),
+                        record: record.record.clone(),
+                    });
+                    request_records.push(record);
+                    requested.insert(record.url.as_str(), requests.len() - 1);
+                    requests.len() - 1
+                }
+            };
+            request_of[index] = Some(request);
+        }
+
+        let results = self.download_scheduler()?.fetch_all(&requests, || self.client(), |index, request| {
+            cache.extract(&request.dest, request_records[index])
+        });
+        let mut unpacked = Vec::with_capacity(results.len());
+        for result in results {
+            unpacked.push(result??);
+        }
+
+        Ok(extracted
+            .into_iter()
+            .zip(request_of)
+            .map(|(found, request)| found.or_else(|| request.map(|request| unpacked[request].clone())))
+            .collect::<Option<Vec<_>>>()
+            .expect("every package is extracted or requested"))
+    }
+
+    /// Look up the channel's signed record of a package file, refusing it
+    /// unless it is signed by a trusted key and describes the same file as
+    /// `record`. The download is then checked against the signed hashes.
+    fn signed_record(&self, channel: &Channel, platform: Platform, filename: &str, record: &PackageRecord) -> Result<PackageRecord, Box<dyn Error>> {
+        let channel_name = channel.canonical_name();
+        let rejected = || SigningError::InvalidSignature { subject: filename.to_string(), channel: channel_name.clone() };
+
+        let repodata = repodata::fetch_subdir(&self.repodata_cache(), &channel.platform_url(platform), || self.client())?;
+        let signed = repodata
+            .records()
+            .find(|(name, _)| name.as_str() == filename)
+            .map(|(_, signed)| signed)
+            .ok_or_else(|| SigningError::Unsigned { subject: filename.to_string(), channel: channel_name.clone() })?;
+        self.trust.verify_record(&channel_name, filename, signed, repodata.signatures.get(filename))?;
+
+        let same_file = match (&signed.sha256, &record.sha256, &signed.md5, &record.md5) {
+            (Some(signed), Some(requested), _, _) => signed.eq_ignore_ascii_case(requested),
+            (_, _, Some(signed), Some(requested)) => signed.eq_ignore_ascii_case(requested),
+            _ => signed.sha256.is_some() || signed.md5.is_some(),
+        };
+        if !same_file {
+            return Err(rejected().into());
+        }
+        Ok(signed.clone())
+    }
+
+    /// Link an extracted package into an environment without invoking conda
+    pub fn link_package(&mut self, env_name: &str, package: &ExtractedPackage, requested_spec: Option<&str>, options: LinkOptions) -> Result<PrefixRecord, Box<dyn Error>> {
+        let _lock = self.lock_environment(env_name)?;
+        let env = self.environments.get_mut(env_name).ok_or("Environment not found")?;
+        let mut linker = Linker::new(&env.path, options)?;
+
+        // Replace any other installed build of the package, once the new
+        // one is known to link without clobbering or an overlong prefix
+        linker.check(package)?;
+        let name = &package.repodata_record.record.name;
+        for installed in prefix::load_prefix_records(&env.path).unwrap_or_default() {
+            if &installed.record.name == name {
+                link::unlink(&env.path, &installed)?;
+            }
+        }
+
+        let record = linker.link(package, requested_spec)?;
+        env.packages = Self::load_packages(&env.path)?;
+        Ok(record)
+    }
+
+    /// Unlink an installed package from an environment without invoking conda
+    pub fn unlink_package(&mut self, env_name: &str, package_name: &str) -> Result<(), Box<dyn Error>> {
+        let _lock = self.lock_environment(env_name)?;
+        let env = self.environments.get_mut(env_name).ok_or("Environment not found")?;
+        let record = prefix::load_prefix_records(&env.path)?
+            .into_iter()
+            .find(|record| record.record.name == package_name)
+            .ok_or("Package not installed")?;
+
+        link::unlink(&env.path, &record)?;
+        env.packages = Self::load_packages(&env.path)?;
+        Ok(())
+    }
+
+    /// Build a package from a staged install directory and a packager manifest,
+    /// using the configured compression type. `build_prefix` is the prefix the
+    /// staged files were built for, if it is not the staging directory
+    pub fn build_package(&self, manifest_path: &Path, staging_dir: &Path, build_prefix: Option<&Path>, output_dir: &Path) -> Result<PathBuf, Box<dyn Error>> {
+        let mut builder = PackageBuilder::from_manifest_file(manifest_path, staging_dir)?
+            .with_compression(self.settings.compression_type.clone(), None);
+        if let Some(build_prefix) = build_prefix {
+            builder = builder.with_build_prefix(build_prefix);
+        }
+        let package = builder.build(output_dir)?;
+        if let Some(key) = self.signing_key()? {
+            key.sign_artifact(&package)?;
+        }
+        Ok(package)
+    }
+
+    /// Inspect a package given as a path to an archive, or as the filename of
+    /// a package in any package cache, preferring its extracted directory
+    pub fn inspect_package(&self, package: &str) -> Result<PackageInspection, Box<dyn Error>> {
+        let path = Path::new(package);
+        if path.is_file() {
+            return Ok(inspect::inspect_archive(path)?);
+        }
+
+        let caches = self.pkgs_caches();
+        for cache in caches.iter() {
+            let extracted = cache.package_dir(package)?;
+            if extracted.join("info").join("index.json").is_file() {
+                return Ok(inspect::inspect_dir(&extracted)?);
+            }
+            let archive_path = cache.archive_path(package);
+            if archive_path.is_file() {
+                return Ok(inspect::inspect_archive(&archive_path)?);
+            }
+        }
+        Err(format!("Package {} not found locally or in the package caches", package).into())
+    }
+
+    /// Convert a package to the other archive format, verifying that the
+    /// converted package has the same contents
+    pub fn transmute_package(&self, source: &Path, dest_dir: &Path, zstd_level: Option<i32>) -> Result<PathBuf, Box<dyn Error>> {
+        let target = match ArchiveType::from_path(source).ok_or("Not a .conda or .tar.bz2 package")? {
+            ArchiveType::TarBz2 => ArchiveType::Conda,
+            ArchiveType::Conda => ArchiveType::TarBz2,
+        };
+        let mut transmuter = Transmuter::new(target);
+        if let Some(level) = zstd_level {
+            transmuter = transmuter.with_zstd_level(level);
+        }
+        Ok(transmuter.transmute(source, dest_dir)?)
+    }
+
+    /// Convert every package of a local channel directory into the given
+    /// format in parallel, then re-index the channel so it serves both
+    pub fn transmute_channel(&self, channel_dir: &Path, target: ArchiveType, zstd_level: Option<i32>) -> Result<TransmuteReport, Box<dyn Error>> {
+        let mut transmuter = Transmuter::new(target);
+        if let Some(level) = zstd_level {
+            transmuter = transmuter.with_zstd_level(level);
+        }
+        let report = transmuter.transmute_channel(channel_dir)?;
+        if !report.converted.is_empty() {
+            self.channel_indexer(channel_dir)?.index()?;
+        }
+        Ok(report)
+    }
+
+    /// Get the key that signs built packages and indexed channels, if signing is enabled
+    fn signing_key(&self) -> Result<Option<Arc<SigningKey>>, Box<dyn Error>> {
+        if !self.settings.sign_packages {
+            return Ok(None);
+        }
+        let path = self.settings.signing_key.as_ref().ok_or("sign_packages is enabled but no signing_key is configured")?;
+        Ok(Some(Arc::new(SigningKey::load(path)?)))
+    }
+
+    /// Create an indexer for a local channel, signing it if signing is enabled
+    fn channel_indexer(&self, channel_dir: &Path) -> Result<ChannelIndexer, Box<dyn Error>> {
+        let indexer = ChannelIndexer::new(channel_dir);
+        Ok(match self.signing_key()? {
+            Some(key) => indexer.with_signing_key(key),
+            None => indexer,
+        })
+    }
+
+    /// Index a local channel, signing its repodata and package files if signing is enabled
+    pub fn index_channel(&self, channel_dir: &Path) -> Result<IndexReport, Box<dyn Error>> {
+        Ok(self.channel_indexer(channel_dir)?.index()?)
+    }
+
+    /// Generate a new signing key at the given path, returning its public key
+    pub fn generate_signing_key(&self, path: &Path) -> Result<String, Box<dyn Error>> {
+        Ok(SigningKey::generate(path)?.key_id().to_string())
+    }
+
+    /// Trust a public key for the packages of a channel
+    pub fn trust_key(&mut self, channel: &str, public_key: &str, name: Option<String>) -> Result<(), Box<dyn Error>> {
+        let channel = self.canonical_channel(channel)?;
+        self.trust.trust(&channel, public_key, name);
+        Ok(self.trust.save()?)
+    }
+
+    /// Stop trusting a public key for a channel, returning whether it was trusted
+    pub fn revoke_key(&mut self, channel: &str, public_key: &str) -> Result<bool, Box<dyn Error>> {
+        let channel = self.canonical_channel(channel)?;
+        let revoked = self.trust.revoke(&channel, public_key);
+        self.trust.save()?;
+        Ok(revoked)
+    }
+
+    /// Replace the signing key with a newly generated one: the local channel is
+    /// re-signed with the new key, the trust store swaps the keys for `channel`,
+    /// and the settings point at the new key file. Returns the new public key.
+    pub fn rotate_signing_key(&mut self, new_key_path: &Path, channel_dir: &Path, channel: &str) -> Result<String, Box<dyn Error>> {
+        let old_key = self.settings.signing_key.as_ref().map(|path| SigningKey::load(path)).transpose()?;
+        let new_key = Arc::new(SigningKey::generate(new_key_path)?);
+        ChannelIndexer::new(channel_dir).with_signing_key(new_key.clone()).index()?;
+
+        let channel = self.canonical_channel(channel)?;
+        match &old_key {
+            Some(old_key) => self.trust.rotate(&channel, old_key.key_id(), new_key.key_id()),
+            None => self.trust.trust(&channel, new_key.key_id(), None),
+        }
+        self.trust.save()?;
+
+        self.settings.signing_key = Some(new_key_path.to_path_buf());
+        self.settings.save(&Self::settings_path()?)?;
+        Ok(new_key.key_id().to_string())
+    }
+
+    /// Get the name a channel is trusted under, as used when verifying its packages
+    fn canonical_channel(&self, channel: &str) -> Result<String, Box<dyn Error>> {
+        let channel = Channel::from_str(channel, &self.channel_config())?;
+        Ok(channel.canonical_name())
+    }
+
+    /// Fetch only the repodata needed to resolve the given package names, using
+    /// sharded repodata where the channel provides it and full repodata otherwise.
+    /// Signatures and hotfixes are applied as for `fetch_channel_repodata`
+    pub fn fetch_repodata_for(&self, subdir_urls: &[&str], names: &[&str]) -> Result<HashMap<String, RepoData>, Box<dyn Error>> {
+        let cache = self.repodata_cache();
+        let config = self.channel_config();
+        let mut subdirs = HashMap::new();
+        let mut sharded = Vec::new();
+        let mut result = HashMap::new();
+
+        for subdir_url in subdir_urls {
+            let url = Url::parse(subdir_url)?;
+            let channel = Channel::from_str(subdir_url, &config)?;
+            let platform = match channel.platforms.as_deref() {
+                Some([platform]) => *platform,
+                _ => return Err(format!("{} is not a channel subdir URL", subdir_url).into()),
+            };
+            subdirs.insert(subdir_url.trim_end_matches('/').to_string(), (channel, platform));
+            let subdir = if url.scheme() == "file" {
+                None
+            } else {
+                ShardedSubdir::open(self.client()?, subdir_url, self.config.cache_dir.join("repodata"))?
+            };
+
+            match subdir {
+                Some(subdir) => sharded.push(subdir),
+                None => {
+                    let repodata = repodata::fetch_subdir(&cache, &url, || self.client())?;
+                    result.insert(subdir_url.trim_end_matches('/').to_string(), repodata);
+                }
+            }
+        }
+
+        if !sharded.is_empty() {
+            result.extend(shards::fetch_closure(self.client()?, &sharded, names.iter().copied())?);
+        }
+        for (url, repodata) in result.iter_mut() {
+            let (channel, platform) = &subdirs[url];
+            self.postprocess_repodata(channel, *platform, repodata).map_err(|e| e as Box<dyn Error>)?;
+        }
+        Ok(result)
+    }
 }
 
 // Implementation of additional utility functions
@@ -354,9 +1145,45 @@ impl CondaPackageManager {
             .map(|package| &package.version)
     }
 
-    /// Add a custom channel to the configuration
+    /// Get the configuration used to resolve channel names, with the `defaults`
+    /// channels of the .condarc taking precedence over those of the settings
+    pub fn channel_config(&self) -> ChannelConfig {
+        let defaults = ChannelConfig::from_settings(&self.settings);
+        ChannelConfig {
+            channel_alias: self.config.channel_alias.clone().unwrap_or(defaults.channel_alias),
+            default_channels: self.config.default_channels.clone().unwrap_or(defaults.default_channels),
+            custom_channels: self.config.channel_locations.clone(),
+            custom_multichannels: self.config.custom_multichannels.clone(),
+            root_dir: None,
+        }
+    }
+
+    /// Get the configured channels, in priority order and without duplicates
+    pub fn channels(&self) -> Result<Vec<Channel>, Box<dyn Error>> {
+        let mut names = vec![self.config.default_channel.clone()];
+        names.extend(self.config.custom_channels.iter().cloned());
+        Ok(self.channel_config().resolve_all(&names)?)
+    }
+
+    /// Add a custom channel to the configuration, moving any embedded credentials
+    /// into the authentication store so they are never written to `.condarc`
     pub fn add_channel(&mut self, channel_url: &str) -> Result<(), Box<dyn Error>> {
-        if !self.config.custom_channels.contains(&channel_url.to_string()) {
+        let channel_url = if channel_url.contains("://") && !channel_url.starts_with("file://") {
+            let stripped = self.auth.extract_credentials(channel_url)?;
+            if stripped != channel_url {
+                self.auth.save()?;
+            }
+            stripped
+        } else {
+            channel_url.to_string()
+        };
+        let channel_url = channel_url.as_str();
+
+        let config = self.channel_config();
+        let added: Vec<String> = config.resolve(channel_url)?.iter().map(Channel::canonical_name).collect();
+        let existing: Vec<String> = self.channels()?.iter().map(Channel::canonical_name).collect();
+
+        if !added.iter().all(|name| existing.contains(name)) {
             self.config.custom_channels.push(channel_url.to_string());
             self.save_config()?;
         }
@@ -365,7 +1192,15 @@ impl CondaPackageManager {
 
     /// Remove a custom channel from the configuration
     pub fn remove_channel(&mut self, channel_url: &str) -> Result<(), Box<dyn Error>> {
-        self.config.custom_channels.retain(|c| c != channel_url);
+        let config = self.channel_config();
+        let removed: Vec<String> = config.resolve(channel_url)?.iter().map(Channel::canonical_name).collect();
+
+        self.config.custom_channels.retain(|c| {
+            config
+                .resolve(c)
+                .map(|channels| !channels.iter().all(|channel| removed.contains(&channel.canonical_name())))
+                .unwrap_or(c != channel_url)
+        });
         self.save_config()?;
         Ok(())
     }
@@ -397,4 +1232,70 @@ impl CondaPackageManager {
             return Err(Box::new(io::Error::new(
                 io::ErrorKind::Other,
                 String::from_utf8_lossy
-            )))}}}
\ No newline at end of file
+            )))}}}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Creates a manager with no environments, caching under `root`.
+    fn manager(root: &Path, settings: CondaSettings) -> CondaPackageManager {
+        CondaPackageManager {
+            environments: HashMap::new(),
+            config: CondaConfig {
+                default_channel: String::new(),
+                custom_channels: Vec::new(),
+                cache_dir: root.join("cache"),
+                channel_alias: None,
+                default_channels: None,
+                channel_locations: HashMap::new(),
+                custom_multichannels: HashMap::new(),
+                repodata_patch_dir: None,
+            },
+            settings,
+            auth: AuthStore::default(),
+            trust: TrustStore::default(),
+            client: OnceCell::new(),
+            scheduler: OnceCell::new(),
+            lock_progress: None,
+        }
+    }
+
+    #[test]
+    fn fetch_and_extract_refuses_unsigned_record_when_verifying_signatures() {
+        let root = tempfile::tempdir().unwrap();
+        let subdir = root.path().join("channel").join("linux-64");
+        let filename = "demo-1.0-0.conda";
+        let record = PackageRecord {
+            name: "demo".to_string(),
+            version: "1.0".to_string(),
+            build: "0".to_string(),
+            subdir: "linux-64".to_string(),
+            sha256: Some("0".repeat(64)),
+            size: Some(0),
+            ..Default::default()
+        };
+        let mut repodata = RepoData::default();
+        repodata.conda_packages.insert(filename.to_string(), record.clone());
+        fs::create_dir_all(&subdir).unwrap();
+        fs::write(subdir.join("repodata.json"), serde_json::to_vec(&repodata).unwrap()).unwrap();
+
+        let mut settings = CondaSettings::new();
+        settings.pkgs_dirs = vec![root.path().join("pkgs")];
+        settings.verify_signatures = true;
+        let mut manager = manager(root.path(), settings);
+
+        let channel_dir = root.path().join("channel");
+        let channel = Channel::from_str(channel_dir.to_str().unwrap(), &manager.channel_config()).unwrap();
+        let key = SigningKey::generate(&root.path().join("signing.key")).unwrap();
+        manager.trust.trust(&channel.canonical_name(), key.key_id(), None);
+
+        let error = manager.fetch_and_extract(&channel, Platform::Linux64, filename, &record).unwrap_err();
+        assert!(
+            matches!(error.downcast_ref::<SigningError>(), Some(SigningError::Unsigned { .. })),
+            "unexpected error: {}",
+            error
+        );
+        assert!(!root.path().join("pkgs").join(filename).exists());
+    }
+}
@@ -0,0 +1,393 @@
+// conda.pkgs.rs
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use rayon::prelude::*;
+use serde::de::DeserializeOwned;
+use thiserror::Error;
+
+use crate::archive::{self, ArchiveError, ArchiveType};
+use crate::auth::redact_url;
+use crate::digest::{self, DigestMismatch, FileDigests};
+use crate::download;
+use crate::package::{self, AboutJson, HasPrefixEntry, IndexJson, LinkJson, PathsJson, RunExportsJson};
+use crate::repodata::RepodataRecord;
+
+/// The name of the record written into each extracted package.
+pub const REPODATA_RECORD_FILE: &str = "info/repodata_record.json";
+
+/// Represents possible errors that can occur in a package cache.
+#[derive(Error, Debug)]
+pub enum PkgsError {
+    #[error("Failed to access {path}: {source}")]
+    IoError { path: PathBuf, source: io::Error },
+
+    #[error(transparent)]
+    ArchiveError(#[from] ArchiveError),
+
+    #[error("Invalid {path}: {source}")]
+    ParseError { path: PathBuf, source: serde_json::Error },
+
+    #[error("{path} downloaded from {url}: {mismatch}")]
+    HashMismatch { path: PathBuf, url: String, mismatch: DigestMismatch },
+
+    #[error("{0} is not a package filename")]
+    InvalidFilename(String),
+
+    #[error("Package cache {0} is not writable")]
+    ReadOnlyCache(PathBuf),
+
+    #[error("None of the package caches is writable: {0}")]
+    NoWritableCache(String),
+}
+
+/// Represents a package extracted into a package cache, with its parsed metadata.
+#[derive(Debug, Clone)]
+pub struct ExtractedPackage {
+    pub dir: PathBuf,
+    pub repodata_record: RepodataRecord,
+    pub index: IndexJson,
+
+    /// The files of the package, built from `info/files` for packages
+    /// predating `info/paths.json`.
+    pub paths: PathsJson,
+    pub about: Option<AboutJson>,
+    pub run_exports: Option<RunExportsJson>,
+    pub has_prefix: Vec<HasPrefixEntry>,
+
+    /// Entry points and the noarch type of `noarch` packages.
+    pub link_json: Option<LinkJson>,
+}
+
+impl ExtractedPackage {
+    /// Reads the metadata of an extracted package directory.
+    pub fn load(dir: &Path) -> Result<Self, PkgsError> {
+        let info = dir.join("info");
+        let has_prefix = load_has_prefix(&info)?;
+
+        Ok(ExtractedPackage {
+            dir: dir.to_path_buf(),
+            repodata_record: read_json(&dir.join(REPODATA_RECORD_FILE))?,
+            index: read_json(&info.join("index.json"))?,
+            paths: load_paths(&info, &has_prefix)?,
+            about: read_optional_json(&info.join("about.json"))?,
+            run_exports: read_optional_json(&info.join("run_exports.json"))?,
+            has_prefix,
+            link_json: read_optional_json(&info.join("link.json"))?,
+        })
+    }
+
+    /// Returns whether this is a `noarch: python` package, whose files need
+    /// remapping for the target environment's Python.
+    pub fn is_noarch_python(&self) -> bool {
+        self.index.noarch.as_deref() == Some("python")
+            || self.link_json.as_ref().map_or(false, |link| link.noarch.noarch_type == "python")
+    }
+}
+
+/// Represents a package cache directory, holding package archives and their
+/// extracted contents side by side, as in conda's `pkgs_dirs`.
+#[derive(Debug, Clone)]
+pub struct PackageCache {
+    dir: PathBuf,
+}
+
+impl PackageCache {
+    /// Creates a cache rooted at a directory.
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        PackageCache { dir: dir.into() }
+    }
+
+    /// Returns the cache directory.
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Returns whether packages can be written into the cache, checking the
+    /// permissions of the directory, or of its nearest existing parent if it
+    /// has yet to be created. Read-only mounts count as not writable.
+    pub fn is_writable(&self) -> bool {
+        let mut dir = self.dir.as_path();
+        while !dir.exists() {
+            match dir.parent() {
+                Some(parent) => dir = parent,
+                None => return false,
+            }
+        }
+        is_dir_writable(dir)
+    }
+
+    /// Returns where a package archive is stored.
+    pub fn archive_path(&self, file_name: &str) -> PathBuf {
+        self.dir.join(file_name)
+    }
+
+    /// Returns where a package is extracted, named after the filename without its extension.
+    pub fn package_dir(&self, file_name: &str) -> Result<PathBuf, PkgsError> {
+        let (stem, _) = ArchiveType::split_filename(file_name).ok_or_else(|| PkgsError::InvalidFilename(file_name.to_string()))?;
+        Ok(self.dir.join(stem))
+    }
+
+    /// Returns the extracted package for a record, if it is cached with the
+    /// same hash.
+    pub fn get(&self, record: &RepodataRecord) -> Option<ExtractedPackage> {
+        let dir = self.package_dir(&record.file_name).ok()?;
+        let extracted = ExtractedPackage::load(&dir).ok()?;
+        if same_package(&extracted.repodata_record, record) {
+            Some(extracted)
+        } else {
+            None
+        }
+    }
+
+    /// Verifies a downloaded archive against its record, extracts it through a
+    /// temporary directory and records `info/repodata_record.json`, replacing
+    /// any previous extraction of the same package.
+    pub fn extract(&self, archive_path: &Path, record: &RepodataRecord) -> Result<ExtractedPackage, PkgsError> {
+        if !self.is_writable() {
+            return Err(PkgsError::ReadOnlyCache(self.dir.clone()));
+        }
+        let digests = verify_archive(archive_path, record)?;
+        let target = self.package_dir(&record.file_name)?;
+        let io_error = |path: &Path| {
+            let path = path.to_path_buf();
+            move |source| PkgsError::IoError { path, source }
+        };
+
+        fs::create_dir_all(&self.dir).map_err(io_error(&self.dir))?;
+        let tmp_dir = tempfile::Builder::new()
+            .prefix(".extract-")
+            .tempdir_in(&self.dir)
+            .map_err(io_error(&self.dir))?;
+        archive::extract(archive_path, tmp_dir.path())?;
+
+        // Fill in hashes the channel did not publish, so later cache hits can be checked.
+        let mut record = record.clone();
+        record.record.md5.get_or_insert(digests.md5);
+        record.record.sha256.get_or_insert(digests.sha256);
+        record.record.size.get_or_insert(digests.size);
+        let record_path = tmp_dir.path().join(REPODATA_RECORD_FILE);
+        let contents = serde_json::to_vec_pretty(&record).map_err(|source| PkgsError::ParseError { path: record_path.clone(), source })?;
+        fs::write(&record_path, contents).map_err(io_error(&record_path))?;
+
+        // Parse before moving into place so a broken package never looks cached.
+        ExtractedPackage::load(tmp_dir.path())?;
+
+        if target.exists() {
+            fs::remove_dir_all(&target).map_err(io_error(&target))?;
+        }
+        // The temporary directory is still removed if the rename fails, and
+        // only released once its contents are in place.
+        fs::rename(tmp_dir.path(), &target).map_err(io_error(&target))?;
+        let _ = tmp_dir.keep();
+
+        ExtractedPackage::load(&target)
+    }
+
+    /// Rechecks every archive in the cache against the record of its
+    /// extraction, in parallel. In a writable cache, mismatching archives are
+    /// quarantined and their extraction removed, so they are downloaded again
+    /// when next needed.
+    pub fn verify(&self) -> Result<CacheVerification, PkgsError> {
+        let io_error = |source| PkgsError::IoError { path: self.dir.clone(), source };
+        let mut archives = Vec::new();
+        match fs::read_dir(&self.dir) {
+            Ok(entries) => {
+                for entry in entries {
+                    let path = entry.map_err(io_error)?.path();
+                    if path.is_file() && ArchiveType::from_path(&path).is_some() {
+                        archives.push(path);
+                    }
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(io_error(e)),
+        }
+        archives.sort();
+
+        let results: Vec<(PathBuf, Result<bool, PkgsError>)> = archives
+            .into_par_iter()
+            .map(|path| {
+                let result = self.verify_cached_archive(&path);
+                (path, result)
+            })
+            .collect();
+
+        let mut report = CacheVerification::default();
+        for (path, result) in results {
+            match result {
+                Ok(true) => report.verified.push(path),
+                Ok(false) => report.unverifiable.push(path),
+                Err(e) => {
+                    log::warn!("{}", e);
+                    report.corrupted.push((path, e.to_string()));
+                }
+            }
+        }
+        Ok(report)
+    }
+
+    /// Checks one archive, returning whether there was anything to check it against.
+    fn verify_cached_archive(&self, path: &Path) -> Result<bool, PkgsError> {
+        let file_name = path.file_name().and_then(|name| name.to_str()).unwrap_or_default();
+        let package_dir = self.package_dir(file_name)?;
+        let record: RepodataRecord = match read_optional_json(&package_dir.join(REPODATA_RECORD_FILE)) {
+            Ok(Some(record)) => record,
+            _ => return Ok(false),
+        };
+        if record.record.size.is_none() && record.record.sha256.is_none() && record.record.md5.is_none() {
+            return Ok(false);
+        }
+
+        match verify_archive(path, &record) {
+            Ok(_) => Ok(true),
+            Err(e @ PkgsError::HashMismatch { .. }) if self.is_writable() => {
+                download::quarantine(path, file_name).map_err(|source| PkgsError::IoError { path: path.to_path_buf(), source })?;
+                fs::remove_dir_all(&package_dir).map_err(|source| PkgsError::IoError { path: package_dir.clone(), source })?;
+                Err(e)
+            }
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// Represents the ordered package caches of `pkgs_dirs`. Every cache is
+/// searched for packages, while new downloads and extractions go to the first
+/// writable one, so a shared read-only cache can back per-user caches.
+#[derive(Debug, Clone)]
+pub struct PackageCaches {
+    caches: Vec<PackageCache>,
+}
+
+impl PackageCaches {
+    /// Creates the caches, in order of preference.
+    pub fn new(dirs: &[PathBuf]) -> Self {
+        PackageCaches { caches: dirs.iter().cloned().map(PackageCache::new).collect() }
+    }
+
+    /// Iterates over the caches in order of preference.
+    pub fn iter(&self) -> impl Iterator<Item = &PackageCache> {
+        self.caches.iter()
+    }
+
+    /// Returns the first writable cache, checked before anything is
+    /// downloaded so a transaction never fails halfway on permissions.
+    pub fn writable(&self) -> Result<&PackageCache, PkgsError> {
+        self.caches.iter().find(|cache| cache.is_writable()).ok_or_else(|| {
+            let dirs: Vec<String> = self.caches.iter().map(|cache| cache.dir.display().to_string()).collect();
+            PkgsError::NoWritableCache(dirs.join(", "))
+        })
+    }
+
+    /// Returns the extracted package for a record from the first cache that
+    /// has it, read-only caches included.
+    pub fn get(&self, record: &RepodataRecord) -> Option<ExtractedPackage> {
+        self.caches.iter().find_map(|cache| cache.get(record))
+    }
+
+    /// Returns an archive of the package matching the record from any cache,
+    /// so it can be extracted without downloading it again.
+    pub fn find_archive(&self, record: &RepodataRecord) -> Option<PathBuf> {
+        self.caches
+            .iter()
+            .map(|cache| cache.archive_path(&record.file_name))
+            .find(|path| path.is_file() && verify_archive(path, record).is_ok())
+    }
+}
+
+/// Represents the result of rechecking the archives of a package cache.
+#[derive(Debug, Clone, Default)]
+pub struct CacheVerification {
+    /// Archives matching their recorded size and hashes.
+    pub verified: Vec<PathBuf>,
+
+    /// Archives that did not match and were quarantined, with the reason.
+    pub corrupted: Vec<(PathBuf, String)>,
+
+    /// Archives without a recorded size or hash to check against.
+    pub unverifiable: Vec<PathBuf>,
+}
+
+impl CacheVerification {
+    /// Adds the results of another cache.
+    pub fn extend(&mut self, other: CacheVerification) {
+        self.verified.extend(other.verified);
+        self.corrupted.extend(other.corrupted);
+        self.unverifiable.extend(other.unverifiable);
+    }
+}
+
+/// Checks the size and hashes of an archive against its record.
+pub fn verify_archive(path: &Path, record: &RepodataRecord) -> Result<FileDigests, PkgsError> {
+    let digests = digest::file_digests(path).map_err(|source| PkgsError::IoError { path: path.to_path_buf(), source })?;
+    let published = &record.record;
+    digest::check_digests(&digests, published.size, published.sha256.as_deref(), published.md5.as_deref()).map_err(|mismatch| {
+        PkgsError::HashMismatch { path: path.to_path_buf(), url: redact_url(&record.url), mismatch }
+    })?;
+    Ok(digests)
+}
+
+#[cfg(unix)]
+fn is_dir_writable(dir: &Path) -> bool {
+    use nix::unistd::{access, AccessFlags};
+    access(dir, AccessFlags::W_OK | AccessFlags::X_OK).is_ok()
+}
+
+#[cfg(not(unix))]
+fn is_dir_writable(dir: &Path) -> bool {
+    fs::metadata(dir).map_or(false, |metadata| !metadata.permissions().readonly())
+}
+
+/// Reads `info/has_prefix` of an extracted package, if present.
+pub fn load_has_prefix(info_dir: &Path) -> Result<Vec<HasPrefixEntry>, PkgsError> {
+    Ok(read_optional_text(&info_dir.join("has_prefix"))?
+        .map(|contents| package::parse_has_prefix(&contents))
+        .unwrap_or_default())
+}
+
+/// Reads `info/paths.json` of an extracted package, falling back to the
+/// legacy `files`, `has_prefix` and `no_link` files.
+pub fn load_paths(info_dir: &Path, has_prefix: &[HasPrefixEntry]) -> Result<PathsJson, PkgsError> {
+    if let Some(paths) = read_optional_json(&info_dir.join("paths.json"))? {
+        return Ok(paths);
+    }
+    let files = read_optional_text(&info_dir.join("files"))?.unwrap_or_default();
+    let no_link: Vec<PathBuf> = read_optional_text(&info_dir.join("no_link"))?
+        .unwrap_or_default()
+        .lines()
+        .map(|line| PathBuf::from(line.trim()))
+        .collect();
+    Ok(PathsJson::from_legacy(&files, has_prefix, &no_link))
+}
+
+/// Returns whether a cached record describes the same package file as a requested one.
+fn same_package(cached: &RepodataRecord, requested: &RepodataRecord) -> bool {
+    match (&requested.record.sha256, &requested.record.md5) {
+        (Some(sha256), _) => cached.record.sha256.as_deref().map_or(false, |c| c.eq_ignore_ascii_case(sha256)),
+        (None, Some(md5)) => cached.record.md5.as_deref().map_or(false, |c| c.eq_ignore_ascii_case(md5)),
+        (None, None) => cached.file_name == requested.file_name,
+    }
+}
+
+pub(crate) fn read_json<T: DeserializeOwned>(path: &Path) -> Result<T, PkgsError> {
+    let contents = fs::read(path).map_err(|source| PkgsError::IoError { path: path.to_path_buf(), source })?;
+    serde_json::from_slice(&contents).map_err(|source| PkgsError::ParseError { path: path.to_path_buf(), source })
+}
+
+pub(crate) fn read_optional_json<T: DeserializeOwned>(path: &Path) -> Result<Option<T>, PkgsError> {
+    if path.is_file() {
+        read_json(path).map(Some)
+    } else {
+        Ok(None)
+    }
+}
+
+fn read_optional_text(path: &Path) -> Result<Option<String>, PkgsError> {
+    match fs::read_to_string(path) {
+        Ok(contents) => Ok(Some(contents)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(source) => Err(PkgsError::IoError { path: path.to_path_buf(), source }),
+    }
+}
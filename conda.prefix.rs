@@ -0,0 +1,222 @@
+// conda.prefix.rs
+
+use std::convert::TryFrom;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::matchspec::{MatchSpec, MatchSpecError};
+use crate::package::{FileMode, PathType};
+use crate::repodata::{write_atomic, PackageRecord};
+
+/// Represents an installed package, as recorded in `<prefix>/conda-meta/<dist>.json`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct PrefixRecord {
+    #[serde(flatten)]
+    pub record: PackageRecord,
+
+    /// The channel the package was installed from, as a URL or name.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub channel: Option<String>,
+
+    /// The package filename.
+    #[serde(default, rename = "fn", skip_serializing_if = "Option::is_none")]
+    pub file_name: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+
+    /// The files the package installed, relative to the prefix.
+    #[serde(default)]
+    pub files: Vec<String>,
+
+    /// The spec the user asked for, if the package was explicitly requested.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub requested_spec: Option<String>,
+
+    /// How each file was installed, for verification and removal.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub paths_data: Option<PrefixPaths>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub link: Option<Link>,
+
+    /// The package cache directory the files were linked from.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub extracted_package_dir: Option<PathBuf>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub package_tarball_full_path: Option<PathBuf>,
+}
+
+/// Represents how files were placed into a prefix. Serialized as conda's
+/// numeric link types.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(into = "u8", try_from = "u8")]
+pub enum LinkType {
+    HardLink,
+    SoftLink,
+    Copy,
+}
+
+impl From<LinkType> for u8 {
+    fn from(link_type: LinkType) -> u8 {
+        match link_type {
+            LinkType::HardLink => 1,
+            LinkType::SoftLink => 2,
+            LinkType::Copy => 3,
+        }
+    }
+}
+
+impl TryFrom<u8> for LinkType {
+    type Error = String;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            1 => Ok(LinkType::HardLink),
+            2 => Ok(LinkType::SoftLink),
+            3 => Ok(LinkType::Copy),
+            other => Err(format!("unknown link type {}", other)),
+        }
+    }
+}
+
+/// Represents where a package was linked from.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Link {
+    pub source: PathBuf,
+    #[serde(rename = "type")]
+    pub link_type: LinkType,
+}
+
+/// Represents one installed file of a package.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PrefixPathsEntry {
+    #[serde(rename = "_path")]
+    pub path: PathBuf,
+    pub path_type: PathType,
+
+    /// The hash of the file in the package.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+
+    /// The hash of the installed file, when placeholder replacement changed it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256_in_prefix: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub size_in_bytes: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file_mode: Option<FileMode>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prefix_placeholder: Option<String>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub no_link: bool,
+}
+
+/// Represents the `paths_data` section of a prefix record.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PrefixPaths {
+    #[serde(default = "default_paths_version")]
+    pub paths_version: u64,
+    pub paths: Vec<PrefixPathsEntry>,
+}
+
+fn default_paths_version() -> u64 {
+    1
+}
+
+impl PrefixRecord {
+    /// Returns the `name-version-build` string of the package.
+    pub fn dist_name(&self) -> String {
+        format!("{}-{}-{}", self.record.name, self.record.version, self.record.build)
+    }
+}
+
+/// Represents possible errors that can occur when reading a prefix.
+#[derive(Error, Debug)]
+pub enum PrefixError {
+    #[error("Failed to read {path}: {source}")]
+    IoError { path: PathBuf, source: io::Error },
+
+    #[error("Invalid prefix record {path}: {source}")]
+    ParseError { path: PathBuf, source: serde_json::Error },
+
+    #[error("Invalid pin in {path}: {source}")]
+    PinError { path: PathBuf, source: MatchSpecError },
+
+    #[error("Failed to serialize prefix record: {0}")]
+    SerializeError(#[from] serde_json::Error),
+}
+
+/// Returns the `conda-meta` directory of a prefix.
+pub fn conda_meta_dir(prefix: &Path) -> PathBuf {
+    prefix.join("conda-meta")
+}
+
+/// Loads the records of every package installed in a prefix.
+pub fn load_prefix_records(prefix: &Path) -> Result<Vec<PrefixRecord>, PrefixError> {
+    let meta_dir = conda_meta_dir(prefix);
+    let io_error = |path: &Path| {
+        let path = path.to_path_buf();
+        move |source| PrefixError::IoError { path, source }
+    };
+
+    let mut records = Vec::new();
+    for entry in fs::read_dir(&meta_dir).map_err(io_error(&meta_dir))? {
+        let path = entry.map_err(io_error(&meta_dir))?.path();
+        if path.extension().and_then(|s| s.to_str()) != Some("json") {
+            continue;
+        }
+        let contents = fs::read_to_string(&path).map_err(io_error(&path))?;
+        let record = serde_json::from_str(&contents).map_err(|source| PrefixError::ParseError { path, source })?;
+        records.push(record);
+    }
+
+    records.sort_by(|a: &PrefixRecord, b| a.record.name.cmp(&b.record.name));
+    Ok(records)
+}
+
+/// Loads the pins of a prefix from `conda-meta/pinned`, one match spec per line.
+pub fn load_pins(prefix: &Path) -> Result<Vec<MatchSpec>, PrefixError> {
+    let path = conda_meta_dir(prefix).join("pinned");
+    let contents = match fs::read_to_string(&path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(source) => return Err(PrefixError::IoError { path, source }),
+    };
+
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| {
+            line.parse()
+                .map_err(|source| PrefixError::PinError { path: path.clone(), source })
+        })
+        .collect()
+}
+
+/// Writes a record to `conda-meta/<name>-<version>-<build>.json`.
+pub fn write_prefix_record(prefix: &Path, record: &PrefixRecord) -> Result<PathBuf, PrefixError> {
+    let meta_dir = conda_meta_dir(prefix);
+    fs::create_dir_all(&meta_dir).map_err(|source| PrefixError::IoError { path: meta_dir.clone(), source })?;
+
+    let path = meta_dir.join(format!("{}.json", record.dist_name()));
+    let contents = serde_json::to_vec_pretty(record)?;
+    write_atomic(&path, &contents).map_err(|source| PrefixError::IoError { path: path.clone(), source })?;
+    Ok(path)
+}
+
+/// Deletes the `conda-meta` record of a package.
+pub fn remove_prefix_record(prefix: &Path, record: &PrefixRecord) -> Result<(), PrefixError> {
+    let path = conda_meta_dir(prefix).join(format!("{}.json", record.dist_name()));
+    match fs::remove_file(&path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(PrefixError::IoError { path, source: e }),
+        _ => Ok(()),
+    }
+}
@@ -0,0 +1,307 @@
+// conda.query.rs
+
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::fmt;
+
+use serde::Serialize;
+
+use crate::matchspec::MatchSpec;
+use crate::repodata::PackageRecord;
+use crate::version::VersionOrder;
+
+/// Represents a package that depends on the queried package, directly or
+/// through the package above it in the tree.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Dependent {
+    pub name: String,
+    pub version: String,
+    pub build: String,
+    pub subdir: String,
+
+    /// The `depends` entry the package above satisfies.
+    pub requirement: String,
+
+    /// Packages depending on this one, if the depth limit allows.
+    pub dependents: Vec<Dependent>,
+
+    /// Whether the package's dependents were already listed where it first
+    /// appears in the tree, and are left out here.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub repeated: bool,
+}
+
+impl Dependent {
+    /// Returns the `name-version-build` string of the package.
+    pub fn dist_name(&self) -> String {
+        format!("{}-{}-{}", self.name, self.version, self.build)
+    }
+}
+
+/// Represents the answer to "what needs packages matching this spec?".
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct WhoNeeds {
+    pub spec: String,
+
+    /// The `name-version-build` of every record matching the spec.
+    pub matches: Vec<String>,
+
+    /// The direct dependents, each carrying its own dependents.
+    pub dependents: Vec<Dependent>,
+}
+
+impl WhoNeeds {
+    /// Returns whether nothing depends on the matching packages.
+    pub fn is_empty(&self) -> bool {
+        self.dependents.is_empty()
+    }
+
+    /// Returns every dependent with its depth, direct dependents at depth 1,
+    /// in tree order.
+    pub fn flatten(&self) -> Vec<(usize, &Dependent)> {
+        fn walk<'a>(dependents: &'a [Dependent], depth: usize, out: &mut Vec<(usize, &'a Dependent)>) {
+            for dependent in dependents {
+                out.push((depth, dependent));
+                walk(&dependent.dependents, depth + 1, out);
+            }
+        }
+
+        let mut out = Vec::new();
+        walk(&self.dependents, 1, &mut out);
+        out
+    }
+
+    /// Renders the dependents as an indented tree.
+    pub fn render_tree(&self) -> String {
+        fn walk(dependents: &[Dependent], prefix: &str, out: &mut String) {
+            for (i, dependent) in dependents.iter().enumerate() {
+                let last = i + 1 == dependents.len();
+                out.push_str(&format!(
+                    "{}{} {} ({}){}\n",
+                    prefix,
+                    if last { "└──" } else { "├──" },
+                    dependent.dist_name(),
+                    dependent.requirement,
+                    if dependent.repeated { " (see above)" } else { "" }
+                ));
+                let child_prefix = format!("{}{}", prefix, if last { "    " } else { "│   " });
+                walk(&dependent.dependents, &child_prefix, out);
+            }
+        }
+
+        let mut out = match self.matches.as_slice() {
+            [] => format!("{} (no matching packages)\n", self.spec),
+            matches => format!("{} (matches {})\n", self.spec, matches.join(", ")),
+        };
+        walk(&self.dependents, "", &mut out);
+        out
+    }
+}
+
+impl fmt::Display for WhoNeeds {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.render_tree())
+    }
+}
+
+/// Indexes a set of records by the package names they depend on, for
+/// reverse dependency queries.
+pub struct ReverseIndex<'a> {
+    records: Vec<&'a PackageRecord>,
+    requirements: HashMap<String, Vec<(usize, MatchSpec)>>,
+}
+
+impl<'a> ReverseIndex<'a> {
+    /// Builds the index, ignoring `depends` entries that are not valid specs.
+    pub fn new(records: impl IntoIterator<Item = &'a PackageRecord>) -> Self {
+        let records: Vec<&PackageRecord> = records.into_iter().collect();
+        let mut requirements: HashMap<String, Vec<(usize, MatchSpec)>> = HashMap::new();
+
+        for (index, record) in records.iter().enumerate() {
+            for dependency in &record.depends {
+                match dependency.parse::<MatchSpec>() {
+                    Ok(spec) => requirements.entry(spec.name.clone()).or_default().push((index, spec)),
+                    Err(e) => log::debug!("Ignoring dependency {:?} of {}: {}", dependency, record.name, e),
+                }
+            }
+        }
+
+        ReverseIndex { records, requirements }
+    }
+
+    /// Finds the records whose dependencies are satisfied by a record matching
+    /// `spec`, then their dependents, up to `max_depth` levels (unlimited if
+    /// `None`). Dependency cycles are cut where they would repeat a package.
+    /// A package reached again elsewhere in the tree is listed without its
+    /// dependents, which were shown where it first appeared, so the tree
+    /// stays proportional to the number of dependencies.
+    pub fn whoneeds(&self, spec: &MatchSpec, max_depth: Option<usize>) -> WhoNeeds {
+        let targets: Vec<&PackageRecord> = self.records.iter().copied().filter(|r| spec.matches(r)).collect();
+        let mut matches: Vec<String> = targets
+            .iter()
+            .map(|r| format!("{}-{}-{}", r.name, r.version, r.build))
+            .collect();
+        matches.sort();
+        matches.dedup();
+
+        let satisfied = |requirement: &MatchSpec| targets.iter().any(|target| requirement.matches(target));
+        let mut walk = Walk {
+            max_depth,
+            ancestors: vec![spec.name.as_str()],
+            expanded: HashMap::new(),
+        };
+        let dependents = self.dependents_of(&spec.name, &satisfied, 1, &mut walk);
+
+        WhoNeeds {
+            spec: spec.to_string(),
+            matches,
+            dependents,
+        }
+    }
+
+    fn dependents_of<'w>(
+        &self,
+        name: &str,
+        satisfied: &dyn Fn(&MatchSpec) -> bool,
+        depth: usize,
+        walk: &mut Walk<'w>,
+    ) -> Vec<Dependent>
+    where
+        'a: 'w,
+    {
+        if walk.max_depth.map_or(false, |max| depth > max) {
+            return Vec::new();
+        }
+
+        let mut found: Vec<(usize, &MatchSpec)> = self
+            .requirements
+            .get(name)
+            .into_iter()
+            .flatten()
+            .filter(|(index, requirement)| !walk.ancestors.contains(&self.records[*index].name.as_str()) && satisfied(requirement))
+            .map(|(index, requirement)| (*index, requirement))
+            .collect();
+        found.sort_by(|(a, _), (b, _)| compare_records(self.records[*a], self.records[*b]));
+        found.dedup_by_key(|(index, _)| *index);
+
+        found
+            .into_iter()
+            .map(|(index, requirement)| {
+                let record = self.records[index];
+
+                // A record already expanded at this depth or above has shown
+                // at least as many levels of dependents as it would here.
+                let repeated = match walk.expanded.entry(index) {
+                    Entry::Occupied(entry) if *entry.get() <= depth => true,
+                    Entry::Occupied(mut entry) => {
+                        entry.insert(depth);
+                        false
+                    }
+                    Entry::Vacant(entry) => {
+                        entry.insert(depth);
+                        false
+                    }
+                };
+                let dependents = if repeated {
+                    Vec::new()
+                } else {
+                    walk.ancestors.push(&record.name);
+                    let dependents =
+                        self.dependents_of(&record.name, &|next: &MatchSpec| next.matches(record), depth + 1, walk);
+                    walk.ancestors.pop();
+                    dependents
+                };
+
+                Dependent {
+                    name: record.name.clone(),
+                    version: record.version.clone(),
+                    build: record.build.clone(),
+                    subdir: record.subdir.clone(),
+                    requirement: requirement.to_string(),
+                    dependents,
+                    repeated,
+                }
+            })
+            .collect()
+    }
+}
+
+/// The state of one reverse dependency walk.
+struct Walk<'w> {
+    max_depth: Option<usize>,
+
+    /// The names of the packages above the current one.
+    ancestors: Vec<&'w str>,
+
+    /// The records whose dependents were listed, with the shallowest depth
+    /// they were listed at.
+    expanded: HashMap<usize, usize>,
+}
+
+/// Orders records by name, then newest version first.
+fn compare_records(a: &PackageRecord, b: &PackageRecord) -> std::cmp::Ordering {
+    let version = |r: &PackageRecord| r.version.parse::<VersionOrder>().ok();
+    a.name
+        .cmp(&b.name)
+        .then_with(|| version(b).cmp(&version(a)))
+        .then_with(|| b.build_number.cmp(&a.build_number))
+        .then_with(|| a.build.cmp(&b.build))
+}
+
+/// Finds what depends on packages matching `spec` among a set of records.
+pub fn whoneeds<'a>(
+    records: impl IntoIterator<Item = &'a PackageRecord>,
+    spec: &MatchSpec,
+    max_depth: Option<usize>,
+) -> WhoNeeds {
+    ReverseIndex::new(records).whoneeds(spec, max_depth)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(name: &str, depends: &[&str]) -> PackageRecord {
+        PackageRecord {
+            name: name.to_string(),
+            version: "1.0".to_string(),
+            build: "0".to_string(),
+            depends: depends.iter().map(|d| d.to_string()).collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn shared_dependents_are_listed_once() {
+        // Each layer depends on both packages of the layer below, so the
+        // unshared tree would double with every layer.
+        let mut records = vec![record("base", &[])];
+        let mut below = vec!["base".to_string()];
+        for layer in 0..20 {
+            let names: Vec<String> = (0..2).map(|i| format!("p{}-{}", layer, i)).collect();
+            let depends: Vec<&str> = below.iter().map(String::as_str).collect();
+            records.extend(names.iter().map(|name| record(name, &depends)));
+            below = names;
+        }
+
+        let result = whoneeds(&records, &"base".parse().unwrap(), None);
+        let listed = result.flatten();
+        assert!(listed.len() < 100, "{} dependents listed", listed.len());
+        assert!(listed.iter().any(|(_, dependent)| dependent.repeated));
+        assert!(result.render_tree().contains("(see above)"));
+    }
+
+    #[test]
+    fn cycles_are_cut_by_package_name() {
+        let records = vec![
+            record("a", &[]),
+            record("b", &["a", "c"]),
+            record("c", &["b"]),
+            PackageRecord { build: "1".to_string(), ..record("b", &["a", "c"]) },
+        ];
+        let result = whoneeds(&records, &"a".parse().unwrap(), None);
+        let c = &result.dependents[0].dependents[0];
+        assert_eq!(c.name, "c");
+        assert!(c.dependents.is_empty(), "no build of b may appear below b");
+    }
+}
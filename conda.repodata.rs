@@ -0,0 +1,547 @@
+// conda.repodata.rs
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use reqwest::header::{HeaderMap, HeaderValue, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED};
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use url::Url;
+
+use crate::channel::{Channel, Platform};
+use crate::jlap::{self, JlapError, JlapState};
+use crate::lock::{FileLock, LockError, Locker};
+use crate::network::{NetworkClient, NetworkError};
+use crate::signing::Signatures;
+
+/// Represents the contents of a channel subdir's `repodata.json`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RepoData {
+    /// Channel-level information, including the subdir this repodata describes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub info: Option<ChannelInfo>,
+
+    /// Records for `.tar.bz2` packages, keyed by filename.
+    #[serde(default)]
+    pub packages: HashMap<String, PackageRecord>,
+
+    /// Records for `.conda` packages, keyed by filename.
+    #[serde(default, rename = "packages.conda")]
+    pub conda_packages: HashMap<String, PackageRecord>,
+
+    /// Filenames that have been removed from the channel.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub removed: Vec<String>,
+
+    /// The repodata format version.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub repodata_version: Option<u64>,
+
+    /// Signatures of the records, keyed by filename.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub signatures: BTreeMap<String, Signatures>,
+}
+
+/// Represents the `info` section of a `repodata.json`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ChannelInfo {
+    #[serde(default)]
+    pub subdir: String,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub base_url: Option<String>,
+}
+
+/// Represents a single package entry in a `repodata.json`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct PackageRecord {
+    pub name: String,
+    pub version: String,
+    pub build: String,
+    #[serde(default)]
+    pub build_number: u64,
+    #[serde(default)]
+    pub depends: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub constrains: Vec<String>,
+    #[serde(default)]
+    pub subdir: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub noarch: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_hash", skip_serializing_if = "Option::is_none")]
+    pub md5: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_hash", skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub license: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub license_family: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub track_features: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub features: Vec<String>,
+
+    /// Set by repodata patches for packages that must no longer be installed.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub revoked: bool,
+}
+
+impl RepoData {
+    /// Iterates over all records in the repodata, `.conda` packages first.
+    pub fn records(&self) -> impl Iterator<Item = (&String, &PackageRecord)> {
+        self.conda_packages.iter().chain(self.packages.iter())
+    }
+
+    /// Returns all records for the given package name.
+    pub fn records_named<'a, 'n>(&'a self, name: &'n str) -> impl Iterator<Item = (&'a String, &'a PackageRecord)> + 'n
+    where
+        'a: 'n,
+    {
+        self.records().filter(move |(_, record)| record.name == name)
+    }
+}
+
+/// Represents a record together with where its package file came from, as
+/// stored in `info/repodata_record.json` of an extracted package.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct RepodataRecord {
+    #[serde(flatten)]
+    pub record: PackageRecord,
+
+    /// The package filename.
+    #[serde(rename = "fn")]
+    pub file_name: String,
+    pub url: String,
+    pub channel: String,
+}
+
+/// Represents the repodata of one platform subdir of a channel.
+#[derive(Debug, Clone)]
+pub struct SubdirRepodata {
+    pub channel: Channel,
+    pub platform: Platform,
+    pub repodata: RepoData,
+}
+
+/// Represents the cached state of a `repodata.json` download.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct CacheState {
+    pub url: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub etag: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_modified: Option<String>,
+
+    /// The blake2b-256 hash of the cached repodata, as tracked by the JLAP chain.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub blake2_hash: Option<String>,
+
+    /// The position and running checksum of the last JLAP file we consumed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub jlap: Option<JlapState>,
+}
+
+/// Represents possible errors that can occur when fetching repodata.
+#[derive(Error, Debug)]
+pub enum RepodataError {
+    #[error("Failed to access repodata cache: {0}")]
+    CacheError(#[from] std::io::Error),
+
+    #[error("Failed to parse repodata: {0}")]
+    ParseError(#[from] serde_json::Error),
+
+    #[error(transparent)]
+    NetworkError(#[from] NetworkError),
+
+    #[error("Server returned {status} for {url}")]
+    StatusError { url: String, status: StatusCode },
+
+    #[error("Invalid local subdir: {0}")]
+    InvalidPath(String),
+
+    #[error("Incremental update failed: {0}")]
+    JlapError(#[from] JlapError),
+
+    #[error(transparent)]
+    LockError(#[from] LockError),
+}
+
+/// A directory of cached `repodata.json` files and their download state.
+#[derive(Debug, Clone)]
+pub struct RepodataCache {
+    cache_dir: PathBuf,
+    locker: Option<Locker>,
+}
+
+impl RepodataCache {
+    /// Creates a cache rooted at the given directory.
+    pub fn new(cache_dir: impl Into<PathBuf>) -> Self {
+        RepodataCache { cache_dir: cache_dir.into(), locker: None }
+    }
+
+    /// Locks each cache entry while it is fetched, so concurrent processes
+    /// never update the same entry at once.
+    pub fn with_locker(mut self, locker: Locker) -> Self {
+        self.locker = Some(locker);
+        self
+    }
+
+    /// Returns the cache key for a subdir URL.
+    pub fn cache_key(subdir_url: &str) -> String {
+        let digest = ring::digest::digest(&ring::digest::SHA256, subdir_url.trim_end_matches('/').as_bytes());
+        data_encoding::HEXLOWER.encode(&digest.as_ref()[..4])
+    }
+
+    /// Returns the path of the cached `repodata.json` for a subdir URL.
+    pub fn repodata_path(&self, subdir_url: &str) -> PathBuf {
+        self.cache_dir.join(format!("{}.json", Self::cache_key(subdir_url)))
+    }
+
+    /// Returns the path of the cache state file for a subdir URL.
+    pub fn state_path(&self, subdir_url: &str) -> PathBuf {
+        self.cache_dir.join(format!("{}.state.json", Self::cache_key(subdir_url)))
+    }
+
+    /// Returns the path of the lock file of the cache entry for a subdir URL.
+    pub fn lock_path(&self, subdir_url: &str) -> PathBuf {
+        self.cache_dir.join(format!("{}.lock", Self::cache_key(subdir_url)))
+    }
+
+    /// Locks the cache entry for a subdir URL, if the cache has a locker.
+    fn lock(&self, subdir_url: &str) -> Result<Option<FileLock>, LockError> {
+        self.lock_entry(&Self::cache_key(subdir_url))
+    }
+
+    /// Locks the cache entry with the given key, if the cache has a locker.
+    pub fn lock_entry(&self, key: &str) -> Result<Option<FileLock>, LockError> {
+        let lock_path = self.cache_dir.join(format!("{}.lock", key));
+        self.locker.as_ref().map(|locker| locker.lock(&lock_path)).transpose()
+    }
+
+    /// Returns the keys of the entries in the cache.
+    pub fn keys(&self) -> std::io::Result<Vec<String>> {
+        let entries = match fs::read_dir(&self.cache_dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+        let mut keys = Vec::new();
+        for entry in entries {
+            let file_name = entry?.file_name().to_string_lossy().into_owned();
+            if let Some((key, _)) = file_name.split_once('.') {
+                keys.push(key.to_string());
+            }
+        }
+        keys.sort();
+        keys.dedup();
+        Ok(keys)
+    }
+
+    /// Returns the files of the entry with the given key: its repodata, its
+    /// state and any leftover temporary files, but not its lock.
+    pub fn entry_files(&self, key: &str) -> std::io::Result<Vec<PathBuf>> {
+        let prefix = format!("{}.", key);
+        let lock_file = format!("{}.lock", key);
+        let mut files = Vec::new();
+        for entry in fs::read_dir(&self.cache_dir)? {
+            let entry = entry?;
+            let file_name = entry.file_name().to_string_lossy().into_owned();
+            if file_name.starts_with(&prefix) && file_name != lock_file {
+                files.push(entry.path());
+            }
+        }
+        Ok(files)
+    }
+
+    /// Loads the cache state for a subdir URL, if both the state and the repodata exist.
+    pub fn load_state(&self, subdir_url: &str) -> Option<CacheState> {
+        if !self.repodata_path(subdir_url).is_file() {
+            return None;
+        }
+        let contents = fs::read_to_string(self.state_path(subdir_url)).ok()?;
+        serde_json::from_str(&contents).ok()
+    }
+
+    /// Writes repodata bytes and state for a subdir URL.
+    pub fn store(&self, subdir_url: &str, repodata: &[u8], state: &CacheState) -> Result<(), RepodataError> {
+        fs::create_dir_all(&self.cache_dir)?;
+        write_atomic(&self.repodata_path(subdir_url), repodata)?;
+        write_atomic(&self.state_path(subdir_url), serde_json::to_string_pretty(state)?.as_bytes())?;
+        Ok(())
+    }
+
+    /// Fetches the repodata for a subdir, preferring an incremental JLAP update over a full download.
+    pub fn fetch(&self, client: &NetworkClient, subdir_url: &str) -> Result<RepoData, RepodataError> {
+        let subdir_url = subdir_url.trim_end_matches('/');
+        let _lock = self.lock(subdir_url)?;
+
+        if let Some(state) = self.load_state(subdir_url) {
+            match self.update_with_jlap(client, subdir_url, &state) {
+                Ok(Some(repodata)) => return Ok(repodata),
+                Ok(None) => {}
+                Err(e) => log::warn!("Falling back to full repodata download for {}: {}", subdir_url, e),
+            }
+        }
+
+        self.fetch_full(client, subdir_url)
+    }
+
+    /// Applies new JLAP patches to the cached repodata. Returns `None` if the
+    /// channel does not publish a `repodata.jlap`.
+    fn update_with_jlap(
+        &self,
+        client: &NetworkClient,
+        subdir_url: &str,
+        state: &CacheState,
+    ) -> Result<Option<RepoData>, RepodataError> {
+        let current_hash = match &state.blake2_hash {
+            Some(hash) => hash.clone(),
+            None => return Ok(None),
+        };
+
+        let jlap_url = format!("{}/repodata.jlap", subdir_url);
+        let fetched = match jlap::fetch(client, &jlap_url, state.jlap.as_ref())? {
+            Some(fetched) => fetched,
+            None => return Ok(None),
+        };
+
+        let cached = fs::read(self.repodata_path(subdir_url))?;
+        let mut document: serde_json::Value = serde_json::from_slice(&cached)?;
+        let latest = fetched.apply(&mut document, &current_hash)?;
+
+        let bytes = serde_json::to_vec(&document)?;
+        let repodata: RepoData = serde_json::from_value(document)?;
+
+        let new_state = CacheState {
+            url: subdir_url.to_string(),
+            etag: state.etag.clone(),
+            last_modified: state.last_modified.clone(),
+            blake2_hash: Some(latest),
+            jlap: Some(fetched.state),
+        };
+        self.store(subdir_url, &bytes, &new_state)?;
+
+        Ok(Some(repodata))
+    }
+
+    /// Downloads the full `repodata.json`, honouring cached ETag and Last-Modified headers.
+    fn fetch_full(&self, client: &NetworkClient, subdir_url: &str) -> Result<RepoData, RepodataError> {
+        let url = format!("{}/repodata.json", subdir_url);
+        let previous = self.load_state(subdir_url);
+
+        let mut headers = HeaderMap::new();
+        if let Some(previous) = &previous {
+            if let Some(etag) = previous.etag.as_deref().and_then(|v| HeaderValue::from_str(v).ok()) {
+                headers.insert(IF_NONE_MATCH, etag);
+            }
+            if let Some(last_modified) = previous.last_modified.as_deref().and_then(|v| HeaderValue::from_str(v).ok()) {
+                headers.insert(IF_MODIFIED_SINCE, last_modified);
+            }
+        }
+
+        let response = client.get_with_headers(&url, headers)?;
+
+        if response.status() == StatusCode::NOT_MODIFIED {
+            let cached = fs::read(self.repodata_path(subdir_url))?;
+            return Ok(serde_json::from_slice(&cached)?);
+        }
+        if !response.status().is_success() {
+            return Err(RepodataError::StatusError { url, status: response.status() });
+        }
+
+        let etag = header_string(&response, ETAG);
+        let last_modified = header_string(&response, LAST_MODIFIED);
+        let bytes = NetworkClient::bytes(&url, response)?;
+        let repodata: RepoData = serde_json::from_slice(&bytes)?;
+
+        let state = CacheState {
+            url: subdir_url.to_string(),
+            etag,
+            last_modified,
+            blake2_hash: Some(jlap::blake2_hex(&bytes)),
+            jlap: None,
+        };
+        self.store(subdir_url, &bytes, &state)?;
+
+        Ok(repodata)
+    }
+}
+
+/// Loads the `repodata.json` of a subdir on the local filesystem. A missing
+/// subdir directory is treated as an empty subdir.
+pub fn load_local(subdir_path: &Path) -> Result<RepoData, RepodataError> {
+    if !subdir_path.is_dir() {
+        return Ok(RepoData::default());
+    }
+    let contents = fs::read(subdir_path.join("repodata.json"))?;
+    Ok(serde_json::from_slice(&contents)?)
+}
+
+/// Fetches the repodata of a subdir URL, reading `file://` subdirs straight
+/// from disk. The client is only requested for remote subdirs.
+pub fn fetch_subdir<'a>(
+    cache: &RepodataCache,
+    subdir_url: &Url,
+    client: impl FnOnce() -> Result<&'a NetworkClient, NetworkError>,
+) -> Result<RepoData, RepodataError> {
+    if subdir_url.scheme() == "file" {
+        let path = subdir_url
+            .to_file_path()
+            .map_err(|_| RepodataError::InvalidPath(subdir_url.to_string()))?;
+        load_local(&path)
+    } else {
+        cache.fetch(client()?, subdir_url.as_str())
+    }
+}
+
+/// Deserializes a hash given either as a hex string (`repodata.json`) or as
+/// raw bytes (msgpack shards) into a lowercase hex string.
+fn deserialize_hash<'de, D>(deserializer: D) -> Result<Option<String>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    struct HashVisitor;
+
+    impl<'de> serde::de::Visitor<'de> for HashVisitor {
+        type Value = Option<String>;
+
+        fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+            formatter.write_str("a hex string or raw hash bytes")
+        }
+
+        fn visit_none<E: serde::de::Error>(self) -> Result<Self::Value, E> {
+            Ok(None)
+        }
+
+        fn visit_unit<E: serde::de::Error>(self) -> Result<Self::Value, E> {
+            Ok(None)
+        }
+
+        fn visit_some<D: serde::Deserializer<'de>>(self, deserializer: D) -> Result<Self::Value, D::Error> {
+            deserializer.deserialize_any(self)
+        }
+
+        fn visit_str<E: serde::de::Error>(self, value: &str) -> Result<Self::Value, E> {
+            Ok(Some(value.to_lowercase()))
+        }
+
+        fn visit_bytes<E: serde::de::Error>(self, value: &[u8]) -> Result<Self::Value, E> {
+            Ok(Some(data_encoding::HEXLOWER.encode(value)))
+        }
+    }
+
+    deserializer.deserialize_option(HashVisitor)
+}
+
+fn header_string(response: &reqwest::blocking::Response, name: reqwest::header::HeaderName) -> Option<String> {
+    response
+        .headers()
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .map(String::from)
+}
+
+/// Writes a file by renaming a sibling temp file into place.
+pub(crate) fn write_atomic(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    let tmp_path = path.with_extension("tmp");
+    fs::write(&tmp_path, contents)?;
+    fs::rename(&tmp_path, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::jlap::tests::{jlap_file, patch};
+    use crate::network::test_server::TestServer;
+
+    const EMPTY: &[u8] = br#"{"packages": {}, "packages.conda": {}}"#;
+
+    #[test]
+    fn fetch_reuses_the_cache_when_not_modified() {
+        let server = TestServer::start();
+        let cache_dir = tempfile::tempdir().unwrap();
+        let cache = RepodataCache::new(cache_dir.path());
+        let subdir_url = server.url("/linux-64");
+
+        server.serve("/linux-64/repodata.json", EMPTY, Some("\"v1\""));
+        cache.fetch(&server.client(), &subdir_url).unwrap();
+        assert_eq!(cache.load_state(&subdir_url).unwrap().etag.as_deref(), Some("\"v1\""));
+
+        // Under an unchanged ETag the server answers 304, so changed contents are never seen.
+        server.serve("/linux-64/repodata.json", br#"{"packages": {"x": {"name": "x", "version": "1", "build": "0"}}}"#.to_vec(), Some("\"v1\""));
+        server.take_requests();
+        let repodata = cache.fetch(&server.client(), &subdir_url).unwrap();
+
+        let requests = server.take_requests();
+        let full = requests.iter().find(|r| r.path == "/linux-64/repodata.json").unwrap();
+        assert_eq!(full.headers.get("if-none-match").map(String::as_str), Some("\"v1\""));
+        assert!(repodata.packages.is_empty());
+    }
+
+    #[test]
+    fn fetch_applies_jlap_patches_and_resumes_from_the_cached_position() {
+        let server = TestServer::start();
+        let cache_dir = tempfile::tempdir().unwrap();
+        let cache = RepodataCache::new(cache_dir.path());
+        let subdir_url = server.url("/linux-64");
+
+        server.serve("/linux-64/repodata.json", EMPTY, Some("\"v1\""));
+        cache.fetch(&server.client(), &subdir_url).unwrap();
+
+        let initial = jlap::blake2_hex(EMPTY);
+        let first = patch(&initial, "h1", "x-1.0-0.tar.bz2");
+        server.serve("/linux-64/repodata.jlap", jlap_file([3; 32], &[first.clone()], "h1"), None);
+        server.take_requests();
+        let repodata = cache.fetch(&server.client(), &subdir_url).unwrap();
+        assert!(repodata.packages.contains_key("x-1.0-0.tar.bz2"));
+        let requests = server.take_requests();
+        assert!(requests.iter().all(|r| r.path == "/linux-64/repodata.jlap"));
+
+        let state = cache.load_state(&subdir_url).unwrap();
+        assert_eq!(state.blake2_hash.as_deref(), Some("h1"));
+        let position = state.jlap.unwrap().position;
+
+        let second = patch("h1", "h2", "y-1.0-0.tar.bz2");
+        server.serve("/linux-64/repodata.jlap", jlap_file([3; 32], &[first, second], "h2"), None);
+        let repodata = cache.fetch(&server.client(), &subdir_url).unwrap();
+        assert!(repodata.packages.contains_key("x-1.0-0.tar.bz2"));
+        assert!(repodata.packages.contains_key("y-1.0-0.tar.bz2"));
+
+        let requests = server.take_requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].headers.get("range"), Some(&format!("bytes={}-", position)));
+        assert_eq!(cache.load_state(&subdir_url).unwrap().blake2_hash.as_deref(), Some("h2"));
+    }
+
+    #[test]
+    fn fetch_downloads_the_full_repodata_when_the_jlap_checksum_mismatches() {
+        let server = TestServer::start();
+        let cache_dir = tempfile::tempdir().unwrap();
+        let cache = RepodataCache::new(cache_dir.path());
+        let subdir_url = server.url("/linux-64");
+
+        server.serve("/linux-64/repodata.json", EMPTY, Some("\"v1\""));
+        cache.fetch(&server.client(), &subdir_url).unwrap();
+
+        let initial = jlap::blake2_hex(EMPTY);
+        let jlap = jlap_file([3; 32], &[patch(&initial, "h1", "x-1.0-0.tar.bz2")], "h1");
+        let corrupt = String::from_utf8(jlap).unwrap().replacen("x-1.0-0", "z-1.0-0", 1);
+        server.serve("/linux-64/repodata.jlap", corrupt, None);
+
+        let updated = br#"{"packages": {"w-1.0-0.tar.bz2": {"name": "w", "version": "1.0", "build": "0"}}}"#;
+        server.serve("/linux-64/repodata.json", updated.to_vec(), Some("\"v2\""));
+        let repodata = cache.fetch(&server.client(), &subdir_url).unwrap();
+
+        assert!(repodata.packages.contains_key("w-1.0-0.tar.bz2"));
+        assert!(!repodata.packages.contains_key("z-1.0-0.tar.bz2"));
+        let state = cache.load_state(&subdir_url).unwrap();
+        assert_eq!(state.etag.as_deref(), Some("\"v2\""));
+        assert_eq!(state.blake2_hash, Some(jlap::blake2_hex(updated)));
+    }
+}
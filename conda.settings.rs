@@ -2,10 +2,12 @@
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use thiserror::Error;
 use toml;
 
+use crate::auth::redact_url;
+
 /// Represents the configuration settings for the Conda package manager.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CondaSettings {
@@ -29,6 +31,10 @@ pub struct CondaSettings {
     
     /// The timeout in seconds for network operations.
     pub network_timeout: u64,
+
+    /// The timeout in seconds for establishing network connections.
+    #[serde(default = "default_connect_timeout")]
+    pub connect_timeout: u64,
     
     /// Whether to automatically update Conda on startup.
     pub auto_update: bool,
@@ -57,17 +63,84 @@ pub struct CondaSettings {
     /// Whether to verify SSL certificates for HTTPS connections.
     pub ssl_verify: bool,
     
+    /// A PEM bundle of additional CA certificates to trust for HTTPS connections.
+    #[serde(default)]
+    pub ssl_ca_bundle: Option<PathBuf>,
+
+    /// A PEM client certificate to present to servers, optionally including its key.
+    #[serde(default)]
+    pub client_cert: Option<PathBuf>,
+
+    /// The PEM private key of the client certificate, if not bundled with it.
+    #[serde(default)]
+    pub client_cert_key: Option<PathBuf>,
+
     /// The proxy settings for network connections.
     pub proxy_settings: Option<ProxySettings>,
     
     /// Whether to use the local package cache.
     pub offline_mode: bool,
+
+    /// The package cache directories, in order of preference.
+    #[serde(default = "default_pkgs_dirs")]
+    pub pkgs_dirs: Vec<PathBuf>,
     
+    /// The maximum number of package files downloaded at once.
+    #[serde(default = "default_download_threads")]
+    pub download_threads: usize,
+
+    /// The maximum number of packages extracted at once.
+    #[serde(default = "default_extract_threads")]
+    pub extract_threads: usize,
+
+    /// The combined download rate limit in bytes per second, if any.
+    #[serde(default)]
+    pub download_bandwidth_limit: Option<u64>,
+
+    /// The time in seconds to wait for a prefix or cache locked by another process.
+    #[serde(default = "default_lock_timeout")]
+    pub lock_timeout: u64,
+
     /// The maximum size of the package cache in bytes.
     pub package_cache_size_limit: u64,
     
     /// Whether to add pip as a dependency to new environments by default.
     pub add_pip_as_python_dependency: bool,
+
+    /// Whether to sign built packages and indexed channels with `signing_key`.
+    #[serde(default)]
+    pub sign_packages: bool,
+
+    /// Whether to reject packages not signed by a key trusted for their channel.
+    #[serde(default)]
+    pub verify_signatures: bool,
+
+    /// The ed25519 private key file used when `sign_packages` is enabled.
+    /// Building or indexing fails while signing is enabled without one.
+    #[serde(default)]
+    pub signing_key: Option<PathBuf>,
+}
+
+fn default_connect_timeout() -> u64 {
+    10
+}
+
+fn default_download_threads() -> usize {
+    5
+}
+
+fn default_extract_threads() -> usize {
+    4
+}
+
+fn default_lock_timeout() -> u64 {
+    300
+}
+
+fn default_pkgs_dirs() -> Vec<PathBuf> {
+    dirs::home_dir()
+        .map(|home| vec![home.join(".conda").join("pkgs")])
+        .unwrap_or_default()
 }
 
 /// Represents the compression type for creating packages.
@@ -96,6 +169,69 @@ pub struct ProxySettings {
     pub no_proxy: Option<Vec<String>>,
 }
 
+/// Represents the `[conda.*]` sections of conda.toml that configure settings.
+/// Every key is optional, so absent keys keep their current values.
+#[derive(Debug, Default, Deserialize)]
+struct CondaToml {
+    #[serde(default)]
+    conda: CondaTomlSections,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct CondaTomlSections {
+    #[serde(default)]
+    network: NetworkSection,
+    #[serde(default)]
+    cache: CacheSection,
+    #[serde(default)]
+    security: SecuritySection,
+    #[serde(default)]
+    performance: PerformanceSection,
+}
+
+/// Represents `[conda.network]`.
+#[derive(Debug, Default, Deserialize)]
+struct NetworkSection {
+    ssl_verify: Option<bool>,
+    proxy_servers: Option<HashMap<String, String>>,
+    no_proxy: Option<Vec<String>>,
+    connect_timeout_secs: Option<u64>,
+    timeout_secs: Option<u64>,
+    max_retries: Option<u32>,
+}
+
+/// Represents `[conda.cache]`.
+#[derive(Debug, Default, Deserialize)]
+struct CacheSection {
+    pkgs_dirs: Option<Vec<PathBuf>>,
+    size_limit: Option<u64>,
+    lock_timeout_secs: Option<u64>,
+}
+
+/// Represents `[conda.security]`.
+#[derive(Debug, Default, Deserialize)]
+struct SecuritySection {
+    sign_packages: Option<bool>,
+    verify_signatures: Option<bool>,
+    signing_key: Option<PathBuf>,
+}
+
+/// Represents `[conda.performance]`.
+#[derive(Debug, Default, Deserialize)]
+struct PerformanceSection {
+    download_threads: Option<usize>,
+    extract_threads: Option<usize>,
+    download_bandwidth_limit: Option<u64>,
+}
+
+/// Expands a leading `~` to the home directory.
+fn expand_home(path: &Path) -> PathBuf {
+    match (path.strip_prefix("~"), dirs::home_dir()) {
+        (Ok(rest), Some(home)) => home.join(rest),
+        _ => path.to_path_buf(),
+    }
+}
+
 /// Represents possible errors that can occur when working with Conda settings.
 #[derive(Error, Debug)]
 pub enum CondaSettingsError {
@@ -123,6 +259,7 @@ impl CondaSettings {
             env_vars: HashMap::new(),
             max_retries: 3,
             network_timeout: 60,
+            connect_timeout: default_connect_timeout(),
             auto_update: false,
             default_shell: String::from("bash"),
             show_channel_urls: false,
@@ -132,10 +269,21 @@ impl CondaSettings {
             create_condarc: true,
             default_architecture: Architecture::X86_64,
             ssl_verify: true,
+            ssl_ca_bundle: None,
+            client_cert: None,
+            client_cert_key: None,
             proxy_settings: None,
             offline_mode: false,
+            pkgs_dirs: default_pkgs_dirs(),
+            download_threads: default_download_threads(),
+            extract_threads: default_extract_threads(),
+            download_bandwidth_limit: None,
+            lock_timeout: default_lock_timeout(),
             package_cache_size_limit: 10 * 1024 * 1024 * 1024, // 10 GB
             add_pip_as_python_dependency: true,
+            sign_packages: false,
+            verify_signatures: false,
+            signing_key: None,
         }
     }
 
@@ -146,6 +294,74 @@ impl CondaSettings {
         Ok(settings)
     }
 
+    /// Applies the `[conda.network]`, `[conda.cache]`, `[conda.security]` and
+    /// `[conda.performance]` sections of a conda.toml file.
+    pub fn apply_conda_toml(&mut self, path: &Path) -> Result<(), CondaSettingsError> {
+        let contents = fs::read_to_string(path)?;
+        self.apply_conda_toml_str(&contents)
+    }
+
+    /// Applies the `[conda.*]` settings sections of conda.toml contents. Keys
+    /// absent from the file keep their current values.
+    pub fn apply_conda_toml_str(&mut self, contents: &str) -> Result<(), CondaSettingsError> {
+        let sections = toml::from_str::<CondaToml>(contents)?.conda;
+
+        let network = sections.network;
+        if let Some(ssl_verify) = network.ssl_verify {
+            self.ssl_verify = ssl_verify;
+        }
+        if let Some(servers) = network.proxy_servers {
+            self.proxy_settings = Some(ProxySettings {
+                http_proxy: servers.get("http").cloned(),
+                https_proxy: servers.get("https").cloned(),
+                no_proxy: network.no_proxy,
+            });
+        }
+        if let Some(timeout) = network.connect_timeout_secs {
+            self.connect_timeout = timeout;
+        }
+        if let Some(timeout) = network.timeout_secs {
+            self.network_timeout = timeout;
+        }
+        if let Some(retries) = network.max_retries {
+            self.max_retries = retries;
+        }
+
+        let cache = sections.cache;
+        if let Some(pkgs_dirs) = cache.pkgs_dirs {
+            self.pkgs_dirs = pkgs_dirs.iter().map(|dir| expand_home(dir)).collect();
+        }
+        if let Some(limit) = cache.size_limit {
+            self.package_cache_size_limit = limit;
+        }
+        if let Some(timeout) = cache.lock_timeout_secs {
+            self.lock_timeout = timeout;
+        }
+
+        let security = sections.security;
+        if let Some(sign_packages) = security.sign_packages {
+            self.sign_packages = sign_packages;
+        }
+        if let Some(verify_signatures) = security.verify_signatures {
+            self.verify_signatures = verify_signatures;
+        }
+        if let Some(key) = security.signing_key {
+            self.signing_key = Some(expand_home(&key));
+        }
+
+        let performance = sections.performance;
+        if let Some(threads) = performance.download_threads {
+            self.download_threads = threads;
+        }
+        if let Some(threads) = performance.extract_threads {
+            self.extract_threads = threads;
+        }
+        if let Some(limit) = performance.download_bandwidth_limit {
+            self.download_bandwidth_limit = Some(limit);
+        }
+        Ok(())
+    }
+
     /// Saves the Conda settings to a TOML file.
     pub fn save(&self, path: &PathBuf) -> Result<(), CondaSettingsError> {
         let toml_string = toml::to_string_pretty(self)?;
@@ -162,6 +378,7 @@ impl CondaSettings {
         self.env_vars = other.env_vars.clone();
         self.max_retries = other.max_retries;
         self.network_timeout = other.network_timeout;
+        self.connect_timeout = other.connect_timeout;
         self.auto_update = other.auto_update;
         self.default_shell = other.default_shell.clone();
         self.show_channel_urls = other.show_channel_urls;
@@ -171,10 +388,21 @@ impl CondaSettings {
         self.create_condarc = other.create_condarc;
         self.default_architecture = other.default_architecture.clone();
         self.ssl_verify = other.ssl_verify;
+        self.ssl_ca_bundle = other.ssl_ca_bundle.clone();
+        self.client_cert = other.client_cert.clone();
+        self.client_cert_key = other.client_cert_key.clone();
         self.proxy_settings = other.proxy_settings.clone();
         self.offline_mode = other.offline_mode;
+        self.pkgs_dirs = other.pkgs_dirs.clone();
+        self.download_threads = other.download_threads;
+        self.extract_threads = other.extract_threads;
+        self.download_bandwidth_limit = other.download_bandwidth_limit;
+        self.lock_timeout = other.lock_timeout;
         self.package_cache_size_limit = other.package_cache_size_limit;
         self.add_pip_as_python_dependency = other.add_pip_as_python_dependency;
+        self.sign_packages = other.sign_packages;
+        self.verify_signatures = other.verify_signatures;
+        self.signing_key = other.signing_key.clone();
     }
 
     /// Adds a custom environment variable to the settings.
@@ -221,10 +449,34 @@ impl CondaSettings {
             errors.push("network_timeout must be greater than 0".to_string());
         }
 
+        if self.connect_timeout == 0 {
+            errors.push("connect_timeout must be greater than 0".to_string());
+        }
+
+        if self.client_cert_key.is_some() && self.client_cert.is_none() {
+            errors.push("client_cert_key requires client_cert to be set".to_string());
+        }
+
         if self.max_environments == 0 {
             errors.push("max_environments must be greater than 0".to_string());
         }
 
+        if self.pkgs_dirs.is_empty() {
+            errors.push("pkgs_dirs must contain at least one directory".to_string());
+        }
+
+        if self.download_threads == 0 {
+            errors.push("download_threads must be greater than 0".to_string());
+        }
+
+        if self.extract_threads == 0 {
+            errors.push("extract_threads must be greater than 0".to_string());
+        }
+
+        if self.download_bandwidth_limit == Some(0) {
+            errors.push("download_bandwidth_limit must be greater than 0 when set".to_string());
+        }
+
         if self.package_cache_size_limit == 0 {
             errors.push("package_cache_size_limit must be greater than 0".to_string());
         }
@@ -246,6 +498,7 @@ impl CondaSettings {
             - Default Channels: {}
             - Max Retries: {}
             - Network Timeout: {} seconds
+            - Connect Timeout: {} seconds
             - Auto Update: {}
             - Default Shell: {}
             - Show Channel URLs: {}
@@ -255,15 +508,30 @@ impl CondaSettings {
             - Create .condarc: {}
             - Default Architecture: {:?}
             - SSL Verify: {}
+            - SSL CA Bundle: {}
+            - Client Certificate: {}
             - Offline Mode: {}
+            - Package Cache Directories: {}
+            - Download Threads: {}
+            - Extract Threads: {}
+            - Download Bandwidth Limit: {}
+            - Lock Timeout: {} seconds
             - Package Cache Size Limit: {} bytes
-            - Add pip as Python Dependency: {}",
+            - Add pip as Python Dependency: {}
+            - Sign Packages: {}
+            - Verify Signatures: {}
+            - Signing Key: {}",
             self.environments_dir.display(),
             self.default_python_version,
             self.channel_priority_strict,
-            self.default_channels.join(", "),
+            self.default_channels
+                .iter()
+                .map(|channel| redact_url(channel))
+                .collect::<Vec<_>>()
+                .join(", "),
             self.max_retries,
             self.network_timeout,
+            self.connect_timeout,
             self.auto_update,
             self.default_shell,
             self.show_channel_urls,
@@ -273,9 +541,23 @@ impl CondaSettings {
             self.create_condarc,
             self.default_architecture,
             self.ssl_verify,
+            self.ssl_ca_bundle.as_ref().map_or("none".to_string(), |p| p.display().to_string()),
+            self.client_cert.as_ref().map_or("none".to_string(), |p| p.display().to_string()),
             self.offline_mode,
+            self.pkgs_dirs
+                .iter()
+                .map(|dir| dir.display().to_string())
+                .collect::<Vec<_>>()
+                .join(", "),
+            self.download_threads,
+            self.extract_threads,
+            self.download_bandwidth_limit.map_or("none".to_string(), |limit| format!("{} bytes/s", limit)),
+            self.lock_timeout,
             self.package_cache_size_limit,
-            self.add_pip_as_python_dependency
+            self.add_pip_as_python_dependency,
+            self.sign_packages,
+            self.verify_signatures,
+            self.signing_key.as_ref().map_or("none".to_string(), |p| p.display().to_string())
         )
     }
 
@@ -309,6 +591,9 @@ impl CondaSettings {
         if other.network_timeout != default.network_timeout {
             self.network_timeout = other.network_timeout;
         }
+        if other.connect_timeout != default.connect_timeout {
+            self.connect_timeout = other.connect_timeout;
+        }
         if other.auto_update != default.auto_update {
             self.auto_update = other.auto_update;
         }
@@ -336,18 +621,51 @@ impl CondaSettings {
         if other.ssl_verify != default.ssl_verify {
             self.ssl_verify = other.ssl_verify;
         }
+        if other.ssl_ca_bundle.is_some() {
+            self.ssl_ca_bundle = other.ssl_ca_bundle.clone();
+        }
+        if other.client_cert.is_some() {
+            self.client_cert = other.client_cert.clone();
+        }
+        if other.client_cert_key.is_some() {
+            self.client_cert_key = other.client_cert_key.clone();
+        }
         if other.proxy_settings.is_some() {
             self.proxy_settings = other.proxy_settings.clone();
         }
         if other.offline_mode != default.offline_mode {
             self.offline_mode = other.offline_mode;
         }
+        if other.pkgs_dirs != default.pkgs_dirs {
+            self.pkgs_dirs = other.pkgs_dirs.clone();
+        }
+        if other.download_threads != default.download_threads {
+            self.download_threads = other.download_threads;
+        }
+        if other.extract_threads != default.extract_threads {
+            self.extract_threads = other.extract_threads;
+        }
+        if other.download_bandwidth_limit.is_some() {
+            self.download_bandwidth_limit = other.download_bandwidth_limit;
+        }
+        if other.lock_timeout != default.lock_timeout {
+            self.lock_timeout = other.lock_timeout;
+        }
         if other.package_cache_size_limit != default.package_cache_size_limit {
             self.package_cache_size_limit = other.package_cache_size_limit;
         }
         if other.add_pip_as_python_dependency != default.add_pip_as_python_dependency {
             self.add_pip_as_python_dependency = other.add_pip_as_python_dependency;
         }
+        if other.sign_packages != default.sign_packages {
+            self.sign_packages = other.sign_packages;
+        }
+        if other.verify_signatures != default.verify_signatures {
+            self.verify_signatures = other.verify_signatures;
+        }
+        if other.signing_key.is_some() {
+            self.signing_key = other.signing_key.clone();
+        }
     }
 
     /// Exports the current settings to a JSON string.
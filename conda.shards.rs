@@ -0,0 +1,291 @@
+// conda.shards.rs
+
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::PathBuf;
+
+use rayon::prelude::*;
+use reqwest::header::{HeaderMap, HeaderValue, ETAG, IF_NONE_MATCH};
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use url::Url;
+
+use crate::digest::sha256_hex;
+use crate::matchspec::spec_name;
+use crate::network::{NetworkClient, NetworkError};
+use crate::repodata::{write_atomic, ChannelInfo, PackageRecord, RepoData, RepodataCache};
+
+/// Represents the shard index of a channel subdir (`repodata_shards.msgpack.zst`).
+#[derive(Debug, Clone, Deserialize)]
+pub struct ShardIndex {
+    pub info: ShardIndexInfo,
+    #[serde(default)]
+    pub repodata_version: u64,
+
+    /// The sha256 of each package's shard, keyed by package name.
+    pub shards: HashMap<String, serde_bytes::ByteBuf>,
+}
+
+/// Represents the `info` section of a shard index.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ShardIndexInfo {
+    pub base_url: String,
+    pub shards_base_url: String,
+    #[serde(default)]
+    pub subdir: String,
+}
+
+/// Represents all records of a single package name in a subdir.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct Shard {
+    #[serde(default)]
+    pub packages: HashMap<String, PackageRecord>,
+    #[serde(default, rename = "packages.conda")]
+    pub conda_packages: HashMap<String, PackageRecord>,
+    #[serde(default)]
+    pub removed: Vec<String>,
+}
+
+/// Represents the cached download state of a shard index.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct ShardIndexState {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    etag: Option<String>,
+}
+
+/// Represents possible errors that can occur when fetching sharded repodata.
+#[derive(Error, Debug)]
+pub enum ShardError {
+    #[error("Failed to access shard cache: {0}")]
+    CacheError(#[from] std::io::Error),
+
+    #[error(transparent)]
+    NetworkError(#[from] NetworkError),
+
+    #[error("Server returned {status} for {url}")]
+    StatusError { url: String, status: StatusCode },
+
+    #[error("Failed to decompress shard data: {0}")]
+    DecompressError(std::io::Error),
+
+    #[error("Failed to decode msgpack: {0}")]
+    DecodeError(#[from] rmp_serde::decode::Error),
+
+    #[error("Invalid shard URL: {0}")]
+    UrlError(#[from] url::ParseError),
+
+    #[error("Shard for {name} has sha256 {actual}, index expects {expected}")]
+    HashMismatch { name: String, expected: String, actual: String },
+}
+
+/// A channel subdir whose repodata is served as per-package shards.
+#[derive(Debug, Clone)]
+pub struct ShardedSubdir {
+    subdir_url: String,
+    base_url: Url,
+    shards_base_url: Url,
+    index: ShardIndex,
+    cache_dir: PathBuf,
+}
+
+impl ShardedSubdir {
+    /// Fetches the shard index of a subdir. Returns `None` if the channel does not publish shards.
+    pub fn open(client: &NetworkClient, subdir_url: &str, cache_dir: impl Into<PathBuf>) -> Result<Option<Self>, ShardError> {
+        let subdir_url = subdir_url.trim_end_matches('/').to_string();
+        let cache_dir = cache_dir.into();
+        let key = RepodataCache::cache_key(&subdir_url);
+        let index_path = cache_dir.join(format!("{}.shards.msgpack.zst", key));
+        let state_path = cache_dir.join(format!("{}.shards.state.json", key));
+
+        let previous: Option<ShardIndexState> = fs::read_to_string(&state_path)
+            .ok()
+            .and_then(|contents| serde_json::from_str(&contents).ok())
+            .filter(|_| index_path.is_file());
+
+        let url = format!("{}/repodata_shards.msgpack.zst", subdir_url);
+        let mut headers = HeaderMap::new();
+        if let Some(etag) = previous
+            .as_ref()
+            .and_then(|s| s.etag.as_deref())
+            .and_then(|etag| HeaderValue::from_str(etag).ok())
+        {
+            headers.insert(IF_NONE_MATCH, etag);
+        }
+        let response = client.get_with_headers(&url, headers)?;
+
+        let compressed = match response.status() {
+            StatusCode::NOT_FOUND => return Ok(None),
+            StatusCode::NOT_MODIFIED => fs::read(&index_path)?,
+            status if status.is_success() => {
+                let etag = response
+                    .headers()
+                    .get(ETAG)
+                    .and_then(|value| value.to_str().ok())
+                    .map(String::from);
+                let bytes = NetworkClient::bytes(&url, response)?;
+                fs::create_dir_all(&cache_dir)?;
+                write_atomic(&index_path, &bytes)?;
+                let state = serde_json::to_string(&ShardIndexState { etag }).unwrap_or_default();
+                write_atomic(&state_path, state.as_bytes())?;
+                bytes
+            }
+            status => return Err(ShardError::StatusError { url, status }),
+        };
+
+        let index: ShardIndex = rmp_serde::from_slice(&decompress(&compressed)?)?;
+        let subdir_base = Url::parse(&format!("{}/", subdir_url))?;
+        let base_url = subdir_base.join(&with_trailing_slash(&index.info.base_url))?;
+        let shards_base_url = subdir_base.join(&with_trailing_slash(&index.info.shards_base_url))?;
+
+        Ok(Some(ShardedSubdir {
+            subdir_url,
+            base_url,
+            shards_base_url,
+            index,
+            cache_dir,
+        }))
+    }
+
+    /// Returns the URL of the subdir.
+    pub fn url(&self) -> &str {
+        &self.subdir_url
+    }
+
+    /// Returns the shard index of the subdir.
+    pub fn index(&self) -> &ShardIndex {
+        &self.index
+    }
+
+    /// Returns the URL package files are served from, resolved against the subdir URL.
+    pub fn base_url(&self) -> &Url {
+        &self.base_url
+    }
+
+    /// Returns the channel info for repodata assembled from the subdir's shards.
+    pub fn channel_info(&self) -> ChannelInfo {
+        let subdir = match self.index.info.subdir.as_str() {
+            "" => self.subdir_url.rsplit('/').next().unwrap_or_default().to_string(),
+            subdir => subdir.to_string(),
+        };
+        ChannelInfo { subdir, base_url: Some(self.base_url.to_string()) }
+    }
+
+    /// Returns the path a shard with the given hex hash is cached at.
+    fn shard_path(&self, hash: &str) -> PathBuf {
+        self.cache_dir.join("shards").join(format!("{}.msgpack.zst", hash))
+    }
+
+    /// Fetches the shard for a package name, reusing a cached copy with the same hash.
+    /// Returns `None` if the subdir has no package with that name.
+    pub fn fetch_shard(&self, client: &NetworkClient, name: &str) -> Result<Option<Shard>, ShardError> {
+        let hash = match self.index.shards.get(name) {
+            Some(hash) => data_encoding::HEXLOWER.encode(hash),
+            None => return Ok(None),
+        };
+
+        let path = self.shard_path(&hash);
+        let compressed = match fs::read(&path) {
+            Ok(bytes) => bytes,
+            Err(_) => {
+                let url = self.shards_base_url.join(&format!("{}.msgpack.zst", hash))?.to_string();
+                let response = client.get(&url)?;
+                if !response.status().is_success() {
+                    return Err(ShardError::StatusError { url, status: response.status() });
+                }
+                let bytes = NetworkClient::bytes(&url, response)?;
+
+                let actual = sha256_hex(&bytes);
+                if actual != hash {
+                    return Err(ShardError::HashMismatch { name: name.to_string(), expected: hash, actual });
+                }
+
+                fs::create_dir_all(path.parent().expect("shard path has a parent"))?;
+                write_atomic(&path, &bytes)?;
+                bytes
+            }
+        };
+
+        Ok(Some(rmp_serde::from_slice(&decompress(&compressed)?)?))
+    }
+}
+
+/// Fetches the shards for the given package names and, transitively, for
+/// every name they depend on, across all subdirs. Each BFS layer is fetched
+/// in parallel. Returns the collected records keyed by subdir URL.
+pub fn fetch_closure<I, S>(
+    client: &NetworkClient,
+    subdirs: &[ShardedSubdir],
+    names: I,
+) -> Result<HashMap<String, RepoData>, ShardError>
+where
+    I: IntoIterator<Item = S>,
+    S: Into<String>,
+{
+    let mut result: HashMap<String, RepoData> = subdirs
+        .iter()
+        .map(|subdir| {
+            let repodata = RepoData { info: Some(subdir.channel_info()), ..RepoData::default() };
+            (subdir.url().to_string(), repodata)
+        })
+        .collect();
+
+    let mut seen: HashSet<String> = HashSet::new();
+    let mut pending: Vec<String> = names
+        .into_iter()
+        .map(Into::into)
+        .filter(|name| seen.insert(name.clone()))
+        .collect();
+
+    while !pending.is_empty() {
+        let work: Vec<(&ShardedSubdir, &String)> = subdirs
+            .iter()
+            .flat_map(|subdir| pending.iter().map(move |name| (subdir, name)))
+            .collect();
+
+        let fetched: Vec<(&ShardedSubdir, Option<Shard>)> = work
+            .into_par_iter()
+            .map(|(subdir, name)| subdir.fetch_shard(client, name).map(|shard| (subdir, shard)))
+            .collect::<Result<_, _>>()?;
+
+        let mut next = Vec::new();
+        for (subdir, shard) in fetched {
+            let shard = match shard {
+                Some(shard) => shard,
+                None => continue,
+            };
+
+            for record in shard.packages.values().chain(shard.conda_packages.values()) {
+                for dependency in &record.depends {
+                    let name = spec_name(dependency);
+                    if !name.is_empty() && seen.insert(name.to_string()) {
+                        next.push(name.to_string());
+                    }
+                }
+            }
+
+            let repodata = result.get_mut(subdir.url()).expect("subdir entry exists");
+            repodata.packages.extend(shard.packages);
+            repodata.conda_packages.extend(shard.conda_packages);
+            repodata.removed.extend(shard.removed);
+        }
+
+        pending = next;
+    }
+
+    Ok(result)
+}
+
+fn decompress(compressed: &[u8]) -> Result<Vec<u8>, ShardError> {
+    zstd::decode_all(compressed).map_err(ShardError::DecompressError)
+}
+
+fn with_trailing_slash(url: &str) -> String {
+    if url.is_empty() {
+        "./".to_string()
+    } else if url.ends_with('/') {
+        url.to_string()
+    } else {
+        format!("{}/", url)
+    }
+}
@@ -0,0 +1,462 @@
+// conda.signing.rs
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use ring::rand::SystemRandom;
+use ring::signature::{self, Ed25519KeyPair, KeyPair, UnparsedPublicKey};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::digest;
+use crate::repodata::{write_atomic, PackageRecord, RepoData};
+
+/// The extension of detached artifact signatures, e.g. `foo-1.0-0.conda.sig`.
+pub const SIGNATURE_EXTENSION: &str = "sig";
+
+/// Represents possible errors that can occur when signing or verifying packages.
+#[derive(Error, Debug)]
+pub enum SigningError {
+    #[error("Failed to access {path}: {source}")]
+    IoError { path: PathBuf, source: io::Error },
+
+    #[error("Invalid signature data: {0}")]
+    ParseError(#[from] serde_json::Error),
+
+    #[error("Invalid signing key {path}: {reason}")]
+    InvalidKey { path: PathBuf, reason: String },
+
+    #[error("Private key file {0} is readable by other users; run `chmod 600` on it")]
+    InsecurePermissions(PathBuf),
+
+    #[error("No trusted keys are configured for channel {0}")]
+    UntrustedChannel(String),
+
+    #[error("{subject} from {channel} is not signed by a trusted key")]
+    Unsigned { subject: String, channel: String },
+
+    #[error("{subject} from {channel} has an invalid signature; it may have been tampered with")]
+    InvalidSignature { subject: String, channel: String },
+}
+
+/// Represents one signature, as stored in repodata and `.sig` files.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RecordSignature {
+    /// The hex-encoded ed25519 signature.
+    pub signature: String,
+}
+
+/// Signatures of one record or artifact, keyed by the hex public key that made them.
+pub type Signatures = BTreeMap<String, RecordSignature>;
+
+/// Represents an ed25519 private key used to sign packages and repodata.
+pub struct SigningKey {
+    key_pair: Ed25519KeyPair,
+    key_id: String,
+}
+
+impl fmt::Debug for SigningKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SigningKey({})", self.key_id)
+    }
+}
+
+impl SigningKey {
+    /// Generates a new key and writes it to `path`, readable only by the owner.
+    /// An existing key file is never overwritten.
+    pub fn generate(path: &Path) -> Result<Self, SigningError> {
+        let io_error = |source| SigningError::IoError { path: path.to_path_buf(), source };
+        let invalid = |reason: String| SigningError::InvalidKey { path: path.to_path_buf(), reason };
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).map_err(|_| invalid("key generation failed".to_string()))?;
+
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).map_err(io_error)?;
+        }
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        let mut file = options.open(path).map_err(io_error)?;
+        writeln!(file, "{}", data_encoding::HEXLOWER.encode(pkcs8.as_ref())).map_err(io_error)?;
+
+        Self::from_pkcs8(pkcs8.as_ref()).map_err(|e| invalid(e.to_string()))
+    }
+
+    /// Loads a key written by [`SigningKey::generate`], refusing files other
+    /// users can read.
+    pub fn load(path: &Path) -> Result<Self, SigningError> {
+        let io_error = |source| SigningError::IoError { path: path.to_path_buf(), source };
+        let invalid = |reason: String| SigningError::InvalidKey { path: path.to_path_buf(), reason };
+        let contents = fs::read_to_string(path).map_err(io_error)?;
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(path).map_err(io_error)?.permissions().mode();
+            if mode & 0o077 != 0 {
+                return Err(SigningError::InsecurePermissions(path.to_path_buf()));
+            }
+        }
+
+        let pkcs8 = data_encoding::HEXLOWER_PERMISSIVE
+            .decode(contents.trim().as_bytes())
+            .map_err(|e| invalid(e.to_string()))?;
+        Self::from_pkcs8(&pkcs8).map_err(|e| invalid(e.to_string()))
+    }
+
+    fn from_pkcs8(pkcs8: &[u8]) -> Result<Self, ring::error::KeyRejected> {
+        let key_pair = Ed25519KeyPair::from_pkcs8(pkcs8)?;
+        let key_id = data_encoding::HEXLOWER.encode(key_pair.public_key().as_ref());
+        Ok(SigningKey { key_pair, key_id })
+    }
+
+    /// Returns the hex-encoded public key, which identifies the key in signatures.
+    pub fn key_id(&self) -> &str {
+        &self.key_id
+    }
+
+    /// Signs a message, returning the hex-encoded signature.
+    pub fn sign(&self, message: &[u8]) -> RecordSignature {
+        RecordSignature {
+            signature: data_encoding::HEXLOWER.encode(self.key_pair.sign(message).as_ref()),
+        }
+    }
+
+    /// Signs a repodata record. Since the record carries the package hashes,
+    /// this also covers the artifact it describes.
+    pub fn sign_record(&self, record: &PackageRecord) -> Result<RecordSignature, SigningError> {
+        Ok(self.sign(&record_message(record)?))
+    }
+
+    /// Signs every record of a repodata, replacing earlier signatures made
+    /// by this key and keeping those of other keys.
+    pub fn sign_repodata(&self, repodata: &mut RepoData) -> Result<(), SigningError> {
+        let mut signed = Vec::new();
+        for (filename, record) in repodata.records() {
+            signed.push((filename.clone(), self.sign_record(record)?));
+        }
+
+        // Drop signatures of records that are gone.
+        let mut signatures = std::mem::take(&mut repodata.signatures);
+        signatures.retain(|filename, _| repodata.packages.contains_key(filename) || repodata.conda_packages.contains_key(filename));
+        for (filename, signature) in signed {
+            signatures.entry(filename).or_default().insert(self.key_id.clone(), signature);
+        }
+        repodata.signatures = signatures;
+        Ok(())
+    }
+
+    /// Signs a package file, adding this key's signature to its detached
+    /// `.sig` file and returning that file's path.
+    pub fn sign_artifact(&self, path: &Path) -> Result<PathBuf, SigningError> {
+        let sig_path = signature_path(path);
+        let mut signatures = read_signatures(&sig_path)?.unwrap_or_default();
+        signatures.insert(self.key_id.clone(), self.sign(artifact_message(path)?.as_bytes()));
+
+        let contents = serde_json::to_vec_pretty(&signatures)?;
+        write_atomic(&sig_path, &contents).map_err(|source| SigningError::IoError { path: sig_path.clone(), source })?;
+        Ok(sig_path)
+    }
+}
+
+/// Represents a public key allowed to sign a channel's packages.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TrustedKey {
+    /// The hex-encoded ed25519 public key.
+    pub public_key: String,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+}
+
+/// A file-based store of the public keys trusted for each channel, keyed by
+/// canonical channel name (e.g. `conda-forge` or `https://conda.example.com/internal`).
+#[derive(Debug, Clone, Default)]
+pub struct TrustStore {
+    path: Option<PathBuf>,
+    channels: BTreeMap<String, Vec<TrustedKey>>,
+}
+
+impl TrustStore {
+    /// Returns the default location of the trust store.
+    pub fn default_path() -> Option<PathBuf> {
+        dirs::home_dir().map(|home| home.join(".conda").join("trusted_keys.json"))
+    }
+
+    /// Loads the store from a file. A missing file yields an empty store.
+    pub fn load(path: &Path) -> Result<Self, SigningError> {
+        let channels = match fs::read_to_string(path) {
+            Ok(contents) => serde_json::from_str(&contents)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(source) => return Err(SigningError::IoError { path: path.to_path_buf(), source }),
+        };
+        Ok(TrustStore { path: Some(path.to_path_buf()), channels })
+    }
+
+    /// Writes the store back to the file it was loaded from.
+    pub fn save(&self) -> Result<(), SigningError> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let io_error = |source| SigningError::IoError { path: path.clone(), source };
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).map_err(io_error)?;
+        }
+        write_atomic(path, serde_json::to_string_pretty(&self.channels)?.as_bytes()).map_err(io_error)
+    }
+
+    /// Trusts a public key for a channel.
+    pub fn trust(&mut self, channel: &str, public_key: &str, name: Option<String>) {
+        let keys = self.channels.entry(channel_key(channel)).or_default();
+        let public_key = public_key.trim().to_ascii_lowercase();
+        if !keys.iter().any(|key| key.public_key == public_key) {
+            keys.push(TrustedKey { public_key, name });
+        }
+    }
+
+    /// Stops trusting a public key for a channel, returning whether it was trusted.
+    pub fn revoke(&mut self, channel: &str, public_key: &str) -> bool {
+        let public_key = public_key.trim().to_ascii_lowercase();
+        match self.channels.get_mut(&channel_key(channel)) {
+            Some(keys) => {
+                let before = keys.len();
+                keys.retain(|key| key.public_key != public_key);
+                keys.len() != before
+            }
+            None => false,
+        }
+    }
+
+    /// Replaces a channel's key with a new one, keeping the old key's name.
+    pub fn rotate(&mut self, channel: &str, old_public_key: &str, new_public_key: &str) {
+        let old = old_public_key.trim().to_ascii_lowercase();
+        let name = self
+            .keys(channel)
+            .iter()
+            .find(|key| key.public_key == old)
+            .and_then(|key| key.name.clone());
+        self.trust(channel, new_public_key, name);
+        self.revoke(channel, &old);
+    }
+
+    /// Returns the channels with trusted keys.
+    pub fn channels(&self) -> impl Iterator<Item = &str> {
+        self.channels.keys().map(String::as_str)
+    }
+
+    /// Returns the keys trusted for a channel.
+    pub fn keys(&self, channel: &str) -> &[TrustedKey] {
+        self.channels.get(&channel_key(channel)).map_or(&[][..], Vec::as_slice)
+    }
+
+    /// Checks that a repodata record carries a valid signature by a key
+    /// trusted for its channel.
+    pub fn verify_record(&self, channel: &str, filename: &str, record: &PackageRecord, signatures: Option<&Signatures>) -> Result<(), SigningError> {
+        self.verify(channel, filename, &record_message(record)?, signatures)
+    }
+
+    /// Checks that a package file carries a valid detached signature by a key
+    /// trusted for its channel.
+    pub fn verify_artifact(&self, channel: &str, path: &Path) -> Result<(), SigningError> {
+        let signatures = read_signatures(&signature_path(path))?;
+        let subject = path.file_name().map_or_else(|| path.display().to_string(), |name| name.to_string_lossy().into_owned());
+        self.verify(channel, &subject, artifact_message(path)?.as_bytes(), signatures.as_ref())
+    }
+
+    /// Removes the records of a repodata that are not validly signed, returning
+    /// the errors for the rejected filenames. A channel without trusted keys
+    /// fails as a whole, rather than losing every record one by one.
+    pub fn verify_repodata(&self, channel: &str, repodata: &mut RepoData) -> Result<Vec<SigningError>, SigningError> {
+        if self.keys(channel).is_empty() {
+            return Err(SigningError::UntrustedChannel(channel.to_string()));
+        }
+
+        let mut rejected = Vec::new();
+        let mut errors = Vec::new();
+        for (filename, record) in repodata.records() {
+            if let Err(e) = self.verify_record(channel, filename, record, repodata.signatures.get(filename)) {
+                rejected.push(filename.clone());
+                errors.push(e);
+            }
+        }
+        for filename in rejected {
+            repodata.packages.remove(&filename);
+            repodata.conda_packages.remove(&filename);
+            repodata.signatures.remove(&filename);
+        }
+        Ok(errors)
+    }
+
+    fn verify(&self, channel: &str, subject: &str, message: &[u8], signatures: Option<&Signatures>) -> Result<(), SigningError> {
+        let trusted = self.keys(channel);
+        if trusted.is_empty() {
+            return Err(SigningError::UntrustedChannel(channel.to_string()));
+        }
+
+        let mut tampered = false;
+        for key in trusted {
+            let signature = match signatures.and_then(|signatures| signatures.get(&key.public_key)) {
+                Some(signature) => signature,
+                None => continue,
+            };
+            let (public_key, signature) = match (
+                data_encoding::HEXLOWER_PERMISSIVE.decode(key.public_key.as_bytes()),
+                data_encoding::HEXLOWER_PERMISSIVE.decode(signature.signature.as_bytes()),
+            ) {
+                (Ok(public_key), Ok(signature)) => (public_key, signature),
+                _ => {
+                    tampered = true;
+                    continue;
+                }
+            };
+            if UnparsedPublicKey::new(&signature::ED25519, &public_key).verify(message, &signature).is_ok() {
+                return Ok(());
+            }
+            tampered = true;
+        }
+
+        let (subject, channel) = (subject.to_string(), channel.to_string());
+        if tampered {
+            Err(SigningError::InvalidSignature { subject, channel })
+        } else {
+            Err(SigningError::Unsigned { subject, channel })
+        }
+    }
+}
+
+/// Returns the bytes signed for a repodata record: its JSON with sorted keys
+/// and no whitespace.
+pub fn record_message(record: &PackageRecord) -> Result<Vec<u8>, SigningError> {
+    // Round-tripping through a Value sorts the keys.
+    Ok(serde_json::to_vec(&serde_json::to_value(record)?)?)
+}
+
+/// Returns the path of the detached signature of a package file.
+pub fn signature_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().map(|name| name.to_os_string()).unwrap_or_default();
+    name.push(".");
+    name.push(SIGNATURE_EXTENSION);
+    path.with_file_name(name)
+}
+
+/// Returns whether the detached signature of a package file includes one by
+/// the given key, without checking it.
+pub fn is_signed_by(path: &Path, key_id: &str) -> bool {
+    read_signatures(&signature_path(path))
+        .ok()
+        .flatten()
+        .map_or(false, |signatures| signatures.contains_key(key_id))
+}
+
+/// Returns the message signed for a package file: its hex-encoded sha256.
+fn artifact_message(path: &Path) -> Result<String, SigningError> {
+    digest::file_digests(path)
+        .map(|digests| digests.sha256)
+        .map_err(|source| SigningError::IoError { path: path.to_path_buf(), source })
+}
+
+fn read_signatures(path: &Path) -> Result<Option<Signatures>, SigningError> {
+    match fs::read(path) {
+        Ok(contents) => Ok(Some(serde_json::from_slice(&contents)?)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(source) => Err(SigningError::IoError { path: path.to_path_buf(), source }),
+    }
+}
+
+fn channel_key(channel: &str) -> String {
+    channel.trim_end_matches('/').to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHANNEL: &str = "https://conda.example.com/internal";
+
+    fn repodata() -> RepoData {
+        let mut repodata = RepoData::default();
+        for name in ["demo", "other"] {
+            let record = PackageRecord {
+                name: name.to_string(),
+                version: "1.0".to_string(),
+                build: "0".to_string(),
+                sha256: Some("0".repeat(64)),
+                ..Default::default()
+            };
+            repodata.conda_packages.insert(format!("{}-1.0-0.conda", name), record);
+        }
+        repodata
+    }
+
+    #[test]
+    fn signed_repodata_verifies() {
+        let dir = tempfile::tempdir().unwrap();
+        let key = SigningKey::generate(&dir.path().join("signing.key")).unwrap();
+        let loaded = SigningKey::load(&dir.path().join("signing.key")).unwrap();
+        assert_eq!(loaded.key_id(), key.key_id());
+
+        let mut repodata = repodata();
+        key.sign_repodata(&mut repodata).unwrap();
+        let mut trust = TrustStore::default();
+        trust.trust(CHANNEL, key.key_id(), None);
+
+        let rejected = trust.verify_repodata(CHANNEL, &mut repodata).unwrap();
+        assert!(rejected.is_empty(), "{:?}", rejected);
+        assert_eq!(repodata.conda_packages.len(), 2);
+    }
+
+    #[test]
+    fn tampered_and_unsigned_records_are_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let key = SigningKey::generate(&dir.path().join("signing.key")).unwrap();
+        let mut repodata = repodata();
+        key.sign_repodata(&mut repodata).unwrap();
+        repodata.conda_packages.get_mut("demo-1.0-0.conda").unwrap().sha256 = Some("1".repeat(64));
+        repodata.signatures.remove("other-1.0-0.conda");
+
+        let mut trust = TrustStore::default();
+        assert!(matches!(trust.verify_repodata(CHANNEL, &mut repodata.clone()), Err(SigningError::UntrustedChannel(_))));
+
+        trust.trust(CHANNEL, key.key_id(), None);
+        let record = &repodata.conda_packages["demo-1.0-0.conda"];
+        assert!(matches!(
+            trust.verify_record(CHANNEL, "demo-1.0-0.conda", record, repodata.signatures.get("demo-1.0-0.conda")),
+            Err(SigningError::InvalidSignature { .. })
+        ));
+
+        let rejected = trust.verify_repodata(CHANNEL, &mut repodata).unwrap();
+        assert_eq!(rejected.len(), 2);
+        assert!(rejected.iter().any(|e| matches!(e, SigningError::Unsigned { .. })));
+        assert!(repodata.conda_packages.is_empty());
+    }
+
+    #[test]
+    fn rotate_replaces_the_key_and_keeps_its_name() {
+        let dir = tempfile::tempdir().unwrap();
+        let old = SigningKey::generate(&dir.path().join("old.key")).unwrap();
+        let new = SigningKey::generate(&dir.path().join("new.key")).unwrap();
+        let mut trust = TrustStore::default();
+        trust.trust(CHANNEL, old.key_id(), Some("release".to_string()));
+
+        trust.rotate(CHANNEL, old.key_id(), new.key_id());
+        assert_eq!(
+            trust.keys(CHANNEL),
+            [TrustedKey { public_key: new.key_id().to_string(), name: Some("release".to_string()) }]
+        );
+
+        let record = &repodata().conda_packages["demo-1.0-0.conda"];
+        let signed_by = |key: &SigningKey| Signatures::from([(key.key_id().to_string(), key.sign_record(record).unwrap())]);
+        assert!(trust.verify_record(CHANNEL, "demo", record, Some(&signed_by(&new))).is_ok());
+        assert!(matches!(
+            trust.verify_record(CHANNEL, "demo", record, Some(&signed_by(&old))),
+            Err(SigningError::Unsigned { .. })
+        ));
+    }
+}
@@ -0,0 +1,345 @@
+// conda.transmute.rs
+
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use rayon::prelude::*;
+use thiserror::Error;
+use zip::write::FileOptions;
+use zip::CompressionMethod;
+
+use crate::archive::{self, ArchiveEntry, ArchiveError, ArchiveType, CondaMetadata};
+
+/// Represents possible errors that can occur when converting packages between formats.
+#[derive(Error, Debug)]
+pub enum TransmuteError {
+    #[error("Failed to convert {path}: {source}")]
+    IoError { path: PathBuf, source: io::Error },
+
+    #[error(transparent)]
+    ArchiveError(#[from] ArchiveError),
+
+    #[error("{0} is already in the target format")]
+    SameFormat(PathBuf),
+
+    #[error("{0} already exists")]
+    AlreadyExists(PathBuf),
+
+    #[error("{converted} does not match {original} at {path}: {reason}")]
+    ContentMismatch {
+        original: PathBuf,
+        converted: PathBuf,
+        path: PathBuf,
+        reason: String,
+    },
+}
+
+/// Represents the outcome of converting a channel directory.
+#[derive(Debug, Clone, Default)]
+pub struct TransmuteReport {
+    /// Converted packages, as `<subdir>/<filename>` of the new file.
+    pub converted: Vec<String>,
+
+    /// Packages whose converted file already exists.
+    pub skipped: Vec<String>,
+
+    /// Packages that could not be converted or verified, with the reason.
+    pub failed: Vec<(String, String)>,
+}
+
+/// Converts packages between `.tar.bz2` and `.conda` by copying tar entries
+/// with their headers, so file modes, symlinks and mtimes carry over exactly.
+#[derive(Debug, Clone)]
+pub struct Transmuter {
+    target: ArchiveType,
+    zstd_level: i32,
+    verify: bool,
+    overwrite: bool,
+}
+
+impl Transmuter {
+    /// Creates a converter into the given format, verifying every conversion.
+    pub fn new(target: ArchiveType) -> Self {
+        Transmuter {
+            target,
+            zstd_level: archive::DEFAULT_ZSTD_LEVEL,
+            verify: true,
+            overwrite: false,
+        }
+    }
+
+    /// Sets the zstd level of the tarballs in `.conda` output.
+    pub fn with_zstd_level(mut self, level: i32) -> Self {
+        self.zstd_level = level;
+        self
+    }
+
+    /// Sets whether converted packages are compared against the original.
+    pub fn with_verify(mut self, verify: bool) -> Self {
+        self.verify = verify;
+        self
+    }
+
+    /// Sets whether existing converted packages are replaced.
+    pub fn with_overwrite(mut self, overwrite: bool) -> Self {
+        self.overwrite = overwrite;
+        self
+    }
+
+    /// Returns where the converted package of `source` goes in `dest_dir`.
+    pub fn target_path(&self, source: &Path, dest_dir: &Path) -> Result<PathBuf, TransmuteError> {
+        let (stem, source_type) = split_path(source)?;
+        if source_type == self.target {
+            return Err(TransmuteError::SameFormat(source.to_path_buf()));
+        }
+        Ok(dest_dir.join(format!("{}{}", stem, self.target.extension())))
+    }
+
+    /// Converts a package into `dest_dir`, returning the new file. The output
+    /// is written next to its destination and only moved into place once
+    /// complete and, if enabled, verified.
+    pub fn transmute(&self, source: &Path, dest_dir: &Path) -> Result<PathBuf, TransmuteError> {
+        let dest = self.target_path(source, dest_dir)?;
+        if dest.exists() && !self.overwrite {
+            return Err(TransmuteError::AlreadyExists(dest));
+        }
+
+        let (stem, _) = split_path(source)?;
+        let tmp_path = dest.with_file_name(format!("{}{}.partial", stem, self.target.extension()));
+        let io_error = |path: &Path| {
+            let path = path.to_path_buf();
+            move |source| TransmuteError::IoError { path, source }
+        };
+        fs::create_dir_all(dest_dir).map_err(io_error(dest_dir))?;
+
+        let result = match self.target {
+            ArchiveType::Conda => self.write_conda(source, &tmp_path, stem),
+            ArchiveType::TarBz2 => self.write_tar_bz2(source, &tmp_path),
+        }
+        .and_then(|()| {
+            if self.verify {
+                compare_contents(source, &tmp_path, self.target)
+            } else {
+                Ok(())
+            }
+        });
+
+        if let Err(e) = result {
+            let _ = fs::remove_file(&tmp_path);
+            return Err(e);
+        }
+        fs::rename(&tmp_path, &dest).map_err(io_error(&dest))?;
+        Ok(dest)
+    }
+
+    /// Converts every package of the other format in the subdirs of a channel
+    /// directory, in parallel. Originals are kept, so the channel serves both
+    /// formats once it is re-indexed.
+    pub fn transmute_channel(&self, channel_dir: &Path) -> Result<TransmuteReport, TransmuteError> {
+        let io_error = |path: &Path| {
+            let path = path.to_path_buf();
+            move |source| TransmuteError::IoError { path, source }
+        };
+
+        let mut sources = Vec::new();
+        for subdir in fs::read_dir(channel_dir).map_err(io_error(channel_dir))? {
+            let subdir = subdir.map_err(io_error(channel_dir))?.path();
+            if !subdir.is_dir() {
+                continue;
+            }
+            for file in fs::read_dir(&subdir).map_err(io_error(&subdir))? {
+                let path = file.map_err(io_error(&subdir))?.path();
+                if ArchiveType::from_path(&path).map_or(false, |archive_type| archive_type != self.target) {
+                    sources.push(path);
+                }
+            }
+        }
+        sources.sort();
+
+        let results: Vec<(String, Result<Option<PathBuf>, TransmuteError>)> = sources
+            .par_iter()
+            .map(|source| {
+                let dir = source.parent().unwrap_or(channel_dir);
+                let result = self.target_path(source, dir).and_then(|dest| {
+                    if dest.exists() && !self.overwrite {
+                        Ok(None)
+                    } else {
+                        self.transmute(source, dir).map(Some)
+                    }
+                });
+                (channel_relative(channel_dir, source), result)
+            })
+            .collect();
+
+        let mut report = TransmuteReport::default();
+        for (source, result) in results {
+            match result {
+                Ok(Some(dest)) => report.converted.push(channel_relative(channel_dir, &dest)),
+                Ok(None) => report.skipped.push(source),
+                Err(e) => {
+                    log::warn!("Failed to convert {}: {}", source, e);
+                    report.failed.push((source, e.to_string()));
+                }
+            }
+        }
+        Ok(report)
+    }
+
+    fn write_conda(&self, source: &Path, dest: &Path, stem: &str) -> Result<(), TransmuteError> {
+        let io_error = |source| TransmuteError::IoError { path: dest.to_path_buf(), source };
+        let file = File::open(source).map_err(|e| TransmuteError::IoError { path: source.to_path_buf(), source: e })?;
+        let mut archive = tar::Archive::new(bzip2::read::BzDecoder::new(BufReader::new(file)));
+
+        // The info tarball is small and stays in memory; the payload is
+        // spooled next to the output until the info member is written.
+        let spool_dir = dest.parent().unwrap_or_else(|| Path::new("."));
+        let spool = tempfile::tempfile_in(spool_dir).map_err(io_error)?;
+        let mut info = tar::Builder::new(zstd::stream::write::Encoder::new(Vec::new(), self.zstd_level).map_err(io_error)?);
+        let mut pkg = tar::Builder::new(zstd::stream::write::Encoder::new(BufWriter::new(spool), self.zstd_level).map_err(io_error)?);
+
+        for entry in archive.entries().map_err(io_error)? {
+            let entry = entry.map_err(io_error)?;
+            if entry.path().map_err(io_error)?.starts_with("info") {
+                copy_entry(entry, &mut info).map_err(io_error)?;
+            } else {
+                copy_entry(entry, &mut pkg).map_err(io_error)?;
+            }
+        }
+
+        let info = info.into_inner().and_then(|encoder| encoder.finish()).map_err(io_error)?;
+        let mut spool = pkg
+            .into_inner()
+            .and_then(|encoder| encoder.finish())
+            .and_then(|writer| writer.into_inner().map_err(|e| e.into_error()))
+            .map_err(io_error)?;
+        spool.seek(SeekFrom::Start(0)).map_err(io_error)?;
+
+        let zip_error = |source| ArchiveError::ZipError { path: dest.to_path_buf(), source };
+        let options = FileOptions::default().compression_method(CompressionMethod::Stored);
+        let mut zip = zip::ZipWriter::new(BufWriter::new(File::create(dest).map_err(io_error)?));
+
+        zip.start_file("metadata.json", options).map_err(zip_error)?;
+        let metadata = serde_json::to_vec(&CondaMetadata::default())
+            .map_err(|source| ArchiveError::InvalidMetadata { path: dest.to_path_buf(), source })?;
+        zip.write_all(&metadata).map_err(io_error)?;
+
+        zip.start_file(format!("info-{}.tar.zst", stem), options).map_err(zip_error)?;
+        zip.write_all(&info).map_err(io_error)?;
+        zip.start_file(format!("pkg-{}.tar.zst", stem), options).map_err(zip_error)?;
+        io::copy(&mut spool, &mut zip).map_err(io_error)?;
+
+        zip.finish().map_err(zip_error)?.flush().map_err(io_error)
+    }
+
+    fn write_tar_bz2(&self, source: &Path, dest: &Path) -> Result<(), TransmuteError> {
+        let io_error = |source| TransmuteError::IoError { path: dest.to_path_buf(), source };
+        let file = File::open(source).map_err(|e| TransmuteError::IoError { path: source.to_path_buf(), source: e })?;
+        let mut zip = zip::ZipArchive::new(BufReader::new(file))
+            .map_err(|e| ArchiveError::ZipError { path: source.to_path_buf(), source: e })?;
+        archive::check_metadata(&mut zip, source)?;
+
+        let output = BufWriter::new(File::create(dest).map_err(io_error)?);
+        let level = bzip2::Compression::new(archive::DEFAULT_BZIP2_LEVEL);
+        let mut builder = tar::Builder::new(bzip2::write::BzEncoder::new(output, level));
+
+        // Info entries first, as conda-build writes them.
+        for section in ["info", "pkg"] {
+            let name = archive::conda_member(&zip, section, source)?;
+            let member = zip
+                .by_name(&name)
+                .map_err(|e| ArchiveError::ZipError { path: source.to_path_buf(), source: e })?;
+            let mut tar = tar::Archive::new(zstd::stream::read::Decoder::new(member).map_err(io_error)?);
+            for entry in tar.entries().map_err(io_error)? {
+                copy_entry(entry.map_err(io_error)?, &mut builder).map_err(io_error)?;
+            }
+        }
+
+        builder
+            .into_inner()
+            .and_then(|encoder| encoder.finish())
+            .and_then(|mut output| output.flush())
+            .map_err(io_error)
+    }
+}
+
+/// Checks that a converted package holds the same entries as the original,
+/// with identical types, modes, mtimes, link targets and contents.
+pub fn verify_same_contents(original: &Path, converted: &Path) -> Result<(), TransmuteError> {
+    let archive_type = ArchiveType::from_path(converted).ok_or_else(|| ArchiveError::UnknownFormat(converted.to_path_buf()))?;
+    compare_contents(original, converted, archive_type)
+}
+
+fn compare_contents(original: &Path, converted: &Path, converted_type: ArchiveType) -> Result<(), TransmuteError> {
+    let by_path = |entries: Vec<ArchiveEntry>| -> BTreeMap<PathBuf, ArchiveEntry> {
+        entries.into_iter().map(|entry| (entry.path.clone(), entry)).collect()
+    };
+    let expected = by_path(archive::entries(original)?);
+    let actual = by_path(archive::entries_as(converted, converted_type)?);
+    let mismatch = |path: &Path, reason: Cow<str>| TransmuteError::ContentMismatch {
+        original: original.to_path_buf(),
+        converted: converted.to_path_buf(),
+        path: path.to_path_buf(),
+        reason: reason.into_owned(),
+    };
+
+    for (path, entry) in &expected {
+        match actual.get(path) {
+            None => return Err(mismatch(path, "missing from the converted package".into())),
+            Some(other) => {
+                if let Some(reason) = difference(entry, other) {
+                    return Err(mismatch(path, reason.into()));
+                }
+            }
+        }
+    }
+    if let Some(path) = actual.keys().find(|path| !expected.contains_key(*path)) {
+        return Err(mismatch(path, "not in the original package".into()));
+    }
+    Ok(())
+}
+
+/// Describes the first difference between two entries of the same path.
+fn difference(expected: &ArchiveEntry, actual: &ArchiveEntry) -> Option<String> {
+    if expected.kind != actual.kind {
+        Some(format!("type {:?} became {:?}", expected.kind, actual.kind))
+    } else if expected.size != actual.size || expected.sha256 != actual.sha256 {
+        Some("contents differ".to_string())
+    } else if expected.mode != actual.mode {
+        Some(format!("mode {:o} became {:o}", expected.mode, actual.mode))
+    } else if expected.mtime != actual.mtime {
+        Some(format!("mtime {} became {}", expected.mtime, actual.mtime))
+    } else if expected.link_target != actual.link_target {
+        Some("link target differs".to_string())
+    } else {
+        None
+    }
+}
+
+/// Appends a tar entry to another archive with its header intact, so mode,
+/// ownership, mtime and link target carry over. Long paths and link targets
+/// are re-encoded by the builder.
+fn copy_entry<R: Read, W: Write>(mut entry: tar::Entry<R>, builder: &mut tar::Builder<W>) -> io::Result<()> {
+    let path = entry.path()?.into_owned();
+    let link_target = entry.link_name()?.map(Cow::into_owned);
+    let mut header = entry.header().clone();
+
+    match link_target {
+        Some(target) => builder.append_link(&mut header, &path, &target),
+        None => builder.append_data(&mut header, &path, &mut entry),
+    }
+}
+
+fn split_path(path: &Path) -> Result<(&str, ArchiveType), TransmuteError> {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .and_then(ArchiveType::split_filename)
+        .ok_or_else(|| ArchiveError::UnknownFormat(path.to_path_buf()).into())
+}
+
+/// Returns a path as `<subdir>/<filename>` relative to the channel directory.
+fn channel_relative(channel_dir: &Path, path: &Path) -> String {
+    path.strip_prefix(channel_dir).unwrap_or(path).display().to_string()
+}
@@ -0,0 +1,297 @@
+// conda.updates.rs
+
+use std::cmp::Ordering;
+use std::collections::HashSet;
+use std::fmt;
+
+use rayon::prelude::*;
+use serde::Serialize;
+
+use crate::channel::ChannelConfig;
+use crate::matchspec::MatchSpec;
+use crate::prefix::PrefixRecord;
+use crate::repodata::{PackageRecord, SubdirRepodata};
+use crate::version::VersionOrder;
+
+/// Represents how far an available update is from the installed version.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UpdateKind {
+    Major,
+    Minor,
+    Patch,
+    /// Same version, newer build number.
+    BuildOnly,
+}
+
+impl fmt::Display for UpdateKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            UpdateKind::Major => "major",
+            UpdateKind::Minor => "minor",
+            UpdateKind::Patch => "patch",
+            UpdateKind::BuildOnly => "build",
+        })
+    }
+}
+
+/// Represents an available update for an installed package.
+#[derive(Debug, Clone, Serialize)]
+pub struct PackageUpdate {
+    pub name: String,
+    pub installed_version: String,
+    pub installed_build: String,
+    pub latest_version: String,
+    pub latest_build: String,
+    pub channel: String,
+    pub subdir: String,
+    pub kind: UpdateKind,
+}
+
+/// Represents a package whose newest version is excluded by a pin.
+#[derive(Debug, Clone, Serialize)]
+pub struct HeldBackUpdate {
+    pub name: String,
+    pub newest_version: String,
+    pub pin: String,
+}
+
+/// Represents a channel subdir whose repodata could not be fetched.
+#[derive(Debug, Clone, Serialize)]
+pub struct SubdirFailure {
+    pub channel: String,
+    pub subdir: String,
+    pub error: String,
+}
+
+impl fmt::Display for SubdirFailure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}: {}", self.channel, self.subdir, self.error)
+    }
+}
+
+/// Represents the result of checking an environment for updates.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct UpdateReport {
+    pub updates: Vec<PackageUpdate>,
+
+    /// Updates that exist but are excluded by a pin.
+    pub held_back: Vec<HeldBackUpdate>,
+
+    /// Installed packages that no longer exist in their channel and subdir.
+    pub unavailable: Vec<String>,
+
+    /// Installed packages that are already the newest allowed version.
+    pub up_to_date: Vec<String>,
+
+    /// Installed packages from a channel subdir whose repodata could not be fetched.
+    pub unchecked: Vec<String>,
+
+    /// Installed packages from a channel that is not configured.
+    pub unconfigured: Vec<String>,
+
+    /// The channel subdirs whose repodata could not be fetched.
+    pub failed: Vec<SubdirFailure>,
+}
+
+impl UpdateReport {
+    /// Returns the updates of the given kind.
+    pub fn updates_of_kind(&self, kind: UpdateKind) -> impl Iterator<Item = &PackageUpdate> {
+        self.updates.iter().filter(move |update| update.kind == kind)
+    }
+}
+
+/// Represents the outcome of checking a single package.
+enum Outcome {
+    Update(PackageUpdate, Option<HeldBackUpdate>),
+    UpToDate(String, Option<HeldBackUpdate>),
+    Unavailable(String),
+    Unchecked(String),
+    Unconfigured(String),
+}
+
+/// Checks every installed package against the given repodata at once. Each
+/// package only considers records from the channel and subdir it was
+/// installed from, and only versions allowed by all pins on its name.
+/// Packages from a subdir in `failed` are reported unchecked rather than
+/// unavailable.
+pub fn check_updates(
+    installed: &[PrefixRecord],
+    subdirs: &[SubdirRepodata],
+    failed: &[SubdirFailure],
+    pins: &[MatchSpec],
+    channel_config: &ChannelConfig,
+) -> UpdateReport {
+    let configured: HashSet<String> = subdirs
+        .iter()
+        .map(|subdir| subdir.channel.canonical_name())
+        .chain(failed.iter().map(|failure| failure.channel.clone()))
+        .collect();
+    let outcomes: Vec<Outcome> = installed
+        .par_iter()
+        .map(|package| check_package(package, subdirs, failed, &configured, pins, channel_config))
+        .collect();
+
+    let mut report = UpdateReport { failed: failed.to_vec(), ..UpdateReport::default() };
+    for outcome in outcomes {
+        match outcome {
+            Outcome::Update(update, held_back) => {
+                report.updates.push(update);
+                report.held_back.extend(held_back);
+            }
+            Outcome::UpToDate(name, held_back) => {
+                report.up_to_date.push(name);
+                report.held_back.extend(held_back);
+            }
+            Outcome::Unavailable(name) => report.unavailable.push(name),
+            Outcome::Unchecked(name) => report.unchecked.push(name),
+            Outcome::Unconfigured(name) => report.unconfigured.push(name),
+        }
+    }
+
+    report.updates.sort_by(|a, b| a.kind.cmp(&b.kind).then_with(|| a.name.cmp(&b.name)));
+    report.unavailable.sort();
+    report.up_to_date.sort();
+    report.unchecked.sort();
+    report.unconfigured.sort();
+    report
+}
+
+fn check_package(
+    package: &PrefixRecord,
+    subdirs: &[SubdirRepodata],
+    failed: &[SubdirFailure],
+    configured: &HashSet<String>,
+    pins: &[MatchSpec],
+    channel_config: &ChannelConfig,
+) -> Outcome {
+    let installed = &package.record;
+    let installed_channel = package
+        .channel
+        .as_deref()
+        .map(|channel| canonical_channel(channel, channel_config));
+
+    if let Some(channel) = &installed_channel {
+        if !configured.contains(channel) {
+            return Outcome::Unconfigured(installed.name.clone());
+        }
+    }
+    // Records missing from a subdir that was not fetched prove nothing
+    let unfetched = failed.iter().any(|failure| {
+        installed_channel.iter().all(|channel| &failure.channel == channel)
+            && (installed.subdir.is_empty() || failure.subdir == installed.subdir)
+    });
+    if unfetched {
+        return Outcome::Unchecked(installed.name.clone());
+    }
+
+    let candidates: Vec<(&SubdirRepodata, &PackageRecord)> = subdirs
+        .iter()
+        .filter(|subdir| {
+            installed_channel
+                .as_ref()
+                .map_or(true, |channel| &subdir.channel.canonical_name() == channel)
+        })
+        .filter(|subdir| installed.subdir.is_empty() || subdir.platform.as_str() == installed.subdir)
+        .flat_map(|subdir| {
+            subdir
+                .repodata
+                .records_named(&installed.name)
+                .map(move |(_, record)| (subdir, record))
+        })
+        .collect();
+
+    if candidates.is_empty() {
+        return Outcome::Unavailable(installed.name.clone());
+    }
+
+    let package_pins: Vec<&MatchSpec> = pins.iter().filter(|pin| pin.name == installed.name).collect();
+    let newest_any = newest(candidates.iter().copied());
+    let newest_allowed = newest(
+        candidates
+            .iter()
+            .copied()
+            .filter(|(_, record)| package_pins.iter().all(|pin| pin.matches(record))),
+    );
+
+    let held_back = match (newest_any, newest_allowed) {
+        (Some((_, any)), allowed) if allowed.map_or(true, |(_, allowed)| compare_records(any, allowed) == Ordering::Greater) => {
+            let pin = package_pins
+                .iter()
+                .find(|pin| !pin.matches(any))
+                .map(|pin| pin.to_string())
+                .unwrap_or_default();
+            Some(HeldBackUpdate {
+                name: installed.name.clone(),
+                newest_version: any.version.clone(),
+                pin,
+            })
+        }
+        _ => None,
+    };
+
+    match newest_allowed {
+        Some((subdir, latest)) if compare_records(latest, installed) == Ordering::Greater => {
+            let update = PackageUpdate {
+                name: installed.name.clone(),
+                installed_version: installed.version.clone(),
+                installed_build: installed.build.clone(),
+                latest_version: latest.version.clone(),
+                latest_build: latest.build.clone(),
+                channel: subdir.channel.canonical_name(),
+                subdir: subdir.platform.as_str().to_string(),
+                kind: classify(&installed.version, &latest.version),
+            };
+            Outcome::Update(update, held_back)
+        }
+        _ => Outcome::UpToDate(installed.name.clone(), held_back),
+    }
+}
+
+/// Returns the newest record by version, then build number.
+fn newest<'a, I>(records: I) -> Option<(&'a SubdirRepodata, &'a PackageRecord)>
+where
+    I: Iterator<Item = (&'a SubdirRepodata, &'a PackageRecord)>,
+{
+    records.max_by(|(_, a), (_, b)| compare_records(a, b))
+}
+
+fn compare_records(a: &PackageRecord, b: &PackageRecord) -> Ordering {
+    match (a.version.parse::<VersionOrder>(), b.version.parse::<VersionOrder>()) {
+        (Ok(x), Ok(y)) => x.cmp(&y),
+        _ => Ordering::Equal,
+    }
+    .then(a.build_number.cmp(&b.build_number))
+}
+
+/// Classifies an update by the first numeric version segment that changed.
+fn classify(installed: &str, latest: &str) -> UpdateKind {
+    let (installed, latest) = match (installed.parse::<VersionOrder>(), latest.parse::<VersionOrder>()) {
+        (Ok(installed), Ok(latest)) => (installed, latest),
+        _ => return UpdateKind::Major,
+    };
+    if installed == latest {
+        return UpdateKind::BuildOnly;
+    }
+
+    let old = installed.numeric_segments();
+    let new = latest.numeric_segments();
+    let first_change = (0..old.len().max(new.len()))
+        .find(|&i| old.get(i).unwrap_or(&0) != new.get(i).unwrap_or(&0));
+
+    match first_change {
+        Some(0) => UpdateKind::Major,
+        Some(1) => UpdateKind::Minor,
+        _ => UpdateKind::Patch,
+    }
+}
+
+/// Returns the canonical name of a channel as recorded in `conda-meta`,
+/// which may be a name or a subdir URL.
+fn canonical_channel(channel: &str, config: &ChannelConfig) -> String {
+    config
+        .resolve(channel)
+        .ok()
+        .and_then(|channels| channels.into_iter().next())
+        .map_or_else(|| channel.to_string(), |channel| channel.canonical_name())
+}
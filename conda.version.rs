@@ -0,0 +1,279 @@
+// conda.version.rs
+
+use std::cmp::Ordering;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::str::FromStr;
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use thiserror::Error;
+
+/// Represents a conda package version, ordered the way conda orders them:
+/// `1.1dev1 < 1.1a1 < 1.1rc1 < 1.1 < 1.1.post1 < 1.1.1`.
+#[derive(Debug, Clone)]
+pub struct VersionOrder {
+    source: String,
+    epoch: u64,
+    version: Vec<Vec<Part>>,
+    local: Vec<Vec<Part>>,
+}
+
+/// Represents one alphanumeric run of a version component.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Part {
+    Number(u64),
+    Text(String),
+}
+
+impl Part {
+    /// Ranks parts: `*` < `dev` < other strings < numbers < `post`.
+    fn rank(&self) -> u8 {
+        match self {
+            Part::Text(s) if s == "*" => 0,
+            Part::Text(s) if s == "dev" => 1,
+            Part::Text(s) if s == "post" => 4,
+            Part::Text(_) => 2,
+            Part::Number(_) => 3,
+        }
+    }
+}
+
+impl Ord for Part {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (self, other) {
+            (Part::Number(a), Part::Number(b)) => a.cmp(b),
+            (Part::Text(a), Part::Text(b)) if self.rank() == other.rank() => a.cmp(b),
+            _ => self.rank().cmp(&other.rank()),
+        }
+    }
+}
+
+impl PartialOrd for Part {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Represents possible errors that can occur when parsing versions.
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum VersionError {
+    #[error("Empty version string")]
+    Empty,
+
+    #[error("Invalid version {0}: {1}")]
+    Invalid(String, &'static str),
+}
+
+impl VersionOrder {
+    /// Returns the version string this was parsed from.
+    pub fn as_str(&self) -> &str {
+        &self.source
+    }
+
+    /// Returns the leading numeric segments of the version, e.g. `[1, 21, 3]`
+    /// for `1.21.3rc1`. Parsing stops at the first segment that does not
+    /// start with a number.
+    pub fn numeric_segments(&self) -> Vec<u64> {
+        self.version
+            .iter()
+            .map_while(|component| match component.first() {
+                Some(Part::Number(n)) => Some(*n),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Returns whether the version is a pre-release (`dev`, `a`, `b`, `rc`, ...).
+    pub fn is_prerelease(&self) -> bool {
+        self.version
+            .iter()
+            .flatten()
+            .any(|part| matches!(part, Part::Text(s) if s != "post" && s != "*"))
+    }
+
+    /// Returns whether `self` starts with all components of `prefix`, as used by `1.2.*` specs.
+    pub fn starts_with(&self, prefix: &VersionOrder) -> bool {
+        if self.epoch != prefix.epoch || prefix.version.len() > self.version.len() {
+            return false;
+        }
+        prefix.version.iter().zip(&self.version).enumerate().all(|(i, (p, v))| {
+            if i + 1 == prefix.version.len() {
+                // The last component may be a prefix, e.g. `1.1` matches `1.1a1`.
+                p.len() <= v.len() && p.iter().zip(v).all(|(a, b)| a == b)
+            } else {
+                p == v
+            }
+        })
+    }
+
+    /// Returns whether `other` satisfies `~=self`: it is at least `self` and
+    /// matches every component of `self` except the last.
+    pub fn is_compatible_with(&self, other: &VersionOrder) -> bool {
+        if other < self {
+            return false;
+        }
+        if self.version.len() < 2 {
+            return true;
+        }
+        let prefix = VersionOrder {
+            source: String::new(),
+            epoch: self.epoch,
+            version: self.version[..self.version.len() - 1].to_vec(),
+            local: Vec::new(),
+        };
+        other.starts_with(&prefix)
+    }
+}
+
+fn parse_components(text: &str) -> Result<Vec<Vec<Part>>, &'static str> {
+    text.split('.')
+        .map(|component| {
+            if component.is_empty() {
+                return Err("empty version component");
+            }
+
+            let mut parts = Vec::new();
+            let mut chars = component.chars().peekable();
+            while let Some(&c) = chars.peek() {
+                if c.is_ascii_digit() {
+                    let mut digits = String::new();
+                    while let Some(&d) = chars.peek().filter(|d| d.is_ascii_digit()) {
+                        digits.push(d);
+                        chars.next();
+                    }
+                    parts.push(Part::Number(digits.parse().map_err(|_| "number out of range")?));
+                } else if c.is_ascii_alphabetic() {
+                    let mut letters = String::new();
+                    while let Some(&l) = chars.peek().filter(|l| l.is_ascii_alphabetic()) {
+                        letters.push(l);
+                        chars.next();
+                    }
+                    parts.push(Part::Text(letters));
+                } else if c == '*' {
+                    parts.push(Part::Text("*".to_string()));
+                    chars.next();
+                } else {
+                    return Err("invalid character");
+                }
+            }
+
+            // Components that start with letters sort as if preceded by zero.
+            if let Some(Part::Text(_)) = parts.first() {
+                parts.insert(0, Part::Number(0));
+            }
+            Ok(parts)
+        })
+        .collect()
+}
+
+/// Compares component lists, padding the shorter one with zeros.
+fn compare_components(a: &[Vec<Part>], b: &[Vec<Part>]) -> Ordering {
+    let zero = Part::Number(0);
+    for i in 0..a.len().max(b.len()) {
+        let x = a.get(i).map(Vec::as_slice).unwrap_or_default();
+        let y = b.get(i).map(Vec::as_slice).unwrap_or_default();
+        for j in 0..x.len().max(y.len()) {
+            let ordering = x.get(j).unwrap_or(&zero).cmp(y.get(j).unwrap_or(&zero));
+            if ordering != Ordering::Equal {
+                return ordering;
+            }
+        }
+    }
+    Ordering::Equal
+}
+
+impl FromStr for VersionOrder {
+    type Err = VersionError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let source = s.trim().to_string();
+        if source.is_empty() {
+            return Err(VersionError::Empty);
+        }
+        let invalid = |reason| VersionError::Invalid(source.clone(), reason);
+
+        let lowered = source.to_lowercase();
+        let (rest, local) = match lowered.split_once('+') {
+            Some((rest, local)) => (rest, Some(local)),
+            None => (lowered.as_str(), None),
+        };
+        let (epoch, rest) = match rest.split_once('!') {
+            Some((epoch, rest)) => (epoch.parse().map_err(|_| invalid("epoch must be an integer"))?, rest),
+            None => (0, rest),
+        };
+
+        // Dashes are only separators when underscores are not used.
+        let normalize = |text: &str| {
+            if text.contains('_') {
+                text.replace('_', ".")
+            } else {
+                text.replace('-', ".")
+            }
+        };
+
+        Ok(VersionOrder {
+            epoch,
+            version: parse_components(&normalize(rest)).map_err(invalid)?,
+            local: match local {
+                Some(local) => parse_components(&normalize(local)).map_err(invalid)?,
+                None => Vec::new(),
+            },
+            source,
+        })
+    }
+}
+
+impl Ord for VersionOrder {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.epoch
+            .cmp(&other.epoch)
+            .then_with(|| compare_components(&self.version, &other.version))
+            .then_with(|| compare_components(&self.local, &other.local))
+    }
+}
+
+impl PartialOrd for VersionOrder {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for VersionOrder {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for VersionOrder {}
+
+impl Hash for VersionOrder {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        // Equal versions may differ in trailing zeros, so only hash the epoch
+        // and the leading numeric segments without trailing zeros.
+        self.epoch.hash(state);
+        let mut segments = self.numeric_segments();
+        while segments.last() == Some(&0) {
+            segments.pop();
+        }
+        segments.hash(state);
+    }
+}
+
+impl fmt::Display for VersionOrder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+impl Serialize for VersionOrder {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.source)
+    }
+}
+
+impl<'de> Deserialize<'de> for VersionOrder {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let source = String::deserialize(deserializer)?;
+        source.parse().map_err(serde::de::Error::custom)
+    }
+}